@@ -0,0 +1,305 @@
+use crate::downloader::IpNet;
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use url::Url;
+
+const MAX_NESTING: usize = 4; // proxy URLs in proxy URLs, and encodings of the same one
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessConfig {
+    pub restricted: Vec<Restriction>, // empty to allow everything to everyone
+    pub signing_key: Option<String>,  // for `sig`, none to only trust networks
+    pub trusted_networks: Vec<IpNet>, // clients allowed restricted requests unsigned
+    pub self_urls: Vec<Url>,          // where this proxy is reachable, besides the Host header
+}
+
+// A parameter only signed or trusted requests may use, e.g. `origin`, or with a value,
+// e.g. `preset=full`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Restriction {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Restriction {
+    fn matches(&self, query: &HashMap<String, String>) -> bool {
+        match (query.get(&self.name), &self.value) {
+            (Some(value), Some(restricted)) => value == restricted,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+impl FromStr for Restriction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+            None => (s.trim(), None),
+        };
+        if name.is_empty() || matches!(name, "url" | "sig") {
+            return Err(format!("invalid restricted parameter: {s}"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+impl fmt::Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={value}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Comma separated list, e.g. `origin, preset=full`
+pub fn parse_restrictions(input: &str) -> Result<Vec<Restriction>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Comma separated list, e.g. `https://media.example.com/proxy/`
+pub fn parse_self_urls(input: &str) -> Result<Vec<Url>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| Url::parse(part.trim()).map_err(|err| format!("invalid URL {part}: {err}")))
+        .collect()
+}
+
+// Before presets are applied, so that those of the operator may use restricted parameters
+// while clients can't pick arbitrary ones
+pub fn permits(
+    config: &AccessConfig,
+    query: &HashMap<String, String>,
+    path_and_query: &str,
+    client: &str,
+) -> bool {
+    if !config.restricted.iter().any(|rule| rule.matches(query)) {
+        return true;
+    }
+    if let Ok(ip) = client.parse::<IpAddr>()
+        && config.trusted_networks.iter().any(|net| net.contains(ip))
+    {
+        return true;
+    }
+    config
+        .signing_key
+        .as_deref()
+        .is_some_and(|key| is_signed(key, path_and_query))
+}
+
+// Whether the url parameter leads back to this proxy: to a configured self URL, or to the
+// requested path on the requested host. Through other proxies too, each with its own url
+// parameter, and however many times it's percent-encoded
+pub fn is_loop(config: &AccessConfig, url: &str, host: Option<&str>, path: &str) -> bool {
+    let host = host.map(|host| {
+        let host = host.rsplit_once(':').map_or(host, |(name, port)| {
+            match port.bytes().all(|b| b.is_ascii_digit()) {
+                true => name,
+                false => host, // a bare IPv6 address
+            }
+        });
+        host.trim_end_matches('.').to_ascii_lowercase()
+    });
+    let mut url = url.to_string();
+    for _ in 0..MAX_NESTING {
+        let Some(parsed) = decode(&url) else {
+            return false;
+        };
+        let target_host = parsed.host_str().unwrap_or_default().trim_end_matches('.');
+        let is_self = config.self_urls.iter().any(|self_url| {
+            self_url.host_str() == Some(target_host) && parsed.path().starts_with(self_url.path())
+        });
+        if is_self || (host.as_deref() == Some(target_host) && parsed.path() == path) {
+            return true;
+        }
+        match parsed.query_pairs().find(|(key, _)| key == "url") {
+            Some((_, nested)) => url = nested.into_owned(),
+            None => return false,
+        }
+    }
+    true // nested deeper than anyone would on purpose
+}
+
+// Percent-decoded until it's an absolute http(s) URL
+fn decode(url: &str) -> Option<Url> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_NESTING {
+        if let Ok(parsed) = Url::parse(&url)
+            && matches!(parsed.scheme(), "http" | "https")
+        {
+            return Some(parsed);
+        }
+        let decoded = percent_decode_str(&url).decode_utf8_lossy().into_owned();
+        if decoded == url {
+            return None;
+        }
+        url = decoded;
+    }
+    None
+}
+
+// `sig` last, the hex HMAC-SHA256 of everything before it, e.g. `/a.webp?url=...&origin=1`
+// signed and then `&sig=...` appended
+fn is_signed(key: &str, path_and_query: &str) -> bool {
+    let Some((message, signature)) = path_and_query
+        .rsplit_once("&sig=")
+        .or_else(|| path_and_query.rsplit_once("?sig="))
+    else {
+        return false;
+    };
+    let Some(signature) = unhex(signature) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(message.as_bytes());
+    mac.verify_slice(&signature).is_ok() // in constant time
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(key: &str, message: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("{message}&sig={signature}")
+    }
+
+    #[test]
+    fn test_permits() {
+        let config = AccessConfig {
+            restricted: parse_restrictions("origin, preset=full,").unwrap(),
+            signing_key: Some("secret".to_string()),
+            trusted_networks: vec!["10.0.0.0/8".parse().unwrap()],
+            self_urls: Vec::new(),
+        };
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let public = query(&[("url", "u"), ("emoji", "1"), ("preset", "small")]);
+        let origin = query(&[("url", "u"), ("origin", "1")]);
+        let full = query(&[("url", "u"), ("preset", "full")]);
+        let path = "/a.webp?url=u&origin=1";
+
+        assert!(permits(
+            &config,
+            &public,
+            "/a.webp?url=u&emoji=1",
+            "203.0.113.1"
+        ));
+        assert!(!permits(&config, &origin, path, "203.0.113.1"));
+        assert!(!permits(
+            &config,
+            &full,
+            "/a.webp?url=u&preset=full",
+            "203.0.113.1"
+        ));
+        assert!(permits(&config, &origin, path, "10.1.2.3"));
+        assert!(permits(
+            &config,
+            &origin,
+            &sign("secret", path),
+            "203.0.113.1"
+        ));
+        assert!(!permits(
+            &config,
+            &origin,
+            &sign("other", path),
+            "203.0.113.1"
+        ));
+        // Changed after signing
+        let tampered = sign("secret", path).replace("url=u", "url=v");
+        assert!(!permits(&config, &origin, &tampered, "203.0.113.1"));
+        assert!(!permits(
+            &config,
+            &origin,
+            &format!("{path}&sig=zz"),
+            "203.0.113.1"
+        ));
+
+        // A trusted address the client put in front of X-Forwarded-For itself
+        let client = crate::ratelimit::forwarded_client("10.0.0.1, 203.0.113.1", 1).unwrap();
+        assert!(!permits(&config, &origin, path, client));
+        let client = crate::ratelimit::forwarded_client("203.0.113.1, 10.0.0.1", 1).unwrap();
+        assert!(permits(&config, &origin, path, client));
+
+        assert_eq!(config.restricted[1].to_string(), "preset=full");
+        assert!("url".parse::<Restriction>().is_err());
+        assert!("=1".parse::<Restriction>().is_err());
+    }
+
+    #[test]
+    fn test_is_loop() {
+        let config = AccessConfig {
+            self_urls: vec![Url::parse("https://media.example.com/proxy/").unwrap()],
+            ..Default::default()
+        };
+        let host = Some("proxy.internal:3000");
+        let path = "/image.webp";
+        let is_loop = |url: &str| is_loop(&config, url, host, path);
+
+        assert!(!is_loop("https://files.example.org/a.png"));
+        assert!(!is_loop("https://media.example.com/files/a.png"));
+        assert!(!is_loop("data:image/png;base64,AAAA"));
+
+        assert!(is_loop("https://media.example.com/proxy/avatar.webp?url=x"));
+        assert!(is_loop("https://MEDIA.example.com./proxy/a.webp"));
+        assert!(is_loop("http://proxy.internal/image.webp?url=x"));
+        assert!(!is_loop("http://proxy.internal/other.webp"));
+        // Encoded once more, or twice
+        assert!(is_loop("https%3A%2F%2Fmedia.example.com%2Fproxy%2Fa.webp"));
+        assert!(is_loop(
+            "https%253A%252F%252Fmedia.example.com%252Fproxy%252Fa.webp"
+        ));
+        // Through another proxy
+        assert!(is_loop(
+            "https://other.example/proxy/a.webp?url=https%3A%2F%2Fmedia.example.com%2Fproxy%2Fa.webp"
+        ));
+        assert!(!is_loop(
+            "https://other.example/proxy/a.webp?url=https%3A%2F%2Ffiles.example.org%2Fa.png"
+        ));
+
+        assert!(super::is_loop(
+            &AccessConfig::default(),
+            "http://[::1]:3000/image.webp",
+            Some("[::1]:3000"),
+            path
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+mod rpc;
+
+use crate::config::Config;
+use crate::downloader::Downloader;
+use crate::quarantine::{Quarantine, QuarantineEntry};
+use crate::{AppState, empty, full};
+use bytes::Bytes;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Incoming;
+use serde_json::{Value, json};
+use std::fmt::Write;
+use tracing::{error, info, warn};
+use url::form_urlencoded;
+
+const PREFIX: &str = "/admin/";
+const MAX_RPC_BODY: usize = 1 << 20;
+
+// Whether it's a request to the admin endpoints (they're disabled without a token)
+pub fn is_admin<B>(config: &Config, req: &Request<B>) -> bool {
+    config.admin_token.is_some() && req.uri().path().starts_with(PREFIX)
+}
+
+pub async fn handle(
+    state: &AppState,
+    config: &Config,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let token = config.admin_token.as_deref().unwrap_or_default();
+    let endpoint = req.uri().path().strip_prefix(PREFIX).unwrap_or_default();
+
+    if !authorized(&req, token) {
+        warn!("Unauthorized admin request: {}", req.uri().path());
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    match (req.method(), endpoint) {
+        (&Method::GET, "redirects") => text(list_redirects(&state.downloader.load())),
+        (_, "redirects") => status(StatusCode::METHOD_NOT_ALLOWED),
+        (&Method::GET, "quarantine") => text(list_quarantine(&state.quarantine)),
+        (&Method::POST | &Method::DELETE, "quarantine") => {
+            update_quarantine(&state.quarantine, req.method(), req.uri().query()).await
+        }
+        (_, "quarantine") => status(StatusCode::METHOD_NOT_ALLOWED),
+        (&Method::POST, "rpc") => {
+            let body = match Limited::new(req.into_body(), MAX_RPC_BODY).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            };
+            match rpc::handle(state, &String::from_utf8_lossy(&body)).await {
+                Some(body) => {
+                    let mut response = Response::new(full(body));
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+                    response
+                }
+                None => status(StatusCode::NO_CONTENT), // only notifications
+            }
+        }
+        (_, "rpc") => status(StatusCode::METHOD_NOT_ALLOWED),
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+fn authorized<B>(req: &Request<B>, token: &str) -> bool {
+    let Some(provided) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    // Compare in constant time, so the token can't be guessed byte by byte
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// One `FROM TO AGE_SECONDS` line per learned redirect
+fn list_redirects(downloader: &Downloader) -> String {
+    let mut body = String::new();
+    for (from, to, age) in downloader.permanent_redirects() {
+        let _ = writeln!(body, "{from} {to} {}", age.as_secs());
+    }
+    body
+}
+
+fn list_redirects_json(downloader: &Downloader) -> Value {
+    let redirects: Vec<_> = downloader
+        .permanent_redirects()
+        .into_iter()
+        .map(|(from, to, age)| json!({"from": from, "to": to, "age_secs": age.as_secs()}))
+        .collect();
+    Value::from(redirects)
+}
+
+// One `url <url>` or `sha256 <hex>` line per entry, same as the quarantine file
+fn list_quarantine(quarantine: &Quarantine) -> String {
+    let mut body = String::new();
+    for entry in quarantine.list() {
+        let _ = writeln!(body, "{entry}");
+    }
+    body
+}
+
+// POST to add, DELETE to remove, with either `?url=` or `?sha256=`
+async fn update_quarantine(
+    quarantine: &Quarantine,
+    method: &Method,
+    query: Option<&str>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let entry = match parse_quarantine_entry(query) {
+        Ok(entry) => entry,
+        Err(err) => {
+            let mut response = text(err);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    let add = method == Method::POST;
+    match change_quarantine(quarantine, add, entry).await {
+        Ok(true) if add => status(StatusCode::CREATED),
+        Ok(false) if add => status(StatusCode::OK),
+        Ok(true) => status(StatusCode::NO_CONTENT),
+        Ok(false) => status(StatusCode::NOT_FOUND),
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// Add or remove, whether it changed anything
+async fn change_quarantine(
+    quarantine: &Quarantine,
+    add: bool,
+    entry: QuarantineEntry,
+) -> Result<bool, String> {
+    let result = if add {
+        quarantine.add(entry.clone()).await.inspect(|added| {
+            if *added {
+                info!(target: "audit", "Quarantine entry added: {entry}");
+            }
+        })
+    } else {
+        quarantine.remove(&entry).await.inspect(|removed| {
+            if *removed {
+                info!(target: "audit", "Quarantine entry removed: {entry}");
+            }
+        })
+    };
+    // The change is kept in memory, but will be lost after a restart (or a sync)
+    result.inspect_err(|err| error!("Failed to save quarantine list: {err}"))
+}
+
+fn parse_quarantine_entry(query: Option<&str>) -> Result<QuarantineEntry, String> {
+    let query = query.unwrap_or_default();
+    match form_urlencoded::parse(query.as_bytes()).next() {
+        Some((key, value)) if key == "url" => QuarantineEntry::url(&value),
+        Some((key, value)) if key == "sha256" => QuarantineEntry::sha256(&value),
+        _ => Err("either url or sha256 is required".to_string()),
+    }
+}
+
+fn text(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(full(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    response
+}
+
+fn status(status_code: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = status_code;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quarantine_entry() {
+        assert_eq!(
+            parse_quarantine_entry(Some("url=https%3A%2F%2Fexample.com%2Fa.png")),
+            Ok(QuarantineEntry::Url(
+                "https://example.com/a.png".to_string()
+            ))
+        );
+        assert!(parse_quarantine_entry(Some("sha256=abc")).is_err());
+        assert!(parse_quarantine_entry(None).is_err());
+    }
+
+    #[test]
+    fn test_authorized() {
+        let req = |value: &str| {
+            Request::builder()
+                .uri("/admin/redirects")
+                .header(AUTHORIZATION, value)
+                .body(())
+                .unwrap()
+        };
+        assert!(authorized(&req("Bearer secret"), "secret"));
+        assert!(!authorized(&req("Bearer secreT"), "secret"));
+        assert!(!authorized(&req("Bearer secret2"), "secret"));
+        assert!(!authorized(&req("secret"), "secret"));
+    }
+}
@@ -0,0 +1,189 @@
+use super::{change_quarantine, list_redirects_json};
+use crate::AppState;
+use crate::quarantine::QuarantineEntry;
+use serde_json::{Value, json};
+use std::sync::atomic::Ordering;
+use tracing::info;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INTERNAL_ERROR: i32 = -32603;
+
+// Description of the methods for generating clients, https://open-rpc.org
+const SCHEMA: &str = include_str!("../../openrpc.json");
+
+struct RpcError(i32, String);
+
+fn error(id: &Value, RpcError(code, message): RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "error": {"code": code, "message": message}, "id": id})
+}
+
+fn quarantine_entry(params: Option<&Value>) -> Result<QuarantineEntry, RpcError> {
+    let params = params.unwrap_or(&Value::Null);
+    let entry = match (
+        params.get("url").and_then(Value::as_str),
+        params.get("sha256").and_then(Value::as_str),
+    ) {
+        (Some(url), None) => QuarantineEntry::url(url),
+        (None, Some(sha256)) => QuarantineEntry::sha256(sha256),
+        _ => Err("either url or sha256 is required".to_string()),
+    };
+    entry.map_err(|err| RpcError(INVALID_PARAMS, err))
+}
+
+async fn call(state: &AppState, method: &str, params: Option<&Value>) -> Result<Value, RpcError> {
+    match method {
+        "redirects.list" => Ok(list_redirects_json(&state.downloader.load())),
+        "quarantine.list" => {
+            let entries: Vec<_> = state
+                .quarantine
+                .list()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            Ok(Value::from(entries))
+        }
+        "quarantine.add" | "quarantine.remove" => {
+            let entry = quarantine_entry(params)?;
+            let add = method == "quarantine.add";
+            change_quarantine(&state.quarantine, add, entry)
+                .await
+                .map(Value::from)
+                .map_err(|err| RpcError(INTERNAL_ERROR, err))
+        }
+        "cache.purge" => {
+            let url = match params.and_then(|params| params.get("url")) {
+                None => None,
+                Some(Value::String(url)) => Some(url.as_str()),
+                Some(_) => {
+                    return Err(RpcError(INVALID_PARAMS, "url must be a string".to_string()));
+                }
+            };
+            state.downloader.load().purge(url);
+            info!(target: "audit", "Caches purged for {}", url.unwrap_or("all URLs"));
+            Ok(Value::Null)
+        }
+        "drain.start" | "drain.stop" => {
+            let draining = method == "drain.start";
+            let changed = state.draining.swap(draining, Ordering::Relaxed) != draining;
+            if changed {
+                info!(target: "audit", "Draining {}", if draining { "started" } else { "stopped" });
+            }
+            Ok(Value::from(changed))
+        }
+        "stats.get" => Ok(state.stats.snapshot().to_value()),
+        "rpc.discover" => serde_json::from_str(SCHEMA)
+            .map_err(|err| RpcError(INTERNAL_ERROR, format!("invalid schema: {err}"))),
+        _ => Err(RpcError(
+            METHOD_NOT_FOUND,
+            format!("method not found: {method}"),
+        )),
+    }
+}
+
+// Whether it's a JSON-RPC 2.0 request, with a method, structured params and a valid id
+fn is_valid(request: &Value) -> bool {
+    request.get("jsonrpc").and_then(Value::as_str) == Some("2.0")
+        && request.get("method").is_some_and(Value::is_string)
+        && matches!(
+            request.get("params"),
+            None | Some(Value::Object(_) | Value::Array(_))
+        )
+        && matches!(
+            request.get("id"),
+            None | Some(Value::Null | Value::Number(_) | Value::String(_))
+        )
+}
+
+// None for notifications, which aren't answered
+async fn respond(state: &AppState, request: &Value) -> Option<Value> {
+    let id = request.get("id");
+    if !is_valid(request) {
+        let invalid = RpcError(INVALID_REQUEST, "invalid request".to_string());
+        return Some(error(id.unwrap_or(&Value::Null), invalid));
+    }
+
+    let method = request.get("method").and_then(Value::as_str).unwrap();
+    let result = call(state, method, request.get("params")).await;
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(err) => error(id, err),
+    })
+}
+
+// The response body, None if there's nothing to answer
+pub async fn handle(state: &AppState, body: &str) -> Option<String> {
+    let Ok(request) = serde_json::from_str::<Value>(body) else {
+        let parse_error = RpcError(PARSE_ERROR, "parse error".to_string());
+        return Some(error(&Value::Null, parse_error).to_string());
+    };
+    let response = match &request {
+        Value::Array(batch) if batch.is_empty() => Some(error(
+            &Value::Null,
+            RpcError(INVALID_REQUEST, "empty batch".to_string()),
+        )),
+        Value::Array(batch) => {
+            let mut responses = Vec::new();
+            for request in batch {
+                responses.extend(respond(state, request).await);
+            }
+            (!responses.is_empty()).then(|| Value::from(responses))
+        }
+        request => respond(state, request).await,
+    };
+    response.map(|response| response.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        let request = |json: &str| serde_json::from_str::<Value>(json).unwrap();
+        assert!(is_valid(&request(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "cache.purge", "params": {"url": "https:\/\/a"}}"#
+        )));
+        assert!(is_valid(&request(
+            r#"{"jsonrpc": "2.0", "method": "drain.start"}"#
+        )));
+        for invalid in [
+            r#"{"method": "stats.get"}"#,
+            r#"{"jsonrpc": "2.0"}"#,
+            r#"{"jsonrpc": "2.0", "method": 1}"#,
+            r#"{"jsonrpc": "2.0", "method": "stats.get", "params": "x"}"#,
+            r#"{"jsonrpc": "2.0", "method": "stats.get", "id": {}}"#,
+        ] {
+            assert!(!is_valid(&request(invalid)), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        let Some(Value::Array(methods)) = schema.get("methods") else {
+            panic!("no methods in the schema");
+        };
+        let names: Vec<_> = methods
+            .iter()
+            .filter_map(|method| method.get("name").and_then(Value::as_str))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "redirects.list",
+                "quarantine.list",
+                "quarantine.add",
+                "quarantine.remove",
+                "cache.purge",
+                "drain.start",
+                "drain.stop",
+                "stats.get"
+            ]
+        );
+    }
+}
@@ -1,28 +1,259 @@
+mod backoff;
+mod breaker;
+#[cfg(feature = "tls-mimic")]
+mod browser_tls;
+mod cache;
+mod client;
+mod data;
+#[cfg(not(target_arch = "wasm32"))]
+mod dns;
+mod failures;
+mod hosts;
+mod ipfs;
+#[cfg(not(target_arch = "wasm32"))]
+mod limiter;
+#[cfg(not(target_arch = "wasm32"))]
+mod local;
+mod redirects;
+mod retry;
+mod s3;
+#[cfg(not(target_arch = "wasm32"))]
+mod singleflight;
+mod ssrf;
+mod throttle;
+mod unix;
+
+pub use breaker::BreakerPolicy;
+pub use data::is_data_uri;
+pub use hosts::{
+    DnsOverride, DnsServer, HostPattern, HotlinkRule, HotlinkStrategy, IpNet, IpPreference,
+    MirrorRule, NoProxyRule, OriginHeader, OriginPolicy, OriginRule, parse_dns_overrides,
+    parse_host_patterns, parse_hotlink_rules, parse_mirror_rules, parse_networks, parse_no_proxy,
+    parse_origin_headers, parse_origin_rules,
+};
+pub use retry::RetryPolicy;
+pub use s3::{S3Config, parse_buckets};
+pub use throttle::BandwidthPolicy;
+pub use unix::{UnixSocket, parse_unix_sockets};
+
+use crate::kv::{KvResult, KvStore};
+use backoff::HostBackoff;
+use breaker::CircuitBreaker;
 use bytes::Bytes;
+use cache::{CachePolicy, Lookup, ResponseCache};
+use client::ClientPool;
+use failures::FailureCache;
 use futures_util::stream::StreamExt;
-use http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, REFERER, USER_AGENT};
-use reqwest::header::HeaderMap;
-use reqwest::{Client, StatusCode};
-use std::env;
+use http::header::{
+    ACCEPT, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    USER_AGENT,
+};
+use redirects::RedirectCache;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderValue};
+use ssrf::SsrfGuard;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::{debug, info};
+use std::time::{Duration, Instant, SystemTime};
+use throttle::Throttle;
+use tracing::{debug, warn};
 use url::Url;
 
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::stream::{BoxStream, Stream};
+#[cfg(not(target_arch = "wasm32"))]
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+#[cfg(feature = "server")]
+use http::header::{ORIGIN, REFERER};
 #[cfg(feature = "server")]
 use tokio::sync::RwLock;
+#[cfg(feature = "server")]
+use tracing::info;
 
+#[derive(Clone)]
 pub enum FileDownloadError {
     Oversize,
     InvalidUrl,
+    BlockedAddress,   // private destination (SSRF)
+    RedirectRejected, // too many redirects, or to a scheme we don't fetch
+    OriginDenied,     // redirected to an origin the policy doesn't permit
+    Timeout,
+    NotModified(Box<Provenance>), // the client's copy is still current, with its validators
+    HostBusy,                     // too many fetches queued for the origin host
+    CircuitOpen,                  // the origin host kept failing recently
+    BackingOff(Duration),         // the origin host asked to retry after this long
+    Overloaded(Duration),         // too many downloads in total, retry after this long
     InvalidStatusCode(StatusCode),
-    RequestError(reqwest::Error),
+    RequestError(Arc<reqwest::Error>), // shared with the requests joining the download
+}
+
+impl FileDownloadError {
+    fn from_request(err: reqwest::Error) -> Self {
+        if ssrf::is_blocked(&err) {
+            FileDownloadError::BlockedAddress
+        } else if ssrf::caused_by::<hosts::OriginDenied>(&err) {
+            FileDownloadError::OriginDenied
+        } else if err.is_redirect() {
+            FileDownloadError::RedirectRejected
+        } else if err.is_timeout() {
+            FileDownloadError::Timeout
+        } else {
+            FileDownloadError::RequestError(Arc::new(err))
+        }
+    }
 }
 
 const DEFAULT_SIZE_LIMIT: u64 = 100_000_000; // 100MB
+const DEFAULT_MAX_REDIRECTS: usize = 10; // same as reqwest's default policy
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90); // same as reqwest's
+const DEFAULT_HOST_QUEUE: usize = 64;
+const DEFAULT_MAX_DOWNLOADS: usize = 64;
+const DEFAULT_DOWNLOAD_QUEUE: usize = 256;
+const DEFAULT_DOWNLOAD_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REDIRECT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_ACCEPT: &str = "image/*,*/*";
+const DEFAULT_NON_IMAGE_LIMIT: u64 = 2_000_000; // 2MB
+const SNIFF_LEN: usize = 64; // enough for the magic bytes of any image format
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60); // stalls hit the read timeout
+
+// Forwarded to clients as-is, as the streamed bytes are the origin's
+#[cfg(not(target_arch = "wasm32"))]
+const STREAMED_HEADERS: [http::HeaderName; 6] = [
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    CONTENT_RANGE,
+    ACCEPT_RANGES,
+    ETAG,
+    LAST_MODIFIED,
+];
+
+#[derive(Clone)]
+pub struct DownloaderConfig {
+    pub size_limit: u64,                       // in bytes
+    pub non_image_limit: u64,                  // for images only fetches, zero to disable
+    pub retry_user_agent: Option<String>,      // for hosts with hotlink protection
+    pub hotlink_rules: Vec<HotlinkRule>,       // how to retry for them, per origin host
+    pub browser_tls_hosts: Vec<HostPattern>,   // use browser-like TLS for these (tls-mimic feature)
+    pub upstream_http2: bool,                  // allow negotiating HTTP/2 with origins
+    pub http1_only_hosts: Vec<HostPattern>,    // for origins with broken HTTP/2
+    pub http3_hosts: Vec<HostPattern>,         // use HTTP/3 for these (http3 feature)
+    pub max_redirects: usize,                  // zero to not follow redirects at all
+    pub allow_redirect_downgrade: bool,        // follow https:// to http:// redirects
+    pub connect_timeout: Duration,             // zero for no timeout, same for the other two
+    pub read_timeout: Duration,                // between two reads
+    pub download_timeout: Duration,            // each request, including redirects and body
+    pub pool_max_idle_per_host: Option<usize>, // kept open for reuse, none for unlimited
+    pub pool_idle_timeout: Duration,           // closed after idling this long, zero for never
+    pub host_concurrency: usize,               // fetches per origin host, zero for unlimited
+    pub host_queue: usize,                     // fetches waiting per host, rejected beyond
+    pub max_downloads: usize,                  // fetches from all hosts, zero for unlimited
+    pub download_queue: usize,                 // fetches waiting for any of them, rejected beyond
+    pub download_queue_timeout: Duration,      // waiting longer is rejected too
+    pub retry: RetryPolicy,                    // for transient failures, disabled by default
+    pub breaker: BreakerPolicy,                // for dead origins, disabled by default
+    pub bandwidth: BandwidthPolicy,            // unlimited by default
+    pub mirrors: Vec<MirrorRule>,              // tried in order when the origin fails
+    pub redirect_cache_ttl: Duration,          // remember 301/308 redirects, zero to disable
+    pub negative_cache_ttl: Duration,          // remember failed fetches, zero to disable
+    pub cache_size: u64,                       // in bytes, zero to disable the response cache
+    pub cache_default_ttl: Duration,           // for responses without expiration or validators
+    pub origins: OriginPolicy,                 // checked for the URL and each redirect
+    pub origin_headers: Vec<OriginHeader>,     // sent to matching hosts, never redirected away
+    pub allowed_private_networks: Vec<IpNet>,  // internal origins allowed on purpose
+    pub dns_overrides: Vec<DnsOverride>,       // fixed addresses, bypassing DNS
+    pub unix_sockets: Vec<UnixSocket>,         // hosts fetched through sockets, bypassing TCP
+    pub dns_server: Option<DnsServer>,         // instead of the system resolver
+    pub outbound_prefer: IpPreference,         // family tried first when connecting
+    pub outbound_address: Option<IpAddr>,      // source address of fetches
+    pub http_proxy: Option<Url>,               // outbound proxy, http:// or https://
+    pub socks_proxy: Option<Url>,              // socks5:// or socks5h://, preferred for .onion
+    pub no_proxy: Vec<NoProxyRule>,            // fetched directly even with a proxy
+    pub ca_bundle: Option<Bytes>,              // extra trusted root certificates, PEM
+    pub insecure_tls: bool,                    // skip certificate verification, for debugging only
+    pub local_files_root: Option<PathBuf>,     // file:// URLs allowed below it, disabled by default
+    pub s3: Option<S3Config>,                  // s3:// URLs fetched with it, disabled by default
+    pub ipfs_gateway: Option<Url>, // ipfs:// URLs fetched through it, disabled by default
+    pub trust_ipfs_gateway: bool,  // for the content it can't be checked against the CID
+}
+
+impl Default for DownloaderConfig {
+    fn default() -> Self {
+        Self {
+            size_limit: DEFAULT_SIZE_LIMIT,
+            non_image_limit: DEFAULT_NON_IMAGE_LIMIT,
+            retry_user_agent: None,
+            hotlink_rules: Vec::new(),
+            browser_tls_hosts: Vec::new(),
+            upstream_http2: true,
+            http1_only_hosts: Vec::new(),
+            http3_hosts: Vec::new(),
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            allow_redirect_downgrade: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            download_timeout: DEFAULT_DOWNLOAD_TIMEOUT,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            host_concurrency: 0,
+            host_queue: DEFAULT_HOST_QUEUE,
+            max_downloads: DEFAULT_MAX_DOWNLOADS,
+            download_queue: DEFAULT_DOWNLOAD_QUEUE,
+            download_queue_timeout: DEFAULT_DOWNLOAD_QUEUE_TIMEOUT,
+            retry: RetryPolicy::default(),
+            breaker: BreakerPolicy::default(),
+            bandwidth: BandwidthPolicy::default(),
+            mirrors: Vec::new(),
+            redirect_cache_ttl: DEFAULT_REDIRECT_CACHE_TTL,
+            negative_cache_ttl: DEFAULT_NEGATIVE_CACHE_TTL,
+            cache_size: 0,
+            cache_default_ttl: DEFAULT_CACHE_TTL,
+            origins: OriginPolicy::default(),
+            origin_headers: Vec::new(),
+            allowed_private_networks: Vec::new(),
+            dns_overrides: Vec::new(),
+            unix_sockets: Vec::new(),
+            dns_server: None,
+            outbound_prefer: IpPreference::Auto,
+            outbound_address: None,
+            http_proxy: None,
+            socks_proxy: None,
+            no_proxy: Vec::new(),
+            ca_bundle: None,
+            insecure_tls: false,
+            local_files_root: None,
+            s3: None,
+            ipfs_gateway: None,
+            trust_ipfs_gateway: false,
+        }
+    }
+}
 
 pub struct Downloader {
-    client: Client,
-    size_limit: u64,
+    config: Arc<DownloaderConfig>,
+    clients: ClientPool,
+    redirects: RedirectCache,
+    failures: FailureCache,
+    cache: ResponseCache,
+    guard: SsrfGuard,
+    breaker: CircuitBreaker,
+    backoff: HostBackoff,
+    throttle: Throttle,
+
+    #[cfg(not(target_arch = "wasm32"))]
+    in_flight: singleflight::InFlight,
+    #[cfg(not(target_arch = "wasm32"))]
+    host_limiter: limiter::HostLimiter,
+    #[cfg(not(target_arch = "wasm32"))]
+    download_limiter: limiter::DownloadLimiter,
 
     #[cfg(feature = "server")]
     troublesome_instances: Arc<RwLock<Vec<String>>>,
@@ -31,8 +262,22 @@ pub struct Downloader {
 impl Clone for Downloader {
     fn clone(&self) -> Self {
         Self {
-            client: self.client.clone(),
-            size_limit: self.size_limit,
+            config: self.config.clone(),
+            clients: self.clients.clone(),
+            redirects: self.redirects.clone(),
+            failures: self.failures.clone(),
+            cache: self.cache.clone(),
+            guard: self.guard.clone(),
+            breaker: self.breaker.clone(),
+            backoff: self.backoff.clone(),
+            throttle: self.throttle.clone(),
+
+            #[cfg(not(target_arch = "wasm32"))]
+            in_flight: self.in_flight.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            host_limiter: self.host_limiter.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            download_limiter: self.download_limiter.clone(),
 
             #[cfg(feature = "server")]
             troublesome_instances: self.troublesome_instances.clone(),
@@ -40,45 +285,307 @@ impl Clone for Downloader {
     }
 }
 
+// Where the bytes come from, for debugging layered caches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheTier {
+    Origin,
+    Memory,      // fresh in the response cache
+    Revalidated, // stale in the response cache, but origin says not modified
+    Placeholder, // generated or configured locally
+    Inline,      // in the URL itself (data:)
+    Local,       // read from LOCAL_FILES_ROOT (file:)
+}
+
+impl CacheTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheTier::Origin => "origin",
+            CacheTier::Memory => "memory",
+            CacheTier::Revalidated => "revalidated",
+            CacheTier::Placeholder => "placeholder",
+            CacheTier::Inline => "inline",
+            CacheTier::Local => "local",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Provenance {
+    pub tier: CacheTier,
+    pub fetched_at: SystemTime, // when the origin responded
+    pub initial_age: Duration,  // including the Age reported by the origin (e.g. a CDN)
+    pub storable: bool,         // origin allows shared caches to keep it
+    pub etag: Option<String>,   // validators of the origin, for conditional requests
+    pub last_modified: Option<String>,
+    pub final_url: Option<String>, // where the bytes came from, if redirected
+}
+
+impl Provenance {
+    pub fn new(tier: CacheTier) -> Self {
+        Self {
+            tier,
+            fetched_at: SystemTime::now(),
+            initial_age: Duration::ZERO,
+            storable: true,
+            etag: None,
+            last_modified: None,
+            final_url: None,
+        }
+    }
+
+    // As in the Age header
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.fetched_at.elapsed().unwrap_or_default()
+    }
+}
+
+// Validators the client already has a copy for, forwarded to origins.
+// Along with the formats it accepts, as some origins only send WebP or AVIF when asked
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Conditional {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub accept: Option<String>, // sanitized by the caller, as it's sent as-is
+}
+
+impl Conditional {
+    fn accept(&self) -> HeaderValue {
+        self.accept
+            .as_ref()
+            .and_then(|accept| accept.parse().ok())
+            .unwrap_or(HeaderValue::from_static(DEFAULT_ACCEPT))
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(etags) = self.if_none_match.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_NONE_MATCH, etags);
+        }
+        if let Some(date) = self.if_modified_since.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_MODIFIED_SINCE, date);
+        }
+        headers
+    }
+
+    // Whether the client's copy is still current (weak comparison, as for If-None-Match)
+    pub fn matches(&self, provenance: &Provenance) -> bool {
+        let opaque = |tag: &str| {
+            tag.trim()
+                .trim_start_matches("W/")
+                .trim_matches('"')
+                .to_string()
+        };
+        if let Some(etags) = &self.if_none_match {
+            return provenance.etag.as_deref().is_some_and(|etag| {
+                etags
+                    .split(',')
+                    .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+            });
+        }
+        let date = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+        };
+        match (
+            date(&self.if_modified_since),
+            date(&provenance.last_modified),
+        ) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct DownloadedFile {
     pub bytes: Bytes,
     pub content_type: Option<String>,
     pub filename: (String, Option<String>),
+    pub provenance: Provenance,
+}
+
+// Response of the origin, forwarded while it arrives instead of buffered first
+#[cfg(not(target_arch = "wasm32"))]
+pub struct StreamedFile {
+    pub status: StatusCode, // 206 for ranges
+    pub headers: HeaderMap, // only STREAMED_HEADERS
+    pub filename: (String, Option<String>),
+    pub provenance: Provenance,
+    pub body: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl StreamedFile {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+    }
+}
+
+// Kept with the file for auditing, while caches stay keyed by the requested URL.
+// Not for s3:// and ipfs:// URLs, which are fetched from elsewhere on purpose (and
+// the storage endpoint is private)
+fn final_url(url: &str, resp: &reqwest::Response) -> Option<String> {
+    let parsed = Url::parse(url).ok();
+    let translated = parsed
+        .as_ref()
+        .is_some_and(|parsed| !matches!(parsed.scheme(), "http" | "https"));
+    (!translated && parsed.as_ref() != Some(resp.url())).then(|| {
+        debug!("Redirected to {}: {url}", resp.url());
+        resp.url().to_string()
+    })
+}
+
+// Read without the network (data: and file: URLs), so there's nothing to stream
+pub fn is_local(url: &str) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    if local::is_file_url(url) {
+        return true;
+    }
+    is_data_uri(url)
+}
+
+// From the URL, unless the origin names it
+fn filename(url: &str, headers: &HeaderMap) -> (String, Option<String>) {
+    let mut filename_ascii = url.split('/').next_back().unwrap_or("unknown").to_string();
+    let mut filename_encoded: Option<String> = None;
+    if let Some(content_disposition) = headers.get(CONTENT_DISPOSITION) {
+        let field_parts = content_disposition.to_str().unwrap().split(';');
+        for part in field_parts {
+            let part = part.trim();
+            if let Some(value) = part.strip_prefix("filename=") {
+                filename_ascii = value.trim_matches('"').to_string();
+            } else if let Some(value) = part.strip_prefix("filename*=") {
+                filename_encoded = Some(value.to_string());
+            }
+        }
+    }
+    (filename_ascii, filename_encoded)
 }
 
 impl Downloader {
-    pub fn new(size_limit: Option<u64>) -> Self {
+    pub fn new(config: DownloaderConfig) -> Self {
+        client::warn_unsupported(&config);
+        client::warn_insecure(&config);
+
+        let redirects = RedirectCache::default();
+        let guard = SsrfGuard::new(
+            config.allowed_private_networks.clone(),
+            config.dns_overrides.clone(),
+        )
+        .with_preference(config.outbound_prefer);
+        #[cfg(not(target_arch = "wasm32"))]
+        let guard = guard.with_resolver(dns::Resolver::new(config.dns_server.clone()));
+        #[cfg(not(target_arch = "wasm32"))]
+        let download_limiter = limiter::DownloadLimiter::new(config.max_downloads);
         Self {
-            client: Client::new(),
-            size_limit: size_limit.unwrap_or(DEFAULT_SIZE_LIMIT),
+            clients: ClientPool::new(&config, &redirects, &guard),
+            redirects,
+            failures: FailureCache::default(),
+            guard,
+            cache: ResponseCache::default(),
+            breaker: CircuitBreaker::default(),
+            backoff: HostBackoff::default(),
+            throttle: Throttle::default(),
+            config: Arc::new(config),
+
+            #[cfg(not(target_arch = "wasm32"))]
+            in_flight: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            host_limiter: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            download_limiter,
 
             #[cfg(feature = "server")]
             troublesome_instances: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
-    pub async fn download_file(
-        &self,
-        url: &str,
-        host: Option<&String>,
-    ) -> Result<DownloadedFile, FileDownloadError> {
-        debug!("Downloading file: {url}");
+    // Apply a new config, but keep the runtime states (e.g. learned troublesome instances)
+    pub fn reconfigure(&self, config: DownloaderConfig) -> Self {
+        let fresh = Self::new(config);
+        Self {
+            clients: ClientPool::new(&fresh.config, &self.redirects, &fresh.guard),
+            redirects: self.redirects.clone(),
+            failures: self.failures.clone(),
+            cache: self.cache.clone(),
+            breaker: self.breaker.clone(),
+            backoff: self.backoff.clone(),
+            throttle: self.throttle.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            in_flight: self.in_flight.clone(),
+            #[cfg(feature = "server")]
+            troublesome_instances: self.troublesome_instances.clone(),
 
-        // Get target host of instance
-        let parsed_url = Url::parse(url).map_err(|_| FileDownloadError::InvalidUrl)?;
-        let target_host = parsed_url
-            .host_str()
-            .ok_or(FileDownloadError::InvalidUrl)?
-            .to_string();
+            ..fresh
+        }
+    }
 
+    // Exchange open circuits with the replicas sharing the store
+    pub async fn sync_circuits(&self, store: &dyn KvStore) -> KvResult<()> {
+        self.breaker.sync(store).await
+    }
+
+    // Learned permanent redirects as (from, to, age)
+    pub fn permanent_redirects(&self) -> Vec<(String, String, Duration)> {
+        self.redirects.list(self.config.redirect_cache_ttl)
+    }
+
+    // Forget what's cached about the URL, or about every URL: the response, the permanent
+    // redirect learned from it and a recent failure. Replicas keep their own caches
+    pub fn purge(&self, url: Option<&str>) {
+        match url {
+            Some(url) => {
+                self.cache.forget(url);
+                self.redirects.forget(url);
+                self.failures.forget(url);
+            }
+            None => {
+                self.cache.clear();
+                self.redirects.clear();
+                self.failures.clear();
+            }
+        }
+    }
+
+    // Direct download first, then with the retry UA and Referer for hosts with hotlink protection
+    #[cfg_attr(not(feature = "server"), allow(unused_variables))]
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        request_url: &str,
+        target_host: &str,
+        host: Option<&String>,
+        conditional_headers: &HeaderMap,
+        timeout: Option<Duration>, // instead of the download timeout
+    ) -> Result<reqwest::Response, FileDownloadError> {
         let mut resp: Option<reqwest::Response> = None;
+        let origin_headers = hosts::origin_headers(&self.config.origin_headers, target_host);
+        let get = |client: &reqwest::Client, url: &str, headers: &HeaderMap| {
+            client
+                .get(url)
+                .headers(headers.clone())
+                .headers(origin_headers.clone())
+        };
 
         #[cfg(feature = "server")]
-        let worth_first_try = !self
-            .troublesome_instances
-            .read()
-            .await
-            .contains(&target_host);
+        let strategy = self
+            .config
+            .hotlink_rules
+            .iter()
+            .find(|rule| rule.host.matches(target_host))
+            .map_or(HotlinkStrategy::default(), |rule| rule.strategy);
+
+        #[cfg(feature = "server")]
+        let worth_first_try = strategy == HotlinkStrategy::Never
+            || !self
+                .troublesome_instances
+                .read()
+                .await
+                .iter()
+                .any(|troublesome| troublesome == target_host);
 
         #[cfg(not(feature = "server"))]
         let worth_first_try = true;
@@ -87,112 +594,731 @@ impl Downloader {
 
         if worth_first_try {
             // First try: direct download
-            let mut default_headers = HeaderMap::new();
+            let mut default_headers = conditional_headers.clone();
             default_headers.insert(USER_AGENT, default_ua.parse().unwrap());
 
             debug!("Trying direct download...");
             resp = Some(
-                self.client
-                    .get(url)
-                    .headers(default_headers)
-                    .send()
-                    .await
-                    .map_err(FileDownloadError::RequestError)?,
+                self.send_checked(
+                    client,
+                    target_host,
+                    |client, url| get(client, url, &default_headers),
+                    request_url,
+                    timeout,
+                )
+                .await?,
             );
         }
 
-        // if is 4xx error (e.g., 403 for hotlink protect), retry with host specified & request UA
+        // if is 4xx error (e.g., 403 for hotlink protect), retry as the strategy for the origin
+        // says & with request UA, unless it asked to wait
         #[cfg(feature = "server")]
-        if !worth_first_try || resp.as_ref().is_some_and(|r| r.status().is_client_error()) {
-            let retry_ua = env::var("USER_AGENT").unwrap_or(default_ua);
+        if !worth_first_try
+            || strategy != HotlinkStrategy::Never
+                && resp.as_ref().is_some_and(|r| {
+                    r.status().is_client_error()
+                        && backoff::retry_after(r.status(), r.headers()).is_none()
+                })
+        {
+            let retry_ua = self.config.retry_user_agent.clone().unwrap_or(default_ua);
 
-            debug!("Direct download failed, retrying with Host: {host:?}, UA: {retry_ua}",);
+            debug!(
+                "Direct download failed, retrying with strategy {strategy} (Host: {host:?}), UA: {retry_ua}",
+            );
 
-            let mut retry_headers = HeaderMap::new();
+            let mut retry_headers = conditional_headers.clone();
 
             retry_headers.insert(USER_AGENT, retry_ua.parse().unwrap());
 
-            if let Some(host) = host {
-                retry_headers.insert(REFERER, format!("https://{}/", host).parse().unwrap());
+            let origin = Url::parse(request_url).map(|url| url.origin().ascii_serialization());
+            match (strategy, origin) {
+                (HotlinkStrategy::Instance, _) => {
+                    if let Some(host) = host {
+                        retry_headers
+                            .insert(REFERER, format!("https://{}/", host).parse().unwrap());
+                    }
+                }
+                (HotlinkStrategy::Referer, Ok(origin)) => {
+                    retry_headers.insert(REFERER, format!("{origin}/").parse().unwrap());
+                }
+                (HotlinkStrategy::Origin, Ok(origin)) => {
+                    retry_headers.insert(ORIGIN, origin.parse().unwrap());
+                }
+                _ => {}
             }
 
             resp = Some(
-                self.client
-                    .get(url)
-                    .headers(retry_headers)
-                    .send()
-                    .await
-                    .map_err(FileDownloadError::RequestError)?,
+                self.send_checked(
+                    client,
+                    target_host,
+                    |client, url| get(client, url, &retry_headers),
+                    request_url,
+                    timeout,
+                )
+                .await?,
             );
 
             if resp.as_ref().is_some_and(|r| r.status().is_success()) && worth_first_try {
                 // It is really a nasty host
                 info!("Host {target_host} marked as troublesome.");
-                self.troublesome_instances.write().await.push(target_host);
+                self.troublesome_instances
+                    .write()
+                    .await
+                    .push(target_host.to_string());
             } // else: the target host might be dead or already marked
         }
 
-        let resp = resp.unwrap();
+        Ok(resp.unwrap())
+    }
+
+    // Proxies resolve host names themselves, out of reach of the guard, so those are checked
+    // before each request, including redirects the client stopped at for that. The redirect
+    // limit and the timeout are for the whole chain, however often it stops
+    async fn send_checked(
+        &self,
+        client: &reqwest::Client,
+        target_host: &str,
+        get: impl Fn(&reqwest::Client, &str) -> reqwest::RequestBuilder,
+        request_url: &str,
+        timeout: Option<Duration>, // instead of the download timeout
+    ) -> Result<reqwest::Response, FileDownloadError> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let routes = client::ProxyRoutes::new(&self.config);
+            let deadline = timeout
+                .or((!self.config.download_timeout.is_zero())
+                    .then_some(self.config.download_timeout))
+                .map(|timeout| Instant::now() + timeout);
+            let mut url = Url::parse(request_url).map_err(|_| FileDownloadError::InvalidUrl)?;
+            let mut client = client.clone();
+            let mut hops = 0;
+            loop {
+                if routes.proxy_for(&url).is_some() {
+                    self.guard
+                        .check_proxied(&url)
+                        .await
+                        .map_err(|_| FileDownloadError::BlockedAddress)?;
+                }
+                let mut request = get(&client, url.as_str());
+                if let Some(deadline) = deadline {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(FileDownloadError::Timeout);
+                    }
+                    request = request.timeout(remaining);
+                }
+                match request.send().await {
+                    Ok(resp) => return Ok(resp),
+                    Err(err) => match ssrf::unchecked_redirect(&err) {
+                        Some((target, taken)) => {
+                            hops += taken;
+                            let left = self.config.max_redirects.saturating_sub(hops);
+                            client = self.clients.limited(&self.config, target_host, left);
+                            url = target;
+                        }
+                        None => return Err(FileDownloadError::from_request(err)),
+                    },
+                }
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let _ = target_host;
+            let request = get(client, request_url);
+            match timeout {
+                Some(timeout) => request.timeout(timeout),
+                None => request,
+            }
+            .send()
+            .await
+            .map_err(FileDownloadError::from_request)
+        }
+    }
+
+    // Whether the URL may be fetched at all, e.g. not on the federation blocklist.
+    // data: URIs have no origin to ask, file: URLs are checked against the root when read,
+    // s3: and ipfs: URLs are for the configured buckets and gateway only, http+unix: ones for
+    // the configured sockets
+    pub fn permits(&self, url: &Url) -> bool {
+        match url.scheme() {
+            "data" => true,
+            "file" => self.config.local_files_root.is_some(),
+            "s3" => self.config.s3.as_ref().is_some_and(|s3| s3.permits(url)),
+            "ipfs" => self.config.ipfs_gateway.is_some(),
+            "http+unix" => !self.config.unix_sockets.is_empty(),
+            _ => self.config.origins.permits(url),
+        }
+    }
+
+    // Whether a range of the URL can be forwarded as the origin sends it, so not local files,
+    // nor IPFS content only trusted once checked as a whole
+    pub fn streamable(&self, url: &str) -> bool {
+        !is_local(url) && (!ipfs::is_ipfs_url(url) || self.config.trust_ipfs_gateway)
+    }
+
+    // Skip the known permanent redirects, unless the target isn't permitted (anymore)
+    fn cached_redirect(&self, url: &str) -> Result<Option<String>, FileDownloadError> {
+        let redirected = self.redirects.resolve(url, self.config.redirect_cache_ttl);
+        if let Some(redirected) = &redirected
+            && Url::parse(redirected).is_ok_and(|parsed| !self.permits(&parsed))
+        {
+            return Err(FileDownloadError::OriginDenied);
+        }
+        Ok(redirected)
+    }
+
+    // Pause between chunks the same way as buffered downloads
+    #[cfg(not(target_arch = "wasm32"))]
+    fn throttled(
+        &self,
+        stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+        host: String,
+    ) -> BoxStream<'static, Result<Bytes, reqwest::Error>> {
+        let policy = self.config.bandwidth.clone();
+        if policy.limit == 0 && policy.host_limit == 0 {
+            return stream.boxed();
+        }
+        let throttle = self.throttle.clone();
+        stream
+            .then(move |chunk| {
+                let delay = match &chunk {
+                    Ok(bytes) => throttle.delay(&host, bytes.len(), &policy),
+                    Err(_) => Duration::ZERO,
+                };
+                async move {
+                    tokio::time::sleep(delay).await;
+                    chunk
+                }
+            })
+            .boxed()
+    }
+
+    // s3:// URLs are fetched from the storage endpoint, signed for each request as
+    // signatures expire, ipfs:// ones through the gateway, and http+unix:// ones through the
+    // socket. The URL itself is still what's cached
+    fn object_request(&self, request_url: &str) -> Result<(String, HeaderMap), FileDownloadError> {
+        if unix::is_unix_url(request_url) {
+            let socket_url = unix::socket_url(&self.config.unix_sockets, request_url)
+                .ok_or(FileDownloadError::OriginDenied)?;
+            return Ok((socket_url, HeaderMap::new()));
+        }
+        if let Some(plain_url) = unix::plain_url(&self.config.unix_sockets, request_url) {
+            return Ok((plain_url, HeaderMap::new()));
+        }
+        if ipfs::is_ipfs_url(request_url) {
+            let gateway = self
+                .config
+                .ipfs_gateway
+                .as_ref()
+                .ok_or(FileDownloadError::OriginDenied)?;
+            let gateway_url =
+                ipfs::gateway_url(gateway, request_url).ok_or(FileDownloadError::InvalidUrl)?;
+            if !self.config.trust_ipfs_gateway && !ipfs::is_verifiable(request_url) {
+                return Err(FileDownloadError::OriginDenied);
+            }
+            return Ok((gateway_url.to_string(), HeaderMap::new()));
+        }
+        if !s3::is_s3_url(request_url) {
+            return Ok((request_url.to_string(), HeaderMap::new()));
+        }
+        let s3 = self
+            .config
+            .s3
+            .as_ref()
+            .ok_or(FileDownloadError::OriginDenied)?;
+        let url = Url::parse(request_url).map_err(|_| FileDownloadError::InvalidUrl)?;
+        if !s3.permits(&url) {
+            return Err(FileDownloadError::OriginDenied);
+        }
+        let object_url = s3.object_url(&url).ok_or(FileDownloadError::InvalidUrl)?;
+        let signature = s3.sign(&object_url, SystemTime::now());
+        Ok((object_url.to_string(), signature))
+    }
+
+    fn target_host(&self, request_url: &str) -> Result<String, FileDownloadError> {
+        let parsed_url = Url::parse(request_url).map_err(|_| FileDownloadError::InvalidUrl)?;
+        ssrf::check_scheme(&parsed_url).map_err(|_| FileDownloadError::InvalidUrl)?;
+        let target_host = parsed_url
+            .host_str()
+            .ok_or(FileDownloadError::InvalidUrl)?
+            .to_string();
+        self.guard
+            .check_url(&parsed_url)
+            .map_err(|_| FileDownloadError::BlockedAddress)?;
+        Ok(target_host)
+    }
+
+    // Count towards opening the circuit of the host, or close it on a healthy answer, and
+    // remember when it asked to come back
+    fn observe(&self, host: &str, result: &Result<reqwest::Response, FileDownloadError>) {
+        let failed = match result {
+            Ok(resp) => {
+                if let Some(delay) = backoff::retry_after(resp.status(), resp.headers()) {
+                    self.backoff.record(host, delay);
+                }
+                breaker::is_failure_status(resp.status())
+            }
+            Err(FileDownloadError::Timeout) => true,
+            Err(FileDownloadError::RequestError(err)) => err.is_connect(),
+            Err(_) => return,
+        };
+        if failed {
+            self.breaker.failure(host, &self.config.breaker);
+        } else {
+            self.breaker.success(host);
+        }
+    }
+
+    // Images only: anything else is given up on after NON_IMAGE_LIMIT as Oversize,
+    // when the caller would have returned it unchanged anyway
+    pub async fn download_file(
+        &self,
+        url: &str,
+        host: Option<&String>,
+        conditional: &Conditional,
+        images_only: bool,
+    ) -> Result<DownloadedFile, FileDownloadError> {
+        if is_data_uri(url) {
+            let file = data::decode(url)?;
+            if file.bytes.len() as u64 > self.config.size_limit {
+                return Err(FileDownloadError::Oversize);
+            }
+            return Ok(file);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if local::is_file_url(url) {
+            let Some(root) = &self.config.local_files_root else {
+                return Err(FileDownloadError::OriginDenied);
+            };
+            let file = local::read(url, root, self.config.size_limit).await?;
+            return match conditional.matches(&file.provenance) {
+                true => Err(FileDownloadError::NotModified(Box::new(file.provenance))),
+                false => Ok(file),
+            };
+        }
+        let ttl = self.config.negative_cache_ttl;
+        if let Some(err) = self.failures.get(url, ttl) {
+            debug!("Failed recently, not fetching again: {url}");
+            return Err(err);
+        }
+        let mut result = self.fetch_shared(url, host, conditional, images_only).await;
+
+        // A mirror has the same file, but may still be up
+        let mirrors = self
+            .config
+            .mirrors
+            .iter()
+            .filter_map(|rule| rule.apply(url));
+        for mirror in mirrors {
+            match &result {
+                Err(FileDownloadError::NotModified(_) | FileDownloadError::Oversize) | Ok(_) => {
+                    break;
+                }
+                Err(_) => {}
+            }
+            debug!("Origin failed, trying mirror: {mirror}");
+            result = self
+                .fetch_shared(&mirror, host, conditional, images_only)
+                .await;
+        }
+        if let Err(err) = &result {
+            self.failures.record(url, err, ttl);
+        }
+
+        match result {
+            Ok(file) if conditional.matches(&file.provenance) => {
+                debug!("Client's copy is current: {url}");
+                Err(FileDownloadError::NotModified(Box::new(file.provenance)))
+            }
+            result => result,
+        }
+    }
+
+    // Joining the same download in flight, if any
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn fetch_shared(
+        &self,
+        url: &str,
+        host: Option<&String>,
+        conditional: &Conditional,
+        images_only: bool,
+    ) -> Result<DownloadedFile, FileDownloadError> {
+        let downloader = self.clone();
+        let key = (
+            url.to_string(),
+            host.cloned(),
+            conditional.clone(),
+            images_only,
+        );
+        let (url, host, conditional, images_only) = key.clone();
+        self.in_flight
+            .run(key, async move {
+                downloader
+                    .fetch(&url, host.as_ref(), &conditional, images_only)
+                    .await
+            })
+            .await
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn fetch_shared(
+        &self,
+        url: &str,
+        host: Option<&String>,
+        conditional: &Conditional,
+        images_only: bool,
+    ) -> Result<DownloadedFile, FileDownloadError> {
+        self.fetch(url, host, conditional, images_only).await
+    }
+
+    async fn fetch(
+        &self,
+        url: &str,
+        host: Option<&String>,
+        conditional: &Conditional,
+        images_only: bool,
+    ) -> Result<DownloadedFile, FileDownloadError> {
+        debug!("Downloading file: {url}");
+
+        // Serve from the response cache if still fresh, or revalidate with the origin.
+        // Without a cached copy, the client's own validators are tried instead
+        let mut conditional_headers = HeaderMap::new();
+        conditional_headers.insert(ACCEPT, conditional.accept());
+        let cached = if self.config.cache_size > 0 {
+            self.cache.get(url)
+        } else {
+            Lookup::Miss
+        };
+        match cached {
+            Lookup::Fresh(file) => {
+                debug!("Cache hit: {url}");
+                return Ok(file);
+            }
+            Lookup::Stale(policy) => {
+                debug!("Cache stale, revalidating: {url}");
+                if let Some(etag) = policy.etag.and_then(|etag| etag.parse().ok()) {
+                    conditional_headers.insert(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = policy.last_modified.and_then(|lm| lm.parse().ok()) {
+                    conditional_headers.insert(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            Lookup::Miss => conditional_headers.extend(conditional.headers()),
+        }
+
+        let redirected = self.cached_redirect(url)?;
+        if let Some(redirected) = &redirected {
+            debug!("Using cached permanent redirect: {redirected}");
+        }
+        let (request_url, signature) = self.object_request(redirected.as_deref().unwrap_or(url))?;
+        conditional_headers.extend(signature);
+
+        let target_host = self.target_host(&request_url)?;
+        self.breaker
+            .check(&target_host, &self.config.breaker)
+            .map_err(|_| FileDownloadError::CircuitOpen)?;
+        if let Some(left) = self.backoff.check(&target_host) {
+            return Err(FileDownloadError::BackingOff(left));
+        }
+
+        // Held until the body is downloaded
+        #[cfg(not(target_arch = "wasm32"))]
+        let _permit = self
+            .host_limiter
+            .acquire(
+                &target_host,
+                self.config.host_concurrency,
+                self.config.host_queue,
+            )
+            .await
+            .map_err(|_| FileDownloadError::HostBusy)?;
+        #[cfg(not(target_arch = "wasm32"))]
+        let _download_permit = self
+            .download_limiter
+            .acquire(
+                self.config.download_queue,
+                self.config.download_queue_timeout,
+            )
+            .await
+            .map_err(|_| {
+                FileDownloadError::Overloaded(
+                    self.config
+                        .download_queue_timeout
+                        .max(Duration::from_secs(1)),
+                )
+            })?;
+
+        let client = self.clients.get(&self.config, &target_host);
+        let retry = &self.config.retry;
+        let deadline = Instant::now() + retry.deadline;
+        let mut attempt = 0;
+        let (resp, request_time) = loop {
+            let request_time = SystemTime::now();
+            let result = self
+                .send(
+                    &client,
+                    &request_url,
+                    &target_host,
+                    host,
+                    &conditional_headers,
+                    None,
+                )
+                .await;
+            self.observe(&target_host, &result);
+            let delay = retry.delay(attempt);
+            let retryable = match &result {
+                Ok(resp) => {
+                    retry::is_transient_status(resp.status())
+                        && self.backoff.check(&target_host).is_none()
+                }
+                Err(FileDownloadError::RequestError(err)) => retry::is_transient_error(err),
+                Err(_) => false,
+            };
+            if !retryable || attempt >= retry.attempts || Instant::now() + delay > deadline {
+                break (result?, request_time);
+            }
+            attempt += 1;
+            debug!(
+                "Transient failure, retrying in {delay:?} ({attempt}/{}): {url}",
+                retry.attempts
+            );
+            retry::sleep(delay).await;
+        };
+
+        let response_time = SystemTime::now();
+        let provenance = Provenance {
+            initial_age: cache::initial_age(resp.headers(), request_time, response_time),
+            final_url: final_url(url, &resp),
+            ..Provenance::new(CacheTier::Origin)
+        };
+        let resp_status = resp.status();
+
+        // Not modified since cached, or since the client got it
+        if resp_status == StatusCode::NOT_MODIFIED {
+            if let Some(file) = self.cache.refresh(
+                url,
+                resp.headers(),
+                provenance.clone(),
+                self.config.cache_default_ttl,
+            ) {
+                debug!("Cache revalidated: {url}");
+                return Ok(file);
+            }
+            if conditional_headers.contains_key(IF_NONE_MATCH)
+                || conditional_headers.contains_key(IF_MODIFIED_SINCE)
+            {
+                // Passed on as is, with the validators the origin confirmed (not always repeated)
+                let policy =
+                    CachePolicy::from_headers(resp.headers(), self.config.cache_default_ttl);
+                let single_etag = conditional
+                    .if_none_match
+                    .clone()
+                    .filter(|tags| !tags.contains(',') && tags.trim() != "*");
+                return Err(FileDownloadError::NotModified(Box::new(Provenance {
+                    storable: policy.storable,
+                    etag: policy.etag.or(single_etag),
+                    last_modified: policy
+                        .last_modified
+                        .or(conditional.if_modified_since.clone()),
+                    ..provenance
+                })));
+            }
+        }
 
         // Check status code
         debug!("Download finish, checking status code...");
-        let resp_status = resp.status();
         if !resp_status.is_success() || resp_status == StatusCode::NO_CONTENT {
+            if redirected.is_some() {
+                // The moved file might have moved again, learn it from scratch next time
+                self.redirects.forget(url);
+            }
+            self.cache.forget(url);
+            if let Some(delay) = backoff::retry_after(resp_status, resp.headers()) {
+                return Err(FileDownloadError::BackingOff(delay));
+            }
             return Err(FileDownloadError::InvalidStatusCode(resp_status));
         }
 
         // Split response headers
         let resp_headers = resp.headers();
+        let policy = CachePolicy::from_headers(resp_headers, self.config.cache_default_ttl);
+        let provenance = Provenance {
+            storable: policy.storable,
+            etag: policy.etag.clone(),
+            last_modified: policy.last_modified.clone(),
+            ..provenance
+        };
+
+        let ct = resp_headers
+            .get(CONTENT_TYPE)
+            .map(|ct| ct.to_str().unwrap().to_string());
+        let mut size_limit = self.config.size_limit;
+        let mut sniffed = !images_only
+            || self.config.non_image_limit == 0
+            || ct.as_ref().is_some_and(|ct| ct.starts_with("image/"));
+        // Never an image, so not even the beginning is worth waiting for
+        if !sniffed
+            && ct
+                .as_ref()
+                .is_some_and(|ct| ct.starts_with("video/") || ct.starts_with("audio/"))
+        {
+            debug!("Not an image ({ct:?}), limited to the non-image budget: {url}");
+            sniffed = true;
+            size_limit = size_limit.min(self.config.non_image_limit);
+        }
 
         // Check response size (content length)
         debug!("Status OK, checking content length (if any)...");
         if let Some(size) = resp.content_length() {
-            if size > self.size_limit {
+            if size > size_limit {
                 return Err(FileDownloadError::Oversize);
             }
-        } else if let Some(size_length) = resp_headers.get(CONTENT_LENGTH) {
-            if let Ok(size) = size_length.to_str().unwrap().parse::<u64>() {
-                if size > self.size_limit {
-                    return Err(FileDownloadError::Oversize);
-                }
-            }
+        } else if let Some(size_length) = resp_headers.get(CONTENT_LENGTH)
+            && let Ok(size) = size_length.to_str().unwrap().parse::<u64>()
+            && size > size_limit
+        {
+            return Err(FileDownloadError::Oversize);
         }
 
         // Set filename
         debug!("Getting filename...");
-        let mut filename_ascii = url.split('/').next_back().unwrap_or("unknown").to_string();
-        let mut filename_encoded: Option<String> = None;
-        if let Some(content_disposition) = resp_headers.get(CONTENT_DISPOSITION) {
-            let field_parts = content_disposition.to_str().unwrap().split(';');
-            for part in field_parts {
-                let part = part.trim();
-                if let Some(value) = part.strip_prefix("filename=") {
-                    filename_ascii = value.trim_matches('"').to_string();
-                } else if let Some(value) = part.strip_prefix("filename*=") {
-                    filename_encoded = Some(value.to_string());
-                }
-            }
-        }
+        let filename = filename(url, resp_headers);
 
         // Nothing wrong, let's download the entire response body and return
         debug!("Length pre-check OK, downloading entire body...");
-        let ct = resp_headers
-            .get(CONTENT_TYPE)
-            .map(|ct| ct.to_str().unwrap().to_string());
         let mut limited_buf = Vec::new();
         let mut stream = resp.bytes_stream();
         while let Some(chunk) = stream.next().await {
-            limited_buf.extend(chunk.map_err(FileDownloadError::RequestError)?);
-            if limited_buf.len() as u64 > self.size_limit {
+            let chunk = chunk.map_err(FileDownloadError::from_request);
+            if let Err(FileDownloadError::Timeout) = chunk {
+                self.breaker.failure(&target_host, &self.config.breaker);
+            }
+            let chunk = chunk?;
+            let delay = self
+                .throttle
+                .delay(&target_host, chunk.len(), &self.config.bandwidth);
+            if !delay.is_zero() {
+                retry::sleep(delay).await;
+            }
+            limited_buf.extend(chunk);
+            // Clearly not an image, e.g. a video linked in a post: don't buffer all of it
+            if !sniffed && limited_buf.len() >= SNIFF_LEN {
+                sniffed = true;
+                let pdf = cfg!(feature = "pdf") && limited_buf.starts_with(b"%PDF-");
+                if image::guess_format(&limited_buf).is_err() && !pdf {
+                    debug!("Not an image ({ct:?}), limited to the non-image budget: {url}");
+                    size_limit = size_limit.min(self.config.non_image_limit);
+                }
+            }
+            if limited_buf.len() as u64 > size_limit {
                 return Err(FileDownloadError::Oversize);
             }
         }
 
+        // Content addressed, so the gateway must hand out exactly what the URL says, as far as
+        // that can be checked
+        if ipfs::is_ipfs_url(url)
+            && (ipfs::is_verifiable(url) || !self.config.trust_ipfs_gateway)
+            && !ipfs::verify(url, &limited_buf)
+        {
+            warn!("Bytes don't match the CID: {url}");
+            return Err(FileDownloadError::InvalidStatusCode(
+                StatusCode::BAD_GATEWAY,
+            ));
+        }
+
         debug!("Response body downloaded, return. ContentType: {ct:?}");
-        Ok(DownloadedFile {
+        let file = DownloadedFile {
             bytes: Bytes::from(limited_buf),
             content_type: ct,
-            filename: (filename_ascii, filename_encoded),
+            filename,
+            provenance,
+        };
+        if self.config.cache_size > 0 {
+            self.cache
+                .insert(url, &file, policy, self.config.cache_size);
+        }
+        Ok(file)
+    }
+
+    // Forward the range of the client (e.g. seeking in a video), without size limit or
+    // response cache. Not retried, as the client may already be receiving the body
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn stream_file(
+        &self,
+        url: &str,
+        host: Option<&String>,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Result<StreamedFile, FileDownloadError> {
+        debug!("Streaming file: {url}");
+        let redirected = self.cached_redirect(url)?;
+        let (request_url, signature) = self.object_request(redirected.as_deref().unwrap_or(url))?;
+        let target_host = self.target_host(&request_url)?;
+        self.breaker
+            .check(&target_host, &self.config.breaker)
+            .map_err(|_| FileDownloadError::CircuitOpen)?;
+        if let Some(left) = self.backoff.check(&target_host) {
+            return Err(FileDownloadError::BackingOff(left));
+        }
+
+        let mut range_headers = signature;
+        // As is, ranges and lengths of a compressed body would be of the compressed bytes
+        range_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        if let Some(range) = range.and_then(|range| range.parse().ok()) {
+            range_headers.insert(RANGE, range);
+        }
+        if let Some(if_range) = if_range.and_then(|if_range| if_range.parse().ok()) {
+            range_headers.insert(IF_RANGE, if_range);
+        }
+        let client = self.clients.get(&self.config, &target_host);
+        let request_time = SystemTime::now();
+        let resp = self
+            .send(
+                &client,
+                &request_url,
+                &target_host,
+                host,
+                &range_headers,
+                Some(STREAM_TIMEOUT),
+            )
+            .await;
+        self.observe(&target_host, &resp);
+        let resp = resp?;
+
+        let resp_status = resp.status();
+        if !resp_status.is_success() || resp_status == StatusCode::NO_CONTENT {
+            if redirected.is_some() {
+                self.redirects.forget(url);
+            }
+            if let Some(delay) = backoff::retry_after(resp_status, resp.headers()) {
+                return Err(FileDownloadError::BackingOff(delay));
+            }
+            return Err(FileDownloadError::InvalidStatusCode(resp_status));
+        }
+
+        let policy = CachePolicy::from_headers(resp.headers(), self.config.cache_default_ttl);
+        let provenance = Provenance {
+            initial_age: cache::initial_age(resp.headers(), request_time, SystemTime::now()),
+            storable: policy.storable,
+            etag: policy.etag,
+            last_modified: policy.last_modified,
+            final_url: final_url(url, &resp),
+            ..Provenance::new(CacheTier::Origin)
+        };
+        let mut headers = HeaderMap::new();
+        for name in STREAMED_HEADERS {
+            if let Some(value) = resp.headers().get(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+        Ok(StreamedFile {
+            status: resp_status,
+            filename: filename(url, resp.headers()),
+            headers,
+            provenance,
+            body: self.throttled(resp.bytes_stream(), target_host),
         })
     }
 }
@@ -200,14 +1326,20 @@ impl Downloader {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[tokio::test]
     async fn test_download_file() {
-        let downloader = Downloader::new(None); // use default size limit
+        let downloader = Downloader::new(DownloaderConfig::default()); // use default size limit
         let file = downloader
             .download_file(
                 "https://public.nyaone-object-storage.com/nyaone/ff02042e-524e-48e8-bb27-17621d96b13a.png",
                 None,
+                &Conditional::default(),
+                false,
             )
             .await;
         assert!(file.is_ok());
@@ -224,13 +1356,68 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_stream_file() {
+        let downloader = Downloader::new(DownloaderConfig {
+            size_limit: 6, // not applied to streams
+            ..Default::default()
+        });
+        let file = downloader
+            .stream_file(
+                "https://public.nyaone-object-storage.com/nyaone/ff02042e-524e-48e8-bb27-17621d96b13a.png",
+                None,
+                Some("bytes=0-7"),
+                None,
+            )
+            .await;
+        assert!(file.is_ok());
+        if let Ok(mut streamed) = file {
+            assert_eq!(streamed.status, StatusCode::PARTIAL_CONTENT);
+            assert!(streamed.headers.contains_key(CONTENT_RANGE));
+            let mut bytes = Vec::new();
+            while let Some(chunk) = streamed.body.next().await {
+                bytes.extend(chunk.unwrap());
+            }
+            assert_eq!(bytes, b"\x89PNG\r\n\x1a\n");
+        }
+    }
+
+    #[test]
+    fn test_conditional() {
+        let provenance = Provenance {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Thu, 01 Jan 2026 00:00:00 GMT".to_string()),
+            ..Provenance::new(CacheTier::Origin)
+        };
+        let if_none_match = |tags: &str| Conditional {
+            if_none_match: Some(tags.to_string()),
+            ..Default::default()
+        };
+        assert!(if_none_match("W/\"abc\"").matches(&provenance));
+        assert!(if_none_match("\"xyz\", \"abc\"").matches(&provenance));
+        assert!(!if_none_match("\"xyz\"").matches(&provenance));
+
+        let if_modified_since = |date: &str| Conditional {
+            if_modified_since: Some(date.to_string()),
+            ..Default::default()
+        };
+        assert!(if_modified_since("Thu, 01 Jan 2026 00:00:00 GMT").matches(&provenance));
+        assert!(!if_modified_since("Wed, 31 Dec 2025 00:00:00 GMT").matches(&provenance));
+        assert!(!Conditional::default().matches(&provenance));
+    }
+
     #[tokio::test]
     async fn test_size_limit() {
-        let downloader = Downloader::new(Some(6));
+        let downloader = Downloader::new(DownloaderConfig {
+            size_limit: 6,
+            ..Default::default()
+        });
         match downloader
             .download_file(
                 "https://public.nyaone-object-storage.com/nyaone/ff02042e-524e-48e8-bb27-17621d96b13a.png",
                 None,
+                &Conditional::default(),
+                false,
             )
             .await
         {
@@ -238,4 +1425,296 @@ mod tests {
             _ => panic!("Wrong status"),
         };
     }
+
+    #[tokio::test]
+    async fn test_media_limit() {
+        let downloader = Downloader::new(DownloaderConfig {
+            non_image_limit: 1000,
+            allowed_private_networks: parse_networks("127.0.0.0/8").unwrap(),
+            ..Default::default()
+        });
+        // A video that never comes, only its headers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 4096]).await;
+                let head =
+                    "HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nContent-Length: 100000\r\n\r\n";
+                let _ = stream.write_all(head.as_bytes()).await;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        });
+        let url = format!("http://{addr}/video.mp4");
+        let file = downloader
+            .download_file(&url, None, &Conditional::default(), true)
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::Oversize)));
+    }
+
+    // Answers every request with the body gzipped, like an origin compressing on the fly
+    async fn serve_gzipped(body: &[u8]) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 4096]).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    gzipped.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&gzipped).await;
+            }
+        });
+        format!("http://{addr}/image.svg")
+    }
+
+    #[tokio::test]
+    async fn test_compressed() {
+        let downloader = Downloader::new(DownloaderConfig {
+            size_limit: 100_000,
+            allowed_private_networks: parse_networks("127.0.0.0/8").unwrap(),
+            ..Default::default()
+        });
+        let svg =
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\"><rect width=\"1\" height=\"1\"/></svg>";
+        let url = serve_gzipped(svg).await;
+        let file = downloader
+            .download_file(&url, None, &Conditional::default(), false)
+            .await;
+        assert!(file.is_ok_and(|file| file.bytes.as_ref() == svg));
+
+        // Far smaller than the limit on the wire, not once decompressed
+        let url = serve_gzipped(&[b' '; 1_000_000]).await;
+        let file = downloader
+            .download_file(&url, None, &Conditional::default(), false)
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::Oversize)));
+    }
+
+    #[tokio::test]
+    async fn test_data_uri_size_limit() {
+        let downloader = Downloader::new(DownloaderConfig {
+            size_limit: 6,
+            ..Default::default()
+        });
+        // "GIF89a", then one byte more
+        for (url, fits) in [
+            ("data:image/gif;base64,R0lGODlh", true),
+            ("data:image/gif;base64,R0lGODlhAA==", false),
+        ] {
+            let file = downloader
+                .download_file(url, None, &Conditional::default(), false)
+                .await;
+            match fits {
+                true => assert!(file.is_ok_and(|file| file.bytes.as_ref() == b"GIF89a")),
+                false => assert!(matches!(file, Err(FileDownloadError::Oversize))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_purge() {
+        let downloader = Downloader::new(DownloaderConfig::default());
+        let ttl = downloader.config.negative_cache_ttl;
+        let (a, b) = ("https://a.example/a.png", "https://a.example/b.png");
+        for url in [a, b] {
+            downloader
+                .failures
+                .record(url, &FileDownloadError::Timeout, ttl);
+            downloader
+                .redirects
+                .record(url, "https://b.example/moved.png");
+        }
+
+        downloader.purge(Some(a));
+        assert!(downloader.failures.get(a, ttl).is_none());
+        assert!(downloader.failures.get(b, ttl).is_some());
+        assert_eq!(downloader.permanent_redirects().len(), 1);
+
+        downloader.purge(None);
+        assert!(downloader.failures.get(b, ttl).is_none());
+        assert!(downloader.permanent_redirects().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_s3_buckets() {
+        let downloader = Downloader::new(DownloaderConfig {
+            s3: Some(S3Config {
+                endpoint: Some("http://127.0.0.1:1".parse().unwrap()),
+                region: "us-east-1".to_string(),
+                access_key: "access".to_string(),
+                secret_key: "secret".to_string(),
+                buckets: vec!["files".to_string()],
+            }),
+            ..Default::default()
+        });
+        let other = Url::parse("s3://backups/db.sql").unwrap();
+        assert!(downloader.permits(&Url::parse("s3://files/a.png").unwrap()));
+        assert!(!downloader.permits(&other));
+        let file = downloader
+            .download_file(other.as_str(), None, &Conditional::default(), false)
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::OriginDenied)));
+    }
+
+    #[tokio::test]
+    async fn test_ipfs_trust() {
+        let config = DownloaderConfig {
+            ipfs_gateway: Some("http://127.0.0.1:1".parse().unwrap()),
+            ..Default::default()
+        };
+        // A UnixFS file can't be checked against its CID, nor can a path
+        let unixfs = "ipfs://QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+        let raw = "ipfs://bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4";
+        let downloader = Downloader::new(config.clone());
+        for url in [unixfs.to_string(), format!("{raw}/a.png")] {
+            let file = downloader
+                .download_file(&url, None, &Conditional::default(), false)
+                .await;
+            assert!(matches!(file, Err(FileDownloadError::OriginDenied)));
+        }
+        assert!(!downloader.streamable(raw)); // only checked as a whole
+
+        let downloader = Downloader::new(DownloaderConfig {
+            trust_ipfs_gateway: true,
+            ..config
+        });
+        let file = downloader
+            .download_file(unixfs, None, &Conditional::default(), false)
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::BlockedAddress))); // went on to the gateway
+        assert!(downloader.streamable(raw));
+    }
+
+    // Forwards nothing: redirects media.example.test to localhost, /hops/N.svg to N-1 on
+    // localhost, answers anything else itself, and reports the request lines it got
+    async fn serve_proxy() -> (Url, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let line = request.lines().next().unwrap_or_default().to_string();
+                let hops = line
+                    .split_once("/hops/")
+                    .and_then(|(_, rest)| rest.split_once(".svg"))
+                    .and_then(|(hops, _)| hops.parse::<u32>().ok());
+                let location = match hops {
+                    Some(hops) if hops > 0 => Some(format!("/hops/{}.svg", hops - 1)),
+                    None if line.contains("media.example.test") => Some("/b.svg".to_string()),
+                    _ => None,
+                };
+                let head = match location {
+                    Some(location) => format!(
+                        "HTTP/1.1 302 Found\r\nLocation: http://localhost:1{location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    ),
+                    None => "HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\nContent-Length: 6\r\nConnection: close\r\n\r\n<svg/>".to_string(),
+                };
+                let _ = requests.send(line);
+                let _ = stream.write_all(head.as_bytes()).await;
+            }
+        });
+        (format!("http://{addr}").parse().unwrap(), received)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_checks_hosts() {
+        // The proxy would resolve localhost to itself, so it's checked before
+        let (proxy, mut received) = serve_proxy().await;
+        let downloader = Downloader::new(DownloaderConfig {
+            http_proxy: Some(proxy.clone()),
+            dns_overrides: parse_dns_overrides("media.example.test=203.0.113.5").unwrap(),
+            ..Default::default()
+        });
+        let file = downloader
+            .download_file(
+                "http://localhost:1/a.svg",
+                None,
+                &Conditional::default(),
+                false,
+            )
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::BlockedAddress)));
+        assert!(received.try_recv().is_err());
+
+        // And so are redirects
+        let file = downloader
+            .download_file(
+                "http://media.example.test/a.svg",
+                None,
+                &Conditional::default(),
+                false,
+            )
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::BlockedAddress)));
+        assert!(
+            received
+                .recv()
+                .await
+                .unwrap()
+                .contains("media.example.test")
+        );
+        assert!(received.try_recv().is_err());
+
+        // Allowed ones still go through the proxy
+        let downloader = Downloader::new(DownloaderConfig {
+            http_proxy: Some(proxy),
+            allowed_private_networks: parse_networks("127.0.0.0/8,::1/128").unwrap(),
+            ..Default::default()
+        });
+        let file = downloader
+            .download_file(
+                "http://localhost:1/a.svg",
+                None,
+                &Conditional::default(),
+                false,
+            )
+            .await;
+        assert!(file.is_ok_and(|file| file.bytes.as_ref() == b"<svg/>"));
+        assert_eq!(
+            received.recv().await.unwrap(),
+            "GET http://localhost:1/a.svg HTTP/1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_redirect_limit() {
+        // The first hop stops at unchecked localhost, the rest are followed once it's checked,
+        // all within the same limit. The chain too long goes first, while localhost isn't checked
+        let (proxy, _received) = serve_proxy().await;
+        let downloader = Downloader::new(DownloaderConfig {
+            http_proxy: Some(proxy),
+            dns_overrides: parse_dns_overrides("media.example.test=203.0.113.5").unwrap(),
+            allowed_private_networks: parse_networks("127.0.0.0/8,::1/128").unwrap(),
+            max_redirects: 2,
+            ..Default::default()
+        });
+        let file = downloader
+            .download_file(
+                "http://media.example.test/hops/3.svg",
+                None,
+                &Conditional::default(),
+                false,
+            )
+            .await;
+        assert!(matches!(file, Err(FileDownloadError::RedirectRejected)));
+
+        let file = downloader
+            .download_file(
+                "http://media.example.test/hops/2.svg",
+                None,
+                &Conditional::default(),
+                false,
+            )
+            .await;
+        assert!(file.is_ok_and(|file| file.bytes.as_ref() == b"<svg/>"));
+    }
 }
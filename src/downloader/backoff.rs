@@ -0,0 +1,117 @@
+use crate::guard::MutexExt;
+use http::HeaderMap;
+use http::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::info;
+
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60); // whatever the origin says
+const PRUNE_THRESHOLD: usize = 1024; // hosts remembered before dropping the expired ones
+
+// Origins that asked us to come back later (429 / 503 with Retry-After), answered the same
+// way until then instead of adding to the load of a rate-limited server. Shared across
+// clones and config reloads
+#[derive(Clone, Default)]
+pub struct HostBackoff {
+    hosts: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+// Delay seconds or an HTTP date, capped
+pub fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if !matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok()?,
+    };
+    (!delay.is_zero()).then(|| delay.min(MAX_RETRY_AFTER))
+}
+
+impl HostBackoff {
+    // Time left to wait for the host, if any
+    pub fn check(&self, host: &str) -> Option<Duration> {
+        let hosts = self.hosts.lock_or_recover();
+        let until = hosts.get(host)?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+
+    pub fn record(&self, host: &str, delay: Duration) {
+        let mut hosts = self.hosts.lock_or_recover();
+        if hosts.len() >= PRUNE_THRESHOLD {
+            let now = Instant::now();
+            hosts.retain(|_, until| *until > now);
+        }
+        info!("{host} asked to retry after {delay:?}, backing off");
+        hosts.insert(host.to_string(), Instant::now() + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, retry_after.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let limited = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(
+            retry_after(limited, &headers("120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers(" 5 ")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry_after(limited, &headers("999999")),
+            Some(MAX_RETRY_AFTER)
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(90));
+        assert!(
+            retry_after(limited, &headers(&later))
+                .is_some_and(|delay| delay > Duration::from_secs(80))
+        );
+
+        assert_eq!(retry_after(limited, &headers("0")), None);
+        assert_eq!(
+            retry_after(limited, &headers("Thu, 01 Jan 2026 00:00:00 GMT")),
+            None
+        );
+        assert_eq!(retry_after(limited, &headers("soon")), None);
+        assert_eq!(retry_after(limited, &HeaderMap::new()), None);
+        assert_eq!(retry_after(StatusCode::NOT_FOUND, &headers("120")), None);
+    }
+
+    #[test]
+    fn test_host_backoff() {
+        let backoff = HostBackoff::default();
+        backoff.record("a.example", Duration::from_secs(60));
+        backoff.record("b.example", Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert!(
+            backoff
+                .check("a.example")
+                .is_some_and(|left| left > Duration::from_secs(50))
+        );
+        assert_eq!(backoff.check("b.example"), None);
+        assert_eq!(backoff.check("c.example"), None);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::guard::MutexExt;
+use crate::kv::{KvResult, KvStore};
+use reqwest::StatusCode;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+const PRUNE_THRESHOLD: usize = 1024; // hosts remembered before dropping the healthy ones
+const STORE_PREFIX: &str = "breaker:";
+
+// Fail fast for origins that keep timing out or erroring, instead of spending
+// the whole download timeout on each request for a dead instance
+#[derive(Clone, Debug)]
+pub struct BreakerPolicy {
+    pub threshold: u32, // consecutive failures to open the circuit, zero to disable
+    pub cooldown: Duration, // open for this long, then one request is let through to probe
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 0,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+pub struct CircuitOpen;
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+#[derive(Clone, Default)]
+pub struct CircuitBreaker {
+    hosts: Arc<Mutex<HashMap<String, Health>>>,
+    changed: Arc<Mutex<HashSet<String>>>, // opened, probed or closed since the last sync
+}
+
+// Whether the origin looks down, rather than refusing this one file
+pub fn is_failure_status(status: StatusCode) -> bool {
+    status.is_server_error()
+}
+
+impl CircuitBreaker {
+    pub fn check(&self, host: &str, policy: &BreakerPolicy) -> Result<(), CircuitOpen> {
+        if policy.threshold == 0 {
+            return Ok(());
+        }
+        let mut hosts = self.hosts.lock_or_recover();
+        let Some(open_until) = hosts
+            .get_mut(host)
+            .and_then(|health| health.open_until.as_mut())
+        else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < *open_until {
+            return Err(CircuitOpen);
+        }
+        // Half-open: this request probes, the others keep failing fast until it's done
+        *open_until = now + policy.cooldown;
+        self.changed.lock_or_recover().insert(host.to_string());
+        Ok(())
+    }
+
+    pub fn success(&self, host: &str) {
+        let mut hosts = self.hosts.lock_or_recover();
+        if hosts
+            .remove(host)
+            .is_some_and(|health| health.open_until.is_some())
+        {
+            info!("Circuit closed for {host}, it's back");
+            self.changed.lock_or_recover().insert(host.to_string());
+        }
+    }
+
+    pub fn failure(&self, host: &str, policy: &BreakerPolicy) {
+        if policy.threshold == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock_or_recover();
+        if hosts.len() >= PRUNE_THRESHOLD {
+            hosts.retain(|_, health| health.open_until.is_some());
+        }
+        let health = hosts.entry(host.to_string()).or_default();
+        health.failures = health.failures.saturating_add(1);
+        // Also a failed probe of a circuit opened by another replica
+        if health.failures >= policy.threshold || health.open_until.is_some() {
+            if health.open_until.is_none() {
+                warn!(
+                    "Circuit opened for {host} after {} failures, failing fast for {:?}",
+                    health.failures, policy.cooldown
+                );
+            }
+            health.open_until = Some(Instant::now() + policy.cooldown);
+            self.changed.lock_or_recover().insert(host.to_string());
+        }
+    }
+
+    // Share the circuits changed here with the replicas using the same store, and pick up
+    // theirs, so that a dead origin isn't probed by each one. The store keeps until when
+    // (unix time in milliseconds) a host fails fast, expiring along with the circuit.
+    pub async fn sync(&self, store: &dyn KvStore) -> KvResult<()> {
+        let changed: Vec<_> = self.changed.lock_or_recover().drain().collect();
+        for host in changed {
+            let key = format!("{STORE_PREFIX}{host}");
+            let open_until = self
+                .hosts
+                .lock_or_recover()
+                .get(&host)
+                .and_then(|h| h.open_until);
+            match open_until.map(|until| until.saturating_duration_since(Instant::now())) {
+                Some(remaining) if !remaining.is_zero() => {
+                    let until = (unix_now() + remaining).as_millis().to_string();
+                    store.set(&key, &until, Some(remaining)).await?;
+                }
+                _ => store.delete(&key).await?,
+            }
+        }
+
+        let mut shared = HashMap::new();
+        for key in store.keys(STORE_PREFIX).await? {
+            let Some(until) = store.get(&key).await?.and_then(|value| value.parse().ok()) else {
+                continue;
+            };
+            let remaining = Duration::from_millis(until).saturating_sub(unix_now());
+            if let Some(host) = key.strip_prefix(STORE_PREFIX)
+                && !remaining.is_zero()
+            {
+                shared.insert(host.to_string(), Instant::now() + remaining);
+            }
+        }
+
+        let changed = self.changed.lock_or_recover();
+        let mut hosts = self.hosts.lock_or_recover();
+        let now = Instant::now();
+        // Closed by another replica whose probe succeeded, unless changed here meanwhile
+        hosts.retain(|host, health| {
+            shared.contains_key(host)
+                || changed.contains(host)
+                || health.open_until.is_none_or(|until| until <= now)
+        });
+        for (host, until) in shared {
+            let health = hosts.entry(host).or_default();
+            if health.open_until.is_none_or(|local| local < until) {
+                health.open_until = Some(until);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::MemoryStore;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::default();
+        let disabled = BreakerPolicy::default();
+        for _ in 0..10 {
+            breaker.failure("a.example", &disabled);
+        }
+        assert!(breaker.check("a.example", &disabled).is_ok());
+
+        let policy = BreakerPolicy {
+            threshold: 2,
+            cooldown: Duration::ZERO,
+        };
+        breaker.failure("a.example", &policy);
+        breaker.success("a.example"); // not consecutive
+        breaker.failure("a.example", &policy);
+        assert!(breaker.check("a.example", &policy).is_ok());
+        breaker.failure("a.example", &policy);
+        assert!(breaker.check("b.example", &policy).is_ok()); // other hosts aren't affected
+
+        let policy = BreakerPolicy {
+            cooldown: Duration::from_secs(60),
+            ..policy
+        };
+        breaker.failure("a.example", &policy);
+        assert!(breaker.check("a.example", &policy).is_err());
+        breaker.success("a.example");
+        assert!(breaker.check("a.example", &policy).is_ok());
+    }
+
+    #[test]
+    fn test_half_open() {
+        let breaker = CircuitBreaker::default();
+        let policy = BreakerPolicy {
+            threshold: 1,
+            cooldown: Duration::ZERO,
+        };
+        breaker.failure("a.example", &policy);
+        assert!(breaker.check("a.example", &policy).is_ok()); // cooled down, probe
+
+        let policy = BreakerPolicy {
+            cooldown: Duration::from_secs(60),
+            ..policy
+        };
+        breaker.failure("a.example", &policy); // the probe failed, open again at once
+        assert!(breaker.check("a.example", &policy).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared() {
+        let store = MemoryStore::default();
+        let (a, b) = (CircuitBreaker::default(), CircuitBreaker::default());
+        let policy = BreakerPolicy {
+            threshold: 1,
+            cooldown: Duration::from_secs(60),
+        };
+        a.failure("a.example", &policy);
+        a.sync(&store).await.unwrap();
+        b.sync(&store).await.unwrap();
+        assert!(b.check("a.example", &policy).is_err()); // opened by the other replica
+        assert!(b.check("b.example", &policy).is_ok());
+
+        a.success("a.example"); // its probe went through
+        a.sync(&store).await.unwrap();
+        b.sync(&store).await.unwrap();
+        assert!(b.check("a.example", &policy).is_ok());
+        assert!(store.keys(STORE_PREFIX).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use super::client::HttpVersion;
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tracing::warn;
+
+// A TLS client hello closer to what mainstream browsers send (cipher suite order,
+// key exchange groups, ALPN), for CDNs blocking non-browser fingerprints.
+// Note: rustls can't reproduce every detail (e.g. GREASE, extension order),
+// so this helps with simple fingerprint filters only.
+// ALPN only offers HTTP/1.1 to hosts that must not negotiate HTTP/2
+pub fn browser_tls_config(ca_bundle: Option<&[u8]>, http_version: HttpVersion) -> ClientConfig {
+    let provider = CryptoProvider {
+        cipher_suites: vec![
+            ring::cipher_suite::TLS13_AES_128_GCM_SHA256,
+            ring::cipher_suite::TLS13_AES_256_GCM_SHA384,
+            ring::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            ring::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            ring::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            ring::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            ring::cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ring::cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            ring::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+        kx_groups: vec![
+            ring::kx_group::X25519,
+            ring::kx_group::SECP256R1,
+            ring::kx_group::SECP384R1,
+        ],
+        ..ring::default_provider()
+    };
+
+    let mut root_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(pem) = ca_bundle {
+        let certificates = CertificateDer::pem_slice_iter(pem).filter_map(Result::ok);
+        let (_, ignored) = root_store.add_parsable_certificates(certificates);
+        if ignored > 0 {
+            warn!("{ignored} certificates in the CA bundle are ignored for browser-like TLS");
+        }
+    }
+
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()
+        .expect("Invalid TLS protocol versions")
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.alpn_protocols = match http_version {
+        HttpVersion::Http1Only => vec![b"http/1.1".to_vec()],
+        HttpVersion::Auto | HttpVersion::Http3 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpn() {
+        let config = browser_tls_config(None, HttpVersion::Auto);
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        let config = browser_tls_config(None, HttpVersion::Http1Only);
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+}
@@ -0,0 +1,366 @@
+use super::{CacheTier, DownloadedFile, Provenance};
+use crate::guard::MutexExt;
+use crate::kv::{normalize_url, url_key};
+use http::header::{AGE, CACHE_CONTROL, DATE, ETAG, EXPIRES, HeaderMap, LAST_MODIFIED};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug;
+
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How a response may be cached, following RFC 9111 for a shared cache
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachePolicy {
+    pub storable: bool,      // neither no-store nor private
+    pub freshness: Duration, // zero for no-cache (always revalidate)
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CachePolicy {
+    // `default_freshness` applies when there's neither explicit expiration nor Last-Modified
+    pub fn from_headers(headers: &HeaderMap, default_freshness: Duration) -> Self {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let date = header(DATE).and_then(|date| httpdate::parse_http_date(date).ok());
+
+        let mut storable = true;
+        let mut no_cache = false;
+        let mut max_age = None;
+        let mut s_maxage = None;
+        for directive in header(CACHE_CONTROL).unwrap_or_default().split(',') {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" | "private" => storable = false,
+                "no-cache" => no_cache = true,
+                "max-age" => max_age = value.and_then(|value| value.parse().ok()),
+                "s-maxage" => s_maxage = value.and_then(|value| value.parse().ok()),
+                _ => {}
+            }
+        }
+
+        let etag = header(ETAG).map(str::to_string);
+        let last_modified = header(LAST_MODIFIED).map(str::to_string);
+        let freshness = if no_cache {
+            Duration::ZERO
+        } else if let Some(seconds) = s_maxage.or(max_age) {
+            Duration::from_secs(seconds)
+        } else if let Some(expires) = header(EXPIRES) {
+            // Invalid dates (e.g. "0") mean already expired
+            httpdate::parse_http_date(expires)
+                .ok()
+                .and_then(|expires| expires.duration_since(date?).ok())
+                .unwrap_or_default()
+        } else if let Some(last_modified) = last_modified
+            .as_deref()
+            .and_then(|last_modified| httpdate::parse_http_date(last_modified).ok())
+        {
+            // 10% of the time since last modified, as suggested by the RFC
+            (date.unwrap_or_else(SystemTime::now))
+                .duration_since(last_modified)
+                .unwrap_or_default()
+                .div_f64(10.0)
+                .min(MAX_HEURISTIC_FRESHNESS)
+        } else {
+            default_freshness
+        };
+
+        Self {
+            storable,
+            freshness,
+            etag,
+            last_modified,
+        }
+    }
+
+    fn revalidatable(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+// Corrected initial age of a response (RFC 9111 section 4.2.3)
+pub fn initial_age(
+    headers: &HeaderMap,
+    request_time: SystemTime,
+    response_time: SystemTime,
+) -> Duration {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let apparent_age = header(DATE)
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or_default();
+    let age_value = header(AGE)
+        .and_then(|age| age.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let response_delay = response_time
+        .duration_since(request_time)
+        .unwrap_or_default();
+    apparent_age.max(age_value + response_delay)
+}
+
+struct Entry {
+    url: String, // normalized, in case the hashed keys of long URLs collide
+    file: DownloadedFile,
+    policy: CachePolicy,
+    last_used: Instant,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        self.file.provenance.age() < self.policy.freshness
+    }
+}
+
+pub enum Lookup {
+    Fresh(DownloadedFile),
+    Stale(CachePolicy), // revalidate with the validators in it
+    Miss,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>, // by url_key
+    size: u64,                   // total bytes
+}
+
+impl Entries {
+    fn get_mut(&mut self, url: &str) -> Option<&mut Entry> {
+        let url = normalize_url(url);
+        self.map
+            .get_mut(&url_key(&url))
+            .filter(|entry| entry.url == url)
+    }
+}
+
+// Downloaded files by URL, shared across clones and config reloads
+#[derive(Clone, Default)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl ResponseCache {
+    pub fn get(&self, url: &str) -> Lookup {
+        let mut entries = self.entries.lock_or_recover();
+        let Some(entry) = entries.get_mut(url) else {
+            return Lookup::Miss;
+        };
+        entry.last_used = Instant::now();
+        if entry.is_fresh() {
+            let mut file = entry.file.clone();
+            file.provenance.tier = CacheTier::Memory;
+            Lookup::Fresh(file)
+        } else {
+            Lookup::Stale(entry.policy.clone())
+        }
+    }
+
+    // Keep it if allowed, evicting the least recently used ones when over capacity
+    pub fn insert(&self, url: &str, file: &DownloadedFile, policy: CachePolicy, capacity: u64) {
+        let size = file.bytes.len() as u64;
+        if !policy.storable
+            || size > capacity
+            || (policy.freshness.is_zero() && !policy.revalidatable())
+        {
+            return;
+        }
+
+        let key = url_key(url);
+        let mut entries = self.entries.lock_or_recover();
+        if let Some(old) = entries.map.remove(&key) {
+            entries.size -= old.file.bytes.len() as u64;
+        }
+        while entries.size + size > capacity {
+            let Some(oldest) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(url, _)| url.clone())
+            else {
+                break;
+            };
+            let evicted = entries.map.remove(&oldest).unwrap();
+            entries.size -= evicted.file.bytes.len() as u64;
+        }
+
+        debug!("Caching {url} for {:?}", policy.freshness);
+        entries.size += size;
+        entries.map.insert(
+            key,
+            Entry {
+                url: normalize_url(url),
+                file: file.clone(),
+                policy,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    // Origin answered 304 Not Modified, so the stored file is fresh again
+    pub fn refresh(
+        &self,
+        url: &str,
+        headers: &HeaderMap,
+        provenance: Provenance,
+        default_freshness: Duration,
+    ) -> Option<DownloadedFile> {
+        let mut entries = self.entries.lock_or_recover();
+        let entry = entries.get_mut(url)?;
+
+        // Headers in 304 responses update the stored ones
+        let updated = CachePolicy::from_headers(headers, default_freshness);
+        if headers.contains_key(CACHE_CONTROL) || headers.contains_key(EXPIRES) {
+            entry.policy.storable = updated.storable;
+            entry.policy.freshness = updated.freshness;
+        }
+        entry.policy.etag = updated.etag.or(entry.policy.etag.take());
+        entry.policy.last_modified = updated.last_modified.or(entry.policy.last_modified.take());
+        entry.file.provenance = Provenance {
+            storable: entry.policy.storable,
+            etag: entry.policy.etag.clone(),
+            last_modified: entry.policy.last_modified.clone(),
+            ..provenance
+        };
+        entry.last_used = Instant::now();
+
+        let mut file = entry.file.clone();
+        file.provenance.tier = CacheTier::Revalidated;
+        Some(file)
+    }
+
+    pub fn forget(&self, url: &str) {
+        let mut entries = self.entries.lock_or_recover();
+        if entries.get_mut(url).is_some()
+            && let Some(old) = entries.map.remove(&url_key(url))
+        {
+            entries.size -= old.file.bytes.len() as u64;
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.entries.lock_or_recover() = Entries::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn file(size: usize) -> DownloadedFile {
+        DownloadedFile {
+            bytes: Bytes::from(vec![0; size]),
+            content_type: None,
+            filename: ("file".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let default = Duration::from_secs(300);
+        let policy = CachePolicy::from_headers(
+            &headers(&[("cache-control", "public, max-age=60, s-maxage=120")]),
+            default,
+        );
+        assert!(policy.storable);
+        assert_eq!(policy.freshness, Duration::from_secs(120));
+
+        let policy = CachePolicy::from_headers(&headers(&[("cache-control", "private")]), default);
+        assert!(!policy.storable);
+
+        let policy = CachePolicy::from_headers(
+            &headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            default,
+        );
+        assert_eq!(policy.freshness, Duration::ZERO);
+        assert_eq!(policy.etag, Some("\"v1\"".to_string()));
+
+        let policy = CachePolicy::from_headers(
+            &headers(&[
+                ("date", "Thu, 01 Jan 2026 00:00:00 GMT"),
+                ("expires", "Thu, 01 Jan 2026 01:00:00 GMT"),
+            ]),
+            default,
+        );
+        assert_eq!(policy.freshness, Duration::from_secs(3600));
+
+        // Heuristic: 10% of 10 hours
+        let policy = CachePolicy::from_headers(
+            &headers(&[
+                ("date", "Thu, 01 Jan 2026 10:00:00 GMT"),
+                ("last-modified", "Thu, 01 Jan 2026 00:00:00 GMT"),
+            ]),
+            default,
+        );
+        assert_eq!(policy.freshness, Duration::from_secs(3600));
+
+        let policy = CachePolicy::from_headers(&HeaderMap::new(), default);
+        assert_eq!(policy.freshness, default);
+    }
+
+    #[test]
+    fn test_initial_age() {
+        let response_time = httpdate::parse_http_date("Thu, 01 Jan 2026 00:01:00 GMT").unwrap();
+        let request_time = response_time - Duration::from_secs(2);
+        let age = initial_age(
+            &headers(&[("date", "Thu, 01 Jan 2026 00:00:00 GMT"), ("age", "30")]),
+            request_time,
+            response_time,
+        );
+        assert_eq!(age, Duration::from_secs(60));
+        let age = initial_age(&headers(&[("age", "30")]), request_time, response_time);
+        assert_eq!(age, Duration::from_secs(32));
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = ResponseCache::default();
+        let fresh =
+            CachePolicy::from_headers(&headers(&[("cache-control", "max-age=60")]), Duration::ZERO);
+        cache.insert("a", &file(60), fresh.clone(), 100);
+        assert!(
+            matches!(cache.get("a"), Lookup::Fresh(file) if file.provenance.tier == CacheTier::Memory)
+        );
+
+        // Evicts the least recently used one
+        cache.insert("b", &file(60), fresh.clone(), 100);
+        assert!(matches!(cache.get("a"), Lookup::Miss));
+        assert!(matches!(cache.get("b"), Lookup::Fresh(_)));
+
+        // Stale ones are kept for revalidation
+        let stale = CachePolicy::from_headers(
+            &headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            Duration::ZERO,
+        );
+        cache.insert("c", &file(10), stale, 100);
+        assert!(matches!(cache.get("c"), Lookup::Stale(policy) if policy.etag.is_some()));
+        let refreshed = cache.refresh(
+            "c",
+            &headers(&[("cache-control", "max-age=60")]),
+            Provenance::new(CacheTier::Origin),
+            Duration::ZERO,
+        );
+        assert!(refreshed.is_some_and(|file| file.provenance.tier == CacheTier::Revalidated));
+        assert!(matches!(cache.get("c"), Lookup::Fresh(_)));
+
+        // Not stored at all
+        let private = CachePolicy::from_headers(
+            &headers(&[("cache-control", "private, max-age=60")]),
+            Duration::ZERO,
+        );
+        cache.insert("d", &file(10), private, 100);
+        assert!(matches!(cache.get("d"), Lookup::Miss));
+    }
+}
@@ -0,0 +1,390 @@
+use super::DownloaderConfig;
+use super::hosts::{NoProxyRule, OriginDenied, matches_any};
+use super::redirects::RedirectCache;
+use super::ssrf::{SsrfGuard, UncheckedRedirect, check_scheme};
+use crate::guard::RwLockExt;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+use url::Url;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HttpVersion {
+    Auto, // negotiated by ALPN
+    Http1Only,
+    Http3, // http3 feature
+}
+
+// Per-host variations that need a dedicated client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientProfile {
+    pub browser_tls: bool,
+    pub http_version: HttpVersion,
+    pub unix_socket: Option<usize>, // in the configured ones
+}
+
+impl ClientProfile {
+    pub fn for_host(config: &DownloaderConfig, host: &str) -> Self {
+        let http_version = if matches_any(&config.http3_hosts, host) {
+            HttpVersion::Http3
+        } else if !config.upstream_http2 || matches_any(&config.http1_only_hosts, host) {
+            HttpVersion::Http1Only
+        } else {
+            HttpVersion::Auto
+        };
+
+        Self {
+            browser_tls: matches_any(&config.browser_tls_hosts, host),
+            http_version,
+            unix_socket: config
+                .unix_sockets
+                .iter()
+                .position(|socket| socket.matches(host))
+                .filter(|_| cfg!(unix)),
+        }
+    }
+}
+
+// Report options not supported by enabled features
+pub fn warn_unsupported(config: &DownloaderConfig) {
+    if cfg!(not(feature = "tls-mimic")) && !config.browser_tls_hosts.is_empty() {
+        warn!("Browser-like TLS is configured, but the tls-mimic feature is not enabled");
+    }
+    if cfg!(not(feature = "http3")) && !config.http3_hosts.is_empty() {
+        warn!("HTTP/3 is configured, but the http3 feature is not enabled");
+    }
+    if cfg!(not(unix)) && !config.unix_sockets.is_empty() {
+        warn!("Unix sockets are configured, but not supported on this platform");
+    }
+}
+
+pub fn warn_insecure(config: &DownloaderConfig) {
+    if config.insecure_tls {
+        warn!(
+            "INSECURE_TLS is on: certificates of origins are NOT verified, don't use this in production!"
+        );
+        if !config.browser_tls_hosts.is_empty() {
+            warn!("INSECURE_TLS doesn't apply to browser-like TLS hosts, they're still verified");
+        }
+    }
+}
+
+// Follow redirects like the default policy, but check every hop the same way as the
+// original URL (so a public URL can't bounce us into an internal or a blocked one), and learn
+// permanent ones. Configured origin headers stay on every hop, so those requests may only be
+// redirected within the same origin, as may those of a client pinned to a Unix socket (every
+// hop would go to it). Neither may a secure request continue in plain text, unless allowed.
+// Host names not checked yet are left for the downloader to check, if they'd go through a proxy
+#[cfg(not(target_arch = "wasm32"))]
+fn redirect_policy(
+    config: &DownloaderConfig,
+    max_redirects: usize,
+    redirects: Option<RedirectCache>,
+    guard: SsrfGuard,
+    pinned: bool,
+) -> reqwest::redirect::Policy {
+    let allow_downgrade = config.allow_redirect_downgrade;
+    let routes = ProxyRoutes::new(config);
+    let origins = config.origins.clone();
+    let origin_headers = config.origin_headers.clone();
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error(format!("more than {max_redirects} redirects"));
+        }
+        if let Err(err) = check_scheme(attempt.url()) {
+            return attempt.error(err);
+        }
+        if !allow_downgrade
+            && attempt.url().scheme() == "http"
+            && attempt
+                .previous()
+                .last()
+                .is_some_and(|from| from.scheme() == "https")
+        {
+            return attempt.error("redirected from https to http");
+        }
+        if let Err(err) = guard.check_url(attempt.url()) {
+            return attempt.error(err);
+        }
+        if !origins.permits(attempt.url()) {
+            let host = attempt.url().host_str().unwrap_or_default().to_string();
+            return attempt.error(OriginDenied(host));
+        }
+        if let Some(first) = attempt.previous().first()
+            && let Some(host) = first.host_str()
+            && (pinned
+                || origin_headers
+                    .iter()
+                    .any(|header| header.host.matches(host)))
+            && first.origin() != attempt.url().origin()
+        {
+            let err = format!("redirected away from {host}, which is pinned or has origin headers");
+            return attempt.error(err);
+        }
+        if let Some(redirects) = &redirects
+            && let Some(from) = attempt.previous().last()
+        {
+            redirects.learn(attempt.status(), from, attempt.url());
+        }
+        if routes.proxy_for(attempt.url()).is_some() && !guard.is_checked(attempt.url()) {
+            let url = attempt.url().clone();
+            let hops = attempt.previous().len();
+            return attempt.error(UncheckedRedirect(url, hops));
+        }
+        attempt.follow()
+    })
+}
+
+// Through the configured proxy unless excluded, with .onion hosts preferring the SOCKS one
+// (e.g. Tor) when both are set
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Default)]
+pub struct ProxyRoutes {
+    http: Option<Url>,
+    socks: Option<Url>,
+    no_proxy: Vec<NoProxyRule>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ProxyRoutes {
+    pub fn new(config: &DownloaderConfig) -> Self {
+        Self {
+            http: config.http_proxy.clone(),
+            socks: config.socks_proxy.clone(),
+            no_proxy: config.no_proxy.clone(),
+        }
+    }
+
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host()?;
+        if self.no_proxy.iter().any(|rule| rule.matches(&host)) {
+            return None;
+        }
+        let host = url.host_str()?;
+        match (&self.http, &self.socks) {
+            (Some(_), Some(socks)) if host.ends_with(".onion") => Some(socks.clone()),
+            (Some(http), _) => Some(http.clone()),
+            (None, socks) => socks.clone(),
+        }
+    }
+}
+
+// The proxy resolves host names itself, out of reach of the guard, so only those it has checked
+// are sent there (the downloader checks them first). Any other goes direct, through the guard
+#[cfg(not(target_arch = "wasm32"))]
+fn outbound_proxy(routes: ProxyRoutes, guard: SsrfGuard) -> Option<reqwest::Proxy> {
+    if routes.http.is_none() && routes.socks.is_none() {
+        return None;
+    }
+    Some(reqwest::Proxy::custom(move |url| {
+        routes.proxy_for(url).filter(|_| guard.is_checked(url))
+    }))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn build_client(
+    profile: ClientProfile,
+    config: &DownloaderConfig,
+    redirects: Option<&RedirectCache>,
+    guard: &SsrfGuard,
+    max_redirects: usize, // of config.max_redirects, the hops left
+) -> Client {
+    let policy = if config.max_redirects == 0 {
+        reqwest::redirect::Policy::none()
+    } else {
+        redirect_policy(
+            config,
+            max_redirects,
+            redirects.cloned(),
+            guard.clone(),
+            profile.unix_socket.is_some(),
+        )
+    };
+    let mut builder = Client::builder()
+        .redirect(policy)
+        .dns_resolver(Arc::new(guard.clone()));
+
+    if let Some(addr) = config.outbound_address {
+        builder = builder.local_address(addr); // other family destinations stay unbound
+    }
+    if let Some(proxy) = outbound_proxy(ProxyRoutes::new(config), guard.clone()) {
+        builder = builder.proxy(proxy);
+    }
+    #[cfg(unix)]
+    if let Some(index) = profile.unix_socket {
+        builder = builder.unix_socket(config.unix_sockets[index].path.clone()); // no TCP nor proxy
+    }
+
+    // So that a hung origin can't keep a task (and its buffer) alive forever
+    if !config.connect_timeout.is_zero() {
+        builder = builder.connect_timeout(config.connect_timeout);
+    }
+    if !config.read_timeout.is_zero() {
+        builder = builder.read_timeout(config.read_timeout);
+    }
+    if !config.download_timeout.is_zero() {
+        builder = builder.timeout(config.download_timeout); // including the body
+    }
+
+    // Connections kept for the next fetches, e.g. to avoid reconnecting to a busy origin
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    builder = builder.pool_idle_timeout(
+        (!config.pool_idle_timeout.is_zero()).then_some(config.pool_idle_timeout),
+    );
+
+    // Private CAs, e.g. for instances behind a corporate gateway
+    if let Some(pem) = &config.ca_bundle {
+        match reqwest::Certificate::from_pem_bundle(pem) {
+            Ok(certificates) => {
+                for certificate in certificates {
+                    builder = builder.add_root_certificate(certificate);
+                }
+            }
+            Err(err) => warn!("Invalid CA bundle, ignored: {err}"),
+        }
+    }
+    if config.insecure_tls {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    #[cfg(feature = "tls-mimic")]
+    if profile.browser_tls {
+        builder = builder.use_preconfigured_tls(super::browser_tls::browser_tls_config(
+            config.ca_bundle.as_deref(),
+            profile.http_version,
+        ));
+    }
+
+    match profile.http_version {
+        HttpVersion::Auto => {}
+        HttpVersion::Http1Only => builder = builder.http1_only(),
+        #[cfg(feature = "http3")]
+        HttpVersion::Http3 => builder = builder.http3_prior_knowledge(),
+        #[cfg(not(feature = "http3"))]
+        HttpVersion::Http3 => {} // already warned
+    }
+
+    builder.build().expect("Failed to build http client")
+}
+
+#[cfg(target_arch = "wasm32")]
+fn build_client(
+    _profile: ClientProfile,
+    _config: &DownloaderConfig,
+    _redirects: Option<&RedirectCache>,
+    _guard: &SsrfGuard,
+    _max_redirects: usize,
+) -> Client {
+    Client::new() // the browser decides everything
+}
+
+// Clients are built on first use of each profile, and shared across clones
+#[derive(Clone)]
+pub struct ClientPool {
+    clients: Arc<RwLock<HashMap<ClientProfile, Client>>>,
+    redirects: Option<RedirectCache>, // learn permanent redirects if enabled
+    guard: SsrfGuard,
+}
+
+impl ClientPool {
+    pub fn new(config: &DownloaderConfig, redirects: &RedirectCache, guard: &SsrfGuard) -> Self {
+        Self {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            redirects: (!config.redirect_cache_ttl.is_zero()).then(|| redirects.clone()),
+            guard: guard.clone(),
+        }
+    }
+
+    pub fn get(&self, config: &DownloaderConfig, host: &str) -> Client {
+        let profile = ClientProfile::for_host(config, host);
+        if let Some(client) = self.clients.read_or_recover().get(&profile) {
+            return client.clone();
+        }
+
+        self.clients
+            .write_or_recover()
+            .entry(profile)
+            .or_insert_with(|| {
+                build_client(
+                    profile,
+                    config,
+                    self.redirects.as_ref(),
+                    &self.guard,
+                    config.max_redirects,
+                )
+            })
+            .clone()
+    }
+
+    // A client of the same profile that may only follow the hops left, to continue a request
+    // the redirect policy stopped. Not shared, as that is rare
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn limited(&self, config: &DownloaderConfig, host: &str, max_redirects: usize) -> Client {
+        let profile = ClientProfile::for_host(config, host);
+        build_client(
+            profile,
+            config,
+            self.redirects.as_ref(),
+            &self.guard,
+            max_redirects,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::{parse_dns_overrides, parse_host_patterns};
+
+    #[test]
+    fn test_client_profile() {
+        let config = DownloaderConfig {
+            http1_only_hosts: parse_host_patterns("old.example.com").unwrap(),
+            http3_hosts: parse_host_patterns("*.fast.example.com").unwrap(),
+            ..Default::default()
+        };
+        let profile = ClientProfile::for_host(&config, "old.example.com");
+        assert_eq!(profile.http_version, HttpVersion::Http1Only);
+        let profile = ClientProfile::for_host(&config, "cdn.fast.example.com");
+        assert_eq!(profile.http_version, HttpVersion::Http3);
+        let profile = ClientProfile::for_host(&config, "example.com");
+        assert_eq!(profile.http_version, HttpVersion::Auto);
+        assert!(!profile.browser_tls);
+
+        let config = DownloaderConfig {
+            upstream_http2: false,
+            ..Default::default()
+        };
+        let profile = ClientProfile::for_host(&config, "example.com");
+        assert_eq!(profile.http_version, HttpVersion::Http1Only);
+    }
+
+    #[tokio::test]
+    async fn test_dns_override_keeps_sni() {
+        use tokio::io::AsyncReadExt;
+
+        // The handshake still names the origin host, so its certificate is the one verified
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = DownloaderConfig {
+            dns_overrides: parse_dns_overrides("media.example.test=127.0.0.1").unwrap(),
+            ..Default::default()
+        };
+        let guard = SsrfGuard::new(Vec::new(), config.dns_overrides.clone());
+        let profile = ClientProfile::for_host(&config, "media.example.test");
+        let client = build_client(profile, &config, None, &guard, config.max_redirects);
+        let url = format!("https://media.example.test:{port}/a.png");
+        let request = tokio::spawn(async move { client.get(url).send().await });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut hello = vec![0; 4096];
+        let len = stream.read(&mut hello).await.unwrap();
+        drop(stream);
+        let sni = b"media.example.test";
+        assert!(hello[..len].windows(sni.len()).any(|window| window == sni));
+        assert!(request.await.unwrap().is_err()); // no TLS server there
+    }
+}
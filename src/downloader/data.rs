@@ -0,0 +1,46 @@
+use super::{CacheTier, DownloadedFile, FileDownloadError, Provenance};
+use bytes::Bytes;
+use data_url::DataUrl;
+
+pub fn is_data_uri(url: &str) -> bool {
+    url.get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+// Small images embedded in notes (e.g. by Misskey), the bytes are in the URL already.
+// The downloader holds them to SIZE_LIMIT like fetched files
+pub fn decode(url: &str) -> Result<DownloadedFile, FileDownloadError> {
+    let data_url = DataUrl::process(url).map_err(|_| FileDownloadError::InvalidUrl)?;
+    let (bytes, _) = data_url
+        .decode_to_vec()
+        .map_err(|_| FileDownloadError::InvalidUrl)?;
+    let mime = data_url.mime_type();
+    Ok(DownloadedFile {
+        bytes: Bytes::from(bytes),
+        content_type: Some(format!("{}/{}", mime.type_, mime.subtype)),
+        filename: ("data".to_string(), None),
+        provenance: Provenance::new(CacheTier::Inline),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_uri() {
+        assert!(is_data_uri("DATA:image/png;base64,"));
+        assert!(!is_data_uri("https://example.com/data:"));
+
+        let file = decode("data:image/png;base64,iVBORw0KGgo=").ok().unwrap();
+        assert_eq!(&file.bytes[..], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(file.content_type.as_deref(), Some("image/png"));
+
+        let file = decode("data:,%3Csvg%3E").ok().unwrap();
+        assert_eq!(&file.bytes[..], b"<svg>");
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+
+        assert!(decode("data:image/png;base64,!!!").is_err());
+        assert!(decode("data:image/png").is_err()); // no comma
+    }
+}
@@ -0,0 +1,217 @@
+use super::hosts::DnsServer;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const MAX_UDP_ANSWER: usize = 512; // without EDNS
+
+// Addresses of hostnames, from the system or the configured server
+#[derive(Clone, Default)]
+pub struct Resolver {
+    server: Option<DnsServer>,
+    doh: reqwest::Client, // without our resolver, so the DoH host itself uses the system one
+}
+
+impl Resolver {
+    pub fn new(server: Option<DnsServer>) -> Self {
+        Self {
+            server,
+            doh: reqwest::Client::builder()
+                .timeout(DNS_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let Some(server) = &self.server else {
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            return Ok(addrs.map(|addr| addr.ip()).collect());
+        };
+        let (v4, v6) = futures_util::future::join(
+            self.query(server, host, TYPE_A),
+            self.query(server, host, TYPE_AAAA),
+        )
+        .await;
+        let addrs: Vec<_> = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (v4, v6) => v4.into_iter().chain(v6).flatten().collect(),
+        };
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses for {host} from {server}"),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    async fn query(&self, server: &DnsServer, host: &str, kind: u16) -> io::Result<Vec<IpAddr>> {
+        let exchange = async {
+            match server {
+                DnsServer::Udp(addr) => exchange_udp(*addr, host, kind).await,
+                DnsServer::Https(url) => {
+                    // ID is 0 for DoH, so that answers can be cached by HTTP caches
+                    let response = self
+                        .doh
+                        .post(url.clone())
+                        .header("content-type", "application/dns-message")
+                        .header("accept", "application/dns-message")
+                        .body(build_query(0, host, kind)?)
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                        .map_err(io::Error::other)?;
+                    let packet = response.bytes().await.map_err(io::Error::other)?;
+                    parse_answer(&packet, 0).map(|(addrs, _)| addrs)
+                }
+            }
+        };
+        tokio::time::timeout(DNS_TIMEOUT, exchange)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{server} timed out")))?
+    }
+}
+
+async fn exchange_udp(server: SocketAddr, host: &str, kind: u16) -> io::Result<Vec<IpAddr>> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    let query = build_query(id, host, kind)?;
+
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(&query).await?;
+    let mut packet = vec![0; MAX_UDP_ANSWER];
+    loop {
+        let len = socket.recv(&mut packet).await?;
+        match parse_answer(&packet[..len], id) {
+            Ok((addrs, false)) => return Ok(addrs),
+            Ok((_, true)) => break, // truncated, ask again over TCP
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => continue, // stray packet
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut stream = TcpStream::connect(server).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend(&query);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut packet = vec![0; len];
+    stream.read_exact(&mut packet).await?;
+    parse_answer(&packet, id).map(|(addrs, _)| addrs)
+}
+
+fn build_query(id: u16, host: &str, kind: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(host.len() + 18);
+    packet.extend(id.to_be_bytes());
+    packet.extend([0x01, 0x00]); // recursion desired
+    packet.extend([0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid hostname: {host}"),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend(kind.to_be_bytes());
+    packet.extend([0, 1]); // class IN
+    Ok(packet)
+}
+
+// Past a (possibly compressed) name
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+// Addresses in the answer section (CNAMEs are followed by the server), and if truncated.
+// InvalidData if the packet isn't the answer to our query
+fn parse_answer(packet: &[u8], id: u16) -> io::Result<(Vec<IpAddr>, bool)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer");
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        let bytes = packet.get(pos..pos + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if u16_at(0)? != id || u16_at(2)? & 0x8000 == 0 {
+        return Err(invalid());
+    }
+    let flags = u16_at(2)?;
+    let truncated = flags & 0x0200 != 0;
+    match flags & 0x000f {
+        0 | 3 => {} // no error, or no such name (no addresses)
+        rcode => {
+            return Err(io::Error::other(format!(
+                "DNS server error (rcode {rcode})"
+            )));
+        }
+    }
+
+    let mut pos = 12;
+    for _ in 0..u16_at(4)? {
+        pos = skip_name(packet, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..u16_at(6)? {
+        pos = skip_name(packet, pos).ok_or_else(invalid)?;
+        let kind = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = packet.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+        match (kind, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => addrs.push(IpAddr::from(octets)),
+            (TYPE_AAAA, _, Ok(octets)) => addrs.push(IpAddr::from(octets)),
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Ok((addrs, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_packets() {
+        let query = build_query(0x1234, "media.example.com.", TYPE_A).unwrap();
+        assert_eq!(&query[..4], [0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..19], b"\x05media\x07");
+        assert!(build_query(1, "a..example.com", TYPE_A).is_err());
+
+        // The query echoed back with a CNAME and an A record, names compressed
+        let mut answer = query.clone();
+        answer[2] = 0x81;
+        answer[3] = 0x80;
+        answer[7] = 2;
+        answer.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 18]);
+        answer.extend([0xc0, 18, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 5]);
+        let (addrs, truncated) = parse_answer(&answer, 0x1234).unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([203, 0, 113, 5])]);
+        assert!(!truncated);
+
+        assert!(parse_answer(&answer, 0x4321).is_err()); // not ours
+        answer[3] = 0x82; // server failure
+        assert!(parse_answer(&answer, 0x1234).is_err());
+        assert!(parse_answer(&answer[..20], 0x1234).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use super::FileDownloadError;
+use crate::guard::RwLockExt;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+const MAX_ENTRIES: usize = 10_000;
+
+// Recent failures per URL (e.g. a dead emoji on every timeline page), answered again without
+// fetching. Shared across clones and config reloads, expiry is checked with the current TTL
+#[derive(Clone, Default)]
+pub struct FailureCache {
+    entries: Arc<RwLock<HashMap<String, (FileDownloadError, Instant)>>>,
+}
+
+impl FailureCache {
+    pub fn get(&self, url: &str, ttl: Duration) -> Option<FileDownloadError> {
+        if ttl.is_zero() {
+            return None;
+        }
+        let entries = self.entries.read_or_recover();
+        let (err, failed_at) = entries.get(url)?;
+        (failed_at.elapsed() < ttl).then(|| err.clone())
+    }
+
+    // Only failures of the origin itself, not of our limits or the client's copy
+    pub fn record(&self, url: &str, err: &FileDownloadError, ttl: Duration) {
+        let remembered = match err {
+            FileDownloadError::InvalidStatusCode(status) => {
+                *status != StatusCode::TOO_MANY_REQUESTS
+            }
+            FileDownloadError::Timeout | FileDownloadError::RedirectRejected => true,
+            FileDownloadError::RequestError(err) => err.is_connect(), // including DNS
+            _ => false,
+        };
+        if ttl.is_zero() || !remembered {
+            return;
+        }
+
+        let mut entries = self.entries.write_or_recover();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (_, failed_at)| failed_at.elapsed() < ttl);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            return; // failing everywhere, the circuit breaker is more useful then
+        }
+        entries.insert(url.to_string(), (err.clone(), Instant::now()));
+    }
+
+    pub fn forget(&self, url: &str) {
+        self.entries.write_or_recover().remove(url);
+    }
+
+    pub fn clear(&self) {
+        self.entries.write_or_recover().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_failure_cache() {
+        let failures = FailureCache::default();
+        let not_found = FileDownloadError::InvalidStatusCode(StatusCode::NOT_FOUND);
+        failures.record("https://a.example/dead.png", &not_found, TTL);
+        failures.record(
+            "https://a.example/slow.png",
+            &FileDownloadError::Timeout,
+            TTL,
+        );
+        failures.record(
+            "https://a.example/busy.png",
+            &FileDownloadError::HostBusy,
+            TTL,
+        );
+        failures.record(
+            "https://a.example/limited.png",
+            &FileDownloadError::InvalidStatusCode(StatusCode::TOO_MANY_REQUESTS),
+            TTL,
+        );
+
+        assert!(matches!(
+            failures.get("https://a.example/dead.png", TTL),
+            Some(FileDownloadError::InvalidStatusCode(StatusCode::NOT_FOUND))
+        ));
+        assert!(matches!(
+            failures.get("https://a.example/slow.png", TTL),
+            Some(FileDownloadError::Timeout)
+        ));
+        assert!(failures.get("https://a.example/busy.png", TTL).is_none());
+        assert!(failures.get("https://a.example/limited.png", TTL).is_none());
+        // Expired with a shorter TTL after a reload, or disabled
+        assert!(
+            failures
+                .get("https://a.example/dead.png", Duration::from_nanos(1))
+                .is_none()
+        );
+        assert!(
+            failures
+                .get("https://a.example/dead.png", Duration::ZERO)
+                .is_none()
+        );
+    }
+}
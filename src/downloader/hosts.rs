@@ -0,0 +1,824 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use url::{Host, Url};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    Wildcard(String), // suffix with the leading dot, e.g. `.example.com`
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            HostPattern::Exact(exact) => host.eq_ignore_ascii_case(exact),
+            HostPattern::Wildcard(suffix) => {
+                host.len() > suffix.len()
+                    && host.is_char_boundary(host.len() - suffix.len())
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                return Err(format!("invalid host pattern: {s}"));
+            }
+            Ok(HostPattern::Wildcard(format!(".{suffix}")))
+        } else if pattern.is_empty() || pattern.contains(['*', '/', ':']) {
+            Err(format!("invalid host pattern: {s}"))
+        } else {
+            Ok(HostPattern::Exact(pattern))
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Exact(exact) => write!(f, "{exact}"),
+            HostPattern::Wildcard(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+// Comma separated list, e.g. `example.com, *.example.org`
+pub fn parse_host_patterns(input: &str) -> Result<Vec<HostPattern>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+pub fn matches_any(patterns: &[HostPattern], host: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(host))
+}
+
+// CIDR network, e.g. `10.0.0.0/8` or `fd00::/8` (a plain address is a single host)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid network: {s}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("invalid network prefix: {s}"))?,
+            None => max_prefix,
+        };
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Comma separated list, e.g. `127.0.0.1, 10.0.0.0/8`
+pub fn parse_networks(input: &str) -> Result<Vec<IpNet>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Origin host or network, e.g. `example.com`, `*.example.org` or `203.0.113.0/24`
+#[derive(Clone, Debug, PartialEq)]
+pub enum OriginRule {
+    Host(HostPattern),
+    Network(IpNet), // only for URLs with an IP address as the host
+}
+
+impl OriginRule {
+    pub fn matches(&self, host: &Host<&str>) -> bool {
+        match (self, host) {
+            (OriginRule::Host(pattern), Host::Domain(domain)) => pattern.matches(domain),
+            (OriginRule::Network(net), Host::Ipv4(ip)) => net.contains(IpAddr::V4(*ip)),
+            (OriginRule::Network(net), Host::Ipv6(ip)) => net.contains(IpAddr::V6(*ip)),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for OriginRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(net) => Ok(OriginRule::Network(net)),
+            Err(_) => s.parse().map(OriginRule::Host),
+        }
+    }
+}
+
+impl fmt::Display for OriginRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginRule::Host(pattern) => write!(f, "{pattern}"),
+            OriginRule::Network(net) => write!(f, "{net}"),
+        }
+    }
+}
+
+// Comma separated list, e.g. `example.com, *.example.org, 203.0.113.0/24`
+pub fn parse_origin_rules(input: &str) -> Result<Vec<OriginRule>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// NO_PROXY entry as curl and most tools read it: `*` for every host, a domain with its
+// subdomains (with or without a leading dot), `*.example.com`, an IP address or a network
+#[derive(Clone, Debug, PartialEq)]
+pub enum NoProxyRule {
+    Any,
+    Domain(String),
+    Origin(OriginRule),
+}
+
+impl NoProxyRule {
+    pub fn matches(&self, host: &Host<&str>) -> bool {
+        match (self, host) {
+            (NoProxyRule::Any, _) => true,
+            (NoProxyRule::Domain(domain), Host::Domain(host)) => {
+                let host = host.trim_end_matches('.');
+                let Some(start) = host.len().checked_sub(domain.len()) else {
+                    return false;
+                };
+                host.is_char_boundary(start)
+                    && host[start..].eq_ignore_ascii_case(domain)
+                    && (start == 0 || host[..start].ends_with('.'))
+            }
+            (NoProxyRule::Origin(rule), host) => rule.matches(host),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for NoProxyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            Ok(NoProxyRule::Any)
+        } else if let Some(domain) = s.strip_prefix('.') {
+            match domain.parse() {
+                Ok(HostPattern::Exact(domain)) => Ok(NoProxyRule::Domain(domain)),
+                _ => Err(format!("invalid no proxy entry: {s}")),
+            }
+        } else {
+            match s.parse() {
+                Ok(OriginRule::Host(HostPattern::Exact(domain))) => Ok(NoProxyRule::Domain(domain)),
+                rule => rule.map(NoProxyRule::Origin),
+            }
+        }
+    }
+}
+
+impl fmt::Display for NoProxyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoProxyRule::Any => write!(f, "*"),
+            NoProxyRule::Domain(domain) => write!(f, "{domain}"),
+            NoProxyRule::Origin(rule) => write!(f, "{rule}"),
+        }
+    }
+}
+
+// Comma separated list, e.g. `localhost, .internal.example, 10.0.0.0/8, ::1`
+pub fn parse_no_proxy(input: &str) -> Result<Vec<NoProxyRule>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Which origins may be fetched from at all, e.g. to apply the federation blocklist
+#[derive(Clone, Debug, Default)]
+pub struct OriginPolicy {
+    pub allow: Vec<OriginRule>, // if not empty, only these
+    pub block: Vec<OriginRule>, // never these, even if allowed
+}
+
+impl OriginPolicy {
+    pub fn permits(&self, url: &Url) -> bool {
+        let Some(host) = url.host() else {
+            return false;
+        };
+        let matches = |rules: &[OriginRule]| rules.iter().any(|rule| rule.matches(&host));
+        !matches(&self.block) && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
+
+// A redirect to an origin the policy doesn't permit
+#[derive(Debug)]
+pub struct OriginDenied(pub String);
+
+impl fmt::Display for OriginDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not a permitted origin", self.0)
+    }
+}
+
+impl Error for OriginDenied {}
+
+// Fixed address for a hostname, like in /etc/hosts, e.g. `media.example.com=10.0.0.5`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsOverride {
+    pub host: String,
+    pub addr: IpAddr,
+}
+
+impl DnsOverride {
+    pub fn matches(&self, host: &str) -> bool {
+        host.trim_end_matches('.').eq_ignore_ascii_case(&self.host)
+    }
+}
+
+impl FromStr for DnsOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, addr) = s
+            .split_once('=')
+            .ok_or(format!("invalid DNS override, expected host=ip: {s}"))?;
+        let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() || host.contains(['*', '/', ':']) {
+            return Err(format!("invalid DNS override host: {s}"));
+        }
+        let addr = addr
+            .trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| format!("invalid DNS override address: {s}"))?;
+        Ok(Self { host, addr })
+    }
+}
+
+impl fmt::Display for DnsOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.addr)
+    }
+}
+
+// Comma separated list, a host may be listed more than once for multiple addresses
+pub fn parse_dns_overrides(input: &str) -> Result<Vec<DnsOverride>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Another copy of an origin's files, e.g. `https://files.example.com/=https://mirror.example.net/files/`,
+// with the rest of the URL after the prefix appended to the mirror
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorRule {
+    pub prefix: String,
+    pub mirror: String,
+}
+
+impl MirrorRule {
+    pub fn apply(&self, url: &str) -> Option<String> {
+        let rest = url.strip_prefix(&self.prefix)?;
+        Some(format!("{}{rest}", self.mirror))
+    }
+}
+
+impl FromStr for MirrorRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, mirror) = s
+            .split_once('=')
+            .ok_or(format!("invalid mirror, expected prefix=mirror: {s}"))?;
+        let (prefix, mirror) = (prefix.trim(), mirror.trim());
+        for url in [prefix, mirror] {
+            match Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => return Err(format!("invalid mirror URL, expected http(s)://: {s}")),
+            }
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            mirror: mirror.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for MirrorRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.prefix, self.mirror)
+    }
+}
+
+// Comma separated list, a prefix may be listed more than once for multiple mirrors
+pub fn parse_mirror_rules(input: &str) -> Result<Vec<MirrorRule>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Header sent to matching hosts only, e.g. `media.internal.example=Authorization: Bearer abc`,
+// for protected storage of the instance
+#[derive(Clone, Debug, PartialEq)]
+pub struct OriginHeader {
+    pub host: HostPattern,
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for OriginHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, header) = s.split_once('=').ok_or(format!(
+            "invalid origin header, expected host=Name: value: {s}"
+        ))?;
+        let (name, value) = header.split_once(':').ok_or(format!(
+            "invalid origin header, expected host=Name: value: {s}"
+        ))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("invalid origin header name: {}", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("invalid origin header value for {name}"))?;
+        value.set_sensitive(true);
+        Ok(Self {
+            host: host.parse()?,
+            name,
+            value,
+        })
+    }
+}
+
+// Without the value, which is usually a secret
+impl fmt::Display for OriginHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}: ***", self.host, self.name)
+    }
+}
+
+// Those for the host, taking over the proxy's own ones of the same name
+pub fn origin_headers(headers: &[OriginHeader], host: &str) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for header in headers.iter().filter(|header| header.host.matches(host)) {
+        map.append(header.name.clone(), header.value.clone());
+    }
+    map
+}
+
+// Comma separated list, a host may be listed more than once for multiple headers
+pub fn parse_origin_headers(input: &str) -> Result<Vec<OriginHeader>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// What a fetch answered with 4xx (e.g. for hotlink protection) is retried with, besides the
+// retry User-Agent
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HotlinkStrategy {
+    #[default]
+    Instance, // Referer of the instance in the `host` parameter
+    Referer, // Referer of the origin itself
+    Origin,  // Origin header of the origin itself
+    Bare,    // nothing else
+    Never,   // not retried at all
+}
+
+impl FromStr for HotlinkStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "instance" => Ok(HotlinkStrategy::Instance),
+            "referer" => Ok(HotlinkStrategy::Referer),
+            "origin" => Ok(HotlinkStrategy::Origin),
+            "none" => Ok(HotlinkStrategy::Bare),
+            "never" => Ok(HotlinkStrategy::Never),
+            _ => Err(format!("unknown hotlink strategy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for HotlinkStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HotlinkStrategy::Instance => write!(f, "instance"),
+            HotlinkStrategy::Referer => write!(f, "referer"),
+            HotlinkStrategy::Origin => write!(f, "origin"),
+            HotlinkStrategy::Bare => write!(f, "none"),
+            HotlinkStrategy::Never => write!(f, "never"),
+        }
+    }
+}
+
+// The strategy for matching hosts, e.g. `*.example.com=referer`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotlinkRule {
+    pub host: HostPattern,
+    pub strategy: HotlinkStrategy,
+}
+
+impl FromStr for HotlinkRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, strategy) = s.split_once('=').ok_or(format!(
+            "invalid hotlink strategy, expected host=strategy: {s}"
+        ))?;
+        Ok(Self {
+            host: host.parse()?,
+            strategy: strategy.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for HotlinkRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.strategy)
+    }
+}
+
+// Comma separated list, the first match applies
+pub fn parse_hotlink_rules(input: &str) -> Result<Vec<HotlinkRule>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Resolver used instead of the system one, e.g. `1.1.1.1`, `[2606:4700::1111]:53`
+// or `https://1.1.1.1/dns-query`
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsServer {
+    Udp(SocketAddr), // plain DNS, over TCP for truncated answers
+    Https(Url),      // DNS over HTTPS (RFC 8484)
+}
+
+impl FromStr for DnsServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with("https://") {
+            return Url::parse(s)
+                .map(DnsServer::Https)
+                .map_err(|err| format!("invalid DNS over HTTPS URL: {err}"));
+        }
+        let addr = s.strip_prefix("udp://").unwrap_or(s).trim_end_matches('/');
+        addr.parse()
+            .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .or_else(|_| {
+                let ip = addr.trim_start_matches('[').trim_end_matches(']');
+                ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53))
+            })
+            .map(DnsServer::Udp)
+            .map_err(|_| format!("invalid DNS server, expected an IP address or https URL: {s}"))
+    }
+}
+
+impl fmt::Display for DnsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsServer::Udp(addr) => write!(f, "{addr}"),
+            DnsServer::Https(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+// Address family tried first when connecting to origins, the other one is still the fallback
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpPreference {
+    #[default]
+    Auto, // in the resolver's order
+    Ipv4,
+    Ipv6,
+}
+
+impl IpPreference {
+    // Happy eyeballs starts with the family of the first address
+    pub fn order(self, addrs: &mut [SocketAddr]) {
+        match self {
+            IpPreference::Auto => {}
+            IpPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(IpPreference::Auto),
+            "ipv4" => Ok(IpPreference::Ipv4),
+            "ipv6" => Ok(IpPreference::Ipv6),
+            _ => Err(format!("unknown address family: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpPreference::Auto => write!(f, "auto"),
+            IpPreference::Ipv4 => write!(f, "ipv4"),
+            IpPreference::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_pattern() {
+        let patterns = parse_host_patterns("Example.com, *.cdn.example.org,").unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(matches_any(&patterns, "example.com"));
+        assert!(matches_any(&patterns, "EXAMPLE.com."));
+        assert!(!matches_any(&patterns, "www.example.com"));
+        assert!(matches_any(&patterns, "a.cdn.example.org"));
+        assert!(!matches_any(&patterns, "cdn.example.org"));
+        assert!(!matches_any(&patterns, "evilcdn.example.org"));
+        assert_eq!(patterns[1].to_string(), "*.cdn.example.org");
+
+        assert!("*".parse::<HostPattern>().is_err());
+        assert!("a.*.com".parse::<HostPattern>().is_err());
+        assert!("example.com:443".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let networks = parse_networks("10.0.0.0/8, 127.0.0.1, fd00::/8").unwrap();
+        let contains = |ip: &str| networks.iter().any(|net| net.contains(ip.parse().unwrap()));
+        assert!(contains("10.1.2.3"));
+        assert!(!contains("11.0.0.1"));
+        assert!(contains("127.0.0.1"));
+        assert!(!contains("127.0.0.2"));
+        assert!(contains("::ffff:10.0.0.1"));
+        assert!(contains("fd12::1"));
+        assert!(!contains("fe80::1"));
+        assert!(
+            "0.0.0.0/0"
+                .parse::<IpNet>()
+                .unwrap()
+                .contains("8.8.8.8".parse().unwrap())
+        );
+        assert_eq!(networks[1].to_string(), "127.0.0.1/32");
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_origin_policy() {
+        let permits = |policy: &OriginPolicy, url: &str| policy.permits(&Url::parse(url).unwrap());
+        let policy = OriginPolicy {
+            allow: Vec::new(),
+            block: parse_origin_rules("bad.example, *.spam.example, 203.0.113.0/24").unwrap(),
+        };
+        assert!(permits(&policy, "https://good.example/a.png"));
+        assert!(!permits(&policy, "https://BAD.example/a.png"));
+        assert!(!permits(&policy, "https://media.spam.example/a.png"));
+        assert!(!permits(&policy, "http://203.0.113.7/a.png"));
+        assert!(permits(&policy, "http://198.51.100.7/a.png"));
+
+        let policy = OriginPolicy {
+            allow: parse_origin_rules("*.example").unwrap(),
+            ..policy
+        };
+        assert!(permits(&policy, "https://good.example/a.png"));
+        assert!(!permits(&policy, "https://bad.example/a.png"));
+        assert!(!permits(&policy, "https://other.test/a.png"));
+        assert_eq!(policy.block[2].to_string(), "203.0.113.0/24");
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let rules = parse_no_proxy("localhost,127.0.0.1,10.0.0.0/8,::1,.example.com").unwrap();
+        let bypass = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            let host = url.host().unwrap();
+            rules.iter().any(|rule| rule.matches(&host))
+        };
+        assert!(bypass("http://localhost:8080/a.png"));
+        assert!(bypass("http://127.0.0.1/a.png"));
+        assert!(bypass("http://10.1.2.3/a.png"));
+        assert!(bypass("http://[::1]/a.png"));
+        assert!(bypass("https://example.com/a.png"));
+        assert!(bypass("https://media.example.com/a.png"));
+        assert!(!bypass("https://notexample.com/a.png"));
+        assert!(!bypass("http://11.0.0.1/a.png"));
+        assert_eq!(rules[4].to_string(), "example.com");
+
+        let rules = parse_no_proxy("*.example.org, *").unwrap();
+        assert!(!rules[0].matches(&Host::Domain("example.org")));
+        assert!(rules[1].matches(&Host::Domain("anything.test")));
+        assert!(parse_no_proxy(".").is_err());
+        assert!(parse_no_proxy("example.com:8080").is_err());
+    }
+
+    #[test]
+    fn test_dns_override() {
+        let overrides =
+            parse_dns_overrides("Media.example.com=10.0.0.5, media.example.com=[fd00::5],")
+                .unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert!(overrides[0].matches("media.example.com."));
+        assert!(!overrides[0].matches("example.com"));
+        assert_eq!(overrides[1].addr, "fd00::5".parse::<IpAddr>().unwrap());
+        assert_eq!(overrides[0].to_string(), "media.example.com=10.0.0.5");
+
+        assert!("media.example.com".parse::<DnsOverride>().is_err());
+        assert!("*.example.com=10.0.0.5".parse::<DnsOverride>().is_err());
+        assert!("media.example.com=internal".parse::<DnsOverride>().is_err());
+    }
+
+    #[test]
+    fn test_mirror_rule() {
+        let rules = parse_mirror_rules(
+            "https://files.example.com/=https://mirror.example.net/files/, \
+             https://files.example.com/=http://10.0.0.5/,",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0]
+                .apply("https://files.example.com/a/b.png?x=1")
+                .as_deref(),
+            Some("https://mirror.example.net/files/a/b.png?x=1")
+        );
+        assert_eq!(rules[1].apply("https://other.example/b.png"), None);
+        assert_eq!(
+            rules[1].to_string(),
+            "https://files.example.com/=http://10.0.0.5/"
+        );
+
+        assert!("https://files.example.com/".parse::<MirrorRule>().is_err());
+        assert!(
+            "files.example.com=mirror.example.net"
+                .parse::<MirrorRule>()
+                .is_err()
+        );
+        assert!(
+            "https://a.example/=ftp://b.example/"
+                .parse::<MirrorRule>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_hotlink_rules() {
+        let rules = parse_hotlink_rules("cdn.example.com=Referer, *.example.com=never,").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].host.matches("cdn.example.com"));
+        assert_eq!(rules[0].strategy, HotlinkStrategy::Referer);
+        assert_eq!(rules[1].to_string(), "*.example.com=never");
+        assert_eq!("none".parse(), Ok(HotlinkStrategy::Bare));
+        assert_eq!(HotlinkStrategy::Bare.to_string(), "none");
+
+        assert!("cdn.example.com".parse::<HotlinkRule>().is_err());
+        assert!("cdn.example.com=sometimes".parse::<HotlinkRule>().is_err());
+    }
+
+    #[test]
+    fn test_origin_header() {
+        let headers = parse_origin_headers(
+            "media.internal.example=Authorization: Bearer abc, *.internal.example=X-Api-Key:k=1,",
+        )
+        .unwrap();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].host.matches("MEDIA.internal.example"));
+        assert_eq!(headers[0].name, "authorization");
+        assert_eq!(headers[0].value, "Bearer abc");
+        assert!(headers[0].value.is_sensitive());
+        assert_eq!(headers[1].value, "k=1");
+        assert_eq!(
+            headers[0].to_string(),
+            "media.internal.example=authorization: ***"
+        );
+
+        assert!("media.internal.example".parse::<OriginHeader>().is_err());
+        assert!(
+            "media.internal.example=Authorization"
+                .parse::<OriginHeader>()
+                .is_err()
+        );
+        assert!("a.example=Bad Name: x".parse::<OriginHeader>().is_err());
+        assert!("a.example=X-Key: a\nb".parse::<OriginHeader>().is_err());
+        assert!(
+            "https://a.example=X-Key: x"
+                .parse::<OriginHeader>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dns_server() {
+        let server = |s: &str| s.parse::<DnsServer>();
+        assert_eq!(
+            server("1.1.1.1"),
+            Ok(DnsServer::Udp("1.1.1.1:53".parse().unwrap()))
+        );
+        assert_eq!(
+            server("udp://9.9.9.9:5353"),
+            Ok(DnsServer::Udp("9.9.9.9:5353".parse().unwrap()))
+        );
+        assert_eq!(
+            server("[2606:4700::1111]"),
+            Ok(DnsServer::Udp("[2606:4700::1111]:53".parse().unwrap()))
+        );
+        assert_eq!(
+            server("https://1.1.1.1/dns-query").unwrap().to_string(),
+            "https://1.1.1.1/dns-query"
+        );
+        assert!(server("dns.example.com").is_err());
+    }
+
+    #[test]
+    fn test_ip_preference() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:0", "[2606:4700::1111]:0", "1.0.0.1:0"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = |preference: IpPreference| {
+            let mut addrs = addrs.clone();
+            preference.order(&mut addrs);
+            addrs
+                .iter()
+                .map(|addr| addr.ip().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ordered(IpPreference::Auto)[0], "1.1.1.1");
+        assert_eq!(
+            ordered(IpPreference::Ipv6),
+            ["2606:4700::1111", "1.1.1.1", "1.0.0.1"]
+        );
+        assert_eq!(
+            ordered(IpPreference::Ipv4),
+            ["1.1.1.1", "1.0.0.1", "2606:4700::1111"]
+        );
+        assert_eq!("IPv6".parse(), Ok(IpPreference::Ipv6));
+        assert!("ipv5".parse::<IpPreference>().is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use sha2::{Digest, Sha256};
+use url::Url;
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE58: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, PartialEq)]
+struct Cid {
+    codec: u64,
+    hash: u64,
+    digest: Vec<u8>,
+}
+
+pub fn is_ipfs_url(url: &str) -> bool {
+    url.get(..7)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("ipfs://"))
+}
+
+// Path-style, e.g. https://ipfs.io/ipfs/<cid>/<path>. None unless the CID is valid
+pub fn gateway_url(gateway: &Url, url: &str) -> Option<Url> {
+    let url = Url::parse(url).ok()?;
+    let cid = url.host_str()?;
+    parse_cid(cid)?;
+    gateway
+        .join(&format!(
+            "{}/ipfs/{cid}{}",
+            gateway.path().trim_end_matches('/'),
+            url.path()
+        ))
+        .ok()
+}
+
+// Whether the bytes can be checked against the CID: a raw block with a SHA-256 multihash,
+// without a path. Files in UnixFS (dag-pb) are chunked into a DAG that a path gateway doesn't
+// hand out, so only a trusted gateway may serve those
+pub fn is_verifiable(url: &str) -> bool {
+    let Some(url) = Url::parse(url).ok() else {
+        return false;
+    };
+    url.host_str()
+        .and_then(parse_cid)
+        .is_some_and(|cid| cid.codec == RAW && cid.hash == SHA2_256)
+        && matches!(url.path(), "" | "/")
+}
+
+// Whether the bytes are what the CID addresses, never for unverifiable ones
+pub fn verify(url: &str, bytes: &[u8]) -> bool {
+    if !is_verifiable(url) {
+        return false;
+    }
+    let Some(cid) = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().and_then(parse_cid))
+    else {
+        return false;
+    };
+    Sha256::digest(bytes).as_slice() == cid.digest
+}
+
+fn parse_cid(cid: &str) -> Option<Cid> {
+    // CIDv0: a base58btc SHA-256 multihash of a dag-pb node
+    if cid.len() == 46 && cid.starts_with("Qm") {
+        let (hash, digest) = multihash(&base58(cid)?)?;
+        return Some(Cid {
+            codec: DAG_PB,
+            hash,
+            digest,
+        });
+    }
+    // CIDv1, multibase: base32 is what's usual, base58btc is seen too
+    let bytes = match cid.as_bytes().first()? {
+        b'b' => base32(&cid[1..])?,
+        b'B' => base32(&cid[1..].to_ascii_lowercase())?,
+        b'z' => base58(&cid[1..])?,
+        _ => return None,
+    };
+    let (version, rest) = varint(&bytes)?;
+    let (codec, rest) = varint(rest)?;
+    if version != 1 {
+        return None;
+    }
+    let (hash, digest) = multihash(rest)?;
+    Some(Cid {
+        codec,
+        hash,
+        digest,
+    })
+}
+
+fn multihash(bytes: &[u8]) -> Option<(u64, Vec<u8>)> {
+    let (hash, rest) = varint(bytes)?;
+    let (length, digest) = varint(rest)?;
+    (digest.len() as u64 == length && !digest.is_empty()).then(|| (hash, digest.to_vec()))
+}
+
+// Unsigned LEB128, at most 9 bytes for multiformats
+fn varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+// RFC 4648, lowercase without padding
+fn base32(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in input.bytes() {
+        let value = BASE32.iter().position(|&digit| digit == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn base58(input: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new(); // little endian while decoding
+    for c in input.bytes() {
+        let mut carry = BASE58.iter().position(|&digit| digit == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = input.bytes().take_while(|&c| c == b'1').count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "hello world\n" added to IPFS, as a raw leaf and as a UnixFS file
+    const RAW_CID: &str = "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4";
+    const V0_CID: &str = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+
+    #[test]
+    fn test_parse_cid() {
+        let cid = parse_cid(RAW_CID).unwrap();
+        assert_eq!((cid.codec, cid.hash), (RAW, SHA2_256));
+        assert_eq!(cid.digest, Sha256::digest(b"hello world\n").to_vec());
+
+        let cid = parse_cid(V0_CID).unwrap();
+        assert_eq!(
+            (cid.codec, cid.hash, cid.digest.len()),
+            (DAG_PB, SHA2_256, 32)
+        );
+
+        assert_eq!(parse_cid(&RAW_CID.to_ascii_uppercase()), parse_cid(RAW_CID));
+        assert!(parse_cid("bafkrei").is_none()); // truncated
+        assert!(
+            parse_cid("bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4!").is_none()
+        );
+        assert!(parse_cid("QmNotACid").is_none());
+        assert!(parse_cid("").is_none());
+    }
+
+    #[test]
+    fn test_gateway_url() {
+        let gateway = Url::parse("https://ipfs.example/").unwrap();
+        assert_eq!(
+            gateway_url(&gateway, &format!("ipfs://{V0_CID}/a/b.png"))
+                .unwrap()
+                .as_str(),
+            format!("https://ipfs.example/ipfs/{V0_CID}/a/b.png")
+        );
+        assert!(gateway_url(&gateway, "ipfs://not-a-cid/a.png").is_none());
+        assert!(is_ipfs_url("IPFS://x"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let url = format!("ipfs://{RAW_CID}");
+        assert!(verify(&url, b"hello world\n"));
+        assert!(!verify(&url, b"goodbye world\n"));
+        assert!(is_verifiable(&url));
+
+        // Only a trusted gateway may serve these
+        for url in [format!("ipfs://{V0_CID}"), format!("ipfs://{RAW_CID}/path")] {
+            assert!(!is_verifiable(&url));
+            assert!(!verify(&url, b"anything"));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::guard::MutexExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+const PRUNE_THRESHOLD: usize = 1024; // hosts remembered before dropping the idle ones
+
+pub struct QueueFull;
+
+struct Slots {
+    semaphore: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Slots {
+    fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    // A free slot at once, or wait in the queue if it isn't full (until the timeout, if any)
+    async fn acquire(
+        &self,
+        queue: usize,
+        timeout: Option<Duration>,
+    ) -> Result<OwnedSemaphorePermit, QueueFull> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= queue {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return Err(QueueFull);
+        }
+        let _waiting = Waiting(&self.waiting);
+        let permit = self.semaphore.clone().acquire_owned();
+        let permit = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, permit)
+                .await
+                .map_err(|_| QueueFull)?,
+            None => permit.await,
+        };
+        Ok(permit.expect("semaphore is never closed"))
+    }
+}
+
+// Concurrent fetches per origin host, so that a burst for one instance doesn't hammer it
+#[derive(Clone, Default)]
+pub struct HostLimiter {
+    hosts: Arc<Mutex<HashMap<String, Arc<Slots>>>>,
+}
+
+impl HostLimiter {
+    // None if unlimited, otherwise hold the permit until the fetch is done
+    pub async fn acquire(
+        &self,
+        host: &str,
+        limit: usize,
+        queue: usize,
+    ) -> Result<Option<OwnedSemaphorePermit>, QueueFull> {
+        if limit == 0 {
+            return Ok(None);
+        }
+        let slots = {
+            let mut hosts = self.hosts.lock_or_recover();
+            if hosts.len() >= PRUNE_THRESHOLD {
+                hosts.retain(|_, slots| {
+                    Arc::strong_count(slots) > 1 || Arc::strong_count(&slots.semaphore) > 1
+                });
+            }
+            hosts
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(Slots::new(limit)))
+                .clone()
+        };
+        if slots.semaphore.available_permits() == 0 {
+            debug!("Too many fetches from {host}, queued");
+        }
+        slots.acquire(queue, None).await.map(Some)
+    }
+}
+
+// Fetches from all origins together, so that thousands of thumbnails requested at once
+// can't exhaust the memory
+#[derive(Clone)]
+pub struct DownloadLimiter {
+    slots: Option<Arc<Slots>>, // None if unlimited
+}
+
+impl DownloadLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            slots: (limit > 0).then(|| Arc::new(Slots::new(limit))),
+        }
+    }
+
+    pub async fn acquire(
+        &self,
+        queue: usize,
+        timeout: Duration,
+    ) -> Result<Option<OwnedSemaphorePermit>, QueueFull> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        if slots.semaphore.available_permits() == 0 {
+            debug!("Too many downloads in progress, queued");
+        }
+        slots.acquire(queue, Some(timeout)).await.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_host_limiter() {
+        let limiter = HostLimiter::default();
+        assert!(
+            limiter
+                .acquire("a.example", 0, 0)
+                .await
+                .ok()
+                .unwrap()
+                .is_none()
+        );
+
+        let first = limiter.acquire("a.example", 1, 1).await.ok().unwrap();
+        assert!(limiter.acquire("b.example", 1, 0).await.is_ok()); // other hosts aren't affected
+
+        let limiter_clone = limiter.clone();
+        let queued =
+            tokio::spawn(async move { limiter_clone.acquire("a.example", 1, 1).await.is_ok() });
+        tokio::task::yield_now().await;
+        while limiter.hosts.lock_or_recover()["a.example"]
+            .waiting
+            .load(Ordering::Relaxed)
+            == 0
+        {
+            tokio::task::yield_now().await;
+        }
+        assert!(limiter.acquire("a.example", 1, 1).await.is_err()); // queue is full
+
+        drop(first);
+        assert!(queued.await.unwrap());
+
+        // Cancelled while queued (e.g. the client went away), its place is given back
+        let held = limiter.acquire("a.example", 1, 1).await.ok().unwrap();
+        let waiting = || {
+            limiter.hosts.lock_or_recover()["a.example"]
+                .waiting
+                .load(Ordering::Relaxed)
+        };
+        let limiter_clone = limiter.clone();
+        let cancelled =
+            tokio::spawn(async move { limiter_clone.acquire("a.example", 1, 1).await.is_ok() });
+        while waiting() == 0 {
+            tokio::task::yield_now().await;
+        }
+        cancelled.abort();
+        assert!(cancelled.await.is_err());
+        assert_eq!(waiting(), 0);
+        drop(held);
+        assert!(limiter.acquire("a.example", 1, 0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_download_limiter() {
+        let unlimited = DownloadLimiter::new(0);
+        assert!(
+            unlimited
+                .acquire(0, Duration::ZERO)
+                .await
+                .ok()
+                .unwrap()
+                .is_none()
+        );
+
+        let limiter = DownloadLimiter::new(1);
+        let first = limiter.acquire(1, Duration::ZERO).await.ok().unwrap();
+        assert!(limiter.acquire(0, Duration::from_secs(1)).await.is_err()); // no queue
+        let timeout = Duration::from_millis(10);
+        assert!(limiter.acquire(1, timeout).await.is_err()); // waited too long
+
+        let limiter_clone = limiter.clone();
+        let queued = tokio::spawn(async move {
+            limiter_clone
+                .acquire(1, Duration::from_secs(10))
+                .await
+                .is_ok()
+        });
+        tokio::task::yield_now().await;
+        drop(first);
+        assert!(queued.await.unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+use crate::guard::RwLockExt;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use url::Url;
+
+pub const MAX_REDIRECTS: usize = 10; // same as reqwest's default policy
+const MAX_ENTRIES: usize = 10_000;
+
+// Learned 301/308 mappings (from -> to), shared across clones and config reloads.
+// Expiry is checked on lookup with the current TTL, so a reload applies to old entries too.
+#[derive(Clone, Default)]
+pub struct RedirectCache {
+    entries: Arc<RwLock<HashMap<String, (String, Instant)>>>,
+}
+
+impl RedirectCache {
+    pub fn record(&self, from: &str, to: &str) {
+        let mut entries = self.entries.write_or_recover();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(from) {
+            // Make room by forgetting the oldest one
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (_, learned_at))| *learned_at)
+                .map(|(from, _)| from.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        debug!("Learned permanent redirect: {from} -> {to}");
+        entries.insert(from.to_string(), (to.to_string(), Instant::now()));
+    }
+
+    pub fn forget(&self, from: &str) {
+        self.entries.write_or_recover().remove(from);
+    }
+
+    pub fn clear(&self) {
+        self.entries.write_or_recover().clear();
+    }
+
+    // Final destination of a chain of known redirects, if any
+    pub fn resolve(&self, url: &str, ttl: Duration) -> Option<String> {
+        if ttl.is_zero() {
+            return None;
+        }
+
+        let entries = self.entries.read_or_recover();
+        let mut visited = vec![url];
+        while let Some((to, learned_at)) = entries.get(*visited.last().unwrap())
+            && learned_at.elapsed() < ttl
+        {
+            if visited.contains(&to.as_str()) || visited.len() > MAX_REDIRECTS {
+                return None; // the origin would fail on this anyway
+            }
+            visited.push(to);
+        }
+        (visited.len() > 1).then(|| visited.last().unwrap().to_string())
+    }
+
+    // Unexpired mappings as (from, to, age), oldest first
+    pub fn list(&self, ttl: Duration) -> Vec<(String, String, Duration)> {
+        let mut list: Vec<_> = self
+            .entries
+            .read_or_recover()
+            .iter()
+            .map(|(from, (to, learned_at))| (from.clone(), to.clone(), learned_at.elapsed()))
+            .filter(|(_, _, age)| *age < ttl)
+            .collect();
+        list.sort_by_key(|(_, _, age)| std::cmp::Reverse(*age));
+        list
+    }
+
+    // Remember it if it's a permanent one
+    pub fn learn(&self, status: StatusCode, from: &Url, to: &Url) {
+        if matches!(
+            status,
+            StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+        ) {
+            self.record(from.as_str(), to.as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_resolve_chain() {
+        let cache = RedirectCache::default();
+        cache.record("http://a/1", "http://b/1");
+        cache.record("http://b/1", "https://c/1");
+        assert_eq!(
+            cache.resolve("http://a/1", TTL),
+            Some("https://c/1".to_string())
+        );
+        assert_eq!(cache.resolve("http://x/1", TTL), None);
+        assert_eq!(cache.resolve("http://a/1", Duration::ZERO), None);
+        assert_eq!(cache.list(TTL).len(), 2);
+
+        cache.forget("http://b/1");
+        assert_eq!(
+            cache.resolve("http://a/1", TTL),
+            Some("http://b/1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_loop_and_expiry() {
+        let cache = RedirectCache::default();
+        cache.record("http://a/1", "http://b/1");
+        cache.record("http://b/1", "http://a/1");
+        assert_eq!(cache.resolve("http://a/1", TTL), None);
+
+        let old = Instant::now() - Duration::from_secs(120);
+        cache.entries.write_or_recover().insert(
+            "http://old/1".to_string(),
+            ("http://new/1".to_string(), old),
+        );
+        assert_eq!(cache.resolve("http://old/1", TTL), None);
+        assert!(
+            cache
+                .list(TTL)
+                .iter()
+                .all(|(from, _, _)| from != "http://old/1")
+        );
+    }
+}
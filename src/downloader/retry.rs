@@ -0,0 +1,81 @@
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(10);
+
+// Retries of transient failures, for flaky instances that are fine on the next try
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,      // retries after the first request, zero to disable
+    pub backoff: Duration,  // before the first retry, doubled for each one after
+    pub deadline: Duration, // no retry would start after this since the first request
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            backoff: DEFAULT_BACKOFF,
+            deadline: DEFAULT_DEADLINE,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential, with half of it random so that replicas don't retry in lockstep
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let full = self.backoff.saturating_mul(1 << attempt.min(16));
+        let half = full / 2;
+        let random = RandomState::new().build_hasher().finish();
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Connection refused / reset and the like, but not timeouts (the budget is already spent)
+pub fn is_transient_error(err: &reqwest::Error) -> bool {
+    (err.is_connect() || err.is_request()) && !err.is_timeout()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(_duration: Duration) {} // no timer without a runtime, retry at once
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+        for (attempt, full) in [(0, 100), (1, 200), (2, 400)] {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(full / 2), "{delay:?}");
+            assert!(delay <= Duration::from_millis(full), "{delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_transient_status() {
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
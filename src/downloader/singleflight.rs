@@ -0,0 +1,158 @@
+use super::{Conditional, DownloadedFile, FileDownloadError};
+use crate::guard::MutexExt;
+use futures_util::FutureExt;
+use futures_util::future::{BoxFuture, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+type Key = (String, Option<String>, Conditional, bool); // url, host, client's headers, images only
+type Download = Shared<BoxFuture<'static, Result<DownloadedFile, FileDownloadError>>>;
+
+// Concurrent requests for the same file (e.g. a viral post) share one download
+#[derive(Clone, Default)]
+pub struct InFlight {
+    downloads: Arc<Mutex<HashMap<Key, Download>>>,
+}
+
+impl InFlight {
+    pub async fn run<F>(&self, key: Key, download: F) -> Result<DownloadedFile, FileDownloadError>
+    where
+        F: Future<Output = Result<DownloadedFile, FileDownloadError>> + Send + 'static,
+    {
+        let shared = {
+            let mut downloads = self.downloads.lock_or_recover();
+            match downloads.get(&key) {
+                Some(shared) => {
+                    debug!("Joining in-flight download: {}", key.0);
+                    shared.clone()
+                }
+                None => {
+                    let done = Done {
+                        key: key.clone(),
+                        downloads: self.downloads.clone(),
+                    };
+                    let shared = async move {
+                        let _done = done; // also if the download panics
+                        download.await
+                    }
+                    .boxed()
+                    .shared();
+                    downloads.insert(key.clone(), shared.clone());
+                    shared
+                }
+            }
+        };
+        let mut waiter = Waiter {
+            key,
+            shared,
+            downloads: self.downloads.clone(),
+        };
+        (&mut waiter.shared).await
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.downloads.lock_or_recover().len()
+    }
+}
+
+// Forgets the download once it's over, so that the next request starts a new one
+struct Done {
+    key: Key,
+    downloads: Arc<Mutex<HashMap<Key, Download>>>,
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.downloads.lock_or_recover().remove(&self.key);
+    }
+}
+
+struct Waiter {
+    key: Key,
+    shared: Download,
+    downloads: Arc<Mutex<HashMap<Key, Download>>>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // The last client gave up before it finished, nobody needs the download anymore
+        let mut downloads = self.downloads.lock_or_recover();
+        if self.shared.strong_count() == Some(2)
+            && downloads
+                .get(&self.key)
+                .is_some_and(|shared| shared.ptr_eq(&self.shared))
+        {
+            downloads.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::{CacheTier, Provenance};
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let key = (
+            "https://example.com/a.png".to_string(),
+            None,
+            Conditional::default(),
+            false,
+        );
+        let download = || {
+            let downloads = downloads.clone();
+            async move {
+                downloads.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(DownloadedFile {
+                    bytes: Bytes::from_static(b"image"),
+                    content_type: None,
+                    filename: ("a.png".to_string(), None),
+                    provenance: Provenance::new(CacheTier::Origin),
+                })
+            }
+        };
+
+        let (first, second) = tokio::join!(
+            in_flight.run(key.clone(), download()),
+            in_flight.run(key.clone(), download()),
+        );
+        assert_eq!(first.ok().unwrap().bytes, second.ok().unwrap().bytes);
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert_eq!(in_flight.len(), 0);
+
+        // Abandoned by every client
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(10),
+            in_flight.run(key.clone(), download()),
+        )
+        .await;
+        assert!(abandoned.is_err());
+        assert_eq!(in_flight.len(), 0);
+
+        // Panicked, the next request downloads again instead of joining it
+        let in_flight_clone = in_flight.clone();
+        let panicked = tokio::spawn({
+            let key = key.clone();
+            async move {
+                in_flight_clone
+                    .run(key, async { panic!("in a download") })
+                    .await
+            }
+        })
+        .await;
+        assert!(panicked.is_err());
+        assert_eq!(in_flight.len(), 0);
+        assert!(in_flight.run(key, download()).await.is_ok());
+        assert_eq!(downloads.load(Ordering::SeqCst), 3);
+    }
+}
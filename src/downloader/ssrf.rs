@@ -0,0 +1,368 @@
+#[cfg(not(target_arch = "wasm32"))]
+use super::dns::Resolver;
+use super::hosts::{DnsOverride, IpNet, IpPreference};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use url::{Host, Url};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::guard::RwLockExt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::RwLock;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+// Long enough for a fetch to connect after the check, short enough to notice DNS changes
+#[cfg(not(target_arch = "wasm32"))]
+const CHECKED_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct BlockedAddress(pub String);
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is a private address", self.0)
+    }
+}
+
+impl Error for BlockedAddress {}
+
+#[derive(Debug)]
+pub struct UnsupportedScheme(pub String);
+
+impl fmt::Display for UnsupportedScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not an allowed scheme", self.0)
+    }
+}
+
+impl Error for UnsupportedScheme {}
+
+// A redirect to a host name that goes through the proxy without being checked yet, to be
+// checked and followed by the downloader, with the hops taken so far
+#[derive(Debug)]
+pub struct UncheckedRedirect(pub Url, pub usize);
+
+impl fmt::Display for UncheckedRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redirected to {}, not checked yet", self.0)
+    }
+}
+
+impl Error for UncheckedRedirect {}
+
+// Only plain web URLs, on the first request and on every redirect hop
+pub fn check_scheme(url: &Url) -> Result<(), UnsupportedScheme> {
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(UnsupportedScheme(scheme.to_string())),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local() // including cloud metadata 169.254.169.254
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0 // "this network"
+        || (a == 100 && (64..128).contains(&b)) // CGNAT, including 100.100.100.200 metadata
+        || (a == 192 && b == 0 && ip.octets()[2] == 0) // IETF protocol assignments
+        || (a == 198 && (b & 0xfe) == 18) // benchmarking
+        || a >= 240 // reserved, including the broadcast address
+}
+
+// IPv4 address embedded by a translator or tunnel, which may deliver to it
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [s0, s1, s2, s3, s4, s5, s6, s7] = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match (s0, s1) {
+        (0x64, 0xff9b) if [s2, s3, s4, s5] == [0; 4] => Some(v4(s6, s7)), // NAT64
+        (0x2002, _) => Some(v4(s1, s2)),                                  // 6to4
+        _ => None,
+    }
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, third, ..] = ip.segments();
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00 // unique local, including fd00:ec2::254 metadata
+        || (first & 0xffc0) == 0xfe80 // link-local
+        || (first & 0xffc0) == 0xfec0 // deprecated site-local
+        || [first, second, third] == [0x64, 0xff9b, 1] // local-use NAT64 64:ff9b:1::/48
+        || embedded_v4(ip).is_some_and(is_private_v4)
+}
+
+// Destinations that shouldn't be reachable from the internet through the proxy
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+// Rejects private destinations, unless allowed on purpose (e.g. internal object storage).
+// Hostnames are checked when resolved, so that redirects and DNS rebinding are covered too.
+// Configured DNS overrides are trusted as is, they're how internal-only origins are reached.
+// Host names fetched through a proxy are resolved by it, so they're checked beforehand
+#[derive(Clone, Default)]
+pub struct SsrfGuard {
+    allowed: Arc<Vec<IpNet>>,
+    overrides: Arc<Vec<DnsOverride>>,
+    preference: IpPreference,
+    #[cfg(not(target_arch = "wasm32"))]
+    checked: Arc<RwLock<HashMap<String, Instant>>>, // host names found allowed, for proxies
+    #[cfg(not(target_arch = "wasm32"))]
+    resolver: Resolver,
+}
+
+impl SsrfGuard {
+    pub fn new(allowed: Vec<IpNet>, overrides: Vec<DnsOverride>) -> Self {
+        Self {
+            allowed: Arc::new(allowed),
+            overrides: Arc::new(overrides),
+            preference: IpPreference::Auto,
+            #[cfg(not(target_arch = "wasm32"))]
+            checked: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            resolver: Resolver::default(),
+        }
+    }
+
+    pub fn with_preference(self, preference: IpPreference) -> Self {
+        Self { preference, ..self }
+    }
+
+    // Checks the answers of this resolver, which is then the one fetches connect with
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_resolver(self, resolver: Resolver) -> Self {
+        Self { resolver, ..self }
+    }
+
+    fn overridden(&self, host: &str) -> Vec<SocketAddr> {
+        self.overrides
+            .iter()
+            .filter(|entry| entry.matches(host))
+            .map(|entry| SocketAddr::new(entry.addr, 0))
+            .collect()
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !is_private(ip) || self.allowed.iter().any(|net| net.contains(ip))
+    }
+
+    // IP literals never reach the resolver, so they're checked here
+    pub fn check_url(&self, url: &Url) -> Result<(), BlockedAddress> {
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if self.is_allowed(ip) {
+            Ok(())
+        } else {
+            Err(BlockedAddress(ip.to_string()))
+        }
+    }
+
+    // Whether the host may be handed to a proxy as is: IP literals are checked by check_url,
+    // overrides are trusted, and .onion names only exist in Tor, which won't reach us
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn is_checked(&self, url: &Url) -> bool {
+        match url.host() {
+            Some(Host::Domain(name)) => {
+                name.ends_with(".onion")
+                    || !self.overridden(name).is_empty()
+                    || self
+                        .checked
+                        .read_or_recover()
+                        .get(name)
+                        .is_some_and(|checked| checked.elapsed() < CHECKED_TTL)
+            }
+            _ => true,
+        }
+    }
+
+    // Resolves the host name like a direct fetch would, and remembers it if allowed. Names that
+    // can't be resolved here are refused too, as there's no telling where the proxy goes
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn check_proxied(&self, url: &Url) -> Result<(), BlockedAddress> {
+        use reqwest::dns::Resolve;
+
+        self.check_url(url)?;
+        if self.is_checked(url) {
+            return Ok(());
+        }
+        let Some(Host::Domain(name)) = url.host() else {
+            return Ok(());
+        };
+        let unresolved = || BlockedAddress(format!("{name} (not resolved)"));
+        let parsed = name.parse().map_err(|_| unresolved())?;
+        if let Err(err) = self.resolve(parsed).await {
+            return match err.downcast::<BlockedAddress>() {
+                Ok(blocked) => Err(*blocked),
+                Err(_) => Err(unresolved()),
+            };
+        }
+        let mut checked = self.checked.write_or_recover();
+        checked.retain(|_, checked| checked.elapsed() < CHECKED_TTL);
+        checked.insert(name.to_string(), Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl reqwest::dns::Resolve for SsrfGuard {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let guard = self.clone();
+        Box::pin(async move {
+            let mut overridden = guard.overridden(name.as_str());
+            guard.preference.order(&mut overridden);
+            if !overridden.is_empty() {
+                return Ok(Box::new(overridden.into_iter()) as reqwest::dns::Addrs);
+            }
+            let addrs: Vec<_> = guard
+                .resolver
+                .lookup(name.as_str())
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+            let mut allowed: Vec<_> = addrs
+                .iter()
+                .copied()
+                .filter(|addr| guard.is_allowed(addr.ip()))
+                .collect();
+            if allowed.is_empty() && !addrs.is_empty() {
+                let blocked = format!("{} ({})", name.as_str(), addrs[0].ip());
+                return Err(Box::new(BlockedAddress(blocked)) as Box<dyn Error + Send + Sync>);
+            }
+            guard.preference.order(&mut allowed);
+            Ok(Box::new(allowed.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// Whether the request failed because of the guard
+pub fn is_blocked(err: &(dyn Error + 'static)) -> bool {
+    caused_by::<BlockedAddress>(err)
+}
+
+// Where the request stopped to have the redirect target checked, and after how many hops
+pub fn unchecked_redirect(err: &(dyn Error + 'static)) -> Option<(Url, usize)> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(UncheckedRedirect(url, hops)) = err.downcast_ref() {
+            return Some((url.clone(), *hops));
+        }
+        source = err.source();
+    }
+    None
+}
+
+// Whether E is somewhere in the chain of sources, e.g. rejected in the redirect policy
+pub fn caused_by<E: Error + 'static>(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<E>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::hosts::{parse_dns_overrides, parse_networks};
+
+    #[test]
+    fn test_is_private() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.5",
+            "172.16.1.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::808:808",
+            "2002:7f00:1::",
+            "2002:a00:5::1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "1.1.1.1",
+            "172.32.0.1",
+            "198.20.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::8.8.8.8",
+            "2002:808:808::1",
+        ] {
+            assert!(!is_private(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_guard() {
+        let guard = SsrfGuard::new(parse_networks("10.1.0.0/16").unwrap(), Vec::new());
+        let check = |url: &str| guard.check_url(&Url::parse(url).unwrap());
+        assert!(check("http://169.254.169.254/latest/meta-data/").is_err());
+        assert!(check("http://[::1]:8080/").is_err());
+        assert!(check("http://10.0.0.5/internal").is_err());
+        assert!(check("http://10.1.0.5/allowed").is_ok());
+        assert!(check("https://example.com/").is_ok()); // checked when resolved
+    }
+
+    #[test]
+    fn test_check_scheme() {
+        let check = |url: &str| check_scheme(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/a.png").is_ok());
+        assert!(check("http://example.com/a.png").is_ok());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check("gopher://127.0.0.1:6379/_INFO").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        use reqwest::dns::Resolve;
+
+        let guard = SsrfGuard::default();
+        let result = guard.resolve("localhost".parse().unwrap()).await;
+        assert!(result.is_err_and(|err| is_blocked(err.as_ref())));
+
+        let guard = SsrfGuard::new(parse_networks("127.0.0.0/8, ::1").unwrap(), Vec::new());
+        assert!(guard.resolve("localhost".parse().unwrap()).await.is_ok());
+
+        let overrides = parse_dns_overrides("media.internal=10.0.0.5").unwrap();
+        let guard = SsrfGuard::new(Vec::new(), overrides);
+        let addrs: Vec<_> = guard
+            .resolve("media.internal".parse().unwrap())
+            .await
+            .ok()
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, vec!["10.0.0.5:0".parse().unwrap()]);
+    }
+}
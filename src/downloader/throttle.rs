@@ -0,0 +1,128 @@
+use crate::guard::MutexExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PRUNE_THRESHOLD: usize = 1024; // hosts remembered before dropping the idle ones
+
+// How fast to pull from origins, for metered VPS. Bursts up to one second of the rate
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthPolicy {
+    pub limit: u64,      // bytes per second from all origins, zero for unlimited
+    pub host_limit: u64, // bytes per second per origin host, zero for unlimited
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: f64, // negative once in debt, i.e. read ahead of the rate
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+    }
+
+    // The bytes are already read, so take them anyway and tell how long to pause for
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Throttle {
+    global: Arc<Mutex<Option<Bucket>>>,
+    hosts: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Throttle {
+    // Pause before reading on, the longer of the global and the host's
+    pub fn delay(&self, host: &str, bytes: usize, policy: &BandwidthPolicy) -> Duration {
+        let now = Instant::now();
+        let mut delay = Duration::ZERO;
+        if policy.limit > 0 {
+            let mut global = self.global.lock_or_recover();
+            let bucket = global.get_or_insert_with(|| Bucket::new(policy.limit, now));
+            if bucket.rate != policy.limit {
+                *bucket = Bucket::new(policy.limit, now); // reconfigured
+            }
+            delay = delay.max(bucket.take(bytes, now));
+        }
+        if policy.host_limit > 0 {
+            let mut hosts = self.hosts.lock_or_recover();
+            if hosts.len() >= PRUNE_THRESHOLD {
+                hosts.retain(|_, bucket| {
+                    bucket.refill(now);
+                    bucket.tokens < bucket.rate as f64
+                });
+            }
+            let bucket = hosts
+                .entry(host.to_string())
+                .or_insert_with(|| Bucket::new(policy.host_limit, now));
+            if bucket.rate != policy.host_limit {
+                *bucket = Bucket::new(policy.host_limit, now);
+            }
+            delay = delay.max(bucket.take(bytes, now));
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1000, now);
+        assert_eq!(bucket.take(1000, now), Duration::ZERO); // one second of burst
+        assert_eq!(bucket.take(500, now), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(0, now + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        bucket.refill(now + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 1000.0); // no more than the burst
+    }
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::default();
+        let unlimited = BandwidthPolicy::default();
+        assert_eq!(
+            throttle.delay("a.example", 1 << 30, &unlimited),
+            Duration::ZERO
+        );
+
+        let policy = BandwidthPolicy {
+            limit: 0,
+            host_limit: 1000,
+        };
+        assert_eq!(throttle.delay("a.example", 1000, &policy), Duration::ZERO);
+        assert!(throttle.delay("a.example", 1000, &policy) > Duration::ZERO);
+        assert_eq!(throttle.delay("b.example", 1000, &policy), Duration::ZERO); // per host
+
+        let policy = BandwidthPolicy {
+            limit: 1000,
+            host_limit: 0,
+        };
+        assert_eq!(throttle.delay("c.example", 1000, &policy), Duration::ZERO);
+        assert!(throttle.delay("d.example", 1000, &policy) > Duration::ZERO); // shared
+    }
+}
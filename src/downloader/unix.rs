@@ -0,0 +1,142 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use url::Url;
+
+// An origin host reached through a Unix socket instead of TCP, e.g. a co-located Misskey
+// backend as `misskey.example.com=/run/misskey/files.sock`
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnixSocket {
+    pub host: String,
+    pub path: PathBuf,
+}
+
+impl UnixSocket {
+    pub fn matches(&self, host: &str) -> bool {
+        host.trim_end_matches('.').eq_ignore_ascii_case(&self.host)
+    }
+}
+
+impl FromStr for UnixSocket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, path) = s
+            .split_once('=')
+            .ok_or(format!("invalid Unix socket, expected host=/path: {s}"))?;
+        let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() || host.contains(['*', '/', ':']) {
+            return Err(format!("invalid Unix socket host: {s}"));
+        }
+        let path = path.trim();
+        if !path.starts_with('/') || path.contains(':') {
+            return Err(format!(
+                "invalid Unix socket path, expected an absolute one: {s}"
+            ));
+        }
+        Ok(Self {
+            host,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl fmt::Display for UnixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.path.display())
+    }
+}
+
+// Comma separated list
+pub fn parse_unix_sockets(input: &str) -> Result<Vec<UnixSocket>, String> {
+    input
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+pub fn is_unix_url(url: &str) -> bool {
+    url.get(..12)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http+unix://"))
+}
+
+// `http+unix:///run/misskey/files.sock:/path` as the http:// URL of the host configured for
+// the socket, which is then fetched through it. None for sockets not configured
+pub fn socket_url(sockets: &[UnixSocket], url: &str) -> Option<String> {
+    let (path, request) = url.get(12..)?.split_once(':')?;
+    if !request.starts_with('/') {
+        return None;
+    }
+    let socket = sockets
+        .iter()
+        .find(|socket| socket.path.as_os_str() == path)?;
+    Some(format!("http://{}{request}", socket.host))
+}
+
+// https:// URLs of a configured host as http:// ones, the socket speaks plain HTTP
+pub fn plain_url(sockets: &[UnixSocket], url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    if url.scheme() != "https" || !sockets.iter().any(|socket| socket.matches(host)) {
+        return None;
+    }
+    url.set_scheme("http").ok()?;
+    url.set_port(None).ok()?;
+    Some(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_url() {
+        let sockets = parse_unix_sockets("Misskey.example.com.=/run/misskey/files.sock,").unwrap();
+        assert_eq!(
+            sockets[0].to_string(),
+            "misskey.example.com=/run/misskey/files.sock"
+        );
+        assert!(sockets[0].matches("MISSKEY.example.com"));
+
+        let url = "http+unix:///run/misskey/files.sock:/files/a.png?x=1";
+        assert!(is_unix_url(url));
+        assert_eq!(
+            socket_url(&sockets, url).as_deref(),
+            Some("http://misskey.example.com/files/a.png?x=1")
+        );
+        // Only the configured sockets
+        assert_eq!(
+            socket_url(
+                &sockets,
+                "http+unix:///var/run/docker.sock:/containers/json"
+            ),
+            None
+        );
+        assert_eq!(
+            socket_url(&sockets, "http+unix:///run/misskey/files.sock"),
+            None
+        );
+        assert!(!is_unix_url("https://misskey.example.com/files/a.png"));
+        assert_eq!(
+            plain_url(&sockets, "https://misskey.example.com/files/a.png").as_deref(),
+            Some("http://misskey.example.com/files/a.png")
+        );
+        assert_eq!(
+            plain_url(&sockets, "https://other.example.com/files/a.png"),
+            None
+        );
+
+        assert!("misskey.example.com".parse::<UnixSocket>().is_err());
+        assert!(
+            "misskey.example.com=files.sock"
+                .parse::<UnixSocket>()
+                .is_err()
+        );
+        assert!(
+            "*.example.com=/run/files.sock"
+                .parse::<UnixSocket>()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,196 @@
+// Names of files shown to people (Content-Disposition, debug dumps), from untrusted
+// input: URLs, headers of origins and query parameters
+
+pub const MAX_BYTES: usize = 200; // below the 255 of most filesystems, room for suffixes
+const MAX_EXTENSION_BYTES: usize = 16; // longer ones are rather part of the name
+
+// Without control characters, path separators and quotes, nor leading and trailing dots
+// and spaces (hidden files, Windows). Shortened to MAX_BYTES, keeping the extension
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '"' | '\\' | '/' => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_matches(|c: char| c.is_whitespace() || c == '.');
+    if name.len() <= MAX_BYTES {
+        return name.to_string();
+    }
+    let extension = extension(name).filter(|extension| extension.len() <= MAX_EXTENSION_BYTES);
+    let suffix = extension.map_or(String::new(), |extension| format!(".{extension}"));
+    let stem = &name[..name.len() - suffix.len()];
+    let mut end = MAX_BYTES - suffix.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{suffix}", stem[..end].trim_end())
+}
+
+// After the last dot, if any
+pub fn extension(name: &str) -> Option<&str> {
+    name.rsplit_once('.')
+        .filter(|(stem, extension)| !stem.is_empty() && !extension.is_empty())
+        .map(|(_, extension)| extension)
+}
+
+// Appended unless the name already ends with it (in any case), so that the name says
+// what the bytes are after converting
+pub fn with_extension(name: &str, extension: &str) -> String {
+    let suffix = format!(".{extension}");
+    let ends_with = name
+        .len()
+        .checked_sub(suffix.len())
+        .and_then(|start| name.get(start..))
+        .is_some_and(|end| end.eq_ignore_ascii_case(&suffix));
+    match ends_with {
+        true => name.to_string(),
+        false => format!("{name}{suffix}"),
+    }
+}
+
+// For the plain filename parameter, which old clients read as Latin-1 at best
+pub fn ascii(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect()
+}
+
+// RFC 5987 ext-value, for the filename* parameter
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::from("UTF-8''");
+    for byte in name.bytes() {
+        if is_attr_char(byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn is_attr_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte)
+}
+
+// Only passed on as is if nothing in it could end the parameter
+fn is_ext_value(value: &str) -> bool {
+    value
+        .split_once('\'')
+        .and_then(|(charset, rest)| Some((charset, rest.split_once('\'')?.1)))
+        .is_some_and(|(charset, encoded)| {
+            charset.bytes().all(is_attr_char)
+                && encoded
+                    .bytes()
+                    .all(|byte| is_attr_char(byte) || byte == b'%')
+        })
+}
+
+// The header value, `inline` or `attachment`. The ASCII name from the URL or the origin,
+// with the origin's encoded one, or encoded here when the name isn't ASCII
+pub fn content_disposition(disposition: &str, filename: &(String, Option<String>)) -> String {
+    let name = sanitize(&filename.0);
+    let mut value = format!("{disposition}; filename=\"{}\"", ascii(&name));
+    match &filename.1 {
+        Some(encoded) if is_ext_value(encoded) => {
+            value.push_str(&format!("; filename*={encoded}"));
+        }
+        _ if !name.is_ascii() => value.push_str(&format!("; filename*={}", encode(&name))),
+        _ => {}
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("image.png"), "image.png");
+        assert_eq!(sanitize("猫 の 写真.webp"), "猫 の 写真.webp");
+        assert_eq!(sanitize("a\r\nb\t\u{7f}c\u{85}.png"), "abc.png");
+        assert_eq!(sanitize("\"quoted\".png"), "_quoted_.png");
+        assert_eq!(sanitize("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize("C:\\Windows\\win.ini"), "C:_Windows_win.ini");
+        assert_eq!(sanitize(" .hidden. "), "hidden");
+        assert_eq!(sanitize("..."), "");
+        assert_eq!(sanitize(""), "");
+    }
+
+    #[test]
+    fn test_sanitize_length() {
+        let long = format!("{}.webp", "a".repeat(300));
+        let sanitized = sanitize(&long);
+        assert_eq!(sanitized.len(), MAX_BYTES);
+        assert!(sanitized.ends_with("a.webp"));
+
+        // Not in the middle of a character
+        let long = format!("{}.png", "猫".repeat(100));
+        let sanitized = sanitize(&long);
+        assert!(sanitized.len() <= MAX_BYTES);
+        assert!(sanitized.ends_with("猫.png"));
+
+        // Too long to be an extension
+        let long = format!("a.{}", "b".repeat(300));
+        assert_eq!(sanitize(&long).len(), MAX_BYTES);
+
+        // Exactly at the limit
+        let exact = "a".repeat(MAX_BYTES);
+        assert_eq!(sanitize(&exact), exact);
+    }
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(extension("a.png"), Some("png"));
+        assert_eq!(extension("a.tar.gz"), Some("gz"));
+        assert_eq!(extension("a"), None);
+        assert_eq!(extension(".png"), None);
+        assert_eq!(extension("a."), None);
+
+        assert_eq!(with_extension("a.webp", "webp"), "a.webp");
+        assert_eq!(with_extension("a.WEBP", "webp"), "a.WEBP");
+        assert_eq!(with_extension("a.gif", "webp"), "a.gif.webp");
+        assert_eq!(with_extension("a", "webp"), "a.webp");
+        assert_eq!(with_extension("", "webp"), ".webp");
+        assert_eq!(with_extension("猫", "webp"), "猫.webp");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(ascii("猫 cat.png"), "_ cat.png");
+        assert_eq!(encode("a b.png"), "UTF-8''a%20b.png");
+        assert_eq!(encode("猫.png"), "UTF-8''%E7%8C%AB.png");
+        assert_eq!(encode("a'b*c%d;e"), "UTF-8''a%27b%2Ac%25d%3Be");
+        assert_eq!(encode("!#$&+-.^_`|~"), "UTF-8''!#$&+-.^_`|~");
+
+        assert!(is_ext_value("UTF-8''a%20b.png"));
+        assert!(is_ext_value("utf-8'en'a.png"));
+        assert!(!is_ext_value("UTF-8''a\"; evil=1"));
+        assert!(!is_ext_value("a.png"));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        let filename =
+            |name: &str, encoded: Option<&str>| (name.to_string(), encoded.map(str::to_string));
+        assert_eq!(
+            content_disposition("inline", &filename("a.png", None)),
+            "inline; filename=\"a.png\""
+        );
+        assert_eq!(
+            content_disposition("attachment", &filename("a b.png", Some("UTF-8''a%20b.png"))),
+            "attachment; filename=\"a b.png\"; filename*=UTF-8''a%20b.png"
+        );
+        assert_eq!(
+            content_disposition("inline", &filename("猫\".png", None)),
+            "inline; filename=\"__.png\"; filename*=UTF-8''%E7%8C%AB_.png"
+        );
+        assert_eq!(
+            content_disposition("inline", &filename("a.png", Some("x\"; y"))),
+            "inline; filename=\"a.png\""
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// The maps behind locks are caches and counters that stay usable after a panic elsewhere
+// while one was held (e.g. in a codec). Unwrapping a poisoned lock would instead fail every
+// later request touching it
+pub trait MutexExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub trait RwLockExt<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Removed when dropped unless kept, so that a failed, cancelled or panicking write doesn't
+// leave partial files behind
+pub struct TempFile(Option<PathBuf>);
+
+impl TempFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self(Some(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.as_deref().expect("only taken by keep")
+    }
+
+    // Moved into place (or otherwise taken care of) by the caller
+    pub fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poisoned() {
+        let mutex = Arc::new(Mutex::new(1));
+        let rwlock = Arc::new(RwLock::new(1));
+        let (mutex_clone, rwlock_clone) = (mutex.clone(), rwlock.clone());
+        let panicked = std::thread::spawn(move || {
+            let _mutex = mutex_clone.lock().unwrap();
+            let _rwlock = rwlock_clone.write().unwrap();
+            panic!("while holding the locks");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(mutex.is_poisoned() && rwlock.is_poisoned());
+
+        *mutex.lock_or_recover() += 1;
+        *rwlock.write_or_recover() += 1;
+        assert_eq!(*mutex.lock_or_recover(), 2);
+        assert_eq!(*rwlock.read_or_recover(), 2);
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let dir = std::env::temp_dir().join(format!("media-proxy-guard-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let temp = TempFile::new(dir.join("dropped.tmp"));
+        std::fs::write(temp.path(), b"partial").unwrap();
+        drop(temp);
+        assert!(!dir.join("dropped.tmp").exists());
+
+        // Unwinding out of a write
+        let path = dir.join("panicked.tmp");
+        let panicked = std::panic::catch_unwind(|| {
+            let temp = TempFile::new(path.clone());
+            std::fs::write(temp.path(), b"partial").unwrap();
+            panic!("while writing");
+        });
+        assert!(panicked.is_err());
+        assert!(!path.exists());
+
+        let temp = TempFile::new(dir.join("kept.tmp"));
+        std::fs::write(temp.path(), b"complete").unwrap();
+        std::fs::rename(temp.path(), dir.join("kept")).unwrap();
+        temp.keep();
+        assert!(dir.join("kept").exists());
+
+        TempFile::new(dir.join("never-written.tmp")); // nothing to remove
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,148 +1,1014 @@
+mod canary;
+mod cancel;
+mod candidates;
+mod capture;
+mod codecs;
+mod color;
 mod decode;
 mod download;
 mod encode;
+mod exif;
+mod frames;
+mod info;
+mod presets;
 mod processors;
+mod savings;
+mod thumbhash;
+mod watermark;
 
-use crate::downloader::{DownloadedFile, Downloader};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::downloader::StreamedFile;
+use crate::downloader::{CacheTier, Conditional, DownloadedFile, Downloader, Provenance, is_local};
 use crate::handler::decode::DecodeImageError;
+use crate::quarantine::{Quarantine, sha256_hex};
 use bytes::Bytes;
+use cancel::Cancellation;
+use codecs::{Encoder, Frames};
+use color::color_json;
 use download::DownloadImageError;
+use frames::FirstFrame;
+use futures_util::FutureExt;
 use http::StatusCode;
 use image::ImageFormat;
-use processors::{shrink_inside_vec, shrink_outside_vec};
+use image::metadata::Orientation;
+use info::info_json;
+use processors::{
+    BADGE_SIZE, Crop, Fit, TRANSPARENT, badge, blur_vec, crop_top_vec, crop_vec, fit_vec,
+    flatten_vec, orient_vec, pad_vec, parse_blur, parse_flip, parse_rotate, requested_size,
+    shrink_inside_vec, shrink_outside_vec, shrink_to_pixels_vec, trim_transparent_vec,
+};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use tracing::error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use thumbhash::{thumb_hash, thumb_hash_json};
+use tracing::{error, info, warn};
+use watermark::watermark_vec;
+
+pub use canary::{CanaryReport, canary};
+pub use candidates::candidate_urls;
+pub use capture::{Bundle, CaptureConfig};
+pub use decode::DecodeConfig;
+pub use encode::EncodeConfig;
+pub use presets::Presets;
+pub use processors::parse_color;
+pub use savings::{EncodeMode, Savings, encode_savings};
+pub use watermark::{Watermark, WatermarkPosition};
 
 pub struct ProxyImageResult {
     pub bytes: Bytes,
     pub content_type: String,
     pub filename: (String, Option<String>),
+    pub provenance: Provenance,
+}
+
+#[derive(Clone)]
+pub struct PresetSizes {
+    pub emoji: u32,  // square
+    pub avatar: u32, // square
+    pub static_image: (u32, u32),
+    pub preview: (u32, u32),
+    pub max_resize: (u32, u32), // the most `w` and `h` may ask for
+}
+
+impl Default for PresetSizes {
+    fn default() -> Self {
+        Self {
+            emoji: 128,
+            avatar: 320,
+            static_image: (498, 422),
+            preview: (200, 200),
+            max_resize: (2048, 2048),
+        }
+    }
+}
+
+// What to do with files claiming a raster image type that their bytes are not
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MismatchPolicy {
+    #[default]
+    Passthrough,
+    Reject,      // 502, the origin served something broken (e.g. an HTML error page)
+    Placeholder, // the configured placeholder image
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "passthrough" => Ok(MismatchPolicy::Passthrough),
+            "reject" => Ok(MismatchPolicy::Reject),
+            "placeholder" => Ok(MismatchPolicy::Placeholder),
+            _ => Err(format!("unknown policy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for MismatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MismatchPolicy::Passthrough => write!(f, "passthrough"),
+            MismatchPolicy::Reject => write!(f, "reject"),
+            MismatchPolicy::Placeholder => write!(f, "placeholder"),
+        }
+    }
+}
+
+// Asking for something only an image can give, so other files aren't worth buffering
+const TRANSFORM_PARAMS: [&str; 19] = [
+    "emoji",
+    "avatar",
+    "static",
+    "preview",
+    "badge",
+    "w",
+    "h",
+    "crop",
+    "mp",
+    "trim",
+    "pad",
+    "exif",
+    "thumbhash",
+    "watermark",
+    "progressive",
+    "lossless",
+    "rotate",
+    "flip",
+    "blur",
+];
+
+const COLOR_PATH: &str = "/color.json";
+const INFO_PATH: &str = "/info.json";
+
+// Answered with data about the image rather than the image, 415 for anything else
+fn metadata_only(path: &str, query: &HashMap<String, String>) -> bool {
+    [COLOR_PATH, INFO_PATH].contains(&path)
+        || ["exif", "thumbhash"]
+            .iter()
+            .any(|key| query.contains_key(*key))
+}
+
+// The configured encoding, with progressive JPEGs and interlaced PNGs, or lossless WebP, when
+// asked for
+fn encode_config<'a>(
+    config: &'a ProxyImageConfig,
+    query: &HashMap<String, String>,
+) -> Cow<'a, EncodeConfig> {
+    let progressive = query.contains_key("progressive");
+    let lossless = query.contains_key("lossless");
+    match progressive || lossless {
+        true => Cow::Owned(EncodeConfig {
+            jpeg_progressive: config.encode.jpeg_progressive || progressive,
+            png_interlaced: config.encode.png_interlaced || progressive,
+            webp_lossless: config.encode.webp_lossless || lossless,
+            ..config.encode.clone()
+        }),
+        false => Cow::Borrowed(&config.encode),
+    }
+}
+
+// Blurred for being sensitive, so the original must not be sent instead on any failure
+pub fn hides_original(query: &HashMap<String, String>) -> bool {
+    query.contains_key("blur")
+}
+
+const CLIENT_CLOSED_REQUEST: u16 = 499; // nginx's, for processing the client abandoned
+
+static CONTENT_TYPE_MISMATCHES: AtomicU64 = AtomicU64::new(0);
+
+// Files whose bytes contradicted their content type since start
+pub fn content_type_mismatches() -> u64 {
+    CONTENT_TYPE_MISMATCHES.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Default)]
+pub struct ProxyImageConfig {
+    pub sizes: PresetSizes,
+    pub long_image_ratio: Option<f64>, // crop top of previews taller than width * ratio
+    pub disable_animation: bool,       // always output the first frame only
+    pub disable_passthrough: bool,     // reject files that can't be processed
+    pub disable_svg: bool, // reject SVG files that can't be rendered (they may carry scripts)
+    pub decode: DecodeConfig,
+    pub encode: EncodeConfig,
+    pub quarantine_placeholder: Option<Bytes>, // served for quarantined media, or 451 if not set
+    pub content_type_mismatch: MismatchPolicy,
+    pub mismatch_placeholder: Option<Bytes>,
+    pub exif_gps: bool,                 // include the location in ?exif responses
+    pub stream_passthrough: bool,       // stream ranges and oversize files instead of buffering
+    pub capture: Option<CaptureConfig>, // record failing requests for replaying
+    pub frame_cache_size: u64,          // first frames of animations for ?static=1, zero to disable
+    pub presets: Presets,               // for ?preset=name
+    pub watermark: Option<Watermark>,
 }
 
 pub enum ProxyImageError {
     StatusCodeOnly(StatusCode),
     Redirectable(String),
     BytesOnly(DownloadedFile),
+    RetryAfter(Duration),         // 503, busy for now
+    NotModified(Box<Provenance>), // 304, with the validators of the origin
+}
+
+fn is_svg(file: &DownloadedFile) -> bool {
+    if let Some(ct) = &file.content_type {
+        return ct.starts_with("image/svg");
+    }
+    let head = &file.bytes[..file.bytes.len().min(1024)];
+    String::from_utf8_lossy(head).contains("<svg")
+}
+
+// Return the file as-is, unless it's disabled by config
+fn passthrough(config: &ProxyImageConfig, file: DownloadedFile) -> ProxyImageError {
+    if config.disable_svg && is_svg(&file) {
+        warn!("SVG rejected");
+        ProxyImageError::StatusCodeOnly(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    } else if config.disable_passthrough {
+        warn!("Passthrough rejected");
+        ProxyImageError::StatusCodeOnly(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    } else {
+        ProxyImageError::BytesOnly(file)
+    }
+}
+
+fn placeholder(bytes: &Bytes, name: &str) -> ProxyImageError {
+    let format = image::guess_format(bytes).ok();
+    ProxyImageError::BytesOnly(DownloadedFile {
+        bytes: bytes.clone(),
+        content_type: format.map(|format| format.to_mime_type().to_string()),
+        filename: (
+            format!(
+                "{name}.{}",
+                format.map_or("bin", |format| format.extensions_str()[0])
+            ),
+            None,
+        ),
+        provenance: Provenance::new(CacheTier::Placeholder),
+    })
+}
+
+// Serve the placeholder instead of moderated media
+fn quarantined(config: &ProxyImageConfig) -> ProxyImageError {
+    match &config.quarantine_placeholder {
+        Some(bytes) => placeholder(bytes, "removed"),
+        None => ProxyImageError::StatusCodeOnly(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS),
+    }
+}
+
+// Claims a raster format we know, but the bytes aren't any image (e.g. an error page)
+fn is_mismatched(file: &DownloadedFile) -> bool {
+    file.content_type
+        .as_deref()
+        .and_then(ImageFormat::from_mime_type)
+        .is_some_and(|format| format != ImageFormat::Tga) // TGA has no magic bytes
+        && image::guess_format(&file.bytes).is_err()
+}
+
+fn mismatched(config: &ProxyImageConfig, file: DownloadedFile) -> ProxyImageError {
+    CONTENT_TYPE_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+    match (config.content_type_mismatch, &config.mismatch_placeholder) {
+        (MismatchPolicy::Reject, _) => ProxyImageError::StatusCodeOnly(StatusCode::BAD_GATEWAY),
+        (MismatchPolicy::Placeholder, Some(bytes)) => placeholder(bytes, "unavailable"),
+        _ => passthrough(config, file),
+    }
+}
+
+fn proxy_error(
+    err: DownloadImageError,
+    not_an_image: impl FnOnce(DownloadedFile) -> ProxyImageError,
+) -> ProxyImageError {
+    match err {
+        DownloadImageError::MissingURL => ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST),
+        DownloadImageError::RecursiveProxy => {
+            ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN)
+        }
+        // Nowhere else to get data: and file: URLs from
+        DownloadImageError::DownloadErrorOversize(url) if is_local(url) => {
+            ProxyImageError::StatusCodeOnly(StatusCode::PAYLOAD_TOO_LARGE)
+        }
+        DownloadImageError::DownloadErrorOversize(url) => {
+            ProxyImageError::Redirectable(url.to_string())
+        }
+        DownloadImageError::DownloadErrorInvalidUrl => {
+            ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST)
+        }
+        DownloadImageError::DownloadErrorBlockedAddress | DownloadImageError::OriginDenied => {
+            ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN)
+        }
+        DownloadImageError::DownloadErrorRedirect
+        | DownloadImageError::DownloadErrorCircuitOpen => {
+            ProxyImageError::StatusCodeOnly(StatusCode::BAD_GATEWAY)
+        }
+        DownloadImageError::DownloadErrorTimeout => {
+            ProxyImageError::StatusCodeOnly(StatusCode::GATEWAY_TIMEOUT)
+        }
+        DownloadImageError::NotModified(provenance) => ProxyImageError::NotModified(provenance),
+        DownloadImageError::DownloadErrorHostBusy => {
+            ProxyImageError::StatusCodeOnly(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        DownloadImageError::DownloadErrorOverloaded(retry_after)
+        | DownloadImageError::DownloadErrorBackingOff(retry_after) => {
+            ProxyImageError::RetryAfter(retry_after)
+        }
+        DownloadImageError::DownloadErrorInvalidStatus(status_code) => {
+            ProxyImageError::StatusCodeOnly(status_code)
+        }
+        DownloadImageError::DownloadErrorRequest => {
+            ProxyImageError::StatusCodeOnly(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        DownloadImageError::NotAnImage(file) => not_an_image(file),
+    }
 }
 
 pub async fn proxy_image(
     downloader: &Downloader,
+    quarantine: &Quarantine,
+    config: &ProxyImageConfig,
     path: &str,
     query: HashMap<String, String>,
     ua: Option<&str>,
+    conditional: &Conditional,
 ) -> Result<ProxyImageResult, ProxyImageError> {
     // Note: these logics come from
     // https://github.com/misskey-dev/misskey/blob/56cc89b/packages/backend/src/server/FileServerService.ts#L293-L479
     // Some of them have been modified to fit our needs.
 
+    let mut query = match query.get("preset") {
+        Some(name) => config.presets.apply(name, query.clone()).ok_or_else(|| {
+            warn!("Unknown preset: {name}");
+            ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST)
+        })?,
+        None => query,
+    };
+
+    let accept = conditional.accept.as_deref(); // for paths without an extension
+
     /**********************************/
     /* Step 1: Download initial image */
     /**********************************/
-    let downloaded_file =
-        download::download_image(downloader, query.get("url"), query.get("host"), ua)
-            .await
-            .map_err(|err| match err {
-                DownloadImageError::MissingURL => {
-                    ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST)
-                }
-                DownloadImageError::RecursiveProxy => {
-                    ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN)
-                }
-                DownloadImageError::DownloadErrorOversize(url) => {
-                    ProxyImageError::Redirectable(url.to_string())
-                }
-                DownloadImageError::DownloadErrorInvalidUrl => {
-                    ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST)
-                }
-                DownloadImageError::DownloadErrorInvalidStatus(status_code) => {
-                    ProxyImageError::StatusCodeOnly(status_code)
+    let candidates = candidate_urls(&query);
+    if let Some(url) = candidates.iter().find(|url| quarantine.contains_url(url)) {
+        info!(target: "audit", "Served placeholder for quarantined url: {url}");
+        return Err(quarantined(config));
+    }
+
+    // Frontends ask for the static variant of an animation they've just shown
+    if query.contains_key("static")
+        && let Some(url) = candidates.first()
+        && let Some(first) = frames::get(url)
+    {
+        if let Some(hash) = quarantine.match_hash(&first.sha256) {
+            info!(target: "audit", "Served placeholder for quarantined sha256 {hash}: {url}");
+            return Err(quarantined(config));
+        }
+        if conditional.matches(&first.provenance) {
+            return Err(ProxyImageError::NotModified(Box::new(first.provenance)));
+        }
+        if let Some(result) = derive_static(config, path, &query, accept, first) {
+            return Ok(result);
+        } // else go the long way, e.g. to pass the original through
+    }
+
+    // Other files are only worth buffering to return them as-is, unless they can be streamed
+    let streaming = config.stream_passthrough && !config.disable_passthrough;
+    let images_only = streaming
+        || metadata_only(path, &query)
+        || TRANSFORM_PARAMS.iter().any(|key| query.contains_key(*key));
+
+    // The next candidate only when this one couldn't be fetched at all
+    let mut urls = candidates.iter();
+    let (url, downloaded) = loop {
+        let url = urls.next();
+        let downloaded = download::download_image(
+            downloader,
+            url,
+            query.get("host"),
+            ua,
+            conditional,
+            images_only,
+        )
+        .await;
+        match downloaded {
+            Err(err) if err.worth_fallback() && urls.len() > 0 => {
+                info!("Trying the next candidate after {url:?}");
+            }
+            downloaded => break (url, downloaded),
+        }
+    };
+    let is_quarantined = |file: &DownloadedFile| {
+        quarantine.match_content(&file.bytes).inspect(|hash| {
+            info!(target: "audit", "Served placeholder for quarantined sha256 {hash}: {url:?}");
+        })
+    };
+    let downloaded_file = downloaded.map_err(|err| {
+        let err = proxy_error(err, |file| match is_quarantined(&file) {
+            Some(_) => quarantined(config),
+            None if metadata_only(path, &query) || hides_original(&query) => {
+                ProxyImageError::StatusCodeOnly(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            }
+            None => passthrough(config, file),
+        });
+        match err {
+            ProxyImageError::Redirectable(_) if hides_original(&query) => {
+                ProxyImageError::StatusCodeOnly(StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            err => err,
+        }
+    })?;
+
+    if is_quarantined(&downloaded_file).is_some() {
+        return Err(quarantined(config));
+    }
+    // What was fetched, for the frames and captures
+    if let Some(url) = url {
+        query.insert("url".to_string(), url.clone());
+    }
+
+    let Some(capture) = &config.capture else {
+        return process_file(config, path, &query, accept, downloaded_file).await;
+    };
+    let file = downloaded_file.clone();
+    let processing = process_file(config, path, &query, accept, downloaded_file);
+    let result = AssertUnwindSafe(processing).catch_unwind().await;
+    match result {
+        Ok(result) => result,
+        Err(panic) => {
+            let bundle = Bundle::new(path, &query, &file, "panicked", capture.size_limit);
+            capture::save(capture, &bundle);
+            panic::resume_unwind(panic) // still counted by soft-fail mode
+        }
+    }
+}
+
+// Run the pipeline on a captured request again, without the network
+pub async fn replay(
+    config: &ProxyImageConfig,
+    bundle: &Bundle,
+) -> Result<ProxyImageResult, ProxyImageError> {
+    let config = ProxyImageConfig {
+        capture: None,
+        ..config.clone()
+    };
+    let mut file = bundle.file();
+    if !download::classify(&mut file) {
+        return Err(
+            match metadata_only(&bundle.path, &bundle.query) || hides_original(&bundle.query) {
+                true => ProxyImageError::StatusCodeOnly(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+                false => passthrough(&config, file),
+            },
+        );
+    }
+    process_file(&config, &bundle.path, &bundle.query, None, file).await
+}
+
+fn capture_failure(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    file: &DownloadedFile,
+    reason: &str,
+) {
+    if let Some(capture) = &config.capture {
+        let bundle = Bundle::new(path, query, file, reason, capture.size_limit);
+        capture::save(capture, &bundle);
+    }
+}
+
+// Everything after the download, on the blocking pool so that the async workers stay free
+// and the request is still dropped when its client disconnects, which abandons the steps left
+#[cfg(not(target_arch = "wasm32"))]
+async fn process_file(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+    downloaded_file: DownloadedFile,
+) -> Result<ProxyImageResult, ProxyImageError> {
+    let cancellation = Cancellation::default();
+    let _cancel_on_drop = cancellation.on_drop();
+    let (config, path, query) = (config.clone(), path.to_string(), query.clone());
+    let accept = accept.map(str::to_string);
+    let processing = tokio::task::spawn_blocking(move || {
+        let accept = accept.as_deref();
+        let file = downloaded_file;
+        let result = process_steps(&config, &path, &query, accept, file, &cancellation);
+        Box::new(result) // the error is too large to move around unboxed
+    });
+    match processing.await {
+        Ok(result) => *result,
+        Err(err) => match err.try_into_panic() {
+            Ok(panic) => panic::resume_unwind(panic), // for the capture and soft-fail mode
+            Err(_) => Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::SERVICE_UNAVAILABLE,
+            )), // shutting down
+        },
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn process_file(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+    downloaded_file: DownloadedFile,
+) -> Result<ProxyImageResult, ProxyImageError> {
+    process_steps(
+        config,
+        path,
+        query,
+        accept,
+        downloaded_file,
+        &Cancellation::default(),
+    )
+}
+
+// Never sent, the client is gone
+fn abandoned() -> ProxyImageError {
+    ProxyImageError::StatusCodeOnly(StatusCode::from_u16(CLIENT_CLOSED_REQUEST).unwrap())
+}
+
+#[allow(clippy::result_large_err)] // same result as process_file, boxed by it across threads
+fn process_steps(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+    downloaded_file: DownloadedFile,
+    cancellation: &Cancellation,
+) -> Result<ProxyImageResult, ProxyImageError> {
+    cancellation.check().map_err(|_| abandoned())?; // while queued for the blocking pool
+    let url = query.get("url");
+
+    // Only the metadata, for gallery-style clients
+    if query.contains_key("exif") {
+        let Some((width, height, raw)) = exif::read_exif(&downloaded_file.bytes) else {
+            return Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ));
+        };
+        let json = exif::exif_json(width, height, raw.as_deref(), config.exif_gps);
+        return Ok(ProxyImageResult {
+            bytes: Bytes::from(json),
+            content_type: "application/json".to_string(),
+            filename: (format!("{}.json", downloaded_file.filename.0), None),
+            provenance: downloaded_file.provenance,
+        });
+    }
+
+    // What the file is, for moderation tools and frontends deciding how to show it
+    if path == INFO_PATH {
+        let content_type = downloaded_file.content_type.as_deref();
+        let json = info_json(&downloaded_file.bytes, content_type, &config.decode);
+        return Ok(ProxyImageResult {
+            bytes: Bytes::from(json),
+            content_type: "application/json".to_string(),
+            filename: (format!("{}.json", downloaded_file.filename.0), None),
+            provenance: downloaded_file.provenance,
+        });
+    }
+
+    /******************************************/
+    /* Step 2: Decode the downloaded image    */
+    /******************************************/
+    let cover = vector_size(config, query);
+    let mut downloaded_image =
+        match decode::decode_image(&downloaded_file.bytes, cover, &config.decode) {
+            Ok(image) => image,
+            Err(err) => {
+                if let DecodeImageError::ImageError(err) = &err {
+                    error!("Failed to decode image: {err}");
+                    let reason = format!("decode failed: {err}");
+                    capture_failure(config, path, query, &downloaded_file, &reason);
+                } // else is unsupported, which has already been reported
+                if is_mismatched(&downloaded_file) {
+                    warn!(
+                        "Content type mismatch ({:?}): {url:?}",
+                        downloaded_file.content_type
+                    );
+                    if matches!(err, DecodeImageError::Unsupported) {
+                        let reason = "content type mismatch";
+                        capture_failure(config, path, query, &downloaded_file, reason);
+                    }
+                    if !hides_original(query) {
+                        return Err(mismatched(config, downloaded_file));
+                    }
                 }
-                DownloadImageError::DownloadErrorRequest => {
-                    ProxyImageError::StatusCodeOnly(StatusCode::INTERNAL_SERVER_ERROR)
+                if metadata_only(path, query) || hides_original(query) {
+                    return Err(ProxyImageError::StatusCodeOnly(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    ));
                 }
-                DownloadImageError::NotAnImage(file) => ProxyImageError::BytesOnly(file),
-            })?;
+                return Err(passthrough(config, downloaded_file));
+            }
+        };
+
+    // Only a placeholder of the first frame, for clients to show while the image loads
+    if let Some(format) = query.get("thumbhash")
+        && let Some((first, _)) = downloaded_image.first()
+    {
+        let hash = thumb_hash(first);
+        let (bytes, content_type, extension) = match format.as_str() {
+            "json" => (
+                thumb_hash_json(&hash, first.width(), first.height()).into_bytes(),
+                "application/json",
+                "json",
+            ),
+            _ => (hash, "application/octet-stream", "thumbhash"),
+        };
+        return Ok(ProxyImageResult {
+            bytes: Bytes::from(bytes),
+            content_type: content_type.to_string(),
+            filename: (format!("{}.{extension}", downloaded_file.filename.0), None),
+            provenance: downloaded_file.provenance,
+        });
+    }
+
+    // Colors of the first frame, for placeholders and theming around the image
+    if path == COLOR_PATH
+        && let Some((first, _)) = downloaded_image.first()
+    {
+        return Ok(ProxyImageResult {
+            bytes: Bytes::from(color_json(first)),
+            content_type: "application/json".to_string(),
+            filename: (format!("{}.json", downloaded_file.filename.0), None),
+            provenance: downloaded_file.provenance,
+        });
+    }
+
+    let metadata = encode::kept_metadata(&downloaded_file.bytes, &config.encode);
+    if config.frame_cache_size > 0
+        && downloaded_image.len() > 1
+        && let Some(url) = url
+    {
+        let first = FirstFrame {
+            frame: downloaded_image[0].clone(),
+            metadata: metadata.clone(),
+            filename: downloaded_file.filename.clone(),
+            provenance: downloaded_file.provenance.clone(),
+            source_bytes: downloaded_file.bytes.len() as u64,
+            sha256: sha256_hex(&downloaded_file.bytes),
+        };
+        frames::insert(url, first, config.frame_cache_size);
+    }
+
+    if config.disable_animation {
+        downloaded_image.truncate(1);
+    }
+    downloaded_image = decode::limit_animation(downloaded_image, &config.decode);
+
+    cancellation.check().map_err(|_| abandoned())?;
+    let (downloaded_image, encoder) = transform(config, path, query, accept, downloaded_image)
+        .map_err(ProxyImageError::StatusCodeOnly)?;
+    cancellation.check().map_err(|_| abandoned())?;
+
+    // Files that can't be decoded (e.g. SVG files over the budget) were returned as-is above,
+    // unless rejected by config
 
     /******************************************/
-    /* Step 2: Decode the downloaded image    */
+    /* Step 4: Encode into target format      */
     /******************************************/
-    let mut downloaded_image = match decode::decode_image(&downloaded_file.bytes) {
-        Ok(image) => image,
-        Err(err) => {
-            if let DecodeImageError::ImageError(err) = err {
-                error!("Failed to decode image: {err}");
-            } // else is unsupported, which has already been reported
-            return Err(ProxyImageError::BytesOnly(downloaded_file));
+    let mode = EncodeMode::of(query);
+    let source_bytes = downloaded_file.bytes.len() as u64;
+    encode::encode_image(
+        downloaded_image,
+        encoder,
+        &metadata,
+        &downloaded_file.filename,
+        downloaded_file.provenance.clone(),
+        &encode_config(config, query),
+    )
+    .inspect(|result| savings::record(mode, source_bytes, result.bytes.len() as u64, url))
+    .map_err(|_| {
+        capture_failure(config, path, query, &downloaded_file, "encode failed");
+        match hides_original(query) {
+            true => ProxyImageError::StatusCodeOnly(StatusCode::INTERNAL_SERVER_ERROR),
+            false => passthrough(config, downloaded_file),
         }
+    })
+}
+
+// Steps 3 and 4 on the first frame kept from an earlier request
+fn derive_static(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+    first: FirstFrame,
+) -> Option<ProxyImageResult> {
+    let (images, encoder) = transform(config, path, query, accept, vec![first.frame]).ok()?;
+    let result = encode::encode_image(
+        images,
+        encoder,
+        &first.metadata,
+        &first.filename,
+        first.provenance,
+        &encode_config(config, query),
+    )
+    .ok()?;
+    let url = query.get("url");
+    savings::record(
+        EncodeMode::of(query),
+        first.source_bytes,
+        result.bytes.len() as u64,
+        url,
+    );
+    Some(result)
+}
+
+// Step 3, into the frames to encode and the encoder for them
+// What vector images are rendered to cover, so that they stay sharp at the size asked for
+// (zero for a free side). Their own size for pixel areas to crop, or when nothing is asked
+fn vector_size(config: &ProxyImageConfig, query: &HashMap<String, String>) -> (u32, u32) {
+    if let Some(Ok(Crop::Area(..))) = query.get("crop").map(|crop| crop.parse::<Crop>()) {
+        return (0, 0);
+    }
+    let sizes = &config.sizes;
+    let preset = if query.contains_key("emoji") {
+        (sizes.emoji, sizes.emoji)
+    } else if query.contains_key("avatar") {
+        (sizes.avatar, sizes.avatar)
+    } else if query.contains_key("static") {
+        sizes.static_image
+    } else if query.contains_key("preview") {
+        sizes.preview
+    } else if query.contains_key("badge") {
+        (BADGE_SIZE, BADGE_SIZE)
+    } else {
+        (0, 0)
+    };
+    let pixels = |key: &str, max: u32| {
+        query
+            .get(key)
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .map_or(0, |pixels| pixels.min(max))
     };
+    let (max_width, max_height) = sizes.max_resize;
+    let size = (
+        preset.0.max(pixels("w", max_width)),
+        preset.1.max(pixels("h", max_height)),
+    );
+    // Rendered before being turned on its side
+    match query.get("rotate").map(|rotate| parse_rotate(rotate)) {
+        Some(Ok(Orientation::Rotate90 | Orientation::Rotate270)) => (size.1, size.0),
+        _ => size,
+    }
+}
 
+fn transform(
+    config: &ProxyImageConfig,
+    path: &str,
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+    mut downloaded_image: Frames,
+) -> Result<(Frames, &'static dyn Encoder), StatusCode> {
     /******************************************/
     /* Step 3: Process the image as requested */
     /******************************************/
 
-    // Check target format
-    let target_format = if path.len() > 1 {
-        // exclude the leading slash
-        ImageFormat::from_extension(
-            Path::new(path)
-                .extension()
-                .and_then(OsStr::to_str)
-                .unwrap_or(""),
+    // Check target format, negotiated once the frames are known if the path has no extension
+    let mut encoder = Path::new(path).extension().map(|extension| {
+        codecs::encoder(extension.to_str().unwrap_or("")).unwrap_or(codecs::default_encoder())
+    });
+
+    // Turned as asked on top of the Exif orientation, before anything that takes a position
+    for (key, parse) in [
+        (
+            "rotate",
+            parse_rotate as fn(&str) -> Result<Orientation, String>,
+        ),
+        ("flip", parse_flip),
+    ] {
+        if let Some(value) = query.get(key) {
+            let orientation = parse(value).map_err(|err| {
+                warn!("{err}");
+                StatusCode::BAD_REQUEST
+            })?;
+            downloaded_image = orient_vec(downloaded_image, orientation);
+        }
+    }
+
+    // Crop transparent borders first, so that the visible part takes the whole size
+    if query.contains_key("trim") {
+        downloaded_image = trim_transparent_vec(downloaded_image);
+    }
+
+    // Letterbox into an exactly sized canvas if requested
+    let pad_color = if query.contains_key("pad") {
+        Some(
+            query
+                .get("bg")
+                .and_then(|bg| parse_color(bg))
+                .unwrap_or(TRANSPARENT),
         )
-        .unwrap_or(ImageFormat::WebP)
     } else {
-        ImageFormat::WebP // No target format specified, use webp as default
+        None
     };
 
+    // Any other size, within the limits. `pad` letterboxes unless told otherwise
+    let fit = match query.get("fit") {
+        Some(fit) => fit.parse::<Fit>().map_err(|err| {
+            warn!("{err}");
+            StatusCode::BAD_REQUEST
+        })?,
+        None if pad_color.is_some() => Fit::Contain,
+        None => Fit::default(),
+    };
+    let resize = requested_size(query.get("w"), query.get("h"), fit, config.sizes.max_resize);
+
+    // Before resizing, a smart crop takes the aspect ratio of the size asked for if any
+    if let Some(crop) = query.get("crop") {
+        let crop = crop.parse::<Crop>().map_err(|err| {
+            warn!("{err}");
+            StatusCode::BAD_REQUEST
+        })?;
+        let aspect = match resize {
+            Some((width, height, _)) if query.contains_key("w") && query.contains_key("h") => {
+                (width, height)
+            }
+            _ => (1, 1), // square
+        };
+        downloaded_image = crop_vec(downloaded_image, crop, aspect);
+    }
+
     // Manipulate image (this may change the target format)
     if query.contains_key("emoji") || query.contains_key("avatar") {
         let target_size = if query.contains_key("emoji") {
-            128
+            config.sizes.emoji
         } else {
-            320
+            config.sizes.avatar
         };
         // Only shrink, not enlarge
-        downloaded_image = shrink_outside_vec(downloaded_image, target_size);
+        if let Some(pad_color) = pad_color {
+            // Fit inside so that the whole image is visible in the canvas
+            downloaded_image = shrink_inside_vec(downloaded_image, target_size, target_size);
+            downloaded_image = pad_vec(downloaded_image, target_size, target_size, pad_color);
+        } else {
+            downloaded_image = shrink_outside_vec(downloaded_image, target_size);
+        }
         if query.contains_key("static") {
             // Prevent animation by only keep the first frame
             downloaded_image.truncate(1);
         }
     } else if query.contains_key("static") {
-        downloaded_image = shrink_inside_vec(downloaded_image, 498, 422);
+        downloaded_image.truncate(1);
+        let (width, height) = config.sizes.static_image;
+        downloaded_image = shrink_inside_vec(downloaded_image, width, height);
+        if let Some(pad_color) = pad_color {
+            downloaded_image = pad_vec(downloaded_image, width, height, pad_color);
+        }
     } else if query.contains_key("preview") {
-        downloaded_image = shrink_inside_vec(downloaded_image, 200, 200);
+        if let Some(ratio) = config.long_image_ratio {
+            // Keep the beginning of long images (e.g. webtoons) readable
+            downloaded_image = crop_top_vec(downloaded_image, ratio);
+        }
+        let (width, height) = config.sizes.preview;
+        downloaded_image = shrink_inside_vec(downloaded_image, width, height);
+        if let Some(pad_color) = pad_color {
+            downloaded_image = pad_vec(downloaded_image, width, height, pad_color);
+        }
     } else if query.contains_key("badge") {
-        // Here's the thing: I'm not sure what this function is for,
-        // and neither can I implement this easily as many advanced operations
-        // (resize with position fit, normalize, flatten, b-w color space, entropy calc)
-        // are involved.
-        // I've tried to let AI to implement, but the result turned out to be not good enough.
-        // This should mean something, but looks not that important for now.
-        // So I'll leave a wrong result here to see if something really breaks.
-        // todo: implement as https://github.com/misskey-dev/misskey/blob/56cc89b/packages/backend/src/server/FileServerService.ts#L386-L415
-        return Err(ProxyImageError::StatusCodeOnly(StatusCode::NOT_IMPLEMENTED));
+        // As https://github.com/misskey-dev/misskey/blob/56cc89b/packages/backend/src/server/FileServerService.ts#L386-L415
+        downloaded_image.truncate(1);
+        downloaded_image = downloaded_image
+            .into_iter()
+            .map(|(image, delay)| Some((badge(image)?, delay)))
+            .collect::<Option<_>>()
+            .ok_or(StatusCode::NOT_FOUND)?; // nothing to show, as Misskey
+        encoder = Some(codecs::badge_encoder());
     };
 
-    // image crate can't process SVG files here,
-    // and it should be returned as-is when decoding fails above.
-    // Rejected type also provided unchanged (I guess).
+    if let Some((width, height, fit)) = resize {
+        let color = pad_color
+            .or_else(|| query.get("bg").and_then(|bg| parse_color(bg)))
+            .unwrap_or(TRANSPARENT);
+        downloaded_image = fit_vec(downloaded_image, width, height, fit, color);
+    }
 
-    /******************************************/
-    /* Step 4: Encode into target format      */
-    /******************************************/
-    encode::encode_image(downloaded_image, target_format, &downloaded_file.filename)
-        .map_err(|_| ProxyImageError::BytesOnly(downloaded_file))
+    // Limit total pixel count (in megapixels), for extreme aspect ratios
+    if let Some(megapixels) = query
+        .get("mp")
+        .and_then(|mp| mp.parse::<f64>().ok())
+        .filter(|mp| *mp > 0.0)
+    {
+        downloaded_image =
+            shrink_to_pixels_vec(downloaded_image, (megapixels * 1_000_000.0) as u64);
+    }
+
+    // At the size sent, so that the sigma is the same whatever the original
+    if let Some(blur) = query.get("blur") {
+        let sigma = parse_blur(blur).map_err(|err| {
+            warn!("{err}");
+            StatusCode::BAD_REQUEST
+        })?;
+        downloaded_image = blur_vec(downloaded_image, sigma);
+    }
+
+    // Branding last, at the size sent. Not on badges, which are masks rather than pictures
+    if let Some(watermark) = &config.watermark
+        && (watermark.always || query.contains_key("watermark"))
+        && !query.contains_key("badge")
+    {
+        downloaded_image = watermark_vec(downloaded_image, watermark);
+    }
+
+    // Onto an opaque `bg` whatever the format, as the background of the page it's shown on
+    if let Some(color) = query
+        .get("bg")
+        .and_then(|bg| parse_color(bg))
+        .filter(|color| color[3] == u8::MAX)
+    {
+        downloaded_image = flatten_vec(downloaded_image, color);
+    }
+
+    let encoder = encoder.unwrap_or_else(|| codecs::negotiate(accept, &downloaded_image));
+    Ok((downloaded_image, encoder))
+}
+
+// Whether the output format depends on the Accept header of the request
+pub fn negotiates_format(path: &str) -> bool {
+    Path::new(path).extension().is_none()
+}
+
+// Passthrough without buffering, so that clients can seek in large media (e.g. videos).
+// Only quarantined URLs apply, the content can't be hashed before it's sent, and never
+// for what's to be blurred
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn stream_media(
+    downloader: &Downloader,
+    quarantine: &Quarantine,
+    config: &ProxyImageConfig,
+    query: &HashMap<String, String>,
+    ua: Option<&str>,
+    range: Option<&str>,
+    if_range: Option<&str>,
+) -> Result<StreamedFile, ProxyImageError> {
+    if config.disable_passthrough || hides_original(query) {
+        warn!("Passthrough rejected");
+        return Err(ProxyImageError::StatusCodeOnly(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ));
+    }
+    // Only the first candidate, the others are for when it can't be fetched at all
+    let candidates = candidate_urls(query);
+    if let Some(url) = candidates.iter().find(|url| quarantine.contains_url(url)) {
+        info!(target: "audit", "Served placeholder for quarantined url: {url}");
+        return Err(quarantined(config));
+    }
+
+    let url = candidates.first();
+    let file = download::stream_media(downloader, url, query.get("host"), ua, range, if_range)
+        .await
+        .map_err(|err| proxy_error(err, |file| passthrough(config, file)))?;
+    if config.disable_svg
+        && file
+            .content_type()
+            .is_some_and(|ct| ct.starts_with("image/svg"))
+    {
+        warn!("SVG rejected");
+        return Err(ProxyImageError::StatusCodeOnly(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ));
+    }
+    Ok(file)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::downloader::DownloaderConfig;
+
+    // A 1x1 opaque PNG, fetched without network access
+    const PNG_DATA_URL: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGP4z8AAAAMBAQDJ/pLvAAAAAElFTkSuQmCC";
+
+    // With the default config and an empty quarantine
+    async fn proxy(
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<ProxyImageResult, ProxyImageError> {
+        let query = query
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        proxy_image(
+            &Downloader::new(DownloaderConfig::default()),
+            &Quarantine::default(),
+            &ProxyImageConfig::default(),
+            path,
+            query,
+            None,
+            &Conditional::default(),
+        )
+        .await
+    }
 
     #[tokio::test]
     async fn test_process_webp() {
-        let downloader = Downloader::new(None);
+        let downloader = Downloader::new(DownloaderConfig::default());
         let query = HashMap::from([
             ("emoji".to_string(), "1".to_string()),
             (
@@ -150,7 +1016,16 @@ mod tests {
                 "https://public.nyaone-object-storage.com/nyaone/7006d5af-fe08-4f50-93ef-0aabd1ec155b.webp".to_string(),
             ),
         ]);
-        let file = proxy_image(&downloader, "image.webp", query, Some("MediaProxyRS@Debug")).await;
+        let file = proxy_image(
+            &downloader,
+            &Quarantine::default(),
+            &ProxyImageConfig::default(),
+            "image.webp",
+            query,
+            Some("MediaProxyRS@Debug"),
+            &Conditional::default(),
+        )
+        .await;
         assert!(file.is_ok());
         if let Ok(image) = file {
             assert!(image.bytes.len() > 0);
@@ -164,7 +1039,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_gif() {
-        let downloader = Downloader::new(None);
+        let downloader = Downloader::new(DownloaderConfig::default());
         let query = HashMap::from([
             ("emoji".to_string(), "1".to_string()),
             (
@@ -172,7 +1047,16 @@ mod tests {
                 "https://public.nyaone-object-storage.com/nyaone/d35b447f-0bfe-4383-97a2-c878557efd90.gif".to_string(),
             ),
         ]);
-        let file = proxy_image(&downloader, "image.webp", query, Some("MediaProxyRS@Debug")).await;
+        let file = proxy_image(
+            &downloader,
+            &Quarantine::default(),
+            &ProxyImageConfig::default(),
+            "image.webp",
+            query,
+            Some("MediaProxyRS@Debug"),
+            &Conditional::default(),
+        )
+        .await;
         assert!(file.is_ok());
         if let Ok(image) = file {
             assert!(image.bytes.len() > 0);
@@ -180,4 +1064,247 @@ mod tests {
             assert_eq!(image.filename, ("yuexia_shy.gif.webp".to_string(), None));
         }
     }
+
+    #[test]
+    fn test_passthrough_policy() {
+        let svg = || DownloadedFile {
+            bytes: Bytes::from_static(b"<?xml version=\"1.0\"?><svg></svg>"),
+            content_type: None,
+            filename: ("image.svg".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+        };
+
+        let config = ProxyImageConfig::default();
+        assert!(matches!(
+            passthrough(&config, svg()),
+            ProxyImageError::BytesOnly(_)
+        ));
+
+        let config = ProxyImageConfig {
+            disable_svg: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            passthrough(&config, svg()),
+            ProxyImageError::StatusCodeOnly(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        ));
+    }
+
+    #[test]
+    fn test_mismatch_policy() {
+        let html = || DownloadedFile {
+            bytes: Bytes::from_static(b"<!DOCTYPE html><html>Not Found</html>"),
+            content_type: Some("image/png".to_string()),
+            filename: ("image.png".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+        };
+        assert!(is_mismatched(&html()));
+        let unknown = DownloadedFile {
+            content_type: Some("image/jxl".to_string()),
+            ..html()
+        };
+        assert!(!is_mismatched(&unknown)); // can't tell without sniffing support
+
+        let config = ProxyImageConfig::default();
+        assert!(matches!(
+            mismatched(&config, html()),
+            ProxyImageError::BytesOnly(file) if file.content_type.as_deref() == Some("image/png")
+        ));
+
+        let config = ProxyImageConfig {
+            content_type_mismatch: MismatchPolicy::Reject,
+            ..Default::default()
+        };
+        assert!(matches!(
+            mismatched(&config, html()),
+            ProxyImageError::StatusCodeOnly(StatusCode::BAD_GATEWAY)
+        ));
+
+        let config = ProxyImageConfig {
+            content_type_mismatch: MismatchPolicy::Placeholder,
+            mismatch_placeholder: Some(Bytes::from_static(b"GIF89a")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            mismatched(&config, html()),
+            ProxyImageError::BytesOnly(file) if file.filename.0 == "unavailable.gif"
+        ));
+        assert!(content_type_mismatches() >= 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_processing() {
+        let png = || DownloadedFile {
+            bytes: Bytes::from_static(include_bytes!("handler/canary.png")),
+            content_type: Some("image/png".to_string()),
+            filename: ("canary.png".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+        };
+        let config = ProxyImageConfig::default();
+        let query = HashMap::from([("emoji".to_string(), "1".to_string())]);
+
+        let result = process_file(&config, "/image.webp", &query, None, png()).await;
+        assert!(result.is_ok_and(|image| image.content_type == "image/webp"));
+
+        // The request was dropped before the blocking pool got to it
+        let cancellation = Cancellation::default();
+        drop(cancellation.on_drop());
+        let result = process_steps(&config, "/image.webp", &query, None, png(), &cancellation);
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(status)) if status.as_u16() == CLIENT_CLOSED_REQUEST
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_url() {
+        let quarantine = Quarantine::default();
+        let url = "https://example.com/removed.png";
+        quarantine
+            .add(crate::quarantine::QuarantineEntry::url(url).unwrap())
+            .await
+            .unwrap();
+        let query = HashMap::from([("url".to_string(), url.to_string())]);
+
+        // Never fetched, so this works offline
+        let result = proxy_image(
+            &Downloader::new(DownloaderConfig::default()),
+            &quarantine,
+            &ProxyImageConfig::default(),
+            "image.webp",
+            query,
+            None,
+            &Conditional::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversize_data_url() {
+        // Nowhere to redirect to
+        let query = HashMap::from([("url".to_string(), PNG_DATA_URL.to_string())]);
+        let result = proxy_image(
+            &Downloader::new(DownloaderConfig {
+                size_limit: 8,
+                ..Default::default()
+            }),
+            &Quarantine::default(),
+            &ProxyImageConfig::default(),
+            "image.webp",
+            query,
+            None,
+            &Conditional::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::PAYLOAD_TOO_LARGE
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_urls() {
+        // The private address is blocked, so this works offline
+        let query = [("url", "http://127.0.0.1/a.png"), ("url2", PNG_DATA_URL)];
+        let result = proxy("image.webp", &query).await;
+        assert!(result.is_ok_and(|image| image.content_type == "image/webp"));
+
+        let url = format!(r#"["http://127.0.0.1/a.png", "{PNG_DATA_URL}"]"#);
+        assert!(proxy("image.webp", &[("url", &url)]).await.is_ok());
+
+        // The last candidate's error when none can be fetched
+        let query = [("url", "not a url"), ("url2", "http://127.0.0.1/a.png")];
+        let result = proxy("image.webp", &query).await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_thumbhash() {
+        let query = [("url", PNG_DATA_URL), ("thumbhash", "1")];
+        let result = proxy("image.webp", &query).await.ok().unwrap();
+        assert_eq!(result.content_type, "application/octet-stream");
+        assert_eq!(result.bytes.len(), 24); // the header, then 37 factors of an opaque square
+        let query = [("url", PNG_DATA_URL), ("thumbhash", "json")];
+        let result = proxy("image.webp", &query).await.ok().unwrap();
+        assert_eq!(result.content_type, "application/json");
+        assert!(result.bytes.starts_with(b"{\"thumbhash\":\""));
+        assert!(result.bytes.ends_with(b"\",\"width\":1,\"height\":1}"));
+    }
+
+    #[tokio::test]
+    async fn test_color() {
+        let result = proxy(COLOR_PATH, &[("url", PNG_DATA_URL)])
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(result.content_type, "application/json");
+        assert!(result.bytes.starts_with(b"{\"dominant\":\"#"));
+        let result = proxy(COLOR_PATH, &[("url", "data:text/plain,hello")]).await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blur() {
+        let query = [("url", PNG_DATA_URL), ("blur", "4")];
+        let result = proxy("/image.png", &query).await.ok().unwrap();
+        assert_eq!(result.content_type, "image/png");
+        let query = [("url", PNG_DATA_URL), ("blur", "lots")];
+        let result = proxy("/image.png", &query).await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(StatusCode::BAD_REQUEST))
+        ));
+
+        // Never the original instead, whatever it is
+        for url in [
+            "data:image/png;base64,iVBORw0KGgo=",
+            "data:text/plain,hello",
+        ] {
+            let result = proxy("/image.png", &[("url", url), ("blur", "4")]).await;
+            assert!(matches!(
+                result,
+                Err(ProxyImageError::StatusCodeOnly(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE
+                ))
+            ));
+        }
+
+        // Nor streamed for a range request, rejected before fetching the private address
+        let query = HashMap::from([
+            ("url".to_string(), "http://127.0.0.1/a.mp4".to_string()),
+            ("blur".to_string(), "4".to_string()),
+        ]);
+        let result = stream_media(
+            &Downloader::new(DownloaderConfig::default()),
+            &Quarantine::default(),
+            &ProxyImageConfig::default(),
+            &query,
+            None,
+            Some("bytes=0-"),
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            ))
+        ));
+    }
 }
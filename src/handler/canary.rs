@@ -0,0 +1,85 @@
+use super::codecs::Metadata;
+use super::processors::shrink_inside_vec;
+use super::{ProxyImageConfig, codecs, decode, encode};
+use crate::downloader::{CacheTier, Provenance};
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+
+// 256x256 with alpha, larger than the emoji size so that it's resized too
+static CANARY_PNG: &[u8] = include_bytes!("canary.png");
+
+// Phases of the pipeline for the embedded image, to watch for slowly creeping latency
+#[derive(Clone, Debug)]
+pub struct CanaryReport {
+    pub decode: Duration,
+    pub process: Duration,
+    pub encode: Duration,
+    pub output_bytes: usize,
+}
+
+impl CanaryReport {
+    pub fn total(&self) -> Duration {
+        self.decode + self.process + self.encode
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "decode_us": self.decode.as_micros() as u64,
+            "process_us": self.process.as_micros() as u64,
+            "encode_us": self.encode.as_micros() as u64,
+            "total_us": self.total().as_micros() as u64,
+            "output_bytes": self.output_bytes,
+        })
+        .to_string()
+    }
+}
+
+// Same steps as an emoji request, without the network
+pub fn canary(config: &ProxyImageConfig) -> Result<CanaryReport, String> {
+    let bytes = Bytes::from_static(CANARY_PNG);
+
+    let started = Instant::now();
+    let images = decode::decode_image(&bytes, (0, 0), &config.decode)
+        .map_err(|_| "decode failed".to_string())?;
+    let decode = started.elapsed();
+
+    let started = Instant::now();
+    let size = config.sizes.emoji;
+    let images = shrink_inside_vec(images, size, size);
+    let process = started.elapsed();
+
+    let started = Instant::now();
+    let result = encode::encode_image(
+        images,
+        codecs::default_encoder(),
+        &Metadata::default(),
+        &("canary.png".to_string(), None),
+        Provenance::new(CacheTier::Placeholder),
+        &config.encode,
+    )
+    .map_err(|_| "encode failed".to_string())?;
+    let encode = started.elapsed();
+
+    Ok(CanaryReport {
+        decode,
+        process,
+        encode,
+        output_bytes: result.bytes.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canary() {
+        let report = canary(&ProxyImageConfig::default()).unwrap();
+        assert!(report.output_bytes > 0);
+        assert_eq!(
+            report.total(),
+            report.decode + report.process + report.encode
+        );
+        assert!(report.to_json().starts_with("{\"decode_us\":"));
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once the request waiting for the processing is dropped (e.g. the client disconnected),
+// checked between the steps running on the blocking pool
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+pub struct Cancelled;
+
+impl Cancellation {
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.0.load(Ordering::Relaxed) {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    // Cancels when dropped, harmless once the processing is done
+    pub fn on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+pub struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        (self.0).0.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+const MAX_CANDIDATES: usize = 4; // tried in order, each one a full download attempt
+
+// The URLs to try in order: `url`, then `url2`, `url3`, … or `url` as a JSON array of
+// strings, e.g. the remote original followed by the copy cached by the instance
+pub fn candidate_urls(query: &HashMap<String, String>) -> Vec<String> {
+    let Some(url) = query.get("url") else {
+        return Vec::new();
+    };
+    let mut urls = match url.trim_start().starts_with('[') {
+        true => json_strings(url).unwrap_or_else(|| vec![url.clone()]),
+        false => vec![url.clone()],
+    };
+    urls.extend((2..=MAX_CANDIDATES).map_while(|n| query.get(&format!("url{n}")).cloned()));
+    urls.retain(|url| !url.is_empty());
+    urls.dedup();
+    urls.truncate(MAX_CANDIDATES);
+    urls
+}
+
+// None unless the whole input is an array of strings
+fn json_strings(input: &str) -> Option<Vec<String>> {
+    serde_json::from_str(input).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_candidate_urls() {
+        assert!(candidate_urls(&query(&[])).is_empty());
+        assert_eq!(
+            candidate_urls(&query(&[("url", "https://a.example/1.png")])),
+            ["https://a.example/1.png"]
+        );
+        // url4 is never reached without url3
+        assert_eq!(
+            candidate_urls(&query(&[
+                ("url", "https://a.example/1.png"),
+                ("url2", "https://b.example/1.png"),
+                ("url4", "https://c.example/1.png"),
+            ])),
+            ["https://a.example/1.png", "https://b.example/1.png"]
+        );
+        assert_eq!(
+            candidate_urls(&query(&[(
+                "url",
+                r#" [ "https:\/\/a.example/1.png", "https://b.example/é😀.png" ] "#
+            )])),
+            ["https://a.example/1.png", "https://b.example/é😀.png"]
+        );
+        assert_eq!(
+            candidate_urls(&query(&[
+                (
+                    "url",
+                    r#"["https://a.example/1.png","https://a.example/1.png"]"#
+                ),
+                ("url2", "https://b.example/1.png"),
+                ("url3", "https://c.example/1.png"),
+                ("url4", "https://d.example/1.png"),
+                ("url5", "https://e.example/1.png"),
+            ])),
+            [
+                "https://a.example/1.png",
+                "https://b.example/1.png",
+                "https://c.example/1.png",
+                "https://d.example/1.png",
+            ]
+        );
+        // Not an array of strings, left to fail as a URL
+        assert_eq!(
+            candidate_urls(&query(&[("url", r#"["https://a.example/1.png", 1]"#)])),
+            [r#"["https://a.example/1.png", 1]"#]
+        );
+        assert_eq!(json_strings("[]"), Some(Vec::new()));
+        assert_eq!(
+            json_strings(r#"["\ud83d\ude00\n"]"#),
+            Some(vec!["😀\n".to_string()])
+        );
+        assert_eq!(json_strings(r#"["a"] x"#), None);
+        assert_eq!(json_strings(r#"["a",]"#), None);
+        assert_eq!(json_strings(r#"["\ud83d"]"#), None);
+    }
+}
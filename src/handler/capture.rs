@@ -0,0 +1,298 @@
+use crate::downloader::{CacheTier, DownloadedFile, Provenance};
+use crate::guard::TempFile;
+use crate::quarantine::sha256_hex;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{info, warn};
+use url::form_urlencoded;
+
+const MAGIC: &str = "MEDIA-PROXY-REPLAY 1";
+const DEFAULT_SIZE_LIMIT: u64 = 10_000_000; // 10MB
+const DEFAULT_MAX_FILES: usize = 1000;
+const DEFAULT_MAX_SIZE: u64 = 1_000_000_000; // 1GB
+
+// Where to record failing requests, so that they can be replayed offline
+#[derive(Clone)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    pub size_limit: u64,  // of the origin's bytes kept, the rest is cut off
+    pub max_files: usize, // the oldest bundles are removed beyond either
+    pub max_size: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            size_limit: DEFAULT_SIZE_LIMIT,
+            max_files: DEFAULT_MAX_FILES,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+// Inputs of one request, with the bytes the origin sent.
+// Text headers for reading with less, then a blank line and the bytes
+pub struct Bundle {
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub content_type: Option<String>,
+    pub filename: (String, Option<String>),
+    pub reason: String,
+    pub truncated: bool,
+    pub bytes: Bytes,
+}
+
+fn one_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+impl Bundle {
+    pub fn new(
+        path: &str,
+        query: &HashMap<String, String>,
+        file: &DownloadedFile,
+        reason: &str,
+        size_limit: u64,
+    ) -> Self {
+        let truncated = file.bytes.len() as u64 > size_limit;
+        Self {
+            path: path.to_string(),
+            query: query.clone(),
+            content_type: file.content_type.clone(),
+            filename: file.filename.clone(),
+            reason: reason.to_string(),
+            truncated,
+            bytes: match truncated {
+                true => file.bytes.slice(..size_limit as usize),
+                false => file.bytes.clone(),
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut query: Vec<_> = self.query.iter().collect();
+        query.sort(); // stable output for the same request
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+
+        let mut header = format!("{MAGIC}\n");
+        header.push_str(&format!("path: {}\n", one_line(&self.path)));
+        header.push_str(&format!("query: {query}\n"));
+        if let Some(ct) = &self.content_type {
+            header.push_str(&format!("content-type: {}\n", one_line(ct)));
+        }
+        header.push_str(&format!("filename: {}\n", one_line(&self.filename.0)));
+        if let Some(encoded) = &self.filename.1 {
+            header.push_str(&format!("filename-encoded: {}\n", one_line(encoded)));
+        }
+        header.push_str(&format!("reason: {}\n", one_line(&self.reason)));
+        header.push_str(&format!("truncated: {}\n", self.truncated));
+        header.push_str(&format!("size: {}\n\n", self.bytes.len()));
+
+        let mut bundle = header.into_bytes();
+        bundle.extend_from_slice(&self.bytes);
+        bundle
+    }
+
+    pub fn parse(bundle: &[u8]) -> Result<Self, String> {
+        let end = bundle
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .ok_or("no end of headers")?;
+        let header = std::str::from_utf8(&bundle[..end]).map_err(|_| "invalid headers")?;
+        let mut lines = header.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err("not a replay bundle (or an unsupported version)".to_string());
+        }
+        let fields: HashMap<_, _> = lines.filter_map(|line| line.split_once(": ")).collect();
+        let field = |key: &str| fields.get(key).map(|value| value.to_string());
+
+        let bytes = Bytes::copy_from_slice(&bundle[end + 2..]);
+        if field("size") != Some(bytes.len().to_string()) {
+            return Err("size doesn't match the bytes, is the file cut off?".to_string());
+        }
+        Ok(Self {
+            path: field("path").ok_or("missing path")?,
+            query: form_urlencoded::parse(field("query").unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+            content_type: field("content-type"),
+            filename: (
+                field("filename").ok_or("missing filename")?,
+                field("filename-encoded"),
+            ),
+            reason: field("reason").unwrap_or_default(),
+            truncated: field("truncated").as_deref() == Some("true"),
+            bytes,
+        })
+    }
+
+    // As downloaded, so that it goes through the same pipeline again
+    pub fn file(&self) -> DownloadedFile {
+        DownloadedFile {
+            bytes: self.bytes.clone(),
+            content_type: self.content_type.clone(),
+            filename: self.filename.clone(),
+            provenance: Provenance::new(CacheTier::Origin),
+        }
+    }
+}
+
+// Write the bundle into the capture directory, named after the URL so that the same failure
+// seen again replaces its bundle. Failures are only logged, debugging must not break serving
+pub fn save(config: &CaptureConfig, bundle: &Bundle) {
+    let url = bundle.query.get("url").map_or("", String::as_str);
+    let name = format!("{}.replay", &sha256_hex(url.as_bytes())[..16]);
+    let path = config.dir.join(name);
+    // Never a truncated bundle, e.g. with the disk full
+    let temp = TempFile::new(path.with_extension("tmp"));
+    let result = std::fs::write(temp.path(), bundle.to_bytes())
+        .and_then(|_| std::fs::rename(temp.path(), &path));
+    if result.is_ok() {
+        temp.keep();
+    }
+    match result {
+        Ok(()) => info!(
+            "Captured failing request ({}) to {}",
+            bundle.reason,
+            path.display()
+        ),
+        Err(err) => warn!("Failed to capture request to {}: {err}", path.display()),
+    }
+    if let Err(err) = prune(&config.dir, config.max_files, config.max_size) {
+        warn!(
+            "Failed to prune captures in {}: {err}",
+            config.dir.display()
+        );
+    }
+}
+
+// Remove the oldest bundles until both limits are met, leaving any other file alone
+fn prune(dir: &Path, max_files: usize, max_size: u64) -> std::io::Result<()> {
+    let mut bundles = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "replay")
+        {
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            bundles.push((modified, metadata.len(), path));
+        }
+    }
+    bundles.sort_by_key(|(modified, _, _)| std::cmp::Reverse(*modified)); // newest first
+    let mut total = 0;
+    for (index, (_, len, path)) in bundles.into_iter().enumerate() {
+        total += len;
+        if index >= max_files || total > max_size {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle() {
+        let file = DownloadedFile {
+            bytes: Bytes::from_static(b"\x89PNG\r\n\n\nbroken"),
+            content_type: Some("image/png".to_string()),
+            filename: ("a b.png".to_string(), Some("UTF-8''a%20b.png".to_string())),
+            provenance: Provenance::new(CacheTier::Origin),
+        };
+        let query = HashMap::from([
+            (
+                "url".to_string(),
+                "https://example.com/a b.png?x=1&y".to_string(),
+            ),
+            ("emoji".to_string(), "1".to_string()),
+        ]);
+        let bundle = Bundle::new("/image.webp", &query, &file, "decode failed:\nEOF", 1024);
+        let parsed = Bundle::parse(&bundle.to_bytes()).unwrap();
+        assert_eq!(parsed.path, "/image.webp");
+        assert_eq!(parsed.query, query);
+        assert_eq!(parsed.content_type.as_deref(), Some("image/png"));
+        assert_eq!(parsed.filename, file.filename);
+        assert_eq!(parsed.reason, "decode failed: EOF");
+        assert!(!parsed.truncated);
+        assert_eq!(parsed.bytes, file.bytes);
+
+        let bundle = Bundle::new("/", &query, &file, "panicked", 4);
+        let parsed = Bundle::parse(&bundle.to_bytes()).unwrap();
+        assert!(parsed.truncated);
+        assert_eq!(&parsed.bytes[..], b"\x89PNG");
+
+        let bytes = bundle.to_bytes();
+        assert!(Bundle::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Bundle::parse(b"GIF89a\n\n").is_err());
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = std::env::temp_dir().join(format!("media-proxy-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = CaptureConfig {
+            dir: dir.clone(),
+            max_files: 2,
+            ..Default::default()
+        };
+        let file = DownloadedFile {
+            bytes: Bytes::from_static(b"GIF89a"),
+            content_type: Some("image/gif".to_string()),
+            filename: ("a.gif".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+        };
+        let capture = |config: &CaptureConfig, url: &str| {
+            let query = HashMap::from([("url".to_string(), url.to_string())]);
+            save(config, &Bundle::new("/", &query, &file, "failed", 1024));
+            // Apart, as modification times may be coarse
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        };
+        let bundles = || {
+            let mut names: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+        let name = |url: &str| format!("{}.replay", &sha256_hex(url.as_bytes())[..16]);
+
+        // The same URL failing again replaces its bundle
+        capture(&config, "https://example.com/a.gif");
+        capture(&config, "https://example.com/a.gif");
+        assert_eq!(bundles(), [name("https://example.com/a.gif")]);
+
+        // The oldest ones go beyond the number of files
+        capture(&config, "https://example.com/b.gif");
+        capture(&config, "https://example.com/c.gif");
+        let mut expected = [
+            name("https://example.com/b.gif"),
+            name("https://example.com/c.gif"),
+        ];
+        expected.sort();
+        assert_eq!(bundles(), expected);
+
+        // And beyond the total size
+        let size = std::fs::metadata(dir.join(name("https://example.com/c.gif")))
+            .unwrap()
+            .len();
+        let config = CaptureConfig {
+            max_size: size,
+            ..config
+        };
+        capture(&config, "https://example.com/d.gif");
+        assert_eq!(bundles(), [name("https://example.com/d.gif")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
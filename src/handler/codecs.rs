@@ -0,0 +1,378 @@
+// Formats are self-contained modules, registered here (behind their features if any),
+// so that adding one doesn't touch decoding or encoding dispatch
+mod avif;
+mod gif;
+#[cfg(feature = "heif")]
+mod heif;
+mod jpeg;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod plain;
+mod png;
+mod svg;
+mod webp;
+
+use crate::handler::{DecodeConfig, EncodeConfig};
+use bytes::Bytes;
+use image::metadata::Orientation;
+use image::{Delay, DynamicImage, Frame, ImageEncoder, ImageFormat, ImageResult};
+use std::sync::LazyLock;
+
+// Decoded frames with their delays, a single one for static images
+pub type Frames = Vec<(DynamicImage, Delay)>;
+
+// Everything else in the source is dropped when encoding, only these are written if set
+#[derive(Clone, Default)]
+pub struct Metadata {
+    pub icc_profile: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>, // rebuilt with the copyright only
+}
+
+pub trait Decoder: Sync {
+    // Asked from origins in Accept
+    fn mime_types(&self) -> &'static [&'static str];
+    // Whether the bytes are this format (never trust the content type or the filename)
+    fn sniff(&self, bytes: &[u8]) -> bool;
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames>;
+    // Vector formats are rendered to cover the size (zero for a free side) within the budget
+    fn render(
+        &self,
+        bytes: &Bytes,
+        _cover: (u32, u32),
+        _config: &DecodeConfig,
+    ) -> ImageResult<Frames> {
+        self.decode(bytes)
+    }
+}
+
+pub trait Encoder: Sync {
+    // The first one is used for output filenames
+    fn extensions(&self) -> &'static [&'static str];
+    fn mime_type(&self) -> &'static str;
+    // Transparent parts are flattened onto the background for formats without alpha
+    fn supports_alpha(&self) -> bool {
+        true
+    }
+    fn encode(
+        &self,
+        images: Frames,
+        metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()>;
+}
+
+// Skipping what the format has no place for
+fn with_metadata<E: ImageEncoder>(mut encoder: E, metadata: &Metadata) -> E {
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+    encoder
+}
+
+// Tried in order, before anything the image crate can guess
+static DECODERS: &[&dyn Decoder] = &[
+    &gif::Gif,
+    &png::Png,
+    &webp::WebP,
+    #[cfg(feature = "heif")]
+    &heif::Avif,
+    #[cfg(feature = "heif")]
+    &heif::Heic, // after AVIF, which may have the generic HEIF brands too
+    #[cfg(feature = "pdf")]
+    &pdf::Pdf,
+    &svg::Svg,
+];
+
+// Looked up by extension, before anything the image crate can write as a static image
+static ENCODERS: &[&dyn Encoder] = &[&webp::WebP, &avif::Avif, &gif::Gif, &jpeg::Jpeg, &png::Png];
+
+static PLAIN: LazyLock<Vec<plain::Plain>> =
+    LazyLock::new(|| ImageFormat::all().map(plain::Plain).collect());
+
+pub fn decoder(bytes: &[u8]) -> Option<&'static dyn Decoder> {
+    DECODERS
+        .iter()
+        .copied()
+        .find(|decoder| decoder.sniff(bytes))
+        .or_else(|| plain::Guessed.sniff(bytes).then_some(&plain::Guessed as _))
+}
+
+// Media type of the bytes by their magic, whatever the origin claims. Never SVG, which
+// keeps the type claimed rather than turning text into something browsers run scripts of
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if let Some(decoder) = DECODERS.iter().find(|decoder| decoder.sniff(bytes)) {
+        return decoder
+            .mime_types()
+            .first()
+            .copied()
+            .filter(|mime_type| *mime_type != svg::MIME_TYPE);
+    }
+    // Recognized even if we can't decode them
+    if is_avif(bytes) {
+        return Some("image/avif");
+    }
+    if is_heic(bytes) {
+        return Some("image/heic");
+    }
+    image::guess_format(bytes)
+        .ok()
+        .map(|format| format.to_mime_type())
+}
+
+// ISO BMFF with one of the brands, major or compatible, in the leading ftyp box
+fn has_brand(bytes: &[u8], wanted: &[&[u8; 4]]) -> bool {
+    if bytes.len() < 16 || &bytes[4..8] != b"ftyp" {
+        return false;
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let brands = &bytes[8..size.clamp(16, bytes.len())];
+    brands
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 1) // minor version
+        .any(|(_, brand)| wanted.iter().any(|wanted| brand == *wanted))
+}
+
+fn is_avif(bytes: &[u8]) -> bool {
+    has_brand(bytes, &[b"avif", b"avis"])
+}
+
+// HEVC in HEIF as iPhones write it, or any HEIF that isn't AVIF
+fn is_heic(bytes: &[u8]) -> bool {
+    has_brand(
+        bytes,
+        &[
+            b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+        ],
+    ) && !is_avif(bytes)
+}
+
+// Whether a media type in Accept is something we can process
+pub fn decodable(mime_type: &str) -> bool {
+    DECODERS
+        .iter()
+        .any(|decoder| decoder.mime_types().contains(&mime_type))
+        || ImageFormat::from_mime_type(mime_type).is_some_and(|format| {
+            // "avif" of the image crate only encodes, decoding needs "avif-native"
+            format.reading_enabled() && format != ImageFormat::Avif
+        })
+}
+
+pub fn encoder(extension: &str) -> Option<&'static dyn Encoder> {
+    let matches = |encoder: &&dyn Encoder| {
+        encoder
+            .extensions()
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(extension))
+    };
+    ENCODERS.iter().copied().find(matches).or_else(|| {
+        PLAIN
+            .iter()
+            .map(|plain| plain as &dyn Encoder)
+            .find(matches)
+    })
+}
+
+// When the request doesn't ask for a format
+pub fn default_encoder() -> &'static dyn Encoder {
+    &webp::WebP
+}
+
+// Formats browsers list explicitly in Accept when they render them, unlike PNG and JPEG
+// which go without saying. Not with q=0
+fn accepts(accept: &str, mime_type: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        params
+            .next()
+            .is_some_and(|range| range.eq_ignore_ascii_case(mime_type))
+            && params
+                .filter_map(|param| param.strip_prefix("q="))
+                .all(|q| q.parse::<f32>().map_or(true, |q| q > 0.0))
+    })
+}
+
+// Looked at in place, as this runs on every frame that may be encoded without alpha
+pub fn has_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+    match image {
+        DynamicImage::ImageLumaA8(buffer) => buffer.pixels().any(|pixel| pixel[1] < u8::MAX),
+        DynamicImage::ImageLumaA16(buffer) => buffer.pixels().any(|pixel| pixel[1] < u16::MAX),
+        DynamicImage::ImageRgba8(buffer) => buffer.pixels().any(|pixel| pixel[3] < u8::MAX),
+        DynamicImage::ImageRgba16(buffer) => buffer.pixels().any(|pixel| pixel[3] < u16::MAX),
+        DynamicImage::ImageRgba32F(buffer) => buffer.pixels().any(|pixel| pixel[3] < 1.0),
+        image => image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX),
+    }
+}
+
+// For paths without an extension: AVIF or WebP when the client says it renders them, else
+// what every browser does. WebP for clients not sending Accept, as without negotiation
+pub fn negotiate(accept: Option<&str>, images: &Frames) -> &'static dyn Encoder {
+    let (Some(accept), Some((first, _))) = (accept, images.first()) else {
+        return default_encoder();
+    };
+    let animated = images.len() > 1;
+    if !animated && accepts(accept, "image/avif") {
+        &avif::Avif // static only
+    } else if accepts(accept, "image/webp") {
+        &webp::WebP
+    } else if animated {
+        &gif::Gif
+    } else if has_transparency(first) {
+        &png::Png
+    } else {
+        &jpeg::Jpeg
+    }
+}
+
+// Badges are alpha masks, whatever format the request asks for
+pub fn badge_encoder() -> &'static dyn Encoder {
+    &png::Png
+}
+
+// Vector images scaled to cover the size (zero for a free side), or the size of their own,
+// then shrunk into the pixel budget
+fn render_size(own: (f32, f32), cover: (u32, u32), max_pixels: u64) -> (u32, u32) {
+    let (width, height) = (f64::from(own.0), f64::from(own.1));
+    let mut scale = match cover {
+        (0, 0) => 1.0,
+        (w, h) => (f64::from(w) / width).max(f64::from(h) / height),
+    };
+    let pixels = width * height * scale * scale;
+    if pixels > max_pixels as f64 {
+        scale *= (max_pixels as f64 / pixels).sqrt();
+    }
+    let side = |length: f64| ((length * scale).floor() as u32).max(1);
+    (side(width), side(height))
+}
+
+fn static_image(ori: ImageResult<Orientation>, mut img: DynamicImage) -> ImageResult<Frames> {
+    if let Ok(ori) = ori {
+        img.apply_orientation(ori);
+    }
+    Ok(vec![(img, Delay::from_numer_denom_ms(0, 1))])
+}
+
+fn frames_to_images(ori: ImageResult<Orientation>, frames: Vec<Frame>) -> Frames {
+    let mut images: Frames = Vec::new();
+    for frame in frames {
+        let delay = frame.delay();
+        let mut img = DynamicImage::from(frame.into_buffer());
+        if let Ok(ori) = ori {
+            img.apply_orientation(ori);
+        }
+        images.push((img, delay));
+    }
+    images
+}
+
+#[inline]
+fn images_to_frames(images: Frames) -> Vec<Frame> {
+    images
+        .into_iter()
+        .map(|img| Frame::from_parts(img.0.to_rgba8(), 0, 0, img.1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, LumaA, Rgba, RgbaImage};
+
+    #[test]
+    fn test_registry() {
+        assert_eq!(encoder("JPG").map(|e| e.mime_type()), Some("image/jpeg"));
+        assert_eq!(encoder("webp").map(|e| e.extensions()[0]), Some("webp"));
+        assert_eq!(encoder("bmp").map(|e| e.mime_type()), Some("image/bmp"));
+        assert!(encoder("exe").is_none());
+
+        // Round trip through a registered codec and the fallback
+        let image = DynamicImage::from(RgbaImage::from_pixel(3, 2, Rgba([255, 0, 0, 128])));
+        for extension in ["png", "bmp"] {
+            let mut bytes = Vec::new();
+            let frames = vec![(image.clone(), Delay::from_numer_denom_ms(0, 1))];
+            encoder(extension)
+                .unwrap()
+                .encode(
+                    frames,
+                    &Metadata::default(),
+                    &EncodeConfig::default(),
+                    &mut bytes,
+                )
+                .unwrap();
+            let bytes = Bytes::from(bytes);
+            let decoded = decoder(&bytes).unwrap().decode(&bytes).unwrap();
+            assert_eq!(decoded.len(), 1);
+            assert_eq!((decoded[0].0.width(), decoded[0].0.height()), (3, 2));
+        }
+        assert!(decoder(b"<html></html>").is_none());
+
+        assert_eq!(sniff(b"GIF89a\x01\x00"), Some("image/gif"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\x00\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(
+            sniff(b"\x00\x00\x00\x1cftypmif1\x00\x00\x00\x00mif1avifmiaf"),
+            Some("image/avif")
+        );
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00isom"), None); // MP4
+        assert_eq!(sniff(b"<!DOCTYPE html>"), None);
+
+        assert!(decodable("image/apng"));
+        assert!(decodable("image/jpeg"));
+        assert_eq!(
+            sniff(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic"),
+            Some("image/heic")
+        );
+        assert_eq!(decodable("image/avif"), cfg!(feature = "heif"));
+        assert_eq!(decodable("image/heic"), cfg!(feature = "heif"));
+        assert_eq!(
+            sniff(b"%PDF-1.7\n"),
+            cfg!(feature = "pdf").then_some("application/pdf")
+        );
+        assert!(decodable("image/svg+xml"));
+        assert!(decoder(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>").is_some());
+        assert_eq!(sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), None);
+        assert!(!decodable("text/html"));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let frames = |alpha, count| {
+            let image = DynamicImage::from(RgbaImage::from_pixel(2, 2, Rgba([9, 9, 9, alpha])));
+            vec![(image, Delay::from_numer_denom_ms(0, 1)); count]
+        };
+        let negotiated = |accept, frames: &Frames| negotiate(accept, frames).mime_type();
+        let chrome = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+        let old_safari = "image/png,image/svg+xml,image/*;q=0.8,video/*;q=0.8,*/*;q=0.5";
+
+        assert_eq!(negotiated(Some(chrome), &frames(255, 1)), "image/avif");
+        assert_eq!(negotiated(Some(chrome), &frames(255, 2)), "image/webp");
+        assert_eq!(
+            negotiated(Some("image/avif;q=0, image/WebP"), &frames(255, 1)),
+            "image/webp"
+        );
+        assert_eq!(negotiated(Some(old_safari), &frames(255, 1)), "image/jpeg");
+        assert_eq!(negotiated(Some(old_safari), &frames(128, 1)), "image/png");
+        assert_eq!(negotiated(Some(old_safari), &frames(255, 2)), "image/gif");
+        assert_eq!(negotiated(None, &frames(255, 1)), "image/webp");
+    }
+
+    #[test]
+    fn test_has_transparency() {
+        let opaque = RgbaImage::from_pixel(2, 2, Rgba([9, 9, 9, 255]));
+        assert!(!has_transparency(&DynamicImage::from(opaque.clone())));
+        assert!(!has_transparency(
+            &DynamicImage::from(opaque).to_rgb8().into()
+        ));
+
+        let mut gray = ImageBuffer::from_pixel(2, 2, LumaA([9u16, u16::MAX]));
+        assert!(!has_transparency(&DynamicImage::from(gray.clone())));
+        gray.put_pixel(1, 1, LumaA([9, 0]));
+        assert!(has_transparency(&DynamicImage::from(gray)));
+    }
+}
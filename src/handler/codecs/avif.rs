@@ -0,0 +1,32 @@
+use super::{Encoder, Frames, Metadata, with_metadata};
+use crate::handler::EncodeConfig;
+use image::codecs::avif::AvifEncoder;
+use image::{ImageFormat, ImageResult};
+
+// Only encoded, decoding needs the "avif-native" feature of the image crate (dav1d)
+pub struct Avif;
+
+impl Encoder for Avif {
+    fn extensions(&self) -> &'static [&'static str] {
+        ImageFormat::Avif.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        ImageFormat::Avif.to_mime_type()
+    }
+
+    // First frame only, AVIS isn't written. No ICC profile, which the encoder can't write
+    fn encode(
+        &self,
+        images: Frames,
+        metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        let encoder =
+            AvifEncoder::new_with_speed_quality(out, config.avif_speed, config.avif_quality);
+        images[0]
+            .0
+            .write_with_encoder(with_metadata(encoder, metadata))
+    }
+}
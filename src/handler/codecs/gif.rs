@@ -0,0 +1,172 @@
+use super::{Decoder, Encoder, Frames, Metadata, frames_to_images, images_to_frames};
+use crate::handler::EncodeConfig;
+use bytes::Bytes;
+use color_quant::NeuQuant;
+use image::codecs::gif::{GifDecoder, GifEncoder};
+use image::{AnimationDecoder, ImageDecoder, ImageFormat, ImageResult, Rgba, RgbaImage};
+use std::collections::HashSet;
+use std::io::Cursor;
+
+const MAX_COLORS: usize = 256;
+
+pub struct Gif;
+
+impl Decoder for Gif {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/gif"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+        let ori = decoder.orientation();
+        decoder
+            .into_frames()
+            .collect_frames()
+            .map(|f| frames_to_images(ori, f))
+    }
+}
+
+impl Encoder for Gif {
+    fn extensions(&self) -> &'static [&'static str] {
+        ImageFormat::Gif.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        ImageFormat::Gif.to_mime_type()
+    }
+
+    // No metadata, GIF has no place for either
+    fn encode(
+        &self,
+        images: Frames,
+        _metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        let mut frames = images_to_frames(images);
+        if config.gif_dither {
+            for frame in &mut frames {
+                dither(frame.buffer_mut(), config.gif_speed.into());
+            }
+        }
+        GifEncoder::new_with_speed(out, config.gif_speed.into()).encode_frames(frames)
+    }
+}
+
+// Floyd-Steinberg onto a palette one short of GIF's, leaving room for the transparent color,
+// so that the palette the encoder builds is exact and the dithering stays. Partly
+// transparent pixels are opaque, as the encoder would have them
+fn dither(image: &mut RgbaImage, speed: i32) {
+    let mut colors = HashSet::new();
+    for pixel in image.pixels_mut() {
+        // Transparent pixels are all the one transparent color, whatever their RGB
+        pixel.0 = if pixel[3] == 0 {
+            [0; 4]
+        } else {
+            [pixel[0], pixel[1], pixel[2], 255]
+        };
+        if colors.len() <= MAX_COLORS {
+            colors.insert(pixel.0);
+        }
+    }
+    if colors.len() <= MAX_COLORS {
+        return; // nothing lost to quantization
+    }
+
+    let quant = NeuQuant::new(speed, MAX_COLORS - 1, image.as_raw());
+    let width = image.width() as usize;
+    // Errors to add, times 16, one pixel of padding on either side
+    let mut current = vec![[0i32; 3]; width + 2];
+    let mut next = vec![[0i32; 3]; width + 2];
+    for (x, _, pixel) in image.enumerate_pixels_mut() {
+        let x = x as usize;
+        if x == 0 {
+            current = std::mem::replace(&mut next, vec![[0; 3]; width + 2]);
+        }
+        if pixel[3] == 0 {
+            continue;
+        }
+        let wanted =
+            [0, 1, 2].map(|c| (i32::from(pixel[c]) + current[x + 1][c] / 16).clamp(0, 255));
+        let mut mapped = [wanted[0] as u8, wanted[1] as u8, wanted[2] as u8, 255];
+        quant.map_pixel(&mut mapped);
+        *pixel = Rgba([mapped[0], mapped[1], mapped[2], 255]);
+        for c in 0..3 {
+            let error = wanted[c] - i32::from(mapped[c]);
+            current[x + 2][c] += error * 7;
+            next[x][c] += error * 3;
+            next[x + 1][c] += error * 5;
+            next[x + 2][c] += error;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, DynamicImage};
+
+    #[test]
+    fn test_dither() {
+        // A gradient with far more colors than fit, and a transparent strip
+        let image = RgbaImage::from_fn(256, 32, |x, y| match x {
+            0..4 => Rgba([0, 0, 0, 0]),
+            _ => Rgba([x as u8, (y * 8) as u8, 255 - x as u8, 255]),
+        });
+        let encode = |gif_dither| {
+            let config = EncodeConfig {
+                gif_dither,
+                gif_speed: 10,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            let images = vec![(
+                DynamicImage::from(image.clone()),
+                Delay::from_numer_denom_ms(0, 1),
+            )];
+            Gif.encode(images, &Metadata::default(), &config, &mut out)
+                .unwrap();
+            image::load_from_memory(&out).unwrap().into_rgba8()
+        };
+        // How far 8x8 blocks are from the original on average, as seen from afar
+        let block_error = |encoded: &RgbaImage| {
+            let mut total = 0;
+            for (bx, by) in (4..32).flat_map(|bx| (0..4).map(move |by| (bx, by))) {
+                for c in 0..3 {
+                    let sum = |image: &RgbaImage| -> i64 {
+                        (0..8)
+                            .flat_map(|x| (0..8).map(move |y| (bx * 8 + x, by * 8 + y)))
+                            .map(|(x, y)| i64::from(image.get_pixel(x, y)[c]))
+                            .sum()
+                    };
+                    total += (sum(encoded) - sum(&image)).abs();
+                }
+            }
+            total
+        };
+
+        let banded = encode(false);
+        let dithered = encode(true);
+        assert!(block_error(&dithered) < block_error(&banded));
+        assert_eq!(dithered.get_pixel(2, 10)[3], 0);
+        assert_eq!(dithered.get_pixel(100, 10)[3], 255);
+
+        // As many colors as fit, once transparent pixels of any RGB count as one
+        let original = RgbaImage::from_fn(250, 8, |x, y| match y {
+            0..4 => Rgba([x as u8, 255 - x as u8, (x / 2) as u8, 255]),
+            _ => Rgba([x as u8, (y * 30) as u8, 200, 0]),
+        });
+        let mut image = original.clone();
+        dither(&mut image, 10);
+        for (pixel, original) in image.pixels().zip(original.pixels()) {
+            match original[3] {
+                0 => assert_eq!(pixel.0, [0; 4]),
+                _ => assert_eq!(pixel, original),
+            }
+        }
+    }
+}
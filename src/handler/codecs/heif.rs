@@ -0,0 +1,69 @@
+use super::{Decoder, Frames, is_avif, is_heic, static_image};
+use bytes::Bytes;
+use image::error::{DecodingError, ImageError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+// The primary image only, through libheif, which applies the rotation and mirroring of the
+// file itself (so the EXIF orientation is not applied again)
+fn decode(bytes: &[u8], format: ImageFormatHint) -> ImageResult<Frames> {
+    let error = |err: &dyn ToString| {
+        ImageError::Decoding(DecodingError::new(format.clone(), err.to_string()))
+    };
+    let context = HeifContext::read_from_bytes(bytes).map_err(|err| error(&err))?;
+    let handle = context.primary_image_handle().map_err(|err| error(&err))?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(|err| error(&err))?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or_else(|| error(&"no interleaved RGBA plane"))?;
+
+    // Rows may be padded
+    let row = plane.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row * plane.height as usize);
+    for line in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(line.get(..row).ok_or_else(|| error(&"short row"))?);
+    }
+    let image = RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or_else(|| error(&"truncated image"))?;
+    static_image(
+        Ok(Orientation::NoTransforms),
+        DynamicImage::ImageRgba8(image),
+    )
+}
+
+pub struct Avif;
+
+impl Decoder for Avif {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/avif"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        is_avif(bytes)
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        decode(bytes, ImageFormat::Avif.into())
+    }
+}
+
+// From iPhones mostly
+pub struct Heic;
+
+impl Decoder for Heic {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/heic", "image/heif"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        is_heic(bytes)
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        decode(bytes, ImageFormatHint::Name("HEIC".to_string()))
+    }
+}
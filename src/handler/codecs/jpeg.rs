@@ -0,0 +1,76 @@
+use super::{Encoder, Frames, Metadata, with_metadata};
+use crate::handler::EncodeConfig;
+use image::codecs::jpeg::JpegEncoder;
+use image::error::EncodingError;
+use image::{DynamicImage, ImageError, ImageFormat, ImageResult};
+use jpeg_encoder::ColorType;
+
+// Decoded by the fallback, only the encoder has options
+pub struct Jpeg;
+
+impl Encoder for Jpeg {
+    fn extensions(&self) -> &'static [&'static str] {
+        ImageFormat::Jpeg.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        ImageFormat::Jpeg.to_mime_type()
+    }
+
+    fn supports_alpha(&self) -> bool {
+        false
+    }
+
+    fn encode(
+        &self,
+        images: Frames,
+        metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        if config.jpeg_progressive {
+            return encode_progressive(&images[0].0, metadata, config.jpeg_quality, out);
+        }
+        let encoder = JpegEncoder::new_with_quality(out, config.jpeg_quality);
+        images[0]
+            .0
+            .write_with_encoder(with_metadata(encoder, metadata))
+    }
+}
+
+// The image crate only writes baseline JPEGs
+fn encode_progressive(
+    image: &DynamicImage,
+    metadata: &Metadata,
+    quality: u8,
+    out: &mut Vec<u8>,
+) -> ImageResult<()> {
+    let to_error = |err: jpeg_encoder::EncodingError| {
+        ImageError::Encoding(EncodingError::new(ImageFormat::Jpeg.into(), err))
+    };
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(ImageError::Encoding(EncodingError::new(
+                ImageFormat::Jpeg.into(),
+                "image is too large for JPEG",
+            )));
+        }
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(out, quality);
+    encoder.set_progressive(true);
+    // Skipping what doesn't fit, as for baseline JPEGs
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let _ = encoder.add_icc_profile(icc_profile);
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.add_exif_metadata(exif);
+    }
+    match image {
+        DynamicImage::ImageLuma8(gray) => encoder.encode(gray, width, height, ColorType::Luma),
+        DynamicImage::ImageRgb8(rgb) => encoder.encode(rgb, width, height, ColorType::Rgb),
+        image => encoder.encode(&image.to_rgb8(), width, height, ColorType::Rgb),
+    }
+    .map_err(to_error)
+}
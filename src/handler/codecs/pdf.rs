@@ -0,0 +1,65 @@
+use super::{Decoder, Frames, render_size, static_image};
+use crate::handler::DecodeConfig;
+use bytes::Bytes;
+use image::ImageResult;
+use image::error::{DecodingError, ImageError, ImageFormatHint};
+use image::metadata::Orientation;
+use pdfium_render::prelude::{PdfRenderConfig, Pdfium, Pixels};
+use std::sync::LazyLock;
+use tracing::warn;
+
+// Loaded once, the library isn't linked in
+static PDFIUM: LazyLock<Option<Pdfium>> = LazyLock::new(|| {
+    Pdfium::bind_to_system_library()
+        .inspect_err(|err| warn!("Can't load libpdfium, PDF files are passed through: {err}"))
+        .ok()
+        .map(Pdfium::new)
+});
+
+fn error(message: impl ToString) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("PDF".to_string()),
+        message.to_string(),
+    ))
+}
+
+// The first page, sized like vector images (from its size in points) and within their budget
+fn render(bytes: &[u8], cover: (u32, u32), config: &DecodeConfig) -> ImageResult<Frames> {
+    let pdfium = PDFIUM
+        .as_ref()
+        .ok_or_else(|| error("libpdfium not loaded"))?;
+    let document = pdfium
+        .load_pdf_from_byte_slice(bytes, None)
+        .map_err(error)?;
+    let page = document.pages().get(0).map_err(error)?;
+    let own = (page.width().value, page.height().value);
+    let (width, height) = render_size(own, cover, config.svg_max_pixels);
+    let render = PdfRenderConfig::new().set_target_size(width as Pixels, height as Pixels);
+    let image = page.render_with_config(&render).map_err(error)?.as_image();
+    static_image(Ok(Orientation::NoTransforms), image)
+}
+
+pub struct Pdf;
+
+impl Decoder for Pdf {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/pdf"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        bytes.starts_with(b"%PDF-")
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        render(bytes, (0, 0), &DecodeConfig::default())
+    }
+
+    fn render(
+        &self,
+        bytes: &Bytes,
+        cover: (u32, u32),
+        config: &DecodeConfig,
+    ) -> ImageResult<Frames> {
+        render(bytes, cover, config)
+    }
+}
@@ -0,0 +1,49 @@
+use super::{Decoder, Encoder, Frames, Metadata, static_image};
+use crate::handler::EncodeConfig;
+use bytes::Bytes;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use std::io::Cursor;
+
+// Any static image the image crate can read, for formats without a dedicated decoder
+pub struct Guessed;
+
+impl Decoder for Guessed {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[] // whatever the image crate reads, see decodable
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        image::guess_format(bytes).is_ok()
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        let mut decoder = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()?
+            .into_decoder()?;
+        static_image(decoder.orientation(), DynamicImage::from_decoder(decoder)?)
+    }
+}
+
+// The first frame as the image crate writes the format, with its default options
+pub struct Plain(pub ImageFormat);
+
+impl Encoder for Plain {
+    fn extensions(&self) -> &'static [&'static str] {
+        self.0.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        self.0.to_mime_type()
+    }
+
+    // Without any metadata
+    fn encode(
+        &self,
+        images: Frames,
+        _metadata: &Metadata,
+        _config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        images[0].0.write_to(&mut Cursor::new(out), self.0)
+    }
+}
@@ -0,0 +1,181 @@
+use super::{Decoder, Encoder, Frames, Metadata, frames_to_images, static_image, with_metadata};
+use crate::handler::EncodeConfig;
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngDecoder, PngEncoder};
+use image::error::EncodingError;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult};
+use std::borrow::Cow;
+use std::io::{Cursor, Write};
+
+// Adam7 passes: first column and row, then the steps between the pixels kept
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+pub struct Png;
+
+impl Decoder for Png {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/png", "image/apng"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        bytes.starts_with(b"\x89PNG\r\n\x1a\n")
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        let mut decoder = PngDecoder::new(Cursor::new(bytes))?;
+        let ori = decoder.orientation();
+        if decoder.is_apng()? {
+            decoder
+                .apng()?
+                .into_frames()
+                .collect_frames()
+                .map(|f| frames_to_images(ori, f))
+        } else {
+            static_image(ori, DynamicImage::from_decoder(decoder)?)
+        }
+    }
+}
+
+impl Encoder for Png {
+    fn extensions(&self) -> &'static [&'static str] {
+        ImageFormat::Png.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        ImageFormat::Png.to_mime_type()
+    }
+
+    // First frame only, APNG isn't written
+    fn encode(
+        &self,
+        images: Frames,
+        metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        if config.png_interlaced {
+            return encode_interlaced(&images[0].0, metadata, config.png_compression_level, out);
+        }
+        let encoder = PngEncoder::new_with_quality(
+            out,
+            config
+                .png_compression_level
+                .map_or(CompressionType::default(), CompressionType::Level),
+            FilterType::default(),
+        );
+        images[0]
+            .0
+            .write_with_encoder(with_metadata(encoder, metadata))
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = (
+        (p - i16::from(a)).abs(),
+        (p - i16::from(b)).abs(),
+        (p - i16::from(c)).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// The passes one after another, every row Paeth filtered against the one above in its pass
+fn adam7(data: &[u8], width: u32, height: u32, pixel: usize) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(data.len() + height as usize * 2);
+    for (x0, y0, dx, dy) in ADAM7 {
+        let columns: Vec<usize> = (x0..width)
+            .step_by(dx as usize)
+            .map(|x| x as usize)
+            .collect();
+        let mut above = vec![0; columns.len() * pixel];
+        for y in (y0..height).step_by(dy as usize) {
+            let start = y as usize * width as usize;
+            let row: Vec<u8> = columns
+                .iter()
+                .flat_map(|x| &data[(start + x) * pixel..(start + x + 1) * pixel])
+                .copied()
+                .collect();
+            if row.is_empty() {
+                break;
+            }
+            filtered.push(4);
+            for i in 0..row.len() {
+                let (left, upper_left) = match i >= pixel {
+                    true => (row[i - pixel], above[i - pixel]),
+                    false => (0, 0),
+                };
+                filtered.push(row[i].wrapping_sub(paeth(left, above[i], upper_left)));
+            }
+            above = row;
+        }
+    }
+    filtered
+}
+
+// The image crate only writes non-interlaced PNGs, so the passes are put together here and
+// written as a single IDAT. Always 8 bits per channel
+fn encode_interlaced(
+    image: &DynamicImage,
+    metadata: &Metadata,
+    compression_level: Option<u8>,
+    out: &mut Vec<u8>,
+) -> ImageResult<()> {
+    let to_error = |err: ::png::EncodingError| {
+        ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), err))
+    };
+    let (color_type, pixel, data): (_, _, Cow<[u8]>) = match image {
+        DynamicImage::ImageLuma8(image) => (::png::ColorType::Grayscale, 1, Cow::Borrowed(image)),
+        DynamicImage::ImageLumaA8(image) => {
+            (::png::ColorType::GrayscaleAlpha, 2, Cow::Borrowed(image))
+        }
+        DynamicImage::ImageRgb8(image) => (::png::ColorType::Rgb, 3, Cow::Borrowed(image)),
+        DynamicImage::ImageRgba8(image) => (::png::ColorType::Rgba, 4, Cow::Borrowed(image)),
+        image if image.color().has_alpha() => (
+            ::png::ColorType::Rgba,
+            4,
+            Cow::Owned(image.to_rgba8().into_raw()),
+        ),
+        image => (
+            ::png::ColorType::Rgb,
+            3,
+            Cow::Owned(image.to_rgb8().into_raw()),
+        ),
+    };
+
+    let compression = compression_level.map_or(Compression::fast(), |level| {
+        Compression::new(u32::from(level))
+    });
+    let mut zlib = ZlibEncoder::new(Vec::new(), compression);
+    zlib.write_all(&adam7(&data, image.width(), image.height(), pixel))?;
+    let idat = zlib.finish()?;
+
+    let mut info = ::png::Info::with_size(image.width(), image.height());
+    info.color_type = color_type;
+    info.bit_depth = ::png::BitDepth::Eight;
+    info.interlaced = true;
+    info.icc_profile = metadata.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+    let mut writer = ::png::Encoder::with_info(out, info)
+        .and_then(|encoder| encoder.write_header())
+        .map_err(to_error)?;
+    writer
+        .write_chunk(::png::chunk::IDAT, &idat)
+        .map_err(to_error)?;
+    writer.finish().map_err(to_error)
+}
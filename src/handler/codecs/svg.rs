@@ -0,0 +1,146 @@
+use super::{Decoder, Frames, render_size, static_image};
+use crate::handler::DecodeConfig;
+use bytes::Bytes;
+use image::error::{DecodingError, ImageError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageResult, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{Group, ImageHrefResolver, Node, Options, Tree};
+
+pub const MIME_TYPE: &str = "image/svg+xml";
+
+fn error(message: impl ToString) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("SVG".to_string()),
+        message.to_string(),
+    ))
+}
+
+// Everything rendered, with `<use>` expanded and masks, patterns and the like included
+fn count_nodes(group: &Group) -> usize {
+    group
+        .children()
+        .iter()
+        .map(|node| {
+            let mut count = 1;
+            if let Node::Group(group) = node {
+                count += count_nodes(group);
+            }
+            node.subroots(|subroot| count += count_nodes(subroot));
+            count
+        })
+        .sum()
+}
+
+// Parsed without fonts (text isn't drawn) nor files, only data URIs for embedded images
+fn render(bytes: &[u8], cover: (u32, u32), config: &DecodeConfig) -> ImageResult<Frames> {
+    let options = Options {
+        image_href_resolver: ImageHrefResolver {
+            resolve_string: Box::new(|_, _| None),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let tree = Tree::from_data(bytes, &options).map_err(error)?;
+    let nodes = count_nodes(tree.root());
+    if nodes > config.svg_max_nodes {
+        return Err(error(format!("too complex, {nodes} elements")));
+    }
+
+    let own = (tree.size().width(), tree.size().height());
+    let (width, height) = render_size(own, cover, config.svg_max_pixels);
+    let mut pixmap = Pixmap::new(width, height).ok_or_else(|| error("invalid size"))?;
+    let transform = Transform::from_scale(width as f32 / own.0, height as f32 / own.1);
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let image = RgbaImage::from_raw(width, height, pixels).ok_or_else(|| error("invalid size"))?;
+    static_image(
+        Ok(Orientation::NoTransforms),
+        DynamicImage::ImageRgba8(image),
+    )
+}
+
+// Text that browsers run the scripts of when served as-is
+pub struct Svg;
+
+impl Decoder for Svg {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[MIME_TYPE]
+    }
+
+    // An XML declaration, comments or a doctype may come first
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+        let head = head.trim_start_matches('\u{feff}').trim_start();
+        let prolog = head.starts_with("<?xml")
+            || head.starts_with("<!--")
+            || head
+                .get(..13)
+                .is_some_and(|doctype| doctype.eq_ignore_ascii_case("<!DOCTYPE svg"));
+        (head.starts_with("<svg") || prolog) && head.contains("<svg")
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        render(bytes, (0, 0), &DecodeConfig::default())
+    }
+
+    fn render(
+        &self,
+        bytes: &Bytes,
+        cover: (u32, u32),
+        config: &DecodeConfig,
+    ) -> ImageResult<Frames> {
+        render(bytes, cover, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARES: &[u8] = br##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 20 10" width="20" height="10">
+  <rect id="a" width="10" height="10" fill="#ff0000"/>
+  <use xlink:href="#a" x="10"/>
+  <image href="/etc/passwd" width="20" height="10"/>
+</svg>"##;
+
+    #[test]
+    fn test_render() {
+        assert!(Svg.sniff(SQUARES));
+        assert!(Svg.sniff(b"\xef\xbb\xbf\n<svg></svg>"));
+        assert!(!Svg.sniff(b"<!DOCTYPE html><html><svg></svg></html>"));
+        assert!(!Svg.sniff(b"<html><body><svg></svg></body></html>"));
+
+        let config = DecodeConfig::default();
+        let frames = render(SQUARES, (0, 0), &config).ok().unwrap();
+        let image = frames[0].0.to_rgba8();
+        assert_eq!(image.dimensions(), (20, 10));
+        assert_eq!(image.get_pixel(15, 5).0, [255, 0, 0, 255]);
+
+        // Sharp at the size asked for, within the budget
+        let frames = render(SQUARES, (0, 128), &config).ok().unwrap();
+        assert_eq!(frames[0].0.width(), 256);
+        let config = DecodeConfig {
+            svg_max_pixels: 800,
+            ..Default::default()
+        };
+        let frames = render(SQUARES, (0, 128), &config).ok().unwrap();
+        assert_eq!((frames[0].0.width(), frames[0].0.height()), (40, 20));
+
+        let config = DecodeConfig {
+            svg_max_nodes: 1,
+            ..Default::default()
+        };
+        assert!(render(SQUARES, (0, 0), &config).is_err());
+        assert!(render(b"<svg", (0, 0), &DecodeConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use super::{Decoder, Encoder, Frames, Metadata, frames_to_images, static_image, with_metadata};
+use crate::handler::EncodeConfig;
+use bytes::Bytes;
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageResult};
+use std::io::Cursor;
+
+#[cfg(feature = "anim")]
+use super::images_to_frames;
+#[cfg(feature = "anim")]
+use image::GenericImageView;
+#[cfg(feature = "anim")]
+use image::error::{EncodingError, ImageError};
+#[cfg(feature = "anim")]
+use std::collections::HashSet;
+
+pub struct WebP;
+
+impl Decoder for WebP {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["image/webp"]
+    }
+
+    fn sniff(&self, bytes: &[u8]) -> bool {
+        bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP"
+    }
+
+    fn decode(&self, bytes: &Bytes) -> ImageResult<Frames> {
+        let mut decoder = WebPDecoder::new(Cursor::new(bytes))?;
+        let ori = decoder.orientation();
+        if decoder.has_animation() {
+            decoder
+                .into_frames()
+                .collect_frames()
+                .map(|f| frames_to_images(ori, f))
+        } else {
+            static_image(ori, DynamicImage::from_decoder(decoder)?)
+        }
+    }
+}
+
+// Flat graphics and pixel art, where lossy encoding rings around the edges
+#[cfg(feature = "anim")]
+fn few_colors(images: &Frames, max: usize) -> bool {
+    let mut colors = HashSet::new();
+    for (image, _) in images {
+        for pixel in image.to_rgba8().pixels() {
+            if colors.insert(pixel.0) && colors.len() > max {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(feature = "anim")]
+fn encode_webp(
+    images: Frames,
+    config: &EncodeConfig,
+) -> Result<webp_animation::WebPData, webp_animation::Error> {
+    let dimensions = images[0].0.dimensions();
+    let lossless = config.webp_lossless
+        || (config.webp_lossless_colors > 0
+            && few_colors(&images, config.webp_lossless_colors.into()));
+    let encoding_type = match lossless {
+        true => webp_animation::EncodingType::Lossless,
+        false => webp_animation::EncodingType::Lossy(webp_animation::LossyEncodingConfig {
+            alpha_quality: config.webp_alpha_quality.into(),
+            ..Default::default()
+        }),
+    };
+    let frames = images_to_frames(images);
+
+    let mut encoder = webp_animation::Encoder::new_with_options(
+        dimensions,
+        webp_animation::EncoderOptions {
+            anim_params: webp_animation::AnimParams { loop_count: 0 },
+            allow_mixed: !lossless, // would pick lossy frames where smaller
+            encoding_config: Some(webp_animation::EncodingConfig {
+                encoding_type,
+                quality: config.webp_quality,
+                method: config.webp_method.into(),
+            }),
+            ..Default::default()
+        },
+    )?;
+
+    let mut current_ts = 0;
+    for frame in frames {
+        // Encode one frame
+        encoder.add_frame(frame.buffer(), current_ts)?;
+
+        // Calc the duration (delay)
+        let frame_delay_tuple = frame.delay().numer_denom_ms();
+        let frame_delay = (frame_delay_tuple.0 / frame_delay_tuple.1) as i32;
+        current_ts += frame_delay;
+    }
+
+    encoder.finalize(current_ts)
+}
+
+impl Encoder for WebP {
+    fn extensions(&self) -> &'static [&'static str] {
+        ImageFormat::WebP.extensions_str()
+    }
+
+    fn mime_type(&self) -> &'static str {
+        ImageFormat::WebP.to_mime_type()
+    }
+
+    // Animated with the anim feature, lossy unless asked for or with few colors. Otherwise the first frame, lossless,
+    // as are single frames with metadata to keep: nothing else has a place for it
+    fn encode(
+        &self,
+        images: Frames,
+        metadata: &Metadata,
+        config: &EncodeConfig,
+        out: &mut Vec<u8>,
+    ) -> ImageResult<()> {
+        #[cfg(feature = "anim")]
+        if images.len() > 1 || (metadata.icc_profile.is_none() && metadata.exif.is_none()) {
+            let webp_data = encode_webp(images, config).map_err(|err| {
+                ImageError::Encoding(EncodingError::new(
+                    ImageFormat::WebP.into(),
+                    err.to_string(),
+                ))
+            })?;
+            out.extend_from_slice(&webp_data);
+            return Ok(());
+        }
+
+        let _ = config; // the lossless encoder has no options
+        let encoder = WebPEncoder::new_lossless(out);
+        images[0]
+            .0
+            .write_with_encoder(with_metadata(encoder, metadata))
+    }
+}
@@ -0,0 +1,128 @@
+use image::DynamicImage;
+use serde_json::json;
+
+const SAMPLE_SIZE: u32 = 64; // longest side looked at, plenty for a few colors
+const PALETTE_SIZE: usize = 6;
+const MIN_ALPHA: u8 = 128; // mostly transparent pixels have no color to speak of
+
+// Median cut: the box of colors spanning the widest range is split at its median value until
+// there are enough, each giving its average color. Largest first, with the pixels they cover
+pub fn palette(image: &DynamicImage, size: usize) -> Vec<([u8; 3], usize)> {
+    let sample = match image.width().max(image.height()) > SAMPLE_SIZE {
+        true => image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8(),
+        false => image.to_rgba8(),
+    };
+    let pixels: Vec<[u8; 3]> = sample
+        .pixels()
+        .filter(|pixel| pixel[3] >= MIN_ALPHA)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+
+    // The channel with the widest range, and the range
+    let widest = |colors: &[[u8; 3]]| {
+        (0..3)
+            .map(|channel| {
+                let values = colors.iter().map(|color| color[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (channel, range)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    };
+    let mut boxes = vec![pixels];
+    while boxes.len() < size {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, colors)| (index, widest(colors)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel))
+        else {
+            break; // fewer colors than asked for
+        };
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|color| color[channel]);
+        // Colors equal to the median stay together, on whichever side isn't left empty
+        let median = colors[colors.len() / 2][channel];
+        let split = match colors.partition_point(|color| color[channel] < median) {
+            0 => colors.partition_point(|color| color[channel] <= median),
+            split => split,
+        };
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    let mut palette: Vec<([u8; 3], usize)> = boxes
+        .iter()
+        .map(|colors| {
+            let sum = colors.iter().fold([0u64; 3], |sum, color| {
+                [0, 1, 2].map(|channel| sum[channel] + u64::from(color[channel]))
+            });
+            let count = colors.len() as u64;
+            (
+                sum.map(|sum| ((sum + count / 2) / count) as u8),
+                colors.len(),
+            )
+        })
+        .collect();
+    palette.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    palette
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+// The dominant color is the largest of the palette, null for fully transparent images
+pub fn color_json(image: &DynamicImage) -> String {
+    let palette = palette(image, PALETTE_SIZE);
+    let total: usize = palette.iter().map(|(_, count)| count).sum();
+    let shares: Vec<_> = palette
+        .iter()
+        .map(|(color, count)| {
+            let share = *count as f64 / total as f64;
+            json!({ "color": hex(*color), "share": (share * 1000.0).round() / 1000.0 })
+        })
+        .collect();
+    json!({
+        "dominant": palette.first().map(|(color, _)| hex(*color)),
+        "palette": shares,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_palette() {
+        // Three quarters red, then blue, with a transparent strip ignored
+        let image = RgbaImage::from_fn(40, 10, |x, _| match x {
+            0..30 => Rgba([250, 10, 10, 255]),
+            30..36 => Rgba([0, 0, 240, 255]),
+            _ => Rgba([0, 255, 0, 0]),
+        });
+        let image = DynamicImage::from(image);
+        let palette = palette(&image, 6);
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette[0].0, [250, 10, 10]);
+        assert_eq!(palette[1].0, [0, 0, 240]);
+        assert_eq!(
+            color_json(&image),
+            r##"{"dominant":"#fa0a0a","palette":[{"color":"#fa0a0a","share":0.833},{"color":"#0000f0","share":0.167}]}"##
+        );
+
+        let transparent = DynamicImage::from(RgbaImage::new(4, 4));
+        assert_eq!(
+            color_json(&transparent),
+            r#"{"dominant":null,"palette":[]}"#
+        );
+    }
+}
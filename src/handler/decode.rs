@@ -1,82 +1,26 @@
+use super::codecs::{self, Frames};
 use bytes::Bytes;
-use image::codecs::gif::GifDecoder;
-use image::codecs::png::PngDecoder;
-use image::codecs::webp::WebPDecoder;
-use image::{AnimationDecoder, Delay, DynamicImage, Frame, ImageDecoder, ImageFormat, ImageReader};
-use std::io::Cursor;
-use tracing::warn;
+use image::{Delay, DynamicImage};
+use std::time::Duration;
+use tracing::{info, warn};
 
-#[cfg(not(feature = "anim"))]
-use tracing::info;
-
-fn static_image(
-    ori: Result<image::metadata::Orientation, image::ImageError>,
-    mut img: DynamicImage,
-) -> Result<Vec<(DynamicImage, Delay)>, image::ImageError> {
-    if let Ok(ori) = ori {
-        img.apply_orientation(ori);
-    }
-    Ok(vec![(img, Delay::from_numer_denom_ms(0, 1))])
+#[derive(Clone)]
+pub struct DecodeConfig {
+    pub svg_max_pixels: u64,  // rendered size of vector images (and PDF pages)
+    pub svg_max_nodes: usize, // elements, with `<use>` expanded
+    pub animation_max_frames: usize,
+    pub animation_max_duration: Duration,
+    pub animation_max_area: u64, // canvas pixels, larger animations keep the first frame only
 }
 
-fn frames_to_images(
-    ori: Result<image::metadata::Orientation, image::ImageError>,
-    frames: Vec<Frame>,
-) -> Vec<(DynamicImage, Delay)> {
-    let mut images: Vec<(DynamicImage, Delay)> = Vec::new();
-    for frame in frames {
-        let delay = frame.delay();
-        let mut img = DynamicImage::from(frame.into_buffer());
-        if let Ok(ori) = ori {
-            img.apply_orientation(ori);
-        }
-        images.push((img, delay));
-    }
-    images
-}
-
-// Inspired by https://github.com/image-rs/image/issues/2360#issuecomment-3092626301
-fn decode_image_format(
-    img_reader: ImageReader<Cursor<&Bytes>>,
-    format: ImageFormat,
-) -> Result<Vec<(DynamicImage, Delay)>, image::ImageError> {
-    match format {
-        ImageFormat::Gif => {
-            let mut decoder = GifDecoder::new(img_reader.into_inner())?;
-            let ori = decoder.orientation();
-            decoder
-                .into_frames()
-                .collect_frames()
-                .map(|f| frames_to_images(ori, f))
-        }
-        ImageFormat::Png => {
-            let mut decoder = PngDecoder::new(img_reader.into_inner())?;
-            let ori = decoder.orientation();
-            if decoder.is_apng()? {
-                decoder
-                    .apng()?
-                    .into_frames()
-                    .collect_frames()
-                    .map(|f| frames_to_images(ori, f))
-            } else {
-                static_image(ori, DynamicImage::from_decoder(decoder)?)
-            }
-        }
-        ImageFormat::WebP => {
-            let mut decoder = WebPDecoder::new(img_reader.into_inner())?;
-            let ori = decoder.orientation();
-            if decoder.has_animation() {
-                decoder
-                    .into_frames()
-                    .collect_frames()
-                    .map(|f| frames_to_images(ori, f))
-            } else {
-                static_image(ori, DynamicImage::from_decoder(decoder)?)
-            }
-        }
-        _ => {
-            let mut decoder = img_reader.into_decoder()?;
-            static_image(decoder.orientation(), DynamicImage::from_decoder(decoder)?)
+impl Default for DecodeConfig {
+    fn default() -> Self {
+        Self {
+            svg_max_pixels: 2048 * 2048,
+            svg_max_nodes: 20_000,
+            animation_max_frames: 300,
+            animation_max_duration: Duration::from_secs(60),
+            animation_max_area: 1024 * 1024,
         }
     }
 }
@@ -86,20 +30,20 @@ pub enum DecodeImageError {
     ImageError(image::ImageError),
 }
 
+// `cover` is the size vector images are rendered for, see `Decoder::render`
 pub fn decode_image(
     downloaded_bytes: &Bytes,
+    cover: (u32, u32),
+    config: &DecodeConfig,
 ) -> Result<Vec<(DynamicImage, Delay)>, DecodeImageError> {
     // Check whether the file is an image (don't trust the content-type header or filename)
     // hint: misskey need to detect whether the file is manipulatable manually,
-    // but here we are using image crate's format guessing feature
-    let img_reader = ImageReader::new(Cursor::new(downloaded_bytes))
-        .with_guessed_format()
-        .unwrap();
-
-    match img_reader.format() {
-        Some(format) => {
-            let decoded =
-                decode_image_format(img_reader, format).map_err(DecodeImageError::ImageError);
+    // but here the registered codecs sniff the bytes
+    match codecs::decoder(downloaded_bytes) {
+        Some(decoder) => {
+            let decoded = decoder
+                .render(downloaded_bytes, cover, config)
+                .map_err(DecodeImageError::ImageError);
 
             #[cfg(feature = "anim")]
             {
@@ -124,3 +68,94 @@ pub fn decode_image(
         }
     }
 }
+
+// Animations cut down to the budget before processing and encoding, which take time and
+// memory for every frame: frames past the duration are dropped, and the rest sampled evenly
+// down to the count, each kept frame taking the delays of the ones it stands for
+pub fn limit_animation(mut images: Frames, config: &DecodeConfig) -> Frames {
+    let Some((first, _)) = images.first().filter(|_| images.len() > 1) else {
+        return images;
+    };
+    let area = u64::from(first.width()) * u64::from(first.height()); // same for all frames
+    if area > config.animation_max_area {
+        info!("Animation canvas of {area} pixels over budget, keeping the first frame");
+        images.truncate(1);
+        return images;
+    }
+
+    let mut elapsed = Duration::ZERO;
+    let within = images
+        .iter()
+        .take_while(|(_, delay)| {
+            let starts = elapsed;
+            elapsed += Duration::from(*delay);
+            starts < config.animation_max_duration
+        })
+        .count();
+    if within < images.len() {
+        info!(
+            "Animation over {:?}, dropping frames",
+            config.animation_max_duration
+        );
+        images.truncate(within.max(1));
+    }
+
+    let (count, kept) = (images.len(), config.animation_max_frames);
+    if count <= kept {
+        return images;
+    }
+    info!("Animation of {count} frames over budget, sampling {kept}");
+    let mut sampled: Vec<(DynamicImage, Duration)> = Vec::with_capacity(kept);
+    for (i, (image, delay)) in images.into_iter().enumerate() {
+        let delay = Duration::from(delay);
+        let skipped = i * kept / count < sampled.len();
+        match sampled.last_mut() {
+            Some((_, total)) if skipped => *total += delay,
+            _ => sampled.push((image, delay)),
+        }
+    }
+    sampled
+        .into_iter()
+        .map(|(image, total)| (image, Delay::from_saturating_duration(total)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn animation(frames: u8, size: u32) -> Frames {
+        (0..frames)
+            .map(|i| {
+                let image = RgbaImage::from_pixel(size, size, Rgba([i, 0, 0, 255]));
+                (image.into(), Delay::from_numer_denom_ms(100, 1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_limit_animation() {
+        let config = DecodeConfig {
+            animation_max_frames: 4,
+            animation_max_duration: Duration::from_millis(1000),
+            animation_max_area: 100,
+            ..Default::default()
+        };
+        assert_eq!(limit_animation(animation(3, 10), &config).len(), 3);
+        assert_eq!(limit_animation(animation(3, 11), &config).len(), 1);
+
+        // 10 frames within the second, sampled to 4 lasting as long
+        let sampled = limit_animation(animation(20, 10), &config);
+        let firsts: Vec<u8> = sampled
+            .iter()
+            .map(|(image, _)| image.to_rgba8().get_pixel(0, 0)[0])
+            .collect();
+        assert_eq!(firsts, [0, 3, 5, 8]);
+        let delays: Vec<u32> = sampled
+            .iter()
+            .map(|(_, delay)| delay.numer_denom_ms().0)
+            .collect();
+        assert_eq!(delays, [300, 200, 300, 200]);
+    }
+}
@@ -1,78 +1,259 @@
-use crate::downloader::{DownloadedFile, Downloader, FileDownloadError};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::downloader::StreamedFile;
+use crate::downloader::{Conditional, DownloadedFile, Downloader, FileDownloadError, Provenance};
+use crate::handler::codecs;
 use http::StatusCode;
-use tracing::{error, warn};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+use url::Url;
+
+const MAX_ACCEPT_RANGES: usize = 8;
 
 pub enum DownloadImageError<'a> {
     MissingURL,
     RecursiveProxy,
+    OriginDenied,
     DownloadErrorOversize(&'a String),
     DownloadErrorInvalidUrl,
+    DownloadErrorBlockedAddress,
+    DownloadErrorRedirect,
+    DownloadErrorTimeout,
+    NotModified(Box<Provenance>),
+    DownloadErrorHostBusy,
+    DownloadErrorCircuitOpen,
+    DownloadErrorOverloaded(Duration),
+    DownloadErrorBackingOff(Duration),
     DownloadErrorInvalidStatus(StatusCode),
     DownloadErrorRequest,
     NotAnImage(DownloadedFile),
 }
 
-pub async fn download_image<'a>(
+impl DownloadImageError<'_> {
+    // Whether another URL for the same file may do better, not when it's the file itself
+    pub fn worth_fallback(&self) -> bool {
+        matches!(
+            self,
+            DownloadImageError::OriginDenied
+                | DownloadImageError::DownloadErrorInvalidUrl
+                | DownloadImageError::DownloadErrorBlockedAddress
+                | DownloadImageError::DownloadErrorRedirect
+                | DownloadImageError::DownloadErrorTimeout
+                | DownloadImageError::DownloadErrorHostBusy
+                | DownloadImageError::DownloadErrorCircuitOpen
+                | DownloadImageError::DownloadErrorBackingOff(_)
+                | DownloadImageError::DownloadErrorInvalidStatus(_)
+                | DownloadImageError::DownloadErrorRequest
+        )
+    }
+}
+
+// Why the request is rejected before touching the network, if it is
+fn rejection(
     downloader: &Downloader,
-    url: Option<&'a String>,
-    host: Option<&String>,
+    url: Option<&String>,
     ua: Option<&str>,
-) -> Result<DownloadedFile, DownloadImageError<'a>> {
+) -> Option<DownloadImageError<'static>> {
     // Check if url parameter is specified
-    if url.is_none() {
+    let Some(url) = url else {
         // Missing url
         warn!("Request missing url");
-        return Err(DownloadImageError::MissingURL);
-    }
+        return Some(DownloadImageError::MissingURL);
+    };
 
     // Check if UserAgent is valid
-    if let Some(ua) = ua {
-        if ua.to_lowercase().contains("misskey/") || ua.to_lowercase().contains("misskeymediaproxy")
-        {
-            // Recursive proxying
-            warn!("Recursive proxying");
-            return Err(DownloadImageError::RecursiveProxy);
+    if let Some(ua) = ua
+        && (ua.to_lowercase().contains("misskey/")
+            || ua.to_lowercase().contains("misskeymediaproxy"))
+    {
+        // Recursive proxying
+        warn!("Recursive proxying");
+        return Some(DownloadImageError::RecursiveProxy);
+    }
+
+    // Check if the origin is allowed (invalid urls are left to the downloader)
+    if let Ok(parsed) = Url::parse(url)
+        && !downloader.permits(&parsed)
+    {
+        warn!("Origin denied: {url}");
+        return Some(DownloadImageError::OriginDenied);
+    }
+
+    None
+}
+
+fn download_error(url: &String, e: FileDownloadError) -> DownloadImageError<'_> {
+    match e {
+        FileDownloadError::Oversize => {
+            // too large to process, redirect instead
+            warn!("File too large: {url}");
+            DownloadImageError::DownloadErrorOversize(url)
+        }
+        FileDownloadError::InvalidUrl => {
+            warn!("Invalid url: {url}");
+            DownloadImageError::DownloadErrorInvalidUrl
+        }
+        FileDownloadError::BlockedAddress => {
+            warn!("Private address blocked: {url}");
+            DownloadImageError::DownloadErrorBlockedAddress
+        }
+        FileDownloadError::OriginDenied => {
+            warn!("Redirected to a denied origin: {url}");
+            DownloadImageError::OriginDenied
+        }
+        FileDownloadError::RedirectRejected => {
+            warn!("Redirect rejected: {url}");
+            DownloadImageError::DownloadErrorRedirect
+        }
+        FileDownloadError::Timeout => {
+            warn!("Download timed out: {url}");
+            DownloadImageError::DownloadErrorTimeout
+        }
+        FileDownloadError::NotModified(provenance) => DownloadImageError::NotModified(provenance),
+        FileDownloadError::HostBusy => {
+            warn!("Too many downloads queued for the host: {url}");
+            DownloadImageError::DownloadErrorHostBusy
+        }
+        FileDownloadError::CircuitOpen => {
+            warn!("Host is failing, not trying for now: {url}");
+            DownloadImageError::DownloadErrorCircuitOpen
+        }
+        FileDownloadError::Overloaded(retry_after) => {
+            warn!("Too many downloads queued: {url}");
+            DownloadImageError::DownloadErrorOverloaded(retry_after)
+        }
+        FileDownloadError::BackingOff(retry_after) => {
+            warn!("Host asked to retry after {retry_after:?}: {url}");
+            DownloadImageError::DownloadErrorBackingOff(retry_after)
+        }
+        FileDownloadError::InvalidStatusCode(status_code) => {
+            warn!("Invalid status code: {url}, {status_code}");
+            // should we pass the exact same body from remote server?
+            // note: misskey will return the dummy.png if the status code is 404, but we don't implement that feature here
+            DownloadImageError::DownloadErrorInvalidStatus(status_code)
+        }
+        FileDownloadError::RequestError(err) => {
+            // request failed, return 500
+            error!("Failed to download file: {url}, {err}");
+            DownloadImageError::DownloadErrorRequest
         }
     }
+}
 
-    // Start download
-    let url = url.unwrap();
-    let downloaded_file = match downloader.download_file(url, host).await {
-        Ok(b) => b,
-        Err(e) => {
-            return Err(match e {
-                FileDownloadError::Oversize => {
-                    // too large to process, redirect instead
-                    warn!("File too large: {url}");
-                    DownloadImageError::DownloadErrorOversize(url)
-                }
-                FileDownloadError::InvalidUrl => {
-                    warn!("Invalid url: {url}");
-                    DownloadImageError::DownloadErrorInvalidUrl
-                }
-                FileDownloadError::InvalidStatusCode(status_code) => {
-                    warn!("Invalid status code: {url}, {status_code}");
-                    // should we pass the exact same body from remote server?
-                    // note: misskey will return the dummy.png if the status code is 404, but we don't implement that feature here
-                    DownloadImageError::DownloadErrorInvalidStatus(status_code)
-                }
-                FileDownloadError::RequestError(err) => {
-                    // request failed, return 500
-                    error!("Failed to download file: {url}, {err}");
-                    DownloadImageError::DownloadErrorRequest
-                }
-            });
+// Only the media ranges of the client we could process, with their weights (q) and
+// nothing else, so that arbitrary header values never reach origins
+fn sanitize_accept(accept: Option<&str>) -> Option<String> {
+    let ranges: Vec<String> = accept?
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let mime_type = params.next()?.to_ascii_lowercase();
+            if !matches!(mime_type.as_str(), "*/*" | "image/*") && !codecs::decodable(&mime_type) {
+                return None;
+            }
+            let weight = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q)));
+            Some(match weight {
+                Some(q) => format!("{mime_type};q={q}"),
+                None => mime_type,
+            })
+        })
+        .take(MAX_ACCEPT_RANGES)
+        .collect();
+    (!ranges.is_empty()).then(|| ranges.join(","))
+}
+
+// Trust the magic bytes over the content type, as object stores often send
+// application/octet-stream. False if it's not an image after all
+pub fn classify(file: &mut DownloadedFile) -> bool {
+    if let Some(sniffed) = codecs::sniff(&file.bytes) {
+        if file.content_type.as_deref() != Some(sniffed) {
+            debug!(
+                "Content type corrected: {:?} -> {sniffed}",
+                file.content_type
+            );
+            file.content_type = Some(sniffed.to_string());
+        }
+        return true;
+    }
+    match &file.content_type {
+        Some(ct) if !ct.starts_with("image/") => {
+            warn!("Not an image ({ct})");
+            false
         }
+        _ => true,
+    }
+}
+
+pub async fn download_image<'a>(
+    downloader: &Downloader,
+    url: Option<&'a String>,
+    host: Option<&String>,
+    ua: Option<&str>,
+    conditional: &Conditional,
+    images_only: bool,
+) -> Result<DownloadedFile, DownloadImageError<'a>> {
+    if let Some(err) = rejection(downloader, url, ua) {
+        return Err(err);
+    }
+    let url = url.unwrap();
+
+    // Start download
+    let conditional = Conditional {
+        accept: sanitize_accept(conditional.accept.as_deref()),
+        ..conditional.clone()
     };
+    let mut downloaded_file = downloader
+        .download_file(url, host, &conditional, images_only)
+        .await
+        .map_err(|e| download_error(url, e))?;
 
-    // Check possible mimetype of the downloaded file
-    if let Some(ct) = downloaded_file.content_type.as_ref() {
-        if !ct.starts_with("image/") {
-            // Not image, return raw bytes
-            warn!("Not an image ({ct}): {url}");
-            return Err(DownloadImageError::NotAnImage(downloaded_file));
-        }
+    // Check possible mimetype of the downloaded file, not image returns raw bytes
+    if !classify(&mut downloaded_file) {
+        return Err(DownloadImageError::NotAnImage(downloaded_file));
     }
 
     Ok(downloaded_file)
 }
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn stream_media<'a>(
+    downloader: &Downloader,
+    url: Option<&'a String>,
+    host: Option<&String>,
+    ua: Option<&str>,
+    range: Option<&str>,
+    if_range: Option<&str>,
+) -> Result<StreamedFile, DownloadImageError<'a>> {
+    if let Some(err) = rejection(downloader, url, ua) {
+        return Err(err);
+    }
+    let url = url.unwrap();
+    downloader
+        .stream_file(url, host, range, if_range)
+        .await
+        .map_err(|e| download_error(url, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_accept() {
+        assert_eq!(
+            sanitize_accept(Some(
+                "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"
+            ))
+            .as_deref(),
+            Some("image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8")
+        );
+        assert_eq!(
+            sanitize_accept(Some("IMAGE/PNG; charset=x; q=0.5, image/gif;q=2")).as_deref(),
+            Some("image/png;q=0.5,image/gif")
+        );
+        assert_eq!(sanitize_accept(Some("text/html\r\nX-Evil: 1")), None);
+        assert_eq!(sanitize_accept(Some("")), None);
+        assert_eq!(sanitize_accept(None), None);
+    }
+}
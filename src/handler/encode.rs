@@ -1,120 +1,401 @@
+use super::codecs::{Encoder, Metadata};
+use super::exif::copyright_exif;
+use super::processors::flatten_vec;
+use crate::downloader::Provenance;
+use crate::filename;
 use crate::handler::ProxyImageResult;
 use bytes::Bytes;
-use image::codecs::gif::GifEncoder;
-use image::{Delay, DynamicImage, Frame, ImageFormat};
+use image::{Delay, DynamicImage, ImageDecoder, ImageReader, Rgba};
 use std::io::Cursor;
 use tracing::error;
 
-#[cfg(feature = "anim")]
-use image::GenericImageView;
-#[cfg(feature = "anim")]
-use std::io::Write;
-#[cfg(feature = "anim")]
-use webp_animation::WebPData;
-
-#[inline]
-fn images_to_frames(images: Vec<(DynamicImage, Delay)>) -> Vec<Frame> {
-    images
-        .into_iter()
-        .map(|img| Frame::from_parts(img.0.to_rgba8(), 0, 0, img.1))
-        .collect()
+#[derive(Clone)]
+pub struct EncodeConfig {
+    pub webp_quality: f32, // 0-100 (anim feature only, static WebP is always lossless)
+    pub webp_alpha_quality: u8, // 0-100
+    pub webp_method: u8,   // 0 (fast) - 6 (small)
+    pub webp_lossless: bool, // instead of webp_quality, same as without the anim feature
+    pub webp_lossless_colors: u16, // at most this many colors are lossless, 0 to never
+    pub gif_speed: u8,     // 1 (small) - 30 (fast)
+    pub gif_dither: bool,  // no banding on gradients, larger files
+    pub jpeg_quality: u8,  // 1-100
+    pub avif_quality: u8,  // 1-100
+    pub avif_speed: u8,    // 1 (small) - 10 (fast)
+    pub png_compression_level: Option<u8>, // 1-9, or the encoder's default (fast)
+    pub jpeg_progressive: bool,
+    pub png_interlaced: bool, // Adam7, larger files but a preview of the whole image early
+    pub preserve_copyright: bool, // the Exif copyright, nothing else of it
+    pub preserve_icc_profile: bool, // the colors are off without it for wide gamut photos
+    pub background: Rgba<u8>, // transparent parts in formats without alpha, not black
 }
 
-#[cfg(feature = "anim")]
-fn encode_webp(images: Vec<(DynamicImage, Delay)>) -> Result<WebPData, webp_animation::Error> {
-    let dimensions = images[0].0.dimensions();
-    let frames = images_to_frames(images);
-
-    let mut encoder = webp_animation::Encoder::new_with_options(
-        dimensions,
-        webp_animation::EncoderOptions {
-            anim_params: webp_animation::AnimParams { loop_count: 0 },
-            allow_mixed: true,
-            encoding_config: Some(webp_animation::EncodingConfig {
-                encoding_type: webp_animation::EncodingType::Lossy(
-                    webp_animation::LossyEncodingConfig {
-                        alpha_quality: 95,
-                        ..Default::default()
-                    },
-                ),
-                quality: 77f32,
-                method: 2,
-                ..Default::default()
-            }),
-            ..Default::default()
-        },
-    )?;
-
-    let mut current_ts = 0;
-    for frame in frames {
-        // Encode one frame
-        encoder.add_frame(&frame.buffer(), current_ts)?;
-
-        // Calc the duration (delay)
-        let frame_delay_tuple = frame.delay().numer_denom_ms();
-        let frame_delay = (frame_delay_tuple.0 / frame_delay_tuple.1) as i32;
-        current_ts += frame_delay;
+impl Default for EncodeConfig {
+    fn default() -> Self {
+        Self {
+            webp_quality: 77.0,
+            webp_alpha_quality: 95,
+            webp_method: 2,
+            webp_lossless: false,
+            webp_lossless_colors: 256,
+            gif_speed: 1,
+            gif_dither: false,
+            jpeg_quality: 75,
+            avif_quality: 60,
+            avif_speed: 8,
+            png_compression_level: None,
+            jpeg_progressive: false,
+            png_interlaced: false,
+            preserve_copyright: false,
+            preserve_icc_profile: false,
+            background: Rgba([255, 255, 255, 255]),
+        }
     }
+}
 
-    encoder.finalize(current_ts)
+// What of the source is kept as configured, nothing by default. The pixels are re-encoded,
+// so the rest of the metadata (Exif with the location, XMP, comments…) never makes it through
+pub fn kept_metadata(bytes: &Bytes, config: &EncodeConfig) -> Metadata {
+    if !config.preserve_copyright && !config.preserve_icc_profile {
+        return Metadata::default();
+    }
+    let decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok());
+    let Some(mut decoder) = decoder else {
+        return Metadata::default();
+    };
+    Metadata {
+        icc_profile: match config.preserve_icc_profile {
+            true => decoder.icc_profile().ok().flatten(),
+            false => None,
+        },
+        exif: match config.preserve_copyright {
+            true => decoder
+                .exif_metadata()
+                .ok()
+                .flatten()
+                .and_then(|exif| copyright_exif(&exif)),
+            false => None,
+        },
+    }
 }
 
 pub fn encode_image(
     images: Vec<(DynamicImage, Delay)>,
-    target_format: ImageFormat,
+    encoder: &dyn Encoder,
+    metadata: &Metadata,
     original_filename: &(String, Option<String>),
+    provenance: Provenance,
+    config: &EncodeConfig,
 ) -> Result<ProxyImageResult, ()> {
+    let images = match encoder.supports_alpha() {
+        true => images,
+        false => flatten_vec(images, config.background),
+    };
     let mut bytes: Vec<u8> = Vec::new();
-
-    #[cfg(feature = "anim")]
-    let mut buffer = Cursor::new(&mut bytes);
-
-    #[cfg(not(feature = "anim"))]
-    let buffer = Cursor::new(&mut bytes);
-
-    match target_format {
-        #[cfg(feature = "anim")]
-        ImageFormat::WebP => {
-            let webp_data = encode_webp(images).map_err(|err| {
-                error!("Failed to encode webp image: {err}");
-            })?;
-            buffer
-                .write_all(&webp_data)
-                .map_err(|err| error!("Failed to write encoded bytes: {err}"))
-        }
-        ImageFormat::Gif => GifEncoder::new(buffer)
-            .encode_frames(images_to_frames(images))
-            .map_err(|err| error!("Failed to encode image: {err}")),
-        // Others: non-dynamic, just process as static images
-        _ => images[0]
-            .0
-            .write_to(buffer, target_format)
-            .map_err(|err| error!("Failed to encode image: {err}")),
-    }?;
+    encoder
+        .encode(images, metadata, config, &mut bytes)
+        .map_err(|err| error!("Failed to encode image: {err}"))?;
 
     // Correct filename with target extension
-    let target_extension = &format!(".{}", target_format.extensions_str()[0]);
+    let target_extension = encoder.extensions()[0];
     let filename: (String, Option<String>) = (
-        if original_filename.0.ends_with(target_extension) {
-            original_filename.0.clone()
-        } else {
-            format!("{}{target_extension}", original_filename.0)
-        },
-        if let Some(filename_encoded) = &original_filename.1 {
-            Some(if filename_encoded.ends_with(target_extension) {
-                filename_encoded.clone()
-            } else {
-                format!("{}{target_extension}", filename_encoded)
-            })
-        } else {
-            None
-        },
+        filename::with_extension(&original_filename.0, target_extension),
+        original_filename
+            .1
+            .as_ref()
+            .map(|filename_encoded| filename::with_extension(filename_encoded, target_extension)),
     );
 
     // Return with encoded bytes
     Ok(ProxyImageResult {
         bytes: Bytes::from(bytes),
-        content_type: target_format.to_mime_type().to_string(),
+        content_type: encoder.mime_type().to_string(),
         filename,
+        provenance,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::CacheTier;
+    use crate::handler::codecs;
+    use crate::handler::decode::{DecodeConfig, decode_image};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{ImageEncoder, Rgb, RgbImage};
+
+    const ICC_PROFILE: &[u8] = b"not quite an ICC profile";
+
+    // A JPEG with Exif (a camera and a copyright), an ICC profile and XMP
+    fn photo() -> Bytes {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        exif.extend(2u16.to_le_bytes());
+        for (tag, value_offset) in [(0x010fu16, 38u32), (0x8298, 47)] {
+            exif.extend(tag.to_le_bytes());
+            exif.extend(2u16.to_le_bytes());
+            exif.extend(9u32.to_le_bytes());
+            exif.extend(value_offset.to_le_bytes());
+        }
+        exif.extend(0u32.to_le_bytes());
+        exif.extend(b"LeakyCam\0(c) Nyaa\0");
+
+        let mut jpeg = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut jpeg);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder.set_icc_profile(ICC_PROFILE.to_vec()).unwrap();
+        DynamicImage::from(RgbImage::new(8, 8))
+            .write_with_encoder(encoder)
+            .unwrap();
+        let xmp = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend((xmp.len() as u16 + 2).to_be_bytes());
+        segment.extend(xmp);
+        jpeg.splice(2..2, segment);
+        Bytes::from(jpeg)
+    }
+
+    // None for formats we can't read back
+    fn read_back(bytes: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        let decoder = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_decoder().ok());
+        match decoder {
+            Some(mut decoder) => (
+                decoder.icc_profile().ok().flatten(),
+                decoder.exif_metadata().ok().flatten(),
+            ),
+            None => (None, None),
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let photo = photo();
+        let encode = |extension, config: &EncodeConfig| {
+            let images = decode_image(&photo, (0, 0), &DecodeConfig::default())
+                .ok()
+                .unwrap();
+            encode_image(
+                images,
+                codecs::encoder(extension).unwrap(),
+                &kept_metadata(&photo, config),
+                &("photo.jpg".to_string(), None),
+                Provenance::new(CacheTier::Origin),
+                config,
+            )
+            .unwrap()
+            .bytes
+        };
+        let leaks = |bytes: &[u8], needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+
+        assert!(leaks(&photo, b"LeakyCam") && leaks(&photo, b"xmpmeta"));
+
+        // Stripped whatever the output format
+        for extension in ["webp", "png", "jpg", "avif", "gif"] {
+            let bytes = encode(extension, &EncodeConfig::default());
+            for needle in [&b"LeakyCam"[..], b"(c) Nya", b"xmpmeta", ICC_PROFILE] {
+                assert!(!leaks(&bytes, needle), "{extension}");
+            }
+            assert_eq!(read_back(&bytes), (None, None), "{extension}");
+        }
+
+        // Only the copyright and the profile are kept if asked to, where they fit
+        let config = EncodeConfig {
+            preserve_copyright: true,
+            preserve_icc_profile: true,
+            ..Default::default()
+        };
+        let copyright = copyright_exif(&read_back(&photo).1.unwrap());
+        assert!(copyright.is_some());
+        for extension in ["webp", "png", "jpg"] {
+            let bytes = encode(extension, &config);
+            assert!(!leaks(&bytes, b"LeakyCam") && !leaks(&bytes, b"xmpmeta"));
+            let (icc_profile, exif) = read_back(&bytes);
+            assert_eq!(icc_profile.as_deref(), Some(ICC_PROFILE), "{extension}");
+            assert_eq!(exif, copyright, "{extension}");
+        }
+        let avif = encode("avif", &config);
+        assert!(leaks(&avif, b"(c) Nya") && !leaks(&avif, b"LeakyCam"));
+    }
+
+    #[test]
+    fn test_jpeg_quality() {
+        // Noisy image, so that the quality makes a difference
+        let image = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x ^ y) * 5 % 256) as u8,
+            ])
+        });
+        let images = vec![(DynamicImage::from(image), Delay::from_numer_denom_ms(0, 1))];
+        let filename = ("image.png".to_string(), None);
+        let encode = |jpeg_quality| {
+            let config = EncodeConfig {
+                jpeg_quality,
+                ..Default::default()
+            };
+            encode_image(
+                images.clone(),
+                codecs::encoder("jpg").unwrap(),
+                &Metadata::default(),
+                &filename,
+                Provenance::new(CacheTier::Origin),
+                &config,
+            )
+            .unwrap()
+            .bytes
+            .len()
+        };
+        assert!(encode(10) < encode(95));
+    }
+
+    #[test]
+    fn test_jpeg_background() {
+        let image = DynamicImage::from(image::RgbaImage::new(16, 16));
+        let images = vec![(image, Delay::from_numer_denom_ms(0, 1))];
+        let config = EncodeConfig {
+            background: Rgba([0, 0, 255, 255]),
+            ..Default::default()
+        };
+        let result = encode_image(
+            images,
+            codecs::encoder("jpg").unwrap(),
+            &Metadata::default(),
+            &("image.png".to_string(), None),
+            Provenance::new(CacheTier::Origin),
+            &config,
+        )
+        .unwrap();
+        let decoded = image::load_from_memory(&result.bytes).unwrap().into_rgb8();
+        let [r, g, b] = decoded.get_pixel(8, 8).0;
+        assert!(r < 8 && g < 8 && b > 247); // blue rather than black
+    }
+
+    #[test]
+    fn test_progressive() {
+        // Odd sizes, so that some passes are short or empty
+        let image = image::RgbaImage::from_fn(13, 6, |x, y| {
+            Rgba([
+                (x * 19) as u8,
+                (y * 41) as u8,
+                ((x ^ y) * 7) as u8,
+                (x * y * 3) as u8,
+            ])
+        });
+        let images = vec![(
+            DynamicImage::from(image.clone()),
+            Delay::from_numer_denom_ms(0, 1),
+        )];
+        let config = EncodeConfig {
+            jpeg_progressive: true,
+            png_interlaced: true,
+            ..Default::default()
+        };
+        let metadata = Metadata {
+            icc_profile: Some(ICC_PROFILE.to_vec()),
+            exif: None,
+        };
+        let encode = |extension| {
+            encode_image(
+                images.clone(),
+                codecs::encoder(extension).unwrap(),
+                &metadata,
+                &("image.png".to_string(), None),
+                Provenance::new(CacheTier::Origin),
+                &config,
+            )
+            .unwrap()
+            .bytes
+        };
+
+        // The interlace method in IHDR, and the same pixels back
+        let png = encode("png");
+        assert_eq!(png[28], 1);
+        assert_eq!(image::load_from_memory(&png).unwrap().into_rgba8(), image);
+        assert_eq!(read_back(&png).0.as_deref(), Some(ICC_PROFILE));
+
+        // A progressive frame (SOF2) instead of a baseline one (SOF0)
+        let jpeg = encode("jpg");
+        assert!(jpeg.windows(2).any(|marker| marker == [0xff, 0xc2]));
+        assert!(!jpeg.windows(2).any(|marker| marker == [0xff, 0xc0]));
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (13, 6));
+        assert_eq!(read_back(&jpeg).0.as_deref(), Some(ICC_PROFILE));
+    }
+
+    #[test]
+    fn test_webp_lossless() {
+        // Pixel art, two colors in hard-edged blocks
+        let pixel_art = RgbImage::from_fn(32, 32, |x, y| match (x / 4 + y / 4) % 2 {
+            0 => Rgb([255, 0, 0]),
+            _ => Rgb([0, 0, 255]),
+        });
+        let noisy = RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 7) as u8, (y * 13) as u8, ((x ^ y) * 5) as u8])
+        });
+        let round_trip = |image: &RgbImage, config: &EncodeConfig| {
+            let images = vec![(
+                DynamicImage::from(image.clone()),
+                Delay::from_numer_denom_ms(0, 1),
+            )];
+            let result = encode_image(
+                images,
+                codecs::encoder("webp").unwrap(),
+                &Metadata::default(),
+                &("image.png".to_string(), None),
+                Provenance::new(CacheTier::Origin),
+                config,
+            )
+            .unwrap();
+            image::load_from_memory(&result.bytes).unwrap().into_rgb8() == *image
+        };
+
+        // Lossless for few colors, or when asked for
+        assert!(round_trip(&pixel_art, &EncodeConfig::default()));
+        let lossless = EncodeConfig {
+            webp_lossless: true,
+            ..Default::default()
+        };
+        assert!(round_trip(&noisy, &lossless));
+        if cfg!(feature = "anim") {
+            assert!(!round_trip(&noisy, &EncodeConfig::default()));
+        }
+    }
+
+    #[test]
+    fn test_avif_quality() {
+        let image = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x ^ y) * 5 % 256) as u8,
+            ])
+        });
+        let images = vec![(DynamicImage::from(image), Delay::from_numer_denom_ms(0, 1))];
+        let encode = |avif_quality| {
+            let config = EncodeConfig {
+                avif_quality,
+                avif_speed: 10,
+                ..Default::default()
+            };
+            let result = encode_image(
+                images.clone(),
+                codecs::encoder("avif").unwrap(),
+                &Metadata::default(),
+                &("image.png".to_string(), None),
+                Provenance::new(CacheTier::Origin),
+                &config,
+            )
+            .unwrap();
+            assert_eq!(result.content_type, "image/avif");
+            assert_eq!(result.filename.0, "image.png.avif");
+            result.bytes.len()
+        };
+        assert!(encode(10) < encode(95));
+    }
+}
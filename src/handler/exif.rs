@@ -0,0 +1,351 @@
+use bytes::Bytes;
+use image::{ImageDecoder, ImageReader};
+use serde_json::{Map, Value};
+use std::io::Cursor;
+
+const MAX_STRING_LEN: usize = 128;
+const MAX_IFD_ENTRIES: u16 = 512; // more is corrupted (or hostile)
+
+// Tags of IFD0
+const MAKE: u16 = 0x010f;
+const MODEL: u16 = 0x0110;
+const ORIENTATION: u16 = 0x0112;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+const COPYRIGHT: u16 = 0x8298;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+
+// Tags of the Exif IFD
+const EXPOSURE_TIME: u16 = 0x829a;
+const F_NUMBER: u16 = 0x829d;
+const ISO: u16 = 0x8827;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const DATE_TIME_DIGITIZED: u16 = 0x9004;
+const FOCAL_LENGTH: u16 = 0x920a;
+const LENS_MODEL: u16 = 0xa434;
+
+// Tags of the GPS IFD
+const GPS_LATITUDE_REF: u16 = 0x0001;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE_REF: u16 = 0x0003;
+const GPS_LONGITUDE: u16 = 0x0004;
+
+// Raw Exif (TIFF structure), only the few value types we read
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Field {
+    kind: u16,
+    count: u32,
+    offset: usize, // of the value, inline or not
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Self {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn ifd(&self, offset: usize) -> Vec<(u16, Field)> {
+        let Some(count) = self.u16(offset).filter(|count| *count <= MAX_IFD_ENTRIES) else {
+            return Vec::new();
+        };
+        (0..count as usize)
+            .filter_map(|i| {
+                let entry = offset + 2 + i * 12;
+                let kind = self.u16(entry + 2)?;
+                let count = self.u32(entry + 4)?;
+                let size = match kind {
+                    1 | 2 | 7 => 1, // byte, ascii, undefined
+                    3 => 2,         // short
+                    4 => 4,         // long
+                    5 => 8,         // rational
+                    _ => return None,
+                } * count as usize;
+                let offset = match size {
+                    0..=4 => entry + 8,
+                    _ => self.u32(entry + 8)? as usize,
+                };
+                Some((
+                    self.u16(entry)?,
+                    Field {
+                        kind,
+                        count,
+                        offset,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn text(&self, field: Field) -> Option<String> {
+        if field.kind != 2 {
+            return None;
+        }
+        let bytes = self
+            .data
+            .get(field.offset..field.offset + field.count as usize)?;
+        let text: String = String::from_utf8_lossy(bytes)
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_STRING_LEN)
+            .collect();
+        let text = text.trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    fn number(&self, field: Field, index: usize) -> Option<u32> {
+        match field.kind {
+            3 => self.u16(field.offset + index * 2).map(u32::from),
+            4 => self.u32(field.offset + index * 4),
+            _ => None,
+        }
+    }
+
+    fn rational(&self, field: Field, index: usize) -> Option<(u32, u32)> {
+        if field.kind != 5 || index >= field.count as usize {
+            return None;
+        }
+        let offset = field.offset + index * 8;
+        Some((self.u32(offset)?, self.u32(offset + 4)?))
+            .filter(|(_, denominator)| *denominator != 0)
+    }
+
+    fn decimal(&self, field: Field, index: usize) -> Option<f64> {
+        self.rational(field, index)
+            .map(|(numerator, denominator)| numerator as f64 / denominator as f64)
+    }
+
+    // Degrees, minutes and seconds to signed degrees
+    fn coordinate(&self, value: Field, reference: Option<Field>, negative: &str) -> Option<f64> {
+        let degrees = self.decimal(value, 0)?
+            + self.decimal(value, 1)? / 60.0
+            + self.decimal(value, 2)? / 3600.0;
+        let negative = reference.and_then(|field| self.text(field)).as_deref() == Some(negative);
+        Some(if negative { -degrees } else { degrees })
+    }
+}
+
+fn find(fields: &[(u16, Field)], tag: u16) -> Option<Field> {
+    fields
+        .iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, field)| *field)
+}
+
+// Fields for display in galleries, no maker notes or thumbnails. GPS only if enabled
+pub fn exif_json(width: u32, height: u32, exif: Option<&[u8]>, gps: bool) -> String {
+    // Decimals are rounded to what the camera could tell
+    let round = |value: f64, places: i32| {
+        let scale = 10f64.powi(places);
+        Value::from((value * scale).round() / scale)
+    };
+    let mut fields = Map::new();
+    fields.insert("width".into(), width.into());
+    fields.insert("height".into(), height.into());
+    if let Some(tiff) = exif.and_then(Tiff::new) {
+        let ifd0 = tiff
+            .u32(4)
+            .map(|offset| tiff.ifd(offset as usize))
+            .unwrap_or_default();
+        let sub_ifd = |tag| {
+            find(&ifd0, tag)
+                .and_then(|field| tiff.number(field, 0))
+                .map(|offset| tiff.ifd(offset as usize))
+                .unwrap_or_default()
+        };
+        let exif_ifd = sub_ifd(EXIF_IFD);
+
+        let text = |ifd: &[(u16, Field)], tag| find(ifd, tag).and_then(|field| tiff.text(field));
+        for (name, ifd, tag) in [
+            ("make", &ifd0, MAKE),
+            ("model", &ifd0, MODEL),
+            ("lens_model", &exif_ifd, LENS_MODEL),
+            ("software", &ifd0, SOFTWARE),
+            ("date_time", &ifd0, DATE_TIME),
+            ("date_time_original", &exif_ifd, DATE_TIME_ORIGINAL),
+            ("date_time_digitized", &exif_ifd, DATE_TIME_DIGITIZED),
+        ] {
+            if let Some(value) = text(ifd, tag) {
+                fields.insert(name.into(), value.into());
+            }
+        }
+
+        let number = |ifd: &[(u16, Field)], tag| find(ifd, tag).and_then(|f| tiff.number(f, 0));
+        if let Some(orientation) = number(&ifd0, ORIENTATION) {
+            fields.insert("orientation".into(), orientation.into());
+        }
+        if let Some(iso) = number(&exif_ifd, ISO) {
+            fields.insert("iso".into(), iso.into());
+        }
+        if let Some((numerator, denominator)) =
+            find(&exif_ifd, EXPOSURE_TIME).and_then(|field| tiff.rational(field, 0))
+        {
+            let exposure_time = format!("{numerator}/{denominator}");
+            fields.insert("exposure_time".into(), exposure_time.into());
+        }
+        let decimal = |tag| find(&exif_ifd, tag).and_then(|field| tiff.decimal(field, 0));
+        if let Some(f_number) = decimal(F_NUMBER) {
+            fields.insert("f_number".into(), round(f_number, 1));
+        }
+        if let Some(focal_length) = decimal(FOCAL_LENGTH) {
+            fields.insert("focal_length".into(), round(focal_length, 1));
+        }
+
+        if gps {
+            let gps_ifd = sub_ifd(GPS_IFD);
+            let coordinate = |value, reference, negative| {
+                tiff.coordinate(find(&gps_ifd, value)?, find(&gps_ifd, reference), negative)
+            };
+            if let Some(latitude) = coordinate(GPS_LATITUDE, GPS_LATITUDE_REF, "S")
+                && let Some(longitude) = coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF, "W")
+            {
+                fields.insert("latitude".into(), round(latitude, 6));
+                fields.insert("longitude".into(), round(longitude, 6));
+            }
+        }
+    }
+
+    Value::Object(fields).to_string()
+}
+
+// Exif with the copyright of the original only, e.g. for photographers' instances
+pub fn copyright_exif(exif: &[u8]) -> Option<Vec<u8>> {
+    let tiff = Tiff::new(exif)?;
+    let ifd0 = tiff.ifd(tiff.u32(4)? as usize);
+    let field = find(&ifd0, COPYRIGHT).filter(|field| field.kind == 2)?;
+    let mut value = tiff
+        .data
+        .get(field.offset..field.offset + field.count as usize)?
+        .to_vec();
+    if value.last() != Some(&0) {
+        value.push(0);
+    }
+
+    // Little endian, IFD0 with the one entry at 8 and the value after it at 26 if not inline
+    let mut data = b"II*\0".to_vec();
+    data.extend(8u32.to_le_bytes());
+    data.extend(1u16.to_le_bytes());
+    data.extend(COPYRIGHT.to_le_bytes());
+    data.extend(2u16.to_le_bytes());
+    data.extend((value.len() as u32).to_le_bytes());
+    if value.len() <= 4 {
+        value.resize(4, 0);
+        data.extend(&value);
+        data.extend(0u32.to_le_bytes());
+    } else {
+        data.extend(26u32.to_le_bytes());
+        data.extend(0u32.to_le_bytes());
+        data.extend(&value);
+    }
+    Some(data)
+}
+
+// Dimensions and raw Exif without decoding the pixels, None if not an image we can read
+pub fn read_exif(bytes: &Bytes) -> Option<(u32, u32, Option<Vec<u8>>)> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height) = decoder.dimensions();
+    let exif = decoder.exif_metadata().ok().flatten();
+    Some((width, height, exif))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Little endian TIFF with IFD0 (Make, Exif and GPS pointers), Exif IFD (ISO) and GPS IFD
+    fn sample() -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        let entry = |data: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32| {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        };
+        // IFD0 at 8: 3 entries, ends at 8 + 2 + 36 + 4 = 50
+        data.extend(3u16.to_le_bytes());
+        entry(&mut data, MAKE, 2, 6, 100);
+        entry(&mut data, EXIF_IFD, 4, 1, 50);
+        entry(&mut data, GPS_IFD, 4, 1, 68);
+        data.extend(0u32.to_le_bytes());
+        // Exif IFD at 50: 1 entry, ends at 68
+        data.extend(1u16.to_le_bytes());
+        entry(&mut data, ISO, 3, 1, 200);
+        data.extend(0u32.to_le_bytes());
+        // GPS IFD at 68: 2 entries, ends at 98
+        data.extend(2u16.to_le_bytes());
+        entry(
+            &mut data,
+            GPS_LATITUDE_REF,
+            2,
+            2,
+            u32::from_le_bytes(*b"S\0\0\0"),
+        );
+        entry(&mut data, GPS_LATITUDE, 5, 3, 106);
+        data.extend(0u32.to_le_bytes());
+        data.resize(100, 0);
+        data.extend(b"Nya\"1\0");
+        for (numerator, denominator) in [(35, 1), (30, 1), (0, 1)] {
+            data.extend(u32::to_le_bytes(numerator));
+            data.extend(u32::to_le_bytes(denominator));
+        }
+        data
+    }
+
+    #[test]
+    fn test_exif_json() {
+        let exif = sample();
+        assert_eq!(
+            exif_json(4, 3, Some(&exif), false),
+            r#"{"height":3,"iso":200,"make":"Nya\"1","width":4}"#
+        );
+        // Longitude is missing, so still no location
+        assert!(!exif_json(4, 3, Some(&exif), true).contains("latitude"));
+        assert_eq!(exif_json(4, 3, None, true), r#"{"height":3,"width":4}"#);
+        assert_eq!(
+            exif_json(4, 3, Some(b"garbage"), true),
+            r#"{"height":3,"width":4}"#
+        );
+
+        let tiff = Tiff::new(&exif).unwrap();
+        let gps = tiff.ifd(68);
+        let latitude = tiff.coordinate(
+            find(&gps, GPS_LATITUDE).unwrap(),
+            find(&gps, GPS_LATITUDE_REF),
+            "S",
+        );
+        assert_eq!(latitude, Some(-35.5));
+    }
+}
@@ -0,0 +1,120 @@
+use super::codecs::Metadata;
+use crate::downloader::{CacheTier, Provenance};
+use crate::guard::MutexExt;
+use image::{Delay, DynamicImage};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const TTL: Duration = Duration::from_secs(5 * 60); // frontends ask for both variants together
+
+// First frame of a decoded animation, so that the static variant needs neither
+// the download nor the decoding again
+#[derive(Clone)]
+pub struct FirstFrame {
+    pub frame: (DynamicImage, Delay),
+    pub metadata: Metadata,
+    pub filename: (String, Option<String>),
+    pub provenance: Provenance,
+    pub source_bytes: u64,
+    pub sha256: String, // of the source, as the quarantine may have changed since
+}
+
+struct Entry {
+    first: FirstFrame,
+    size: u64,
+    inserted: Instant,
+}
+
+#[derive(Default)]
+struct Frames {
+    map: HashMap<String, Entry>, // by source url
+    size: u64,                   // total bytes of the pixels
+}
+
+static FRAMES: LazyLock<Mutex<Frames>> = LazyLock::new(Default::default);
+
+pub fn get(url: &str) -> Option<FirstFrame> {
+    let mut frames = FRAMES.lock_or_recover();
+    let entry = frames.map.get(url)?;
+    if entry.inserted.elapsed() >= TTL {
+        let size = entry.size;
+        frames.map.remove(url);
+        frames.size -= size;
+        return None;
+    }
+    let mut first = entry.first.clone();
+    first.provenance.tier = CacheTier::Memory;
+    Some(first)
+}
+
+// Evicting the oldest ones when over capacity
+pub fn insert(url: &str, first: FirstFrame, capacity: u64) {
+    let size = first.frame.0.as_bytes().len() as u64;
+    if !first.provenance.storable || size > capacity {
+        return;
+    }
+    let mut frames = FRAMES.lock_or_recover();
+    if let Some(old) = frames.map.remove(url) {
+        frames.size -= old.size;
+    }
+    while frames.size + size > capacity {
+        let Some(oldest) = frames
+            .map
+            .iter()
+            .min_by_key(|(_, entry)| entry.inserted)
+            .map(|(url, _)| url.clone())
+        else {
+            break;
+        };
+        if let Some(old) = frames.map.remove(&oldest) {
+            frames.size -= old.size;
+        }
+    }
+    debug!("Keeping the first frame ({size} bytes): {url}");
+    frames.size += size;
+    frames.map.insert(
+        url.to_string(),
+        Entry {
+            first,
+            size,
+            inserted: Instant::now(),
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first(size: u32) -> FirstFrame {
+        FirstFrame {
+            frame: (
+                DynamicImage::ImageRgba8(image::RgbaImage::new(size, size)),
+                Delay::from_numer_denom_ms(100, 1),
+            ),
+            metadata: Metadata::default(),
+            filename: ("a.gif".to_string(), None),
+            provenance: Provenance::new(CacheTier::Origin),
+            source_bytes: 100,
+            sha256: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        insert("https://frames.example/a.gif", first(10), 1000); // 400 bytes
+        let hit = get("https://frames.example/a.gif").unwrap();
+        assert_eq!(hit.provenance.tier, CacheTier::Memory);
+        assert_eq!(hit.frame.0.width(), 10);
+
+        insert("https://frames.example/b.gif", first(10), 1000);
+        insert("https://frames.example/c.gif", first(10), 1000); // evicts a
+        assert!(get("https://frames.example/a.gif").is_none());
+        assert!(get("https://frames.example/c.gif").is_some());
+
+        insert("https://frames.example/d.gif", first(20), 1000); // larger than all of it
+        assert!(get("https://frames.example/d.gif").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+use super::codecs;
+use super::decode::{DecodeConfig, decode_image};
+use super::exif::read_exif;
+use bytes::Bytes;
+use image::metadata::Orientation;
+use std::time::Duration;
+
+fn or_null(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+// What the file is and whether it would be processed, without the pixels. The size is as
+// displayed, after the Exif orientation, and the duration in milliseconds
+pub fn info_json(bytes: &Bytes, content_type: Option<&str>, config: &DecodeConfig) -> String {
+    let format = codecs::sniff(bytes).or(content_type);
+    let header = read_exif(bytes);
+    let orientation = header
+        .as_ref()
+        .and_then(|(_, _, exif)| exif.as_deref())
+        .and_then(Orientation::from_exif_chunk)
+        .map(Orientation::to_exif);
+
+    // Decoded as for processing, except the budget for animations, to tell their real length
+    let decoded = decode_image(bytes, (0, 0), config).ok();
+    let size = match &decoded {
+        Some(frames) => frames
+            .first()
+            .map(|(first, _)| (first.width(), first.height())),
+        None => header.map(|(width, height, _)| match orientation {
+            Some(5..=8) => (height, width),
+            _ => (width, height),
+        }),
+    };
+    let duration = decoded.as_ref().map(|frames| {
+        let total: Duration = frames.iter().map(|(_, delay)| Duration::from(*delay)).sum();
+        total.as_millis()
+    });
+
+    format!(
+        "{{\"format\":{},\"width\":{},\"height\":{},\"frames\":{},\"duration\":{},\"orientation\":{},\"processable\":{}}}",
+        or_null(format.map(serde_json::Value::from)),
+        or_null(size.map(|(width, _)| width)),
+        or_null(size.map(|(_, height)| height)),
+        or_null(decoded.as_ref().map(Vec::len)),
+        or_null(duration),
+        or_null(orientation),
+        decoded.is_some(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_info_json() {
+        let mut png = Cursor::new(Vec::new());
+        RgbImage::new(4, 3)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = Bytes::from(png.into_inner());
+        assert_eq!(
+            info_json(&png, Some("image/jpeg"), &DecodeConfig::default()),
+            r#"{"format":"image/png","width":4,"height":3,"frames":1,"duration":0,"orientation":null,"processable":true}"#
+        );
+
+        // Only what the header tells
+        let idat = png.windows(4).position(|chunk| chunk == b"IDAT").unwrap();
+        let truncated = png.slice(..idat + 6);
+        assert_eq!(
+            info_json(&truncated, None, &DecodeConfig::default()),
+            r#"{"format":"image/png","width":4,"height":3,"frames":null,"duration":null,"orientation":null,"processable":false}"#
+        );
+        assert_eq!(
+            info_json(
+                &Bytes::from_static(b"hello"),
+                None,
+                &DecodeConfig::default()
+            ),
+            r#"{"format":null,"width":null,"height":null,"frames":null,"duration":null,"orientation":null,"processable":false}"#
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+// Never set by a preset: what to fetch stays up to the request
+const RESERVED: [&str; 3] = ["url", "host", "preset"];
+
+// Named sets of query parameters, e.g. `banner:preview,mp=0.5;icon:avatar,static`, so
+// that clients use stable names while operators decide what they mean
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Presets(BTreeMap<String, Vec<(String, String)>>);
+
+impl Presets {
+    // The parameters of the preset take over the request's. None for unknown presets
+    pub fn apply(
+        &self,
+        name: &str,
+        mut query: HashMap<String, String>,
+    ) -> Option<HashMap<String, String>> {
+        for (key, value) in self.0.get(name)? {
+            query.insert(key.clone(), value.clone());
+        }
+        Some(query)
+    }
+}
+
+impl FromStr for Presets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut presets = BTreeMap::new();
+        for preset in s
+            .split(';')
+            .map(str::trim)
+            .filter(|preset| !preset.is_empty())
+        {
+            let (name, params) = preset
+                .split_once(':')
+                .ok_or_else(|| format!("missing ':' in preset: {preset}"))?;
+            let name = name.trim();
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!("invalid preset name: {name}"));
+            }
+            let params = params
+                .split(',')
+                .map(str::trim)
+                .filter(|param| !param.is_empty())
+                .map(|param| {
+                    let (key, value) = param.split_once('=').unwrap_or((param, "1"));
+                    let key = key.trim();
+                    if key.is_empty() || RESERVED.contains(&key) {
+                        return Err(format!("invalid parameter in preset {name}: {param}"));
+                    }
+                    Ok((key.to_string(), value.trim().to_string()))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            if presets.insert(name.to_string(), params).is_some() {
+                return Err(format!("duplicate preset: {name}"));
+            }
+        }
+        Ok(Presets(presets))
+    }
+}
+
+impl fmt::Display for Presets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let presets: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, params)| {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect();
+                format!("{name}:{}", params.join(","))
+            })
+            .collect();
+        write!(f, "{}", presets.join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let presets: Presets = "banner: preview, mp=0.5 ; icon:avatar,static"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            presets.to_string(),
+            "banner:preview=1,mp=0.5;icon:avatar=1,static=1"
+        );
+        assert_eq!(presets.to_string().parse::<Presets>().unwrap(), presets);
+
+        let query = HashMap::from([
+            ("url".to_string(), "https://example.com/a.png".to_string()),
+            ("mp".to_string(), "8".to_string()),
+        ]);
+        let applied = presets.apply("banner", query.clone()).unwrap();
+        assert_eq!(applied["mp"], "0.5"); // the operator's
+        assert!(applied.contains_key("preview"));
+        assert_eq!(applied["url"], "https://example.com/a.png");
+        assert!(presets.apply("unknown", query).is_none());
+
+        assert_eq!("".parse::<Presets>().unwrap(), Presets::default());
+        assert!("banner".parse::<Presets>().is_err());
+        assert!("ban ner:preview".parse::<Presets>().is_err());
+        assert!("a:url=https://example.com/".parse::<Presets>().is_err());
+        assert!("a:preview;a:static".parse::<Presets>().is_err());
+    }
+}
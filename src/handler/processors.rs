@@ -1,4 +1,92 @@
-use image::{Delay, DynamicImage};
+use super::codecs::has_transparency;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    ColorType, Delay, DynamicImage, GrayImage, ImageBuffer, Luma, Rgba, RgbaImage, imageops,
+};
+use std::str::FromStr;
+
+pub const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+pub const BADGE_SIZE: u32 = 96;
+const BADGE_CONTRAST: f32 = 1.75;
+const BADGE_MIN_ENTROPY: f64 = 0.1; // bits, below that there's nothing to show
+const MAX_BLUR_SIGMA: f32 = 100.0; // beyond that it's all one color anyway, only slower
+const SMART_CROP_SAMPLE: u32 = 256; // longest side looked at, the details don't matter
+
+type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+fn premultiply_alpha(buffer: &mut Rgba16Image) {
+    for pixel in buffer.pixels_mut() {
+        let alpha = u32::from(pixel[3]);
+        for channel in 0..3 {
+            pixel[channel] = (u32::from(pixel[channel]) * alpha / 0xFFFF) as u16;
+        }
+    }
+}
+
+fn unpremultiply_alpha(buffer: &mut Rgba16Image) {
+    for pixel in buffer.pixels_mut() {
+        let alpha = u32::from(pixel[3]);
+        for channel in 0..3 {
+            pixel[channel] = (u32::from(pixel[channel]) * 0xFFFF)
+                .checked_div(alpha)
+                .map_or(0, |value| value.min(0xFFFF) as u16);
+        }
+    }
+}
+
+pub fn resize_exact(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    premultiplied(image, |image| image.thumbnail_exact(width, height))
+}
+
+// An operation averaging pixels (resizing, blurring), on colors weighted by alpha
+fn premultiplied(
+    image: DynamicImage,
+    operation: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    // Also opaque images with an alpha channel, the weights would all be 1
+    if !has_transparency(&image) {
+        return operation(image);
+    }
+
+    // Color of fully transparent pixels (usually black) would bleed into
+    // the visible edges when averaged directly, producing dark halos.
+    // Weight colors by alpha before the operation, and restore them afterward.
+    // 16 bits per channel keeps enough precision for dark, almost transparent pixels.
+    let color_type = image.color();
+    let mut buffer = image.into_rgba16();
+    premultiply_alpha(&mut buffer);
+    let mut averaged = operation(DynamicImage::ImageRgba16(buffer)).into_rgba16();
+    unpremultiply_alpha(&mut averaged);
+
+    // Convert back to a depth close to the original one
+    let averaged = DynamicImage::ImageRgba16(averaged);
+    match color_type {
+        ColorType::Rgba16 | ColorType::La16 => averaged,
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(averaged.into_rgba32f()),
+        _ => DynamicImage::ImageRgba8(averaged.into_rgba8()),
+    }
+}
+
+// `blur=<sigma>` in pixels, at most MAX_BLUR_SIGMA, 0 for none
+pub fn parse_blur(s: &str) -> Result<f32, String> {
+    s.trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|sigma| *sigma >= 0.0) // and not NaN
+        .map(|sigma| sigma.min(MAX_BLUR_SIGMA))
+        .ok_or_else(|| format!("invalid blur: {s}"))
+}
+
+pub fn blur_vec(images: Vec<(DynamicImage, Delay)>, sigma: f32) -> Vec<(DynamicImage, Delay)> {
+    if sigma <= 0.0 {
+        return images;
+    }
+    images
+        .into_iter()
+        .map(|img| (premultiplied(img.0, |image| image.fast_blur(sigma)), img.1))
+        .collect()
+}
 
 pub fn shrink_outside(image: DynamicImage, size: u32) -> DynamicImage {
     // image::math::resize_dimensions is not a public function,
@@ -22,22 +110,193 @@ pub fn shrink_outside(image: DynamicImage, size: u32) -> DynamicImage {
         }
 
         // Do the shrinking
-        image.thumbnail_exact(w2, h2)
+        resize_exact(image, w2, h2)
     } else {
         // keep as-is
         image
     }
 }
 
-#[inline]
 pub fn shrink_inside(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
-    if image.width() > width || image.height() > height {
-        image.thumbnail(width, height)
+    let w = image.width();
+    let h = image.height();
+    if w > width || h > height {
+        // same as what image.thumbnail does to keep aspect ratio
+        let ratio = f64::min(
+            f64::from(width) / f64::from(w),
+            f64::from(height) / f64::from(h),
+        );
+        let w2 = ((f64::from(w) * ratio).round() as u32).max(1);
+        let h2 = ((f64::from(h) * ratio).round() as u32).max(1);
+        resize_exact(image, w2, h2)
+    } else {
+        image // keep as-is
+    }
+}
+
+// Bounding box (x, y, width, height) of non-transparent pixels, None if fully transparent
+fn opaque_bounds(image: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    let buffer = image.to_rgba8();
+    let (mut left, mut top) = (u32::MAX, u32::MAX);
+    let (mut right, mut bottom) = (0, 0);
+    for (x, y, pixel) in buffer.enumerate_pixels() {
+        if pixel[3] > 0 {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+    if left > right {
+        None
+    } else {
+        Some((left, top, right - left + 1, bottom - top + 1))
+    }
+}
+
+pub fn trim_transparent_vec(images: Vec<(DynamicImage, Delay)>) -> Vec<(DynamicImage, Delay)> {
+    if !images.first().is_some_and(|img| img.0.has_alpha()) {
+        return images; // nothing can be transparent
+    }
+
+    // Use the union of all frames, so that animations don't jitter
+    let bounds = images
+        .iter()
+        .filter_map(|img| opaque_bounds(&img.0))
+        .map(|(x, y, w, h)| (x, y, x + w, y + h))
+        .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)));
+
+    match bounds {
+        Some((left, top, right, bottom)) => images
+            .into_iter()
+            .map(|img| (img.0.crop_imm(left, top, right - left, bottom - top), img.1))
+            .collect(),
+        None => images, // fully transparent, keep as-is
+    }
+}
+
+// Accepts `transparent`, or hex colors like `fff`, `ffffff`, `#ffffff80`
+pub fn parse_color(input: &str) -> Option<Rgba<u8>> {
+    if input.eq_ignore_ascii_case("transparent") {
+        return Some(TRANSPARENT);
+    }
+
+    let hex = input.strip_prefix('#').unwrap_or(input);
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize, len: usize| {
+        u8::from_str_radix(&hex[i * len..(i + 1) * len], 16)
+            .ok()
+            .map(|value| if len == 1 { value * 0x11 } else { value })
+    };
+    match hex.len() {
+        3 => Some(Rgba([channel(0, 1)?, channel(1, 1)?, channel(2, 1)?, 255])),
+        6 => Some(Rgba([channel(0, 2)?, channel(1, 2)?, channel(2, 2)?, 255])),
+        8 => Some(Rgba([
+            channel(0, 2)?,
+            channel(1, 2)?,
+            channel(2, 2)?,
+            channel(3, 2)?,
+        ])),
+        _ => None,
+    }
+}
+
+pub fn pad(image: DynamicImage, width: u32, height: u32, color: Rgba<u8>) -> DynamicImage {
+    if image.width() == width && image.height() == height {
+        return image; // already fits
+    }
+
+    // Place the image at the center of the canvas
+    let mut canvas = RgbaImage::from_pixel(width, height, color);
+    let x = (i64::from(width) - i64::from(image.width())) / 2;
+    let y = (i64::from(height) - i64::from(image.height())) / 2;
+    imageops::overlay(&mut canvas, &image.to_rgba8(), x, y);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+#[inline]
+pub fn pad_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    width: u32,
+    height: u32,
+    color: Rgba<u8>,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|img| (pad(img.0, width, height, color), img.1))
+        .collect()
+}
+
+// Transparent parts over an opaque color, as sharp's flatten
+pub fn flatten(image: DynamicImage, color: Rgba<u8>) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let background = Rgba([color[0], color[1], color[2], 255]);
+    let mut canvas = RgbaImage::from_pixel(image.width(), image.height(), background);
+    imageops::overlay(&mut canvas, &image.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).into_rgb8())
+}
+
+#[inline]
+pub fn flatten_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    color: Rgba<u8>,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|img| (flatten(img.0, color), img.1))
+        .collect()
+}
+
+pub fn shrink_to_pixels(image: DynamicImage, max_pixels: u64) -> DynamicImage {
+    let w = image.width();
+    let h = image.height();
+    let pixels = u64::from(w) * u64::from(h);
+    if pixels > max_pixels {
+        // keep aspect ratio, scale both sides by the same factor
+        let ratio = (max_pixels as f64 / pixels as f64).sqrt();
+        let w2 = ((f64::from(w) * ratio).floor() as u32).max(1);
+        let h2 = ((f64::from(h) * ratio).floor() as u32).max(1);
+        resize_exact(image, w2, h2)
     } else {
         image // keep as-is
     }
 }
 
+#[inline]
+pub fn shrink_to_pixels_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    max_pixels: u64,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|img| (shrink_to_pixels(img.0, max_pixels), img.1))
+        .collect()
+}
+
+pub fn crop_top(image: DynamicImage, max_ratio: f64) -> DynamicImage {
+    let max_height = (f64::from(image.width()) * max_ratio).round() as u32;
+    if max_height > 0 && image.height() > max_height {
+        image.crop_imm(0, 0, image.width(), max_height)
+    } else {
+        image // not that long
+    }
+}
+
+#[inline]
+pub fn crop_top_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    max_ratio: f64,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|img| (crop_top(img.0, max_ratio), img.1))
+        .collect()
+}
+
 #[inline]
 pub fn shrink_outside_vec(
     images: Vec<(DynamicImage, Delay)>,
@@ -61,6 +320,352 @@ pub fn shrink_inside_vec(
         .collect()
 }
 
+// How `w` and `h` apply, as sharp's fit option. Never enlarging, except to fill
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Fit {
+    Cover,   // exactly the box, the overflow cropped from the center
+    Contain, // exactly the box, letterboxed
+    Fill,    // exactly the box, stretched
+    #[default]
+    Inside, // at most the box
+    Outside, // at least the box
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cover" => Ok(Fit::Cover),
+            "contain" => Ok(Fit::Contain),
+            "fill" => Ok(Fit::Fill),
+            "inside" => Ok(Fit::Inside),
+            "outside" => Ok(Fit::Outside),
+            _ => Err(format!("invalid fit: {s}")),
+        }
+    }
+}
+
+// The box of `w` and `h`, either one unbounded (up to the maximum) when missing, which
+// only fits inside then. None when neither is a size in pixels
+pub fn requested_size(
+    width: Option<&String>,
+    height: Option<&String>,
+    fit: Fit,
+    max: (u32, u32),
+) -> Option<(u32, u32, Fit)> {
+    let pixels = |value: Option<&String>| {
+        value
+            .and_then(|value| value.trim().parse::<u32>().ok())
+            .filter(|pixels| *pixels > 0)
+    };
+    match (pixels(width), pixels(height)) {
+        (None, None) => None,
+        (Some(width), Some(height)) => Some((width.min(max.0), height.min(max.1), fit)),
+        (width, height) => Some((
+            width.map_or(max.0, |width| width.min(max.0)),
+            height.map_or(max.1, |height| height.min(max.1)),
+            Fit::Inside,
+        )),
+    }
+}
+
+pub fn shrink_outside_box(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let w = image.width();
+    let h = image.height();
+    if w > width && h > height {
+        // the side shrinking less decides, so that both still cover the box
+        let ratio = f64::max(
+            f64::from(width) / f64::from(w),
+            f64::from(height) / f64::from(h),
+        );
+        let w2 = ((f64::from(w) * ratio).round() as u32).max(1);
+        let h2 = ((f64::from(h) * ratio).round() as u32).max(1);
+        resize_exact(image, w2, h2)
+    } else {
+        image // keep as-is
+    }
+}
+
+// The largest centered part with the aspect ratio of the box, shrunk into it
+pub fn crop_cover(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let w = image.width();
+    let h = image.height();
+    let (w2, h2) = if u64::from(w) * u64::from(height) > u64::from(h) * u64::from(width) {
+        let w2 = (f64::from(h) * f64::from(width) / f64::from(height)).round() as u32;
+        (w2.clamp(1, w), h)
+    } else {
+        let h2 = (f64::from(w) * f64::from(height) / f64::from(width)).round() as u32;
+        (w, h2.clamp(1, h))
+    };
+    let cropped = match (w2, h2) == (w, h) {
+        true => image,
+        false => image.crop_imm((w - w2) / 2, (h - h2) / 2, w2, h2),
+    };
+    shrink_inside(cropped, width, height)
+}
+
+pub fn fit(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    mode: Fit,
+    color: Rgba<u8>,
+) -> DynamicImage {
+    match mode {
+        Fit::Cover => crop_cover(image, width, height),
+        Fit::Contain => pad(shrink_inside(image, width, height), width, height, color),
+        Fit::Fill if image.width() == width && image.height() == height => image,
+        Fit::Fill => resize_exact(image, width, height),
+        Fit::Inside => shrink_inside(image, width, height),
+        Fit::Outside => shrink_outside_box(image, width, height),
+    }
+}
+
+#[inline]
+pub fn fit_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    width: u32,
+    height: u32,
+    mode: Fit,
+    color: Rgba<u8>,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|img| (fit(img.0, width, height, mode, color), img.1))
+        .collect()
+}
+
+// `rotate=90|180|270`, clockwise
+pub fn parse_rotate(s: &str) -> Result<Orientation, String> {
+    match s.trim() {
+        "0" => Ok(Orientation::NoTransforms),
+        "90" => Ok(Orientation::Rotate90),
+        "180" => Ok(Orientation::Rotate180),
+        "270" => Ok(Orientation::Rotate270),
+        _ => Err(format!("invalid rotate: {s}")),
+    }
+}
+
+// `flip=h|v`, mirrored left to right or top to bottom
+pub fn parse_flip(s: &str) -> Result<Orientation, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "h" => Ok(Orientation::FlipHorizontal),
+        "v" => Ok(Orientation::FlipVertical),
+        _ => Err(format!("invalid flip: {s}")),
+    }
+}
+
+pub fn orient_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    orientation: Orientation,
+) -> Vec<(DynamicImage, Delay)> {
+    images
+        .into_iter()
+        .map(|(mut image, delay)| {
+            image.apply_orientation(orientation);
+            (image, delay)
+        })
+        .collect()
+}
+
+// `crop=x,y,w,h` in pixels, or `crop=smart` for the most detailed part
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crop {
+    Area(u32, u32, u32, u32),
+    Smart,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("smart") {
+            return Ok(Crop::Smart);
+        }
+        let area = s
+            .split(',')
+            .map(|value| value.trim().parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>();
+        match area.as_deref() {
+            Some(&[x, y, width, height]) if width > 0 && height > 0 => {
+                Ok(Crop::Area(x, y, width, height))
+            }
+            _ => Err(format!("invalid crop: {s}")),
+        }
+    }
+}
+
+// The same part of every frame, found on the first one for `smart` with the aspect ratio
+// of (width, height). Areas past the edges are cut to the image, kept as-is if none is left
+pub fn crop_vec(
+    images: Vec<(DynamicImage, Delay)>,
+    crop: Crop,
+    aspect: (u32, u32),
+) -> Vec<(DynamicImage, Delay)> {
+    let Some((first, _)) = images.first() else {
+        return images;
+    };
+    let (w, h) = (first.width(), first.height());
+    let (x, y, width, height) = match crop {
+        Crop::Area(x, y, width, height) if x < w && y < h => {
+            (x, y, width.min(w - x), height.min(h - y))
+        }
+        Crop::Area(..) => return images,
+        Crop::Smart => smart_area(first, aspect),
+    };
+    if (x, y, width, height) == (0, 0, w, h) {
+        return images;
+    }
+    images
+        .into_iter()
+        .map(|img| (img.0.crop_imm(x, y, width, height), img.1))
+        .collect()
+}
+
+// The largest area with the aspect ratio, slid along the side with room to spare to where
+// the histogram has the most entropy, e.g. a face rather than the plain background
+fn smart_area(image: &DynamicImage, aspect: (u32, u32)) -> (u32, u32, u32, u32) {
+    let (w, h) = (image.width(), image.height());
+    let (aspect_w, aspect_h) = (u64::from(aspect.0), u64::from(aspect.1));
+    let horizontal = u64::from(w) * aspect_h > u64::from(h) * aspect_w;
+    let (width, height) = match horizontal {
+        true => (((u64::from(h) * aspect_w / aspect_h) as u32).clamp(1, w), h),
+        false => (w, ((u64::from(w) * aspect_h / aspect_w) as u32).clamp(1, h)),
+    };
+    if (width, height) == (w, h) {
+        return (0, 0, w, h);
+    }
+
+    // Histograms of the rows or columns of a smaller copy, summed up while sliding
+    let sample = image
+        .thumbnail(SMART_CROP_SAMPLE, SMART_CROP_SAMPLE)
+        .into_luma8();
+    let (lines, length, window) = match horizontal {
+        true => (
+            sample.width(),
+            w,
+            (u64::from(width) * u64::from(sample.width()) / u64::from(w)) as u32,
+        ),
+        false => (
+            sample.height(),
+            h,
+            (u64::from(height) * u64::from(sample.height()) / u64::from(h)) as u32,
+        ),
+    };
+    let window = window.clamp(1, lines) as usize;
+    let mut histograms = vec![[0u32; 256]; lines as usize];
+    for (x, y, pixel) in sample.enumerate_pixels() {
+        let line = if horizontal { x } else { y };
+        histograms[line as usize][usize::from(pixel[0])] += 1;
+    }
+    let entropy = |histogram: &[u32; 256]| {
+        let total = f64::from(histogram.iter().sum::<u32>());
+        histogram
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = f64::from(*count) / total;
+                -p * p.log2()
+            })
+            .sum::<f64>()
+    };
+    let mut current = [0u32; 256];
+    for histogram in &histograms[..window] {
+        current.iter_mut().zip(histogram).for_each(|(a, b)| *a += b);
+    }
+    let (mut best, mut best_entropy) = (0, entropy(&current));
+    for start in 1..=histograms.len() - window {
+        let (gone, added) = (&histograms[start - 1], &histograms[start + window - 1]);
+        for (value, (gone, added)) in current.iter_mut().zip(gone.iter().zip(added)) {
+            *value = *value - gone + added;
+        }
+        let candidate = entropy(&current);
+        if candidate > best_entropy {
+            (best, best_entropy) = (start, candidate);
+        }
+    }
+
+    let slack = match horizontal {
+        true => w - width,
+        false => h - height,
+    };
+    let offset = (u64::from(best as u32) * u64::from(length) / u64::from(lines)) as u32;
+    match horizontal {
+        true => (offset.min(slack), 0, width, height),
+        false => (0, offset.min(slack), width, height),
+    }
+}
+
+// Misskey's badge for web push notifications: fit into 96x96 (enlarging too), flattened onto
+// black, greyscale, normalized and contrasted, then used as the alpha of a white image.
+// None when the result would be (almost) uniform
+pub fn badge(image: DynamicImage) -> Option<DynamicImage> {
+    let mut gray = GrayImage::new(image.width(), image.height());
+    for (x, y, pixel) in image.to_rgba8().enumerate_pixels() {
+        let [r, g, b, a] = pixel.0.map(f32::from);
+        let luma = (0.2126 * r + 0.7152 * g + 0.0722 * b) * a / 255.0;
+        gray.put_pixel(x, y, Luma([luma.round() as u8]));
+    }
+    let ratio = f64::from(BADGE_SIZE) / f64::from(image.width().max(image.height()));
+    let width = ((f64::from(image.width()) * ratio).round() as u32).clamp(1, BADGE_SIZE);
+    let height = ((f64::from(image.height()) * ratio).round() as u32).clamp(1, BADGE_SIZE);
+    let resized = imageops::resize(&gray, width, height, FilterType::Triangle);
+    let mut mask = GrayImage::new(BADGE_SIZE, BADGE_SIZE);
+    imageops::overlay(
+        &mut mask,
+        &resized,
+        i64::from((BADGE_SIZE - width) / 2),
+        i64::from((BADGE_SIZE - height) / 2),
+    );
+
+    // Stretch the 1st to 99th percentiles to the full range, as sharp's normalise
+    let mut histogram = [0u32; 256];
+    for pixel in mask.pixels() {
+        histogram[usize::from(pixel[0])] += 1;
+    }
+    let percentile = |fraction: f64| {
+        let target = (f64::from(BADGE_SIZE * BADGE_SIZE) * fraction) as u32;
+        let mut seen = 0;
+        histogram
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen > target
+            })
+            .unwrap_or(255) as f32
+    };
+    let (low, high) = (percentile(0.01), percentile(0.99));
+    let mut histogram = [0u32; 256];
+    for pixel in mask.pixels_mut() {
+        let mut value = f32::from(pixel[0]);
+        if high > low {
+            value = (value - low) * 255.0 / (high - low);
+        }
+        value = BADGE_CONTRAST * value - 128.0 * BADGE_CONTRAST + 128.0;
+        pixel[0] = value.round().clamp(0.0, 255.0) as u8;
+        histogram[usize::from(pixel[0])] += 1;
+    }
+
+    let total = f64::from(BADGE_SIZE * BADGE_SIZE);
+    let entropy: f64 = histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = f64::from(*count) / total;
+            -p * p.log2()
+        })
+        .sum();
+    if entropy < BADGE_MIN_ENTROPY {
+        return None;
+    }
+
+    let badge = RgbaImage::from_fn(BADGE_SIZE, BADGE_SIZE, |x, y| {
+        Rgba([255, 255, 255, mask.get_pixel(x, y)[0]])
+    });
+    Some(DynamicImage::ImageRgba8(badge))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,6 +686,105 @@ mod tests {
         assert_eq!(image.height(), 5);
     }
 
+    #[test]
+    fn test_trim_transparent() {
+        let mut frame1 = image::RgbaImage::new(20, 20);
+        frame1.put_pixel(5, 6, image::Rgba([255, 0, 0, 255]));
+        let mut frame2 = image::RgbaImage::new(20, 20);
+        frame2.put_pixel(10, 12, image::Rgba([0, 255, 0, 128]));
+        let images = trim_transparent_vec(vec![
+            (
+                DynamicImage::ImageRgba8(frame1),
+                Delay::from_numer_denom_ms(0, 1),
+            ),
+            (
+                DynamicImage::ImageRgba8(frame2),
+                Delay::from_numer_denom_ms(0, 1),
+            ),
+        ]);
+        for (image, _) in images {
+            assert_eq!(image.width(), 6);
+            assert_eq!(image.height(), 7);
+        }
+    }
+
+    #[test]
+    fn test_trim_transparent_skip() {
+        let empty = DynamicImage::ImageRgba8(image::RgbaImage::new(20, 20));
+        let images = trim_transparent_vec(vec![(empty, Delay::from_numer_denom_ms(0, 1))]);
+        assert_eq!(images[0].0.width(), 20);
+
+        let opaque = DynamicImage::ImageRgb8(image::RgbImage::new(20, 10));
+        let images = trim_transparent_vec(vec![(opaque, Delay::from_numer_denom_ms(0, 1))]);
+        assert_eq!(images[0].0.height(), 10);
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("transparent"), Some(TRANSPARENT));
+        assert_eq!(parse_color("fff"), Some(Rgba([255, 255, 255, 255])));
+        assert_eq!(parse_color("#102030"), Some(Rgba([16, 32, 48, 255])));
+        assert_eq!(parse_color("10203080"), Some(Rgba([16, 32, 48, 128])));
+        assert_eq!(parse_color("white"), None);
+        assert_eq!(parse_color("ffé"), None);
+    }
+
+    #[test]
+    fn test_pad() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(10, 4, Rgba([255, 0, 0, 255])));
+        let image = pad(image, 10, 10, Rgba([255, 255, 255, 255])).into_rgba8();
+        assert_eq!(image.dimensions(), (10, 10));
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(image.get_pixel(0, 3), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(0, 6), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(0, 7), &Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 1, |x, _| {
+            Rgba([255, 0, 0, [0, 128, 255][x as usize]])
+        }));
+        let image = flatten(image, Rgba([255, 255, 255, 255]));
+        let image = image.as_rgb8().unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [255, 127, 127]);
+        assert_eq!(image.get_pixel(2, 0).0, [255, 0, 0]);
+
+        // Already opaque
+        let image = DynamicImage::ImageLuma8(image::GrayImage::new(2, 2));
+        assert!(matches!(
+            flatten(image, TRANSPARENT),
+            DynamicImage::ImageLuma8(_)
+        ));
+    }
+
+    #[test]
+    fn test_shrink_to_pixels() {
+        // a very tall comic
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(100, 4000));
+        let image = shrink_to_pixels(image, 10_000);
+        assert!(u64::from(image.width()) * u64::from(image.height()) <= 10_000);
+        assert_eq!(image.width(), 15);
+        assert_eq!(image.height(), 632);
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(100, 100));
+        let image = shrink_to_pixels(image, 10_000);
+        assert_eq!(image.width(), 100);
+    }
+
+    #[test]
+    fn test_crop_top() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(100, 1000));
+        let image = crop_top(image, 2.0);
+        assert_eq!(image.width(), 100);
+        assert_eq!(image.height(), 200);
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(100, 150));
+        let image = crop_top(image, 2.0);
+        assert_eq!(image.height(), 150);
+    }
+
     #[test]
     fn test_shrink_outside_skip() {
         let image = DynamicImage::ImageRgba8(image::RgbaImage::new(18, 9));
@@ -89,6 +793,64 @@ mod tests {
         assert_eq!(image.height(), 9);
     }
 
+    #[test]
+    fn test_resize_premultiplied_alpha() {
+        // Opaque red next to transparent black, the black must not bleed into the result
+        let mut buffer = image::RgbaImage::new(2, 2);
+        buffer.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        buffer.put_pixel(0, 1, image::Rgba([255, 0, 0, 255]));
+        let image = resize_exact(DynamicImage::ImageRgba8(buffer), 1, 1).into_rgba8();
+        let pixel = image.get_pixel(0, 0);
+        assert_eq!(pixel[0], 255);
+        assert_eq!(pixel[1], 0);
+        assert_eq!(pixel[2], 0);
+        assert!(pixel[3] > 120 && pixel[3] < 135);
+    }
+
+    #[test]
+    fn test_resize_premultiplied_alpha_fixture() {
+        // White circle with antialiased edges, transparent pixels are black
+        let image = image::load_from_memory(include_bytes!("testdata/circle.png")).unwrap();
+        for size in [32, 16, 7] {
+            let resized = resize_exact(image.clone(), size, size).into_rgba8();
+            for pixel in resized.pixels().filter(|pixel| pixel[3] > 0) {
+                assert!(
+                    pixel[0] > 250 && pixel[1] > 250 && pixel[2] > 250,
+                    "{pixel:?}"
+                );
+            }
+        }
+        let blurred = blur_vec(vec![(image, Delay::from_numer_denom_ms(0, 1))], 3.0);
+        for pixel in blurred[0]
+            .0
+            .to_rgba8()
+            .pixels()
+            .filter(|pixel| pixel[3] > 0)
+        {
+            assert!(
+                pixel[0] > 250 && pixel[1] > 250 && pixel[2] > 250,
+                "{pixel:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resize_keep_color_type() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(4, 4));
+        let image = resize_exact(image, 2, 2);
+        assert_eq!(image.color(), ColorType::Rgb8);
+
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4));
+        let image = resize_exact(image, 2, 2);
+        assert_eq!(image.color(), ColorType::Rgba8);
+
+        let opaque = image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]));
+        let opaque = DynamicImage::ImageRgba8(opaque).into_rgba16();
+        let image = resize_exact(DynamicImage::ImageRgba16(opaque), 2, 2);
+        assert_eq!(image.color(), ColorType::Rgba16);
+        assert_eq!(image.into_rgba8().get_pixel(1, 1).0, [10, 20, 30, 255]);
+    }
+
     #[test]
     fn test_shrink_outside_resize() {
         let image = DynamicImage::ImageRgba8(image::RgbaImage::new(24, 12));
@@ -96,4 +858,169 @@ mod tests {
         assert_eq!(image.width(), 20);
         assert_eq!(image.height(), 10);
     }
+
+    #[test]
+    fn test_badge() {
+        // A dark circle on white, white where the circle isn't
+        let image = RgbaImage::from_fn(40, 20, |x, y| {
+            let (dx, dy) = (x as i32 - 20, y as i32 - 10);
+            match dx * dx + dy * dy < 36 {
+                true => Rgba([0, 0, 0, 255]),
+                false => Rgba([255, 255, 255, 255]),
+            }
+        });
+        let mask = badge(DynamicImage::ImageRgba8(image)).unwrap().into_rgba8();
+        assert_eq!(mask.dimensions(), (96, 96));
+        assert!(mask.pixels().all(|pixel| pixel.0[..3] == [255, 255, 255]));
+        assert_eq!(mask.get_pixel(48, 0)[3], 0); // letterboxed
+        assert_eq!(mask.get_pixel(10, 48)[3], 255);
+        assert_eq!(mask.get_pixel(48, 48)[3], 0);
+
+        // Transparent is black, so nothing to show
+        assert!(badge(DynamicImage::ImageRgba8(RgbaImage::new(20, 20))).is_none());
+        let gray = RgbaImage::from_pixel(20, 20, Rgba([128, 128, 128, 255]));
+        assert!(badge(DynamicImage::ImageRgba8(gray)).is_none());
+    }
+
+    #[test]
+    fn test_requested_size() {
+        let size = |w: Option<&str>, h: Option<&str>| {
+            let (w, h) = (w.map(str::to_string), h.map(str::to_string));
+            requested_size(w.as_ref(), h.as_ref(), Fit::Cover, (1000, 800))
+        };
+        assert_eq!(size(Some("300"), Some("200")), Some((300, 200, Fit::Cover)));
+        assert_eq!(size(Some("300"), None), Some((300, 800, Fit::Inside)));
+        assert_eq!(size(None, Some("200")), Some((1000, 200, Fit::Inside)));
+        assert_eq!(
+            size(Some("5000"), Some("5000")),
+            Some((1000, 800, Fit::Cover))
+        );
+        assert_eq!(size(None, None), None);
+        assert_eq!(size(Some("0"), Some("-1")), None);
+        assert_eq!(
+            size(Some("abc"), Some("200")),
+            Some((1000, 200, Fit::Inside))
+        );
+    }
+
+    #[test]
+    fn test_fit() {
+        let image = || DynamicImage::ImageRgba8(RgbaImage::new(400, 200));
+        let dimensions = |mode: &str| {
+            let image = fit(image(), 100, 100, mode.parse().unwrap(), TRANSPARENT);
+            (image.width(), image.height())
+        };
+        assert_eq!(dimensions("cover"), (100, 100));
+        assert_eq!(dimensions("contain"), (100, 100));
+        assert_eq!(dimensions("fill"), (100, 100));
+        assert_eq!(dimensions("inside"), (100, 50));
+        assert_eq!(dimensions("Outside"), (200, 100));
+        assert!("crop".parse::<Fit>().is_err());
+
+        // Never enlarged, but still cropped to the aspect ratio
+        let image = fit(image(), 1000, 1000, Fit::Cover, TRANSPARENT);
+        assert_eq!((image.width(), image.height()), (200, 200));
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(50, 50));
+        let image = fit(image, 100, 100, Fit::Outside, TRANSPARENT);
+        assert_eq!((image.width(), image.height()), (50, 50));
+    }
+
+    #[test]
+    fn test_orient() {
+        // Red in the top left corner of a landscape image
+        let mut image = image::RgbImage::new(4, 2);
+        image.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        let red = |images: &[(DynamicImage, Delay)]| {
+            let image = images[0].0.as_rgb8().unwrap();
+            let (x, y, _) = image
+                .enumerate_pixels()
+                .find(|(_, _, pixel)| pixel[0] == 255)
+                .unwrap();
+            (image.width(), image.height(), x, y)
+        };
+        let orient = |rotate: &str, flip: Option<&str>| {
+            let images = vec![(
+                DynamicImage::from(image.clone()),
+                Delay::from_numer_denom_ms(0, 1),
+            )];
+            let images = orient_vec(images, parse_rotate(rotate).unwrap());
+            let images = match flip {
+                Some(flip) => orient_vec(images, parse_flip(flip).unwrap()),
+                None => images,
+            };
+            red(&images)
+        };
+
+        assert_eq!(orient("0", None), (4, 2, 0, 0));
+        assert_eq!(orient("90", None), (2, 4, 1, 0));
+        assert_eq!(orient("180", None), (4, 2, 3, 1));
+        assert_eq!(orient("270", None), (2, 4, 0, 3));
+        assert_eq!(orient("0", Some("h")), (4, 2, 3, 0));
+        assert_eq!(orient("0", Some("V")), (4, 2, 0, 1));
+        assert_eq!(orient("90", Some("h")), (2, 4, 0, 0));
+
+        assert!(parse_rotate("45").is_err());
+        assert!(parse_rotate("-90").is_err());
+        assert!(parse_flip("x").is_err());
+    }
+
+    #[test]
+    fn test_blur() {
+        // A red square on transparency, spread out without darkening
+        let image = image::RgbaImage::from_fn(20, 20, |x, y| match (x, y) {
+            (5..15, 5..15) => Rgba([255, 0, 0, 255]),
+            _ => TRANSPARENT,
+        });
+        let images = vec![(
+            DynamicImage::ImageRgba8(image),
+            Delay::from_numer_denom_ms(0, 1),
+        )];
+        let blurred = blur_vec(images.clone(), parse_blur("3").unwrap());
+        let blurred = blurred[0].0.as_rgba8().unwrap();
+        let edge = blurred.get_pixel(4, 10);
+        assert!(edge[3] > 0 && edge[3] < 255);
+        assert_eq!(&edge.0[..3], &[255, 0, 0]);
+        assert!(blurred.get_pixel(10, 10)[3] > edge[3]);
+
+        let unchanged = blur_vec(images.clone(), parse_blur("0").unwrap());
+        assert_eq!(unchanged[0].0, images[0].0);
+        assert_eq!(parse_blur("1e9"), Ok(MAX_BLUR_SIGMA));
+        assert!(parse_blur("-1").is_err());
+        assert!(parse_blur("NaN").is_err());
+        assert!(parse_blur("strong").is_err());
+    }
+
+    #[test]
+    fn test_crop() {
+        let frames = || {
+            // Plain, except for some detail in the right part
+            let image = RgbaImage::from_fn(300, 100, |x, y| match x >= 200 {
+                true => Rgba([(x * 7 % 256) as u8, (y * 13 % 256) as u8, 0, 255]),
+                false => Rgba([255, 255, 255, 255]),
+            });
+            vec![(
+                DynamicImage::ImageRgba8(image),
+                Delay::from_numer_denom_ms(0, 1),
+            )]
+        };
+        let area = |crop: &str, aspect| {
+            let images = crop_vec(frames(), crop.parse().unwrap(), aspect);
+            (images[0].0.width(), images[0].0.height())
+        };
+        assert_eq!(area("10,20,50,30", (1, 1)), (50, 30));
+        assert_eq!(area("280,90,50,30", (1, 1)), (20, 10));
+        assert_eq!(area("300,0,50,30", (1, 1)), (300, 100));
+        assert_eq!(area("smart", (1, 1)), (100, 100));
+        assert_eq!(area("smart", (3, 1)), (300, 100));
+
+        let image = frames()[0].0.clone();
+        assert_eq!(smart_area(&image, (1, 1)), (200, 0, 100, 100));
+        let (x, y, width, height) = smart_area(&image, (1, 2));
+        assert!(x >= 200 && (y, width, height) == (0, 50, 100));
+
+        assert!("smart".parse::<Crop>().is_ok());
+        assert!("1,2,3".parse::<Crop>().is_err());
+        assert!("0,0,0,10".parse::<Crop>().is_err());
+        assert!("attention".parse::<Crop>().is_err());
+    }
 }
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::debug;
+
+// What the request asked for, as the savings differ a lot between them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeMode {
+    Emoji,
+    Avatar,
+    Static,
+    Preview,
+    Original, // only converted
+}
+
+impl EncodeMode {
+    pub const ALL: [EncodeMode; 5] = [
+        EncodeMode::Emoji,
+        EncodeMode::Avatar,
+        EncodeMode::Static,
+        EncodeMode::Preview,
+        EncodeMode::Original,
+    ];
+
+    // In the same order as processing checks them
+    pub fn of(query: &HashMap<String, String>) -> Self {
+        if query.contains_key("emoji") {
+            EncodeMode::Emoji
+        } else if query.contains_key("avatar") {
+            EncodeMode::Avatar
+        } else if query.contains_key("static") {
+            EncodeMode::Static
+        } else if query.contains_key("preview") {
+            EncodeMode::Preview
+        } else {
+            EncodeMode::Original
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncodeMode::Emoji => "emoji",
+            EncodeMode::Avatar => "avatar",
+            EncodeMode::Static => "static",
+            EncodeMode::Preview => "preview",
+            EncodeMode::Original => "original",
+        }
+    }
+}
+
+struct Counters {
+    encoded: AtomicU64,
+    source_bytes: AtomicU64,
+    output_bytes: AtomicU64,
+}
+
+static SAVINGS: [Counters; EncodeMode::ALL.len()] = [const {
+    Counters {
+        encoded: AtomicU64::new(0),
+        source_bytes: AtomicU64::new(0),
+        output_bytes: AtomicU64::new(0),
+    }
+}; EncodeMode::ALL.len()];
+
+// Bytes downloaded from origins against the bytes encoded from them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Savings {
+    pub encoded: u64,
+    pub source_bytes: u64,
+    pub output_bytes: u64,
+}
+
+impl Savings {
+    pub fn since(&self, earlier: &Savings) -> Savings {
+        Savings {
+            encoded: self.encoded - earlier.encoded,
+            source_bytes: self.source_bytes - earlier.source_bytes,
+            output_bytes: self.output_bytes - earlier.output_bytes,
+        }
+    }
+
+    // Negative if the output is larger, None before anything was encoded
+    pub fn saved_ratio(&self) -> Option<f64> {
+        (self.source_bytes > 0).then(|| 1.0 - self.output_bytes as f64 / self.source_bytes as f64)
+    }
+}
+
+pub fn record(mode: EncodeMode, source_bytes: u64, output_bytes: u64, url: Option<&String>) {
+    let counters = &SAVINGS[mode as usize];
+    counters.encoded.fetch_add(1, Ordering::Relaxed);
+    counters
+        .source_bytes
+        .fetch_add(source_bytes, Ordering::Relaxed);
+    counters
+        .output_bytes
+        .fetch_add(output_bytes, Ordering::Relaxed);
+    debug!(
+        "Encoded {}: {source_bytes} -> {output_bytes} bytes: {url:?}",
+        mode.as_str()
+    );
+}
+
+// Per mode since start, in the order of EncodeMode::ALL
+pub fn encode_savings() -> [Savings; EncodeMode::ALL.len()] {
+    EncodeMode::ALL.map(|mode| {
+        let counters = &SAVINGS[mode as usize];
+        Savings {
+            encoded: counters.encoded.load(Ordering::Relaxed),
+            source_bytes: counters.source_bytes.load(Ordering::Relaxed),
+            output_bytes: counters.output_bytes.load(Ordering::Relaxed),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_savings() {
+        let query = |key: &str| HashMap::from([(key.to_string(), "1".to_string())]);
+        assert_eq!(EncodeMode::of(&query("avatar")), EncodeMode::Avatar);
+        assert_eq!(EncodeMode::of(&query("url")), EncodeMode::Original);
+
+        let before = encode_savings();
+        record(EncodeMode::Preview, 1000, 250, None);
+        let savings = encode_savings()[EncodeMode::Preview as usize]
+            .since(&before[EncodeMode::Preview as usize]);
+        assert_eq!(savings.encoded, 1);
+        assert_eq!(savings.saved_ratio(), Some(0.75));
+        assert_eq!(Savings::default().saved_ratio(), None);
+    }
+}
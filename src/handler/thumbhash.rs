@@ -0,0 +1,199 @@
+use image::DynamicImage;
+use std::f64::consts::PI;
+
+const MAX_SIZE: u32 = 100; // larger is slower for the same hash
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// A ThumbHash (https://evanw.github.io/thumbhash/) of the image, which keeps its alpha and
+// aspect ratio, unlike a BlurHash
+pub fn thumb_hash(image: &DynamicImage) -> Vec<u8> {
+    let image = match image.width() > MAX_SIZE || image.height() > MAX_SIZE {
+        true => image.thumbnail(MAX_SIZE, MAX_SIZE),
+        false => image.clone(),
+    };
+    let rgba = image.to_rgba8();
+    encode(rgba.width() as usize, rgba.height() as usize, rgba.as_raw())
+}
+
+// DC (constant) and normalized AC (varying) terms of the DCT, with the scale of the latter
+fn encode_channel(
+    channel: &[f64],
+    (w, h): (usize, usize),
+    (nx, ny): (usize, usize),
+) -> (f64, Vec<f64>, f64) {
+    let (mut dc, mut ac, mut scale) = (0.0, Vec::new(), 0.0f64);
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            let fx: Vec<f64> = (0..w)
+                .map(|x| (PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos())
+                .collect();
+            let mut f = 0.0;
+            for y in 0..h {
+                let fy = (PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                for x in 0..w {
+                    f += channel[x + y * w] * fx[x] * fy;
+                }
+            }
+            f /= (w * h) as f64;
+            if cx > 0 || cy > 0 {
+                ac.push(f);
+                scale = scale.max(f.abs());
+            } else {
+                dc = f;
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        for f in &mut ac {
+            *f = 0.5 + 0.5 / scale * *f;
+        }
+    }
+    (dc, ac, scale)
+}
+
+// As the reference implementation, for images of at most 100x100
+fn encode(w: usize, h: usize, rgba: &[u8]) -> Vec<u8> {
+    let pixels = || {
+        rgba.chunks_exact(4).map(|pixel| {
+            let alpha = f64::from(pixel[3]) / 255.0;
+            let [r, g, b] = [0, 1, 2].map(|i| alpha / 255.0 * f64::from(pixel[i]));
+            (r, g, b, alpha)
+        })
+    };
+
+    // The average color, what transparent parts are composited on
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0, 0.0, 0.0, 0.0);
+    for (r, g, b, alpha) in pixels() {
+        avg_r += r;
+        avg_g += g;
+        avg_b += b;
+        avg_a += alpha;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < (w * h) as f64;
+    let l_limit = if has_alpha { 5.0 } else { 7.0 }; // fewer luminance bits for the alpha
+    let longest = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / longest).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / longest).round() as usize).max(1);
+
+    // Luminance, yellow - blue, red - green and alpha
+    let (mut l, mut p, mut q, mut a) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for (r, g, b, alpha) in pixels() {
+        let r = avg_r * (1.0 - alpha) + r;
+        let g = avg_g * (1.0 - alpha) + g;
+        let b = avg_b * (1.0 - alpha) + b;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let size = (w, h);
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, size, (lx.max(3), ly.max(3)));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, size, (3, 3));
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, size, (3, 3));
+    let (a_dc, a_ac, a_scale) = match has_alpha {
+        true => encode_channel(&a, size, (5, 5)),
+        false => (0.0, Vec::new(), 0.0),
+    };
+
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | u32::from(has_alpha) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | u32::from(is_landscape) << 15;
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+    if has_alpha {
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+    }
+
+    // Two varying factors in each byte, low nibble first
+    let factors = [l_ac, p_ac, q_ac, a_ac].concat();
+    for pair in factors.chunks(2) {
+        let nibble = |f: f64| (15.0 * f).round() as u8;
+        hash.push(nibble(pair[0]) | pair.get(1).map_or(0, |f| nibble(*f) << 4));
+    }
+    hash
+}
+
+// Standard, padded
+pub fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+pub fn thumb_hash_json(hash: &[u8], width: u32, height: u32) -> String {
+    format!(
+        "{{\"thumbhash\":\"{}\",\"width\":{width},\"height\":{height}}}",
+        base64(hash)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_thumb_hash() {
+        // Checked against the reference implementation in JavaScript
+        let image = RgbaImage::from_fn(8, 6, |x, y| {
+            Rgba([
+                (x * 32) as u8,
+                (y * 40) as u8,
+                128,
+                if x < 2 { 0 } else { 255 },
+            ])
+        });
+        let hash = thumb_hash(&image.into());
+        assert_eq!(base64(&hash), "31eGJJI7w4mgiYd4OAx6w18IUoh3d4h4Bw==");
+        let image = RgbaImage::from_fn(10, 7, |x, y| {
+            Rgba([(x * 25) as u8, 64, (x * y * 3) as u8, 255])
+        });
+        let hash = thumb_hash(&image.into());
+        assert_eq!(base64(&hash), "UlkOLZhwd4eFiIiHiHiIh3VPCIeI");
+
+        // Downscaled first
+        let image = RgbaImage::from_pixel(300, 150, Rgba([255, 0, 0, 255]));
+        assert_eq!(thumb_hash(&image.into())[4] >> 7, 1); // landscape
+
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(
+            thumb_hash_json(b"foo", 2, 1),
+            r#"{"thumbhash":"Zm9v","width":2,"height":1}"#
+        );
+    }
+}
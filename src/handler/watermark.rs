@@ -0,0 +1,136 @@
+use super::codecs::Frames;
+use super::processors::shrink_inside;
+use image::{DynamicImage, RgbaImage, imageops};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+const MAX_SHARE: u32 = 4; // of the width and height of the image, at most
+const MARGIN_SHARE: u32 = 50; // of the shorter side, from the edges
+
+// Where on the image the watermark goes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+impl FromStr for WatermarkPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "top-left" => Ok(WatermarkPosition::TopLeft),
+            "top-right" => Ok(WatermarkPosition::TopRight),
+            "bottom-left" => Ok(WatermarkPosition::BottomLeft),
+            "bottom-right" => Ok(WatermarkPosition::BottomRight),
+            "center" => Ok(WatermarkPosition::Center),
+            _ => Err(format!("unknown position: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for WatermarkPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatermarkPosition::TopLeft => write!(f, "top-left"),
+            WatermarkPosition::TopRight => write!(f, "top-right"),
+            WatermarkPosition::BottomLeft => write!(f, "bottom-left"),
+            WatermarkPosition::BottomRight => write!(f, "bottom-right"),
+            WatermarkPosition::Center => write!(f, "center"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Watermark {
+    pub image: Arc<RgbaImage>,
+    pub position: WatermarkPosition,
+    pub opacity: u8,  // 0-100
+    pub always: bool, // on every processed image, or only with ?watermark=1
+}
+
+// The watermark as it goes on an image of this size, shrunk to fit and faded
+fn overlay_for(watermark: &Watermark, width: u32, height: u32) -> RgbaImage {
+    let image = DynamicImage::ImageRgba8(watermark.image.as_ref().clone());
+    let mut overlay = shrink_inside(
+        image,
+        (width / MAX_SHARE).max(1),
+        (height / MAX_SHARE).max(1),
+    )
+    .into_rgba8();
+    for pixel in overlay.pixels_mut() {
+        pixel[3] = ((u32::from(pixel[3]) * u32::from(watermark.opacity) + 50) / 100) as u8;
+    }
+    overlay
+}
+
+// Every frame the same, keeping images without alpha opaque so that the format stays
+pub fn watermark_vec(images: Frames, watermark: &Watermark) -> Frames {
+    let Some((first, _)) = images.first() else {
+        return images;
+    };
+    let (width, height) = (first.width(), first.height());
+    let overlay = overlay_for(watermark, width, height);
+    let margin = i64::from(width.min(height) / MARGIN_SHARE);
+    let (free_x, free_y) = (
+        i64::from(width) - i64::from(overlay.width()),
+        i64::from(height) - i64::from(overlay.height()),
+    );
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (free_x - margin, margin),
+        WatermarkPosition::BottomLeft => (margin, free_y - margin),
+        WatermarkPosition::BottomRight => (free_x - margin, free_y - margin),
+        WatermarkPosition::Center => (free_x / 2, free_y / 2),
+    };
+
+    images
+        .into_iter()
+        .map(|(image, delay)| {
+            let has_alpha = image.color().has_alpha();
+            let mut canvas = image.into_rgba8();
+            imageops::overlay(&mut canvas, &overlay, x, y);
+            let canvas = DynamicImage::ImageRgba8(canvas);
+            match has_alpha {
+                true => (canvas, delay),
+                false => (DynamicImage::ImageRgb8(canvas.into_rgb8()), delay),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, Rgb, RgbImage, Rgba};
+
+    #[test]
+    fn test_watermark() {
+        let watermark = Watermark {
+            image: Arc::new(RgbaImage::from_pixel(40, 20, Rgba([255, 255, 255, 255]))),
+            position: WatermarkPosition::BottomRight,
+            opacity: 50,
+            always: false,
+        };
+        let image = DynamicImage::from(RgbImage::from_pixel(100, 100, Rgb([0, 0, 0])));
+        let images = vec![(image, Delay::from_numer_denom_ms(0, 1))];
+        let marked = watermark_vec(images, &watermark);
+        let marked = marked[0].0.as_rgb8().unwrap();
+
+        // Shrunk to a quarter of the width, 2px from the bottom right corner, half opaque
+        assert_eq!(marked.get_pixel(97, 97).0, [128, 128, 128]);
+        assert_eq!(marked.get_pixel(73, 88).0, [128, 128, 128]);
+        assert_eq!(marked.get_pixel(72, 97).0, [0, 0, 0]);
+        assert_eq!(marked.get_pixel(98, 98).0, [0, 0, 0]);
+        assert_eq!(marked.get_pixel(50, 50).0, [0, 0, 0]);
+
+        assert_eq!("Top-Left".parse(), Ok(WatermarkPosition::TopLeft));
+        assert_eq!(WatermarkPosition::Center.to_string(), "center");
+        assert!("middle".parse::<WatermarkPosition>().is_err());
+    }
+}
@@ -0,0 +1,599 @@
+use crate::guard::MutexExt;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::guard::TempFile;
+use crate::quarantine::sha256_hex;
+use futures_util::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+const MAX_URL_KEY_LEN: usize = 128; // longer ones (e.g. with signed tokens) are hashed
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_FILE_NAME_LEN: usize = 200; // NAME_MAX is 255 on common filesystems, with room for .tmp
+#[cfg(not(target_arch = "wasm32"))]
+const HASHED_FILE_PREFIX: &str = "sha256-"; // not hex, so never a decodable name
+
+#[derive(Debug)]
+pub struct KvError(pub String);
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KV store error: {}", self.0)
+    }
+}
+
+pub type KvResult<T> = Result<T, KvError>;
+
+// Runtime state (quarantine lists, rate limits, circuit breakers, ...) that replicas of
+// a cluster should share instead of each one learning it independently
+pub trait KvStore: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<Option<String>>>;
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<()>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<()>>;
+
+    // All (unexpired) keys starting with the prefix
+    fn keys<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, KvResult<Vec<String>>>;
+
+    // Add to a counter (created as 0), the TTL only applies when it's created
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<i64>>;
+}
+
+// Same resource, same key (e.g. the host's case and fragments don't matter)
+pub fn normalize_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            parsed.set_fragment(None);
+            parsed.into()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+// Bounded key for a URL, so that file names and Redis keys stay short. As hashes may
+// collide, the full (normalized) URL should be kept along with what's stored under it
+pub fn url_key(url: &str) -> String {
+    let url = normalize_url(url);
+    if url.len() <= MAX_URL_KEY_LEN {
+        url
+    } else {
+        format!("sha256:{}", sha256_hex(url.as_bytes()))
+    }
+}
+
+// Where to keep the shared state: `memory`, a directory path (or `file:///path`),
+// or `redis://host:port/db` (redis feature)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum KvConfig {
+    #[default]
+    Memory,
+    File(PathBuf),
+    Redis(String),
+}
+
+impl FromStr for KvConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s == "memory" {
+            Ok(KvConfig::Memory)
+        } else if s.starts_with("redis://") || s.starts_with("rediss://") {
+            Ok(KvConfig::Redis(s.to_string()))
+        } else if let Some(path) = s.strip_prefix("file://") {
+            Ok(KvConfig::File(PathBuf::from(path)))
+        } else if s.starts_with('/') || s.starts_with('.') {
+            Ok(KvConfig::File(PathBuf::from(s)))
+        } else {
+            Err(format!("invalid KV store: {s}"))
+        }
+    }
+}
+
+impl fmt::Display for KvConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvConfig::Memory => write!(f, "memory"),
+            KvConfig::File(path) => write!(f, "file://{}", path.display()),
+            KvConfig::Redis(url) => match url::Url::parse(url) {
+                // Don't leak the password into logs
+                Ok(mut url) if url.password().is_some() => {
+                    let _ = url.set_password(Some("***"));
+                    write!(f, "{url}")
+                }
+                _ => write!(f, "{url}"),
+            },
+        }
+    }
+}
+
+impl KvConfig {
+    pub async fn open(&self) -> KvResult<Arc<dyn KvStore>> {
+        match self {
+            KvConfig::Memory => Ok(Arc::new(MemoryStore::default())),
+            #[cfg(not(target_arch = "wasm32"))]
+            KvConfig::File(path) => Ok(Arc::new(FileStore::open(path.clone()).await?)),
+            #[cfg(feature = "redis")]
+            KvConfig::Redis(url) => Ok(Arc::new(RedisStore::open(url).await?)),
+            #[allow(unreachable_patterns)]
+            _ => Err(KvError(format!(
+                "{self} is not supported by enabled features"
+            ))),
+        }
+    }
+}
+
+fn expiry(ttl: Option<Duration>) -> Option<Instant> {
+    ttl.map(|ttl| Instant::now() + ttl)
+}
+
+fn is_expired(expires_at: Option<Instant>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= Instant::now())
+}
+
+// Process local, for single instance deployments
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (String, Option<Instant>)>>,
+}
+
+impl MemoryStore {
+    fn get_sync(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock_or_recover();
+        match entries.get(key) {
+            Some((_, expires_at)) if is_expired(*expires_at) => {
+                entries.remove(key);
+                None
+            }
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<Option<String>>> {
+        Box::pin(async move { Ok(self.get_sync(key)) })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<()>> {
+        Box::pin(async move {
+            self.entries
+                .lock_or_recover()
+                .insert(key.to_string(), (value.to_string(), expiry(ttl)));
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<()>> {
+        Box::pin(async move {
+            self.entries.lock_or_recover().remove(key);
+            Ok(())
+        })
+    }
+
+    fn keys<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, KvResult<Vec<String>>> {
+        Box::pin(async move {
+            let mut entries = self.entries.lock_or_recover();
+            entries.retain(|_, (_, expires_at)| !is_expired(*expires_at));
+            Ok(entries
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<i64>> {
+        Box::pin(async move {
+            let mut entries = self.entries.lock_or_recover();
+            let entry = entries
+                .entry(key.to_string())
+                .or_insert_with(|| ("0".to_string(), expiry(ttl)));
+            if is_expired(entry.1) {
+                *entry = ("0".to_string(), expiry(ttl));
+            }
+            let value = entry.0.parse::<i64>().unwrap_or(0) + delta;
+            entry.0 = value.to_string();
+            Ok(value)
+        })
+    }
+}
+
+// One file per key in a directory, can be shared by replicas on the same host or volume.
+// Counters are only atomic within a process.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileStore {
+    dir: PathBuf,
+    counter_lock: tokio::sync::Mutex<()>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileStore {
+    pub async fn open(dir: PathBuf) -> KvResult<Self> {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|err| KvError(format!("failed to create {}: {err}", dir.display())))?;
+        Ok(Self {
+            dir,
+            counter_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // Keys may contain anything, so file names are hex encoded, or hashed for long keys
+    fn path(&self, key: &str) -> PathBuf {
+        let name: String = if Self::is_hashed(key) {
+            format!("{HASHED_FILE_PREFIX}{}", sha256_hex(key.as_bytes()))
+        } else {
+            key.bytes().map(|byte| format!("{byte:02x}")).collect()
+        };
+        self.dir.join(name)
+    }
+
+    fn is_hashed(key: &str) -> bool {
+        key.len() * 2 > MAX_FILE_NAME_LEN
+    }
+
+    fn decode_name(name: &str) -> Option<String> {
+        let bytes = (0..name.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        String::from_utf8(bytes).ok()
+    }
+
+    async fn hashed_key(path: &std::path::Path) -> Option<String> {
+        let content = tokio::fs::read_to_string(path).await.ok()?;
+        content.split('\n').nth(1).map(str::to_string)
+    }
+
+    // Stored as `<expires at, unix ms or 0>\n<value>`, with the key on a line between them
+    // if hashed, to list it and to tell collisions apart
+    async fn read(&self, key: &str) -> KvResult<Option<(u128, String)>> {
+        let path = self.path(key);
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(KvError(format!("failed to read {key}: {err}"))),
+        };
+        let (expires_at, mut value) = content.split_once('\n').unwrap_or(("0", &content));
+        if Self::is_hashed(key) {
+            match value.split_once('\n') {
+                Some((stored, rest)) if stored == key => value = rest,
+                _ => return Ok(None), // a collision, as good as missing
+            }
+        }
+        let expires_at: u128 = expires_at.parse().unwrap_or(0);
+        if expires_at != 0 && expires_at <= unix_millis(Duration::ZERO) {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Ok(None);
+        }
+        Ok(Some((expires_at, value.to_string())))
+    }
+
+    async fn write(&self, key: &str, value: &str, expires_at: u128) -> KvResult<()> {
+        // Write then rename, so that readers never see a partial file
+        let path = self.path(key);
+        let temp = TempFile::new(path.with_extension("tmp"));
+        let content = if Self::is_hashed(key) {
+            format!("{expires_at}\n{key}\n{value}")
+        } else {
+            format!("{expires_at}\n{value}")
+        };
+        let result = match tokio::fs::write(temp.path(), content).await {
+            Ok(()) => tokio::fs::rename(temp.path(), &path).await,
+            Err(err) => Err(err),
+        };
+        if result.is_ok() {
+            temp.keep();
+        }
+        result.map_err(|err| KvError(format!("failed to write {key}: {err}")))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_millis(after: Duration) -> u128 {
+    (std::time::SystemTime::now() + after)
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl KvStore for FileStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<Option<String>>> {
+        Box::pin(async move { Ok(self.read(key).await?.map(|(_, value)| value)) })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<()>> {
+        Box::pin(self.write(key, value, ttl.map_or(0, unix_millis)))
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(KvError(format!("failed to delete {key}: {err}")))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn keys<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, KvResult<Vec<String>>> {
+        Box::pin(async move {
+            let mut dir = tokio::fs::read_dir(&self.dir)
+                .await
+                .map_err(|err| KvError(format!("failed to list keys: {err}")))?;
+            let mut keys = Vec::new();
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                let key = if name.starts_with(HASHED_FILE_PREFIX) && !name.ends_with(".tmp") {
+                    Self::hashed_key(&entry.path()).await
+                } else {
+                    Self::decode_name(&name)
+                };
+                if let Some(key) = key
+                    && key.starts_with(prefix)
+                    && self.read(&key).await?.is_some()
+                {
+                    keys.push(key);
+                }
+            }
+            Ok(keys)
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<i64>> {
+        Box::pin(async move {
+            let _guard = self.counter_lock.lock().await;
+            // Existing counters keep their expiry
+            let (expires_at, value) = match self.read(key).await? {
+                Some((expires_at, value)) => (expires_at, value.parse::<i64>().unwrap_or(0)),
+                None => (ttl.map_or(0, unix_millis), 0),
+            };
+            let value = value + delta;
+            self.write(key, &value.to_string(), expires_at).await?;
+            Ok(value)
+        })
+    }
+}
+
+// Shared by all replicas, keys are namespaced to coexist with other applications
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    connection: redis::aio::ConnectionManager,
+}
+
+#[cfg(feature = "redis")]
+const REDIS_NAMESPACE: &str = "media-proxy-rs:";
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    pub async fn open(url: &str) -> KvResult<Self> {
+        let connection = redis::Client::open(url)
+            .map_err(redis_error)?
+            .get_connection_manager()
+            .await
+            .map_err(redis_error)?;
+        Ok(Self { connection })
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_error(err: redis::RedisError) -> KvError {
+    KvError(err.to_string())
+}
+
+#[cfg(feature = "redis")]
+impl KvStore for RedisStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<Option<String>>> {
+        use redis::AsyncCommands;
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            connection
+                .get(format!("{REDIS_NAMESPACE}{key}"))
+                .await
+                .map_err(redis_error)
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<()>> {
+        use redis::AsyncCommands;
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let key = format!("{REDIS_NAMESPACE}{key}");
+            match ttl {
+                Some(ttl) => connection.pset_ex(key, value, ttl.as_millis() as u64).await,
+                None => connection.set(key, value).await,
+            }
+            .map_err(redis_error)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, KvResult<()>> {
+        use redis::AsyncCommands;
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            connection
+                .del(format!("{REDIS_NAMESPACE}{key}"))
+                .await
+                .map_err(redis_error)
+        })
+    }
+
+    fn keys<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, KvResult<Vec<String>>> {
+        use futures_util::StreamExt;
+        use redis::AsyncCommands;
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let keys: Vec<String> = connection
+                .scan_match(format!("{REDIS_NAMESPACE}{prefix}*"))
+                .await
+                .map_err(redis_error)?
+                .collect()
+                .await;
+            Ok(keys
+                .into_iter()
+                .filter_map(|key| key.strip_prefix(REDIS_NAMESPACE).map(str::to_string))
+                .collect())
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, KvResult<i64>> {
+        use redis::AsyncCommands;
+        Box::pin(async move {
+            let mut connection = self.connection.clone();
+            let key = format!("{REDIS_NAMESPACE}{key}");
+            let value: i64 = connection.incr(&key, delta).await.map_err(redis_error)?;
+            if value == delta
+                && let Some(ttl) = ttl
+            {
+                // Just created
+                let _: bool = connection
+                    .pexpire(&key, ttl.as_millis() as i64)
+                    .await
+                    .map_err(redis_error)?;
+            }
+            Ok(value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(store: &dyn KvStore) {
+        store.set("test:a", "1", None).await.unwrap();
+        store
+            .set("test:b", "2", Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        store
+            .set("test:gone", "3", Some(Duration::ZERO))
+            .await
+            .unwrap();
+        store.set("other:c", "4", None).await.unwrap();
+
+        assert_eq!(store.get("test:a").await.unwrap(), Some("1".to_string()));
+        assert_eq!(store.get("test:gone").await.unwrap(), None);
+        let mut keys = store.keys("test:").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["test:a", "test:b"]);
+
+        store.delete("test:a").await.unwrap();
+        assert_eq!(store.get("test:a").await.unwrap(), None);
+
+        assert_eq!(store.incr("test:n", 2, None).await.unwrap(), 2);
+        assert_eq!(store.incr("test:n", 3, None).await.unwrap(), 5);
+
+        // As long as URL keys get, with a prefix
+        let url = format!("https://example.com/{}", "a".repeat(108));
+        assert_eq!(url.len(), MAX_URL_KEY_LEN);
+        let key = format!("test:url {}", url_key(&url));
+        store.set(&key, "6", None).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), Some("6".to_string()));
+        assert!(store.keys("test:url ").await.unwrap().contains(&key));
+        assert_eq!(store.incr(&key, 1, None).await.unwrap(), 7);
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        exercise(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir().join("media-proxy-rs-test-kv");
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileStore::open(dir.clone()).await.unwrap();
+        exercise(&store).await;
+
+        // Failing to move the value into place, there's no temporary file left behind
+        std::fs::create_dir_all(store.path("test:dir").join("occupied")).unwrap();
+        assert!(store.set("test:dir", "5", None).await.is_err());
+        assert!(!store.path("test:dir").with_extension("tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_url_key() {
+        assert_eq!(
+            url_key("HTTPS://Example.com/a.png#frag"),
+            "https://example.com/a.png"
+        );
+        let long = format!("https://example.com/a.png?token={}", "x".repeat(500));
+        let key = url_key(&long);
+        assert_eq!(key.len(), "sha256:".len() + 64);
+        assert_eq!(key, url_key(&format!("{long}#frag")));
+        assert_ne!(key, url_key(&format!("{long}y")));
+    }
+
+    #[test]
+    fn test_kv_config() {
+        assert_eq!("memory".parse(), Ok(KvConfig::Memory));
+        assert_eq!(
+            "file:///var/lib/media-proxy".parse(),
+            Ok(KvConfig::File(PathBuf::from("/var/lib/media-proxy")))
+        );
+        assert_eq!(
+            "redis://127.0.0.1:6379/0".parse(),
+            Ok(KvConfig::Redis("redis://127.0.0.1:6379/0".to_string()))
+        );
+        assert!("mongodb://nope".parse::<KvConfig>().is_err());
+        assert_eq!(
+            KvConfig::Redis("redis://:secret@127.0.0.1/".to_string()).to_string(),
+            "redis://:***@127.0.0.1/"
+        );
+    }
+}
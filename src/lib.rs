@@ -1,5 +1,32 @@
+// Existing tests compare lengths to zero
+#![cfg_attr(test, allow(clippy::len_zero))]
+
 mod downloader;
+pub mod filename;
+mod guard;
 mod handler;
+mod kv;
+mod quarantine;
+mod ratelimit;
 
-pub use crate::downloader::Downloader;
-pub use crate::handler::{ProxyImageError, proxy_image};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::downloader::StreamedFile;
+pub use crate::downloader::{
+    BandwidthPolicy, BreakerPolicy, Conditional, DnsOverride, DnsServer, Downloader,
+    DownloaderConfig, HostPattern, HotlinkRule, HotlinkStrategy, IpNet, IpPreference, MirrorRule,
+    NoProxyRule, OriginHeader, OriginPolicy, OriginRule, RetryPolicy, S3Config, UnixSocket,
+    is_data_uri, is_local, parse_buckets, parse_dns_overrides, parse_host_patterns,
+    parse_hotlink_rules, parse_mirror_rules, parse_networks, parse_no_proxy, parse_origin_headers,
+    parse_origin_rules, parse_unix_sockets,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::handler::stream_media;
+pub use crate::handler::{
+    Bundle, CanaryReport, CaptureConfig, DecodeConfig, EncodeConfig, EncodeMode, MismatchPolicy,
+    PresetSizes, Presets, ProxyImageConfig, ProxyImageError, Savings, Watermark, WatermarkPosition,
+    canary, candidate_urls, content_type_mismatches, encode_savings, negotiates_format,
+    parse_color, proxy_image, replay,
+};
+pub use crate::kv::{KvConfig, KvError, KvStore, MemoryStore};
+pub use crate::quarantine::{Quarantine, QuarantineEntry};
+pub use crate::ratelimit::{RateLimitConfig, RateLimiter, forwarded_client};
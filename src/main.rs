@@ -1,19 +1,58 @@
+// Existing tests compare lengths to zero
+#![cfg_attr(test, allow(clippy::len_zero))]
+
+mod access;
+mod admin;
+mod config;
 mod downloader;
+mod filename;
+mod guard;
 mod handler;
+mod kv;
+mod quarantine;
+mod ratelimit;
+mod softfail;
+mod stats;
 
-use crate::downloader::Downloader;
-use crate::handler::{ProxyImageError, proxy_image};
+use crate::config::{Cli, Command, Config};
+use crate::downloader::{Conditional, Downloader, Provenance, StreamedFile};
+use crate::handler::{
+    Bundle, CanaryReport, ProxyImageError, candidate_urls, negotiates_format, proxy_image,
+    stream_media,
+};
+use crate::kv::{KvConfig, KvStore};
+use crate::quarantine::Quarantine;
+use crate::ratelimit::RateLimiter;
+use crate::softfail::SoftFail;
+use crate::stats::Stats;
+use arc_swap::ArcSwap;
 use bytes::Bytes;
-use http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION, USER_AGENT};
-use http::{Request, Response, StatusCode};
-use http_body_util::{BodyExt, combinators::BoxBody};
+use clap::Parser;
+use futures_util::{FutureExt, StreamExt};
+use http::header::{
+    ACCEPT, AGE, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, HOST, HeaderName,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE, RETRY_AFTER,
+    USER_AGENT, VARY,
+};
+use http::{HeaderMap, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Channel, combinators::BoxBody};
 use http_body_util::{Empty, Full};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
 use url::form_urlencoded;
 
 // We create some utility functions to make Empty and Full bodies
@@ -33,14 +72,34 @@ pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
+const X_CACHE_TIER: HeaderName = HeaderName::from_static("x-cache-tier");
+const X_FETCHED_AT: HeaderName = HeaderName::from_static("x-fetched-at");
+const X_FINAL_URL: HeaderName = HeaderName::from_static("x-final-url");
+const SHARED_STATE_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const SHUTDOWN_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const STREAM_BUFFER: usize = 16; // chunks from the origin ahead of the client
+
 #[inline]
 pub fn response_raw(
     bytes: Bytes,
     ct: Option<String>,
     filename: (String, Option<String>),
+    disposition: &str,
+    provenance: Provenance,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    response_file(full(bytes), ct, filename, disposition, provenance)
+}
+
+fn response_file(
+    body: BoxBody<Bytes, hyper::Error>,
+    ct: Option<String>,
+    filename: (String, Option<String>),
+    disposition: &str,
+    provenance: Provenance,
 ) -> Response<BoxBody<Bytes, hyper::Error>> {
-    // Fill bytes
-    let mut response = Response::new(full(bytes));
+    // Fill body
+    let mut response = Response::new(body);
 
     // Fill content-type
     if let Some(ct) = ct {
@@ -50,71 +109,566 @@ pub fn response_raw(
     }
 
     // Fill content-disposition
-    let mut content_disposition = format!("inline; filename=\"{}\"", filename.0);
-    if let Some(filename_encoded) = filename.1 {
-        content_disposition = format!("{content_disposition}; filename*={filename_encoded}");
-    }
+    let content_disposition = filename::content_disposition(disposition, &filename);
     response
         .headers_mut()
         .insert(CONTENT_DISPOSITION, content_disposition.parse().unwrap());
 
+    fill_provenance(response.headers_mut(), provenance);
+
     // Return
     response
 }
 
+// For debugging layered caches without logs, and revalidating through us
+fn fill_provenance(headers: &mut HeaderMap, provenance: Provenance) {
+    if !provenance.storable {
+        // Origin doesn't want it in shared caches, neither do we
+        headers.insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    }
+    headers.insert(X_CACHE_TIER, provenance.tier.as_str().parse().unwrap());
+    headers.insert(AGE, provenance.age().as_secs().into());
+    headers.insert(
+        X_FETCHED_AT,
+        httpdate::fmt_http_date(provenance.fetched_at)
+            .parse()
+            .unwrap(),
+    );
+    if let Some(final_url) = provenance.final_url.and_then(|url| url.parse().ok()) {
+        headers.insert(X_FINAL_URL, final_url);
+    }
+
+    // Validators of the origin, so that clients can revalidate through us. Weak, as the
+    // bytes are (usually) transformed, but the same for each proxy URL and origin version
+    if let Some(etag) = provenance.etag {
+        let opaque = etag.trim_start_matches("W/").trim_matches('"');
+        if let Ok(etag) = format!("W/\"{opaque}\"").parse() {
+            headers.insert(ETAG, etag);
+        }
+    }
+    if let Some(last_modified) = provenance.last_modified.and_then(|lm| lm.parse().ok()) {
+        headers.insert(LAST_MODIFIED, last_modified);
+    }
+}
+
+// Forward the body of the origin while it arrives, with its status and headers
+fn response_stream(
+    file: StreamedFile,
+    disposition: &str,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (mut sender, body) = Channel::new(STREAM_BUFFER);
+    let mut origin = file.body;
+    tokio::spawn(async move {
+        while let Some(chunk) = origin.next().await {
+            match chunk {
+                Ok(bytes) => {
+                    if sender.send_data(bytes).await.is_err() {
+                        break; // client is gone
+                    }
+                }
+                Err(err) => {
+                    // The client sees the body is shorter than its length
+                    warn!("Streaming from origin failed: {err}");
+                    break;
+                }
+            }
+        }
+    });
+
+    let storable = file.provenance.storable;
+    let mut response = response_file(
+        body.boxed(),
+        None,
+        file.filename,
+        disposition,
+        file.provenance,
+    );
+    *response.status_mut() = file.status;
+    let headers = response.headers_mut();
+    headers.extend(file.headers); // the bytes are the origin's, so are the validators
+    if storable {
+        headers.insert(
+            CACHE_CONTROL,
+            "max-age=31536000, immutable".parse().unwrap(),
+        );
+    }
+    response
+}
+
+// For "save media" links in clients: named by the request if asked, with the extension
+// of what's sent, or else the same as inline
+fn disposition(
+    query: &HashMap<String, String>,
+    filename: &mut (String, Option<String>),
+) -> &'static str {
+    if !query.contains_key("download") {
+        return "inline";
+    }
+    if let Some(name) = query
+        .get("filename")
+        .map(|name| filename::sanitize(name))
+        .filter(|name| !name.is_empty())
+    {
+        let name = match filename::extension(&filename.0) {
+            Some(extension) => filename::with_extension(&name, extension),
+            None => name,
+        };
+        *filename = (name, None);
+    }
+    "attachment"
+}
+
+// Empty, with the wait rounded up to whole seconds
+fn response_retry_after(
+    status_code: StatusCode,
+    retry_after: Duration,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = status_code;
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, seconds.to_string().parse().unwrap());
+    response
+}
+
+fn response_error(err: ProxyImageError) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match err {
+        ProxyImageError::StatusCodeOnly(status_code) => {
+            let mut response = Response::new(empty());
+            *response.status_mut() = status_code;
+            response
+        }
+        ProxyImageError::Redirectable(url) => {
+            let mut response = Response::new(empty());
+            *response.status_mut() = StatusCode::FOUND;
+            response
+                .headers_mut()
+                .insert(LOCATION, url.parse().unwrap());
+            response
+        }
+        ProxyImageError::BytesOnly(file) => response_raw(
+            file.bytes,
+            file.content_type,
+            file.filename,
+            "inline",
+            file.provenance,
+        ),
+        ProxyImageError::RetryAfter(retry_after) => {
+            response_retry_after(StatusCode::SERVICE_UNAVAILABLE, retry_after)
+        }
+        ProxyImageError::NotModified(provenance) => {
+            let mut response = Response::new(empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            if provenance.storable {
+                response.headers_mut().insert(
+                    CACHE_CONTROL,
+                    "max-age=31536000, immutable".parse().unwrap(),
+                );
+            }
+            fill_provenance(response.headers_mut(), *provenance);
+            response
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    cli: Arc<Cli>,
+    config: Arc<ArcSwap<Config>>,
+    downloader: Arc<ArcSwap<Downloader>>,
+    quarantine: Arc<Quarantine>,
+    rate_limiter: Arc<RateLimiter>,
+    soft_fail: Arc<SoftFail>,
+    stats: Arc<Stats>,
+    draining: Arc<AtomicBool>, // failing health checks, so that load balancers move away
+    log_filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl AppState {
+    // Re-read config, in-flight requests keep using the snapshot they've loaded
+    async fn reload(&self) {
+        let config = match Config::load(&self.cli) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Failed to reload config, keep using the current one: {err}");
+                return;
+            }
+        };
+
+        if config.listen != self.config.load().listen {
+            warn!("Listen address can't be changed without a restart");
+        }
+        if config.worker_threads != self.config.load().worker_threads {
+            warn!("Worker threads can't be changed without a restart");
+        }
+        if config.kv_store != self.config.load().kv_store {
+            warn!("KV store can't be changed without a restart");
+        }
+        if let Err(err) = self.log_filter.reload(EnvFilter::new(&config.log_level)) {
+            warn!("Failed to reload log level: {err}");
+        }
+
+        if let Err(err) = self.quarantine.load(config.quarantine_file.clone()) {
+            error!("Failed to reload quarantine list, keep using the current one: {err}");
+        } else if let Err(err) = self.quarantine.publish().await {
+            error!("Failed to share quarantine list: {err}");
+        } else if let Err(err) = self.quarantine.sync().await {
+            warn!("Failed to sync quarantine list: {err}");
+        }
+
+        let downloader = self
+            .downloader
+            .load()
+            .reconfigure(config.downloader.clone());
+        self.downloader.store(Arc::new(downloader));
+        self.config.store(Arc::new(config));
+        info!("Config reloaded");
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(state: AppState) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("Failed to listen for SIGHUP, config reload disabled: {err}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading config...");
+        state.reload().await;
+    }
+}
+
+// Pick up the runtime state changed by other replicas sharing the KV store
+async fn sync_shared_state(state: AppState, store: Arc<dyn KvStore>) {
+    let mut interval = tokio::time::interval(SHARED_STATE_SYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = state.quarantine.sync().await {
+            warn!("Failed to sync quarantine list: {err}");
+        }
+        if let Err(err) = state.downloader.load().sync_circuits(store.as_ref()).await {
+            warn!("Failed to sync circuit breakers: {err}");
+        }
+    }
+}
+
+// Self-metrics in plain logs, for deployments without a metrics stack
+async fn log_metrics(state: AppState) {
+    let mut last = state.stats.snapshot();
+    loop {
+        let interval = state.config.load().metrics_log_interval;
+        if interval.is_zero() {
+            // Disabled, but it may be turned on by a reload
+            tokio::time::sleep(SHARED_STATE_SYNC_INTERVAL).await;
+            last = state.stats.snapshot();
+            continue;
+        }
+        tokio::time::sleep(interval).await;
+        let snapshot = state.stats.snapshot();
+        info!("{}", snapshot.since(&last).to_metrics_json(stats::rss()));
+        last = snapshot;
+    }
+}
+
+// The configured header (set by a trusted reverse proxy) or the peer address
+fn client_address<B>(config: &Config, req: &Request<B>, peer: SocketAddr) -> String {
+    config
+        .client_ip_header
+        .as_ref()
+        .and_then(|header| req.headers().get(header))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| ratelimit::forwarded_client(value, config.client_ip_hops))
+        .map(str::to_string)
+        .unwrap_or_else(|| peer.ip().to_string())
+}
+
 async fn handle(
-    downloader: &Downloader,
+    state: &AppState,
+    peer: SocketAddr,
     req: Request<hyper::body::Incoming>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let uri = req.uri();
-    match uri.query() {
-        None => Ok(Response::new(full("OK"))), // healthcheck
-        Some(query) => Ok(
-            match proxy_image(
-                downloader,
-                uri.path(),
-                form_urlencoded::parse(query.as_bytes())
-                    .into_owned()
-                    .collect(),
-                req.headers().get(USER_AGENT).map(|ua| ua.to_str().unwrap()),
-            )
+    let config = state.config.load_full();
+    if admin::is_admin(&config, &req) {
+        return Ok(admin::handle(state, &config, req).await);
+    }
+    if req.uri().path() == "/canary" {
+        return Ok(canary(state, &config).await);
+    }
+    if req.uri().query().is_none() {
+        // Healthcheck, requests are still served while draining
+        if state.draining.load(Ordering::Relaxed) {
+            let mut response = Response::new(full("Draining"));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Ok(response);
+        }
+        return Ok(Response::new(full("OK")));
+    }
+
+    let response = proxy(state, &config, peer, &req).await;
+    state.stats.record(&response);
+    Ok(response)
+}
+
+// Phase timings of the pipeline on an embedded image, for external monitoring, on the
+// blocking pool like every other run of it. 503 while shedding load in soft-fail mode or
+// draining, but still timed
+async fn canary(state: &AppState, config: &Config) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let shedding =
+        state.soft_fail.is_active(&config.soft_fail) || state.draining.load(Ordering::Relaxed);
+    let proxy_config = config.proxy.clone();
+    let report: Result<CanaryReport, String> =
+        tokio::task::spawn_blocking(move || handler::canary(&proxy_config))
             .await
+            .unwrap_or_else(|err| Err(format!("canary task failed: {err}")));
+    let (status, body) = match report {
+        Ok(report) if shedding => (StatusCode::SERVICE_UNAVAILABLE, report.to_json()),
+        Ok(report) => (StatusCode::OK, report.to_json()),
+        Err(err) => {
+            error!("Canary failed: {err}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                serde_json::json!({ "error": err }).to_string(),
+            )
+        }
+    };
+    let mut response = Response::new(full(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    response
+}
+
+async fn proxy(
+    state: &AppState,
+    config: &Config,
+    peer: SocketAddr,
+    req: &Request<hyper::body::Incoming>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let downloader = state.downloader.load_full();
+
+    let client = client_address(config, req, peer);
+    if let Err(retry_after) = state.rate_limiter.check(&config.rate_limit, &client).await {
+        warn!("Rate limited: {client}");
+        return response_retry_after(StatusCode::TOO_MANY_REQUESTS, retry_after);
+    }
+
+    let uri = req.uri();
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let path_and_query = uri.path_and_query().map_or("", |pq| pq.as_str());
+    if !access::permits(&config.access, &query, path_and_query, &client) {
+        warn!("Restricted request denied: {client}");
+        return response_error(ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN));
+    }
+    let candidates = candidate_urls(&query);
+    let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+    if let Some(url) = candidates
+        .iter()
+        .find(|url| access::is_loop(&config.access, url, host, uri.path()))
+    {
+        warn!("Proxying to itself: {url}");
+        return response_error(ProxyImageError::StatusCodeOnly(StatusCode::FORBIDDEN));
+    }
+
+    // Only redirect to origins while processing is unavailable (quarantine still applies, and
+    // blurred media is still processed). Processed as usual without an origin to send them to
+    if state.soft_fail.is_active(&config.soft_fail)
+        && !handler::hides_original(&query)
+        && let Some(url) = softfail::redirect_target(&candidates, |url| downloader.permits(url))
+        && let Ok(location) = url.as_str().parse()
+        && !candidates
+            .iter()
+            .any(|url| state.quarantine.contains_url(url))
+    {
+        let mut response = Response::new(empty());
+        *response.status_mut() = StatusCode::FOUND;
+        response.headers_mut().insert(LOCATION, location);
+        return response;
+    }
+    // Forwarded to the origin, so that unchanged media is neither downloaded nor encoded again,
+    // and so that it may send a better format
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value: &http::HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let conditional = Conditional {
+        if_none_match: header(IF_NONE_MATCH),
+        if_modified_since: header(IF_MODIFIED_SINCE),
+        accept: header(ACCEPT),
+    };
+    let ua = req.headers().get(USER_AGENT).map(|ua| ua.to_str().unwrap());
+    let (range, if_range) = (header(RANGE), header(IF_RANGE));
+    let stream = |query| {
+        stream_media(
+            &downloader,
+            &state.quarantine,
+            &config.proxy,
+            query,
+            ua,
+            range.as_deref(),
+            if_range.as_deref(),
+        )
+    };
+
+    // Seeking in media (e.g. videos), streamed from the origin instead of buffered.
+    // Images are processed from the whole file, ignoring the range. Never when blurred
+    let streaming = config.proxy.stream_passthrough
+        && !config.proxy.disable_passthrough
+        && !handler::hides_original(&query);
+    if streaming
+        && range.is_some()
+        && candidates
+            .first()
+            .is_none_or(|url| downloader.streamable(url))
+    {
+        match stream(&query).await {
+            Ok(mut file)
+                if !file
+                    .content_type()
+                    .is_some_and(|ct| ct.starts_with("image/")) =>
             {
-                Ok(file) => {
-                    let mut response =
-                        response_raw(file.bytes, Some(file.content_type), file.filename);
-                    response.headers_mut().insert(
-                        CACHE_CONTROL,
-                        "max-age=31536000, immutable".parse().unwrap(),
-                    );
-
-                    response
+                let disposition = disposition(&query, &mut file.filename);
+                return response_stream(file, disposition);
+            }
+            Ok(_) => {}
+            Err(err) => return response_error(err),
+        }
+    }
+
+    // A panic in processing (e.g. in a codec) or a server error counts towards soft-fail mode
+    let result = AssertUnwindSafe(proxy_image(
+        &downloader,
+        &state.quarantine,
+        &config.proxy,
+        uri.path(),
+        query.clone(),
+        ua,
+        &conditional,
+    ))
+    .catch_unwind()
+    .await;
+    let internal_error = match &result {
+        Ok(Err(ProxyImageError::StatusCodeOnly(status))) => status.is_server_error(),
+        Ok(_) => false,
+        Err(_) => true, // panicked
+    };
+    state.soft_fail.record(&config.soft_fail, internal_error);
+    let result = match result {
+        Ok(result) => result,
+        Err(_) => {
+            error!("Panicked while processing {uri}");
+            Err(ProxyImageError::StatusCodeOnly(
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+
+    let mut response = match result {
+        Ok(mut file) => {
+            let storable = file.provenance.storable;
+            let disposition = disposition(&query, &mut file.filename);
+            let mut response = response_raw(
+                file.bytes,
+                Some(file.content_type),
+                file.filename,
+                disposition,
+                file.provenance,
+            );
+            if storable {
+                response.headers_mut().insert(
+                    CACHE_CONTROL,
+                    "max-age=31536000, immutable".parse().unwrap(),
+                );
+            }
+
+            response
+        }
+        // Too large to buffer, stream it rather than sending the client to the origin
+        Err(ProxyImageError::Redirectable(_)) if streaming => match stream(&query).await {
+            Ok(mut file) => {
+                let disposition = disposition(&query, &mut file.filename);
+                response_stream(file, disposition)
+            }
+            Err(err) => response_error(err),
+        },
+        Err(err) => response_error(err),
+    };
+    // Shared caches keep one response for each Accept then, not WebP for every browser
+    if negotiates_format(uri.path()) {
+        response.headers_mut().insert(VARY, ACCEPT.into());
+    }
+    response
+}
+
+// Resolves on Ctrl-C, or SIGTERM (e.g. from docker stop)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
                 }
-                Err(err) => match err {
-                    ProxyImageError::StatusCodeOnly(status_code) => {
-                        let mut response = Response::new(empty());
-                        *response.status_mut() = status_code;
-                        response
-                    }
-                    ProxyImageError::Redirectable(url) => {
-                        let mut response = Response::new(empty());
-                        *response.status_mut() = StatusCode::FOUND;
-                        response
-                            .headers_mut()
-                            .insert(LOCATION, url.parse().unwrap());
-                        response
-                    }
-                    ProxyImageError::BytesOnly(file) => {
-                        response_raw(file.bytes, file.content_type, file.filename)
-                    }
-                },
-            },
-        ),
+                return;
+            }
+            Err(err) => error!("Failed to listen for SIGTERM: {err}"),
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl-C: {err}");
+        std::future::pending::<()>().await;
+    }
+}
+
+// Summary of this run, for evaluating each deployment cycle
+async fn report(state: &AppState) {
+    let snapshot = state.stats.snapshot();
+    info!(
+        uptime_secs = snapshot.uptime.as_secs(),
+        requests = snapshot.requests,
+        bytes_sent = snapshot.bytes_sent,
+        cache_hit_ratio = snapshot.cache_hit_ratio(),
+        encode_bytes_saved = snapshot.encode_bytes_saved(),
+        client_errors = snapshot.client_errors,
+        server_errors = snapshot.server_errors,
+        "Shutdown report"
+    );
+
+    let Some(webhook) = state.config.load().shutdown_webhook.clone() else {
+        return;
+    };
+    let result = reqwest::Client::new()
+        .post(webhook)
+        .timeout(SHUTDOWN_WEBHOOK_TIMEOUT)
+        .header(CONTENT_TYPE, "application/json")
+        .body(snapshot.to_json())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    if let Err(err) = result {
+        warn!("Failed to post shutdown report: {err}");
     }
 }
 
 async fn start_server(
-    downloader: Downloader,
+    state: AppState,
     addr: SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     info!(
@@ -125,65 +679,227 @@ async fn start_server(
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr).await?;
 
+    // Connections are tracked, so that in-flight requests can finish on shutdown
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+
     // We start a loop to continuously accept incoming connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
-        let downloader = downloader.clone();
+        let state = state.clone();
+
+        // Finally, we bind the incoming connection to our `hello` service
+        let connection = graceful.watch(
+            http1::Builder::new()
+                // `service_fn` converts our function in a `Service`
+                .serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        let state = state.clone();
+                        async move { handle(&state, peer, req).await }
+                    }),
+                ),
+        );
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
-            // Finally, we bind the incoming connection to our `hello` service
-            if let Err(err) = http1::Builder::new()
-                // `service_fn` converts our function in a `Service`
-                .serve_connection(io, service_fn(|req| handle(&downloader, req)))
-                .await
-            {
+            if let Err(err) = connection.await {
                 error!("Error serving connection: {:?}", err);
             }
         });
     }
-}
 
-#[tokio::main]
-async fn main() {
-    // Prepare logger
-    tracing_subscriber::fmt::init();
+    info!("Shutting down, waiting for in-flight requests...");
+    drop(listener);
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("Some connections were still open after {SHUTDOWN_GRACE_PERIOD:?}, closing them");
+    }
+    report(&state).await;
+    Ok(())
+}
 
-    // Get address to listen from env or default
-    let env_listen = std::env::var("LISTEN").unwrap_or("127.0.0.1:3000".to_string());
+// Print the outcome of a captured request processed again, false if it still fails
+async fn replay(config: &Config, bundle: &Path, output: Option<&Path>) -> bool {
+    let bundle = match std::fs::read(bundle) {
+        Ok(bytes) => Bundle::parse(&bytes),
+        Err(err) => Err(err.to_string()),
+    };
+    let bundle = match bundle {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            println!("[FAIL] Invalid bundle: {err}");
+            return false;
+        }
+    };
+    println!(
+        "Replaying {} {} ({} bytes, {:?}), captured for: {}",
+        bundle.path,
+        bundle.query.get("url").map_or("", String::as_str),
+        bundle.bytes.len(),
+        bundle.content_type,
+        bundle.reason
+    );
+    if bundle.truncated {
+        println!("Note: the file was cut off at CAPTURE_SIZE_LIMIT when captured");
+    }
 
-    // Parse to socket address
-    let addr: SocketAddr = env_listen.parse().expect("Invalid listen address");
+    let (processed, bytes) = match handler::replay(&config.proxy, &bundle).await {
+        Ok(file) => {
+            println!("[ OK ] Processed into {}", file.content_type);
+            (true, file.bytes)
+        }
+        Err(ProxyImageError::BytesOnly(file)) => {
+            println!(
+                "[FAIL] Not processed, served as-is ({:?})",
+                file.content_type
+            );
+            (false, file.bytes)
+        }
+        Err(ProxyImageError::StatusCodeOnly(status_code)) => {
+            println!("[FAIL] Answered with {status_code}");
+            return false;
+        }
+        Err(ProxyImageError::Redirectable(url)) => {
+            println!("[FAIL] Redirected to {url}");
+            return false;
+        }
+        Err(ProxyImageError::NotModified(_)) => {
+            println!("[FAIL] Answered with 304");
+            return false;
+        }
+        Err(ProxyImageError::RetryAfter(retry_after)) => {
+            println!("[FAIL] Busy, retry after {retry_after:?}");
+            return false;
+        }
+    };
+    if let Some(output) = output {
+        match std::fs::write(output, &bytes) {
+            Ok(()) => println!("{} bytes written to {}", bytes.len(), output.display()),
+            Err(err) => println!("Failed to write {}: {err}", output.display()),
+        }
+    }
+    processed
+}
 
-    // Init file downloader (Read size limit from env)
-    let downloader = Downloader::new(match std::env::var("SIZE_LIMIT") {
-        Ok(size_limit_str) => match size_limit_str.parse::<u64>() {
-            Ok(size_limit) => {
-                info!("Size limit set to {size_limit}");
-                Some(size_limit)
-            }
-            Err(err) => {
-                warn!("Failed to parse size limit {size_limit_str}: {err}, fallback to default");
-                None
-            }
-        },
+fn main() {
+    // Parse command line (falls back to env, then config file)
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
         Err(err) => {
-            if err == std::env::VarError::NotPresent {
-                info!("Size limit not set, using default");
+            if cli.check {
+                println!("[FAIL] Config is invalid: {err}");
             } else {
-                warn!("Failed to read size limit from env: {err}, fallback to default");
+                eprintln!("{err}");
             }
-            None
+            std::process::exit(1);
         }
-    });
+    };
+
+    // The number of worker threads is configurable, so the runtime is built by hand
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if config.worker_threads > 0 {
+        runtime.worker_threads(config.worker_threads);
+    }
+    runtime
+        .enable_all()
+        .build()
+        .expect("Failed to build async runtime")
+        .block_on(run(cli, config));
+}
+
+async fn run(cli: Cli, config: Config) {
+    if cli.check {
+        println!("[ OK ] Config is valid");
+        let mut passed = true;
+        for (item, result) in config.check().await {
+            match result {
+                Ok(()) => println!("[ OK ] {item}"),
+                Err(err) => {
+                    println!("[FAIL] {item}: {err}");
+                    passed = false;
+                }
+            }
+        }
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if cli.print_config {
+        print!("{config}");
+        return;
+    }
+
+    // Prepare logger (with reloadable level)
+    let (log_filter, log_filter_handle) = reload::Layer::new(EnvFilter::new(&config.log_level));
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt::layer())
+        .init();
+
+    if let Some(Command::Replay { bundle, output }) = &cli.command {
+        let processed = replay(&config, bundle, output.as_deref()).await;
+        std::process::exit(if processed { 0 } else { 1 });
+    }
+
+    info!(
+        "Effective config: {}",
+        config.to_string().trim_end().replace('\n', ", ")
+    );
+
+    // Init file downloader
+    let downloader = Downloader::new(config.downloader.clone());
+
+    let store = match config.kv_store.open().await {
+        Ok(store) => store,
+        Err(err) => {
+            error!("Failed to open KV store: {err}");
+            std::process::exit(1);
+        }
+    };
+    let shared = config.kv_store != KvConfig::Memory;
+
+    let quarantine = Quarantine::with_store(store.clone());
+    if let Err(err) = quarantine.load(config.quarantine_file.clone()) {
+        error!("Failed to load quarantine list: {err}");
+        std::process::exit(1);
+    }
+    if let Err(err) = quarantine.publish().await {
+        error!("Failed to share quarantine list: {err}");
+        std::process::exit(1);
+    }
+
+    let listen = config.listen;
+    let state = AppState {
+        cli: Arc::new(cli),
+        config: Arc::new(ArcSwap::from_pointee(config)),
+        downloader: Arc::new(ArcSwap::from_pointee(downloader)),
+        quarantine: Arc::new(quarantine),
+        rate_limiter: Arc::new(RateLimiter::new(store.clone())),
+        soft_fail: Arc::new(SoftFail::default()),
+        stats: Arc::new(Stats::default()),
+        draining: Arc::new(AtomicBool::new(false)),
+        log_filter: log_filter_handle,
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone()));
+    if shared {
+        tokio::spawn(sync_shared_state(state.clone(), store));
+    }
+    tokio::spawn(log_metrics(state.clone()));
 
     // Start server
-    start_server(downloader, addr)
+    start_server(state, listen)
         .await
         .expect("Server start failed");
 }
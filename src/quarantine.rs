@@ -0,0 +1,329 @@
+use crate::guard::{MutexExt, RwLockExt};
+use crate::kv::{KvStore, url_key};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use url::Url;
+
+// Moderated media, served as a placeholder instead of the original
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum QuarantineEntry {
+    Url(String),    // normalized
+    Sha256(String), // lowercase hex of the content
+}
+
+impl QuarantineEntry {
+    pub fn url(url: &str) -> Result<Self, String> {
+        Url::parse(url)
+            .map(|url| Self::Url(url.to_string()))
+            .map_err(|err| format!("invalid url {url}: {err}"))
+    }
+
+    pub fn sha256(hash: &str) -> Result<Self, String> {
+        if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(Self::Sha256(hash.to_ascii_lowercase()))
+        } else {
+            Err(format!("invalid sha256: {hash}"))
+        }
+    }
+}
+
+// `url <url>` or `sha256 <hex>`, one per line in the quarantine file
+impl FromStr for QuarantineEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(' ') {
+            Some(("url", url)) => Self::url(url.trim()),
+            Some(("sha256", hash)) => Self::sha256(hash.trim()),
+            _ => Err(format!("invalid quarantine entry: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for QuarantineEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuarantineEntry::Url(url) => write!(f, "url {url}"),
+            QuarantineEntry::Sha256(hash) => write!(f, "sha256 {hash}"),
+        }
+    }
+}
+
+impl QuarantineEntry {
+    // Long URLs are hashed, the entry itself is stored as the value
+    fn store_key(&self) -> String {
+        match self {
+            QuarantineEntry::Url(url) => format!("{STORE_PREFIX}url {}", url_key(url)),
+            QuarantineEntry::Sha256(hash) => format!("{STORE_PREFIX}sha256 {hash}"),
+        }
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+const STORE_PREFIX: &str = "quarantine:";
+// Bumped on every change, so that replicas only list the entries when there's something new
+const STORE_VERSION_KEY: &str = "quarantine-version";
+
+// Runtime state managed through the admin API, saved to a file (if any) on every change.
+// With a KV store, entries are shared with other replicas using the same store.
+#[derive(Default)]
+pub struct Quarantine {
+    entries: RwLock<HashSet<QuarantineEntry>>,
+    file: RwLock<Option<PathBuf>>,
+    store: Option<Arc<dyn KvStore>>,
+    synced_version: Mutex<Option<String>>,
+}
+
+impl Quarantine {
+    pub fn with_store(store: Arc<dyn KvStore>) -> Self {
+        Self {
+            store: Some(store),
+            ..Default::default()
+        }
+    }
+
+    // Replace the entries with the ones in the file (a missing file is an empty list)
+    pub fn load(&self, file: Option<PathBuf>) -> Result<(), String> {
+        let mut entries = HashSet::new();
+        if let Some(path) = &file {
+            let content = match std::fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(format!("failed to read {}: {err}", path.display())),
+            };
+            for line in content.lines() {
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with('#') {
+                    entries.insert(line.parse()?);
+                }
+            }
+        }
+
+        *self.entries.write_or_recover() = entries;
+        *self.file.write_or_recover() = file;
+        *self.synced_version.lock_or_recover() = None;
+        Ok(())
+    }
+
+    fn save(&self, entries: &HashSet<QuarantineEntry>) -> Result<(), String> {
+        let Some(path) = self.file.read_or_recover().clone() else {
+            return Ok(());
+        };
+        let mut lines: Vec<_> = entries.iter().map(|entry| format!("{entry}\n")).collect();
+        lines.sort();
+        std::fs::write(&path, lines.concat())
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))
+    }
+
+    // Returns false if it's already there
+    pub async fn add(&self, entry: QuarantineEntry) -> Result<bool, String> {
+        {
+            let mut entries = self.entries.write_or_recover();
+            if !entries.insert(entry.clone()) {
+                return Ok(false);
+            }
+            self.save(&entries)?;
+        }
+        self.share(&[entry], true).await?;
+        Ok(true)
+    }
+
+    // Returns false if it's not there
+    pub async fn remove(&self, entry: &QuarantineEntry) -> Result<bool, String> {
+        {
+            let mut entries = self.entries.write_or_recover();
+            if !entries.remove(entry) {
+                return Ok(false);
+            }
+            self.save(&entries)?;
+        }
+        self.share(std::slice::from_ref(entry), false).await?;
+        Ok(true)
+    }
+
+    // Share the loaded entries through the store, so the file seeds the whole cluster
+    pub async fn publish(&self) -> Result<(), String> {
+        self.share(&self.list(), true).await
+    }
+
+    async fn share(&self, entries: &[QuarantineEntry], present: bool) -> Result<(), String> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        for entry in entries {
+            let key = entry.store_key();
+            if present {
+                store.set(&key, &entry.to_string(), None).await
+            } else {
+                store.delete(&key).await
+            }
+            .map_err(|err| err.to_string())?;
+        }
+        store
+            .incr(STORE_VERSION_KEY, 1, None)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    // Pick up the changes made by other replicas
+    pub async fn sync(&self) -> Result<(), String> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let version = store
+            .get(STORE_VERSION_KEY)
+            .await
+            .map_err(|err| err.to_string())?;
+        if version.is_some() && *self.synced_version.lock_or_recover() == version {
+            return Ok(());
+        }
+
+        let keys = store
+            .keys(STORE_PREFIX)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut entries = HashSet::new();
+        for key in keys {
+            let value = store.get(&key).await.map_err(|err| err.to_string())?;
+            // Entries shared by older versions are only in the keys
+            let entry = match value.as_deref() {
+                Some(value) if !value.is_empty() => value.parse(),
+                _ => key.strip_prefix(STORE_PREFIX).unwrap_or(&key).parse(),
+            };
+            if let Ok(entry) = entry {
+                entries.insert(entry);
+            }
+        }
+        *self.entries.write_or_recover() = entries;
+        *self.synced_version.lock_or_recover() = version;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<QuarantineEntry> {
+        let mut list: Vec<_> = self.entries.read_or_recover().iter().cloned().collect();
+        list.sort();
+        list
+    }
+
+    pub fn contains_url(&self, url: &str) -> bool {
+        QuarantineEntry::url(url).is_ok_and(|entry| self.entries.read_or_recover().contains(&entry))
+    }
+
+    // Same, for content hashed earlier
+    pub fn match_hash(&self, hash: &str) -> Option<String> {
+        let entry = QuarantineEntry::Sha256(hash.to_string());
+        self.entries
+            .read_or_recover()
+            .contains(&entry)
+            .then(|| hash.to_string())
+    }
+
+    // The matched hash, if the content is quarantined
+    pub fn match_content(&self, bytes: &[u8]) -> Option<String> {
+        let entries = self.entries.read_or_recover();
+        if !entries
+            .iter()
+            .any(|entry| matches!(entry, QuarantineEntry::Sha256(_)))
+        {
+            return None; // don't hash for nothing
+        }
+        let hash = sha256_hex(bytes);
+        entries
+            .contains(&QuarantineEntry::Sha256(hash.clone()))
+            .then_some(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(
+            "url https://example.com".parse(),
+            Ok(QuarantineEntry::Url("https://example.com/".to_string()))
+        );
+        let hash = sha256_hex(b"hello");
+        assert_eq!(
+            format!("sha256 {}", hash.to_ascii_uppercase()).parse(),
+            Ok(QuarantineEntry::Sha256(hash))
+        );
+        assert!("sha256 1234".parse::<QuarantineEntry>().is_err());
+        assert!("md5 1234".parse::<QuarantineEntry>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        let path = std::env::temp_dir().join("media-proxy-rs-test-quarantine");
+        let _ = std::fs::remove_file(&path);
+
+        let quarantine = Quarantine::default();
+        quarantine.load(Some(path.clone())).unwrap();
+        assert_eq!(quarantine.match_content(b"hello"), None);
+        assert!(
+            quarantine
+                .add(QuarantineEntry::url("https://example.com/a.png").unwrap())
+                .await
+                .unwrap()
+        );
+        assert!(
+            quarantine
+                .add(QuarantineEntry::sha256(&sha256_hex(b"hello")).unwrap())
+                .await
+                .unwrap()
+        );
+        assert!(quarantine.contains_url("https://EXAMPLE.com/a.png"));
+        assert!(!quarantine.contains_url("https://example.com/b.png"));
+        assert_eq!(
+            quarantine.match_content(b"hello"),
+            Some(sha256_hex(b"hello"))
+        );
+
+        // Survives a restart
+        let restarted = Quarantine::default();
+        restarted.load(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.list(), quarantine.list());
+    }
+
+    #[tokio::test]
+    async fn test_shared_quarantine() {
+        use crate::kv::MemoryStore;
+
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::default());
+        let replica1 = Quarantine::with_store(store.clone());
+        let replica2 = Quarantine::with_store(store);
+        let entry = QuarantineEntry::url("https://example.com/a.png").unwrap();
+
+        assert!(replica1.add(entry.clone()).await.unwrap());
+        assert!(!replica2.contains_url("https://example.com/a.png"));
+        replica2.sync().await.unwrap();
+        assert!(replica2.contains_url("https://example.com/a.png"));
+
+        let long = format!("https://example.com/b.png?token={}", "x".repeat(500));
+        assert!(
+            replica1
+                .add(QuarantineEntry::url(&long).unwrap())
+                .await
+                .unwrap()
+        );
+        replica2.sync().await.unwrap();
+        assert!(replica2.contains_url(&long));
+
+        assert!(replica2.remove(&entry).await.unwrap());
+        replica1.sync().await.unwrap();
+        assert_eq!(replica1.list().len(), 1);
+    }
+}
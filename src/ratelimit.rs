@@ -0,0 +1,122 @@
+use crate::kv::KvStore;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub limit: u64,       // requests per window and client, zero to disable
+    pub window: Duration, // at least a second
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            limit: 0,
+            window: DEFAULT_WINDOW,
+        }
+    }
+}
+
+// The client in a header like X-Forwarded-For, where each proxy appends the address it got
+// the request from: counted from the right, past the trusted proxies (`hops` of them, at
+// least one), as anything further left may be made up by the client
+pub fn forwarded_client(value: &str, hops: usize) -> Option<&str> {
+    let entries: Vec<_> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    entries
+        .len()
+        .checked_sub(hops.max(1))
+        .map_or(entries.first(), |index| entries.get(index))
+        .copied()
+}
+
+// Fixed window counters kept in the KV store, so that replicas sharing it enforce one
+// limit together instead of each allowing the full amount. One INCR per request.
+pub struct RateLimiter {
+    store: Arc<dyn KvStore>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn KvStore>) -> Self {
+        Self { store }
+    }
+
+    // Err with how long to wait if the client is over the limit
+    pub async fn check(&self, config: &RateLimitConfig, client: &str) -> Result<(), Duration> {
+        if config.limit == 0 {
+            return Ok(());
+        }
+
+        let window = config.window.as_secs().max(1);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let index = now.as_secs() / window;
+        let key = format!("ratelimit:{client}:{index}");
+        let count = match self
+            .store
+            .incr(&key, 1, Some(Duration::from_secs(window)))
+            .await
+        {
+            Ok(count) => count,
+            Err(err) => {
+                // Better to serve than to be down with the store
+                warn!("Failed to count request of {client}, not limited: {err}");
+                return Ok(());
+            }
+        };
+
+        if count as u64 > config.limit {
+            let window_end = Duration::from_secs((index + 1) * window);
+            Err(window_end.saturating_sub(now))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::MemoryStore;
+
+    #[test]
+    fn test_forwarded_client() {
+        // The client added a fake first hop, the proxy appended the real address
+        let value = "10.0.0.1, 203.0.113.5";
+        assert_eq!(forwarded_client(value, 1), Some("203.0.113.5"));
+        assert_eq!(forwarded_client(value, 2), Some("10.0.0.1"));
+        assert_eq!(forwarded_client("203.0.113.5", 2), Some("203.0.113.5"));
+        assert_eq!(forwarded_client("203.0.113.5", 0), Some("203.0.113.5"));
+        assert_eq!(forwarded_client(" , ", 1), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let store: Arc<dyn KvStore> = Arc::new(MemoryStore::default());
+        let config = RateLimitConfig {
+            limit: 2,
+            window: Duration::from_secs(3600),
+        };
+
+        // Replicas sharing the store share the limit
+        let replica1 = RateLimiter::new(store.clone());
+        let replica2 = RateLimiter::new(store);
+        assert!(replica1.check(&config, "192.0.2.1").await.is_ok());
+        assert!(replica2.check(&config, "192.0.2.1").await.is_ok());
+        let retry_after = replica1.check(&config, "192.0.2.1").await.unwrap_err();
+        assert!(retry_after <= config.window);
+        assert!(replica2.check(&config, "192.0.2.2").await.is_ok());
+
+        let disabled = RateLimitConfig::default();
+        for _ in 0..10 {
+            assert!(replica1.check(&disabled, "192.0.2.1").await.is_ok());
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::guard::MutexExt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use url::Url;
+
+const DEFAULT_DURATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Debug)]
+pub struct SoftFailConfig {
+    pub enabled: bool,      // manually, until turned off
+    pub threshold: u32,     // consecutive internal errors to turn it on, zero to never
+    pub duration: Duration, // how long it stays on once triggered by errors
+}
+
+impl Default for SoftFailConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 0,
+            duration: DEFAULT_DURATION,
+        }
+    }
+}
+
+// While on, the proxy only redirects to the original URLs, so that media stays visible
+// when processing is broken (e.g. a codec dependency after an upgrade)
+#[derive(Default)]
+pub struct SoftFail {
+    consecutive_errors: AtomicU32,
+    triggered_until: Mutex<Option<Instant>>,
+}
+
+impl SoftFail {
+    pub fn is_active(&self, config: &SoftFailConfig) -> bool {
+        if config.enabled {
+            return true;
+        }
+        let mut triggered_until = self.triggered_until.lock_or_recover();
+        match *triggered_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                info!("Soft-fail mode ended, processing media again");
+                *triggered_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn record(&self, config: &SoftFailConfig, internal_error: bool) {
+        if !internal_error {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            return;
+        }
+        if config.threshold == 0 {
+            return;
+        }
+        let errors = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if errors >= config.threshold {
+            warn!(
+                "{errors} internal errors in a row, redirecting to origins for {:?}",
+                config.duration
+            );
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            *self.triggered_until.lock_or_recover() = Some(Instant::now() + config.duration);
+        }
+    }
+}
+
+// The first candidate clients may be sent to: plain http(s) URLs only, as the other schemes
+// are for the proxy (local files, storage, sockets), and only origins it would fetch from
+pub fn redirect_target(candidates: &[String], permits: impl Fn(&Url) -> bool) -> Option<Url> {
+    candidates
+        .iter()
+        .filter_map(|candidate| Url::parse(candidate).ok())
+        .find(|url| matches!(url.scheme(), "http" | "https") && permits(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_fail() {
+        let config = SoftFailConfig {
+            threshold: 2,
+            ..Default::default()
+        };
+        let soft_fail = SoftFail::default();
+        soft_fail.record(&config, true);
+        soft_fail.record(&config, false); // not in a row
+        soft_fail.record(&config, true);
+        assert!(!soft_fail.is_active(&config));
+        soft_fail.record(&config, true);
+        assert!(soft_fail.is_active(&config));
+
+        let expired = SoftFailConfig {
+            duration: Duration::ZERO,
+            ..config
+        };
+        let soft_fail = SoftFail::default();
+        soft_fail.record(&expired, true);
+        soft_fail.record(&expired, true);
+        assert!(!soft_fail.is_active(&expired));
+
+        let manual = SoftFailConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(SoftFail::default().is_active(&manual));
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let candidates = |urls: &[&str]| urls.iter().map(|url| url.to_string()).collect::<Vec<_>>();
+        let permits = |url: &Url| url.host_str() != Some("blocked.example.com");
+        let target = |urls: &[&str]| redirect_target(&candidates(urls), permits).map(String::from);
+
+        assert_eq!(
+            target(&["https://example.com/a.png"]).as_deref(),
+            Some("https://example.com/a.png")
+        );
+        assert_eq!(
+            target(&[
+                "data:image/png;base64,iVBORw0KGgo=",
+                "file:///srv/media/a.png",
+                "s3://bucket/a.png",
+                "http+unix:///run/files.sock:/a.png",
+                "https://blocked.example.com/a.png",
+                "http://mirror.example.com/a.png",
+            ])
+            .as_deref(),
+            Some("http://mirror.example.com/a.png")
+        );
+        assert_eq!(target(&["ipfs://bafyfoo", "not a url"]), None);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::X_CACHE_TIER;
+use crate::handler::{EncodeMode, Savings, content_type_mismatches, encode_savings};
+use http::Response;
+use hyper::body::Body;
+use serde_json::{Map, Value, json};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Counters of proxied requests since start
+pub struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    client_errors: AtomicU64, // 4xx
+    server_errors: AtomicU64, // 5xx
+    bytes_sent: AtomicU64,
+    cache_hits: AtomicU64,   // served from memory, or revalidated
+    cache_misses: AtomicU64, // fetched from origins
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub uptime: Duration,
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub bytes_sent: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub content_type_mismatches: u64,
+    pub encode_savings: [Savings; EncodeMode::ALL.len()],
+}
+
+impl Stats {
+    pub fn record<B: Body>(&self, response: &Response<B>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let status = response.status();
+        if status.is_client_error() {
+            self.client_errors.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(size) = response.body().size_hint().exact() {
+            self.bytes_sent.fetch_add(size, Ordering::Relaxed);
+        }
+        match response
+            .headers()
+            .get(X_CACHE_TIER)
+            .and_then(|tier| tier.to_str().ok())
+        {
+            Some("memory" | "revalidated") => self.cache_hits.fetch_add(1, Ordering::Relaxed),
+            Some("origin") => self.cache_misses.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Snapshot {
+            uptime: self.started.elapsed(),
+            requests: load(&self.requests),
+            client_errors: load(&self.client_errors),
+            server_errors: load(&self.server_errors),
+            bytes_sent: load(&self.bytes_sent),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            content_type_mismatches: content_type_mismatches(),
+            encode_savings: encode_savings(),
+        }
+    }
+}
+
+impl Snapshot {
+    // Counters during the time between the two snapshots
+    pub fn since(&self, earlier: &Snapshot) -> Snapshot {
+        Snapshot {
+            uptime: self.uptime.saturating_sub(earlier.uptime),
+            requests: self.requests - earlier.requests,
+            client_errors: self.client_errors - earlier.client_errors,
+            server_errors: self.server_errors - earlier.server_errors,
+            bytes_sent: self.bytes_sent - earlier.bytes_sent,
+            cache_hits: self.cache_hits - earlier.cache_hits,
+            cache_misses: self.cache_misses - earlier.cache_misses,
+            content_type_mismatches: self.content_type_mismatches - earlier.content_type_mismatches,
+            encode_savings: std::array::from_fn(|i| {
+                self.encode_savings[i].since(&earlier.encode_savings[i])
+            }),
+        }
+    }
+
+    // None before anything was looked up
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let total = self.cache_hits + self.cache_misses;
+        (total > 0).then(|| self.cache_hits as f64 / total as f64)
+    }
+
+    pub fn error_rate(&self) -> Option<f64> {
+        let errors = self.client_errors + self.server_errors;
+        (self.requests > 0).then(|| errors as f64 / self.requests as f64)
+    }
+
+    // One line for the logs, with rates over the interval this snapshot covers
+    pub fn to_metrics_json(&self, rss: Option<u64>) -> String {
+        let requests_per_sec = self.requests as f64 / self.uptime.as_secs_f64().max(1.0);
+        json!({
+            "interval_secs": self.uptime.as_secs(),
+            "requests": self.requests,
+            "requests_per_sec": round(requests_per_sec, 2),
+            "error_rate": self.error_rate().map(|rate| round(rate, 4)),
+            "cache_hit_ratio": self.cache_hit_ratio().map(|ratio| round(ratio, 4)),
+            "bytes_sent": self.bytes_sent,
+            "content_type_mismatches": self.content_type_mismatches,
+            "encode_saved_ratio": self.per_mode(|savings| {
+                savings.saved_ratio().map(|ratio| round(ratio, 4)).into()
+            }),
+            "encode_bytes_saved": self.encode_bytes_saved(),
+            "rss_bytes": rss,
+        })
+        .to_string()
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    pub fn to_value(&self) -> Value {
+        json!({
+            "uptime_secs": self.uptime.as_secs(),
+            "requests": self.requests,
+            "client_errors": self.client_errors,
+            "server_errors": self.server_errors,
+            "bytes_sent": self.bytes_sent,
+            "cache_hits": self.cache_hits,
+            "cache_misses": self.cache_misses,
+            "cache_hit_ratio": self.cache_hit_ratio().map(|ratio| round(ratio, 4)),
+            "content_type_mismatches": self.content_type_mismatches,
+            "encode_savings": self.per_mode(|savings| json!({
+                "encoded": savings.encoded,
+                "source_bytes": savings.source_bytes,
+                "output_bytes": savings.output_bytes,
+                "saved_ratio": savings.saved_ratio().map(|ratio| round(ratio, 4)),
+            })),
+        })
+    }
+
+    // Negative if outputs were larger than what origins sent
+    pub fn encode_bytes_saved(&self) -> i64 {
+        self.encode_savings
+            .iter()
+            .map(|savings| savings.source_bytes as i64 - savings.output_bytes as i64)
+            .sum()
+    }
+
+    // Object with a value for each mode of encoding
+    fn per_mode(&self, value: impl Fn(&Savings) -> Value) -> Value {
+        let modes: Map<String, Value> = EncodeMode::ALL
+            .iter()
+            .zip(&self.encode_savings)
+            .map(|(mode, savings)| (mode.as_str().to_string(), value(savings)))
+            .collect();
+        Value::Object(modes)
+    }
+}
+
+// Rates and ratios to a few decimal places, enough for dashboards
+fn round(value: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value * scale).round() / scale
+}
+
+// Resident set size of this process, only known on Linux
+pub fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    fn response(status: StatusCode, tier: Option<&str>, body: &'static str) -> Response<String> {
+        let mut response = Response::new(body.to_string());
+        *response.status_mut() = status;
+        if let Some(tier) = tier {
+            response
+                .headers_mut()
+                .insert(X_CACHE_TIER, tier.parse().unwrap());
+        }
+        response
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::default();
+        stats.record(&response(StatusCode::OK, Some("origin"), "12345"));
+        stats.record(&response(StatusCode::OK, Some("memory"), "12345"));
+        stats.record(&response(StatusCode::OK, Some("revalidated"), ""));
+        stats.record(&response(StatusCode::NOT_FOUND, None, ""));
+        stats.record(&response(StatusCode::BAD_GATEWAY, None, ""));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 5);
+        assert_eq!(snapshot.client_errors, 1);
+        assert_eq!(snapshot.server_errors, 1);
+        assert_eq!(snapshot.bytes_sent, 10);
+        assert_eq!(snapshot.cache_hit_ratio(), Some(2.0 / 3.0));
+        assert!(
+            snapshot
+                .to_json()
+                .contains("\"cache_hit_ratio\":0.6667,\"cache_hits\":2,\"cache_misses\":1,")
+        );
+
+        let later = Snapshot {
+            uptime: snapshot.uptime + Duration::from_secs(10),
+            requests: snapshot.requests + 20,
+            server_errors: snapshot.server_errors + 5,
+            content_type_mismatches: snapshot.content_type_mismatches + 1,
+            encode_savings: snapshot.encode_savings.map(|savings| Savings {
+                encoded: savings.encoded + 1,
+                source_bytes: savings.source_bytes + 1000,
+                output_bytes: savings.output_bytes + 400,
+            }),
+            ..snapshot.clone()
+        };
+        let interval = later.since(&snapshot);
+        assert_eq!(interval.requests, 20);
+        assert_eq!(interval.error_rate(), Some(0.25));
+        assert_eq!(interval.cache_hit_ratio(), None);
+        assert!(interval.to_json().contains(
+            "\"preview\":{\"encoded\":1,\"output_bytes\":400,\"saved_ratio\":0.6,\"source_bytes\":1000}"
+        ));
+        assert_eq!(
+            interval.to_metrics_json(None),
+            "{\"bytes_sent\":0,\"cache_hit_ratio\":null,\"content_type_mismatches\":1,\"encode_bytes_saved\":3000,\"encode_saved_ratio\":{\"avatar\":0.6,\"emoji\":0.6,\"original\":0.6,\"preview\":0.6,\"static\":0.6},\"error_rate\":0.25,\"interval_secs\":10,\"requests\":20,\"requests_per_sec\":2.0,\"rss_bytes\":null}"
+        );
+    }
+}